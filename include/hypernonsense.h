@@ -0,0 +1,59 @@
+/*
+ * C interface to hypernonsense, built with the `ffi` feature. Keep this in sync with `src/ffi.rs`.
+ *
+ * An index maps 64 bit keys to float vectors, stores a copy of every vector and ranks results by cosine distance.
+ * Every function returns a status, panics inside the library are caught and reported as HYPERNONSENSE_PANIC.
+ */
+
+#ifndef HYPERNONSENSE_H
+#define HYPERNONSENSE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct HypernonsenseIndex HypernonsenseIndex;
+
+typedef enum HypernonsenseStatus {
+    HYPERNONSENSE_OK = 0,
+    HYPERNONSENSE_NULL_POINTER = 1,
+    HYPERNONSENSE_INVALID_ARGUMENT = 2,
+    HYPERNONSENSE_DIMENSION_MISMATCH = 3,
+    HYPERNONSENSE_NON_FINITE_INPUT = 4,
+    HYPERNONSENSE_IO = 5,
+    HYPERNONSENSE_INVALID_FORMAT = 6,
+    HYPERNONSENSE_PANIC = 7,
+} HypernonsenseStatus;
+
+/* Create a new index, the planes are generated from the seed. Free it with hypernonsense_free. */
+HypernonsenseStatus hypernonsense_create(size_t dimension, uint8_t index_count, uint8_t plane_count, uint64_t seed, HypernonsenseIndex **out);
+
+/* Free an index, passing NULL does nothing */
+void hypernonsense_free(HypernonsenseIndex *index);
+
+/* Add a vector with `len` elements, adding a key which is already in the index replaces it */
+HypernonsenseStatus hypernonsense_add(HypernonsenseIndex *index, uint64_t key, const float *vector, size_t len);
+
+/* Number of items in the index, zero if the index is NULL */
+size_t hypernonsense_len(const HypernonsenseIndex *index);
+
+/*
+ * Find the nearest `count` items to a query vector, sorted by distance (small->large). `out_keys` (and `out_distances`,
+ * which may be NULL) must have room for `count` elements, the number of results written is stored in `out_found`.
+ */
+HypernonsenseStatus hypernonsense_query(const HypernonsenseIndex *index, const float *vector, size_t len, size_t count, uint64_t *out_keys, float *out_distances, size_t *out_found);
+
+/* Save an index to a file, `path` is a null terminated UTF-8 string */
+HypernonsenseStatus hypernonsense_save(const HypernonsenseIndex *index, const char *path);
+
+/* Load an index saved with hypernonsense_save. Free it with hypernonsense_free. */
+HypernonsenseStatus hypernonsense_load(const char *path, HypernonsenseIndex **out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
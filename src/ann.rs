@@ -0,0 +1,148 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::path::Path;
+
+use crate::error::HypernonsenseError;
+use crate::hyperindex::HyperIndex;
+use crate::multiindex::{DistanceNode, MultiIndex};
+use crate::persist::Persist;
+use crate::vector::Scalar;
+
+/// The operations shared by every kind of index, so an application can swap between them with a generic parameter or a
+/// `Box<dyn AnnIndex<K>>`. The type specific methods (e.g. `nearest_with`) have more options.
+pub trait AnnIndex<K:Eq+Hash, T:Scalar=f32> {
+    /// Add a point to the index
+    fn add(&mut self, key: K, vector: &[T]);
+
+    /// Remove a point from the index, returns true if anything was removed
+    fn remove(&mut self, key: &K) -> bool;
+
+    /// Find the nearest `count` points, ranked by `get_dist`
+    fn nearest(&self, point: &[T], count: usize, get_dist: &(dyn Fn(&[T], &K) -> T + Send + Sync)) -> Vec<DistanceNode<K, T>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the index to a file in the binary index format
+    fn save(&self, path: &Path) -> Result<(), HypernonsenseError>;
+
+    /// Load an index from a file previously written with `save`
+    fn load(path: &Path) -> Result<Self, HypernonsenseError>
+        where Self : Sized;
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync+Persist, T:Scalar+Persist> AnnIndex<K, T> for HyperIndex<K, T> {
+    fn add(&mut self, key: K, vector: &[T]) {
+        HyperIndex::add(self, key, vector);
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        HyperIndex::remove(self, key)
+    }
+
+    fn nearest(&self, point: &[T], count: usize, get_dist: &(dyn Fn(&[T], &K) -> T + Send + Sync)) -> Vec<DistanceNode<K, T>> {
+        HyperIndex::nearest(self, point, count, get_dist)
+    }
+
+    fn len(&self) -> usize {
+        HyperIndex::len(self)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), HypernonsenseError> {
+        HyperIndex::save(self, path)
+    }
+
+    fn load(path: &Path) -> Result<Self, HypernonsenseError> {
+        HyperIndex::load(path)
+    }
+}
+
+impl<K:Clone+Eq+Hash+Debug+Send+Sync+Persist, T:Scalar+Persist, V:Send+Sync+Persist> AnnIndex<K, T> for MultiIndex<K, T, V> {
+    fn add(&mut self, key: K, vector: &[T]) {
+        MultiIndex::add(self, key, vector);
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        MultiIndex::remove(self, key)
+    }
+
+    fn nearest(&self, point: &[T], count: usize, get_dist: &(dyn Fn(&[T], &K) -> T + Send + Sync)) -> Vec<DistanceNode<K, T>> {
+        MultiIndex::nearest(self, point, count, get_dist)
+    }
+
+    fn len(&self) -> usize {
+        MultiIndex::len(self)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), HypernonsenseError> {
+        MultiIndex::save(self, path)
+    }
+
+    fn load(path: &Path) -> Result<Self, HypernonsenseError> {
+        MultiIndex::load(path)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::ann::AnnIndex;
+    use crate::hyperindex::HyperIndex;
+    use crate::multiindex::MultiIndex;
+    use crate::vector::{ cosine_distance, random_unit_vector };
+
+    fn fill<I:AnnIndex<usize>>(index: &mut I, vectors: &[Vec<f32>]) {
+        for (key, v) in vectors.iter().enumerate() {
+            index.add(key, v);
+        }
+    }
+
+    #[test]
+    fn backends_are_interchangeable() {
+        let mut rng = thread_rng();
+        let vectors = (0..500).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+
+        let mut hyper = HyperIndex::new(30, 6, &mut rng);
+        fill(&mut hyper, &vectors);
+        let mut multi = MultiIndex::new(30, 5, 6, &mut rng);
+        fill(&mut multi, &vectors);
+
+        let mut backends: Vec<Box<dyn AnnIndex<usize>>> = vec![Box::new(hyper), Box::new(multi)];
+        let get_dist = |p: &[f32], k: &usize| cosine_distance(p, &vectors[*k]);
+        for index in backends.iter_mut() {
+            assert_eq!(500, index.len());
+            assert_eq!(7, index.nearest(&vectors[7], 3, &get_dist)[0].key);
+
+            assert!(index.remove(&7));
+            assert!(!index.remove(&7));
+            assert_eq!(499, index.len());
+        }
+    }
+
+    #[test]
+    fn hyperindex_save_and_load() {
+        let mut rng = thread_rng();
+        let vectors = (0..200).map(|_| random_unit_vector(20, &mut rng)).collect::<Vec<_>>();
+
+        let mut a = HyperIndex::new(20, 8, &mut rng);
+        fill(&mut a, &vectors);
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_ann_{}.hyp", std::process::id()));
+        AnnIndex::save(&a, &path).unwrap();
+        let b = <HyperIndex<usize> as AnnIndex<usize>>::load(&path).unwrap();
+        let c = MultiIndex::<usize>::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(a.len(), b.len());
+        assert_eq!(1, c.indices_len());
+        for v in vectors.iter().take(10) {
+            assert_eq!(a.key(v), b.key(v));
+            assert_eq!(a.group(&a.key(v)), b.group(&b.key(v)));
+        }
+    }
+}
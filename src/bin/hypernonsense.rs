@@ -0,0 +1,374 @@
+// Command line tool for building, querying and inspecting index files.
+//
+// Vector files are text, with one vector per line and the elements separated by whitespace or commas. The key of each vector
+// is its line number (starting from zero), blank lines and lines starting with `#` are skipped but still counted. Files
+// ending in `.fvecs` or `.bvecs` are read in the binary benchmark formats instead, keyed by their position in the file, and
+// files ending in `.jsonl` are JSON Lines with an integer key for each vector.
+
+// Explicit `return` is the house style
+#![allow(clippy::needless_return)]
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::exit;
+
+use rand::thread_rng;
+
+use hypernonsense::builder::MultiIndexBuilder;
+use hypernonsense::dataset;
+use hypernonsense::error::HypernonsenseError;
+use hypernonsense::jsonl;
+use hypernonsense::metric::Metric;
+use hypernonsense::multiindex::{MultiIndex, Progress};
+use hypernonsense::query::QueryOptions;
+
+const USAGE: &str = "usage:
+    hypernonsense build <vectors> <index> [--indices N] [--planes N] [--seed N] [--metric cosine|euclidean]
+    hypernonsense query <index> <vectors> [--count N] [--probe-radius N]
+    hypernonsense stats <index>
+    hypernonsense export <index> <output.jsonl>
+    hypernonsense tune <vectors> [--group-size N] [--candidates N] [--max-indices N]";
+
+type Index = MultiIndex<u64, f32>;
+
+fn read_vectors(path: &str) -> Result<Vec<(u64, Vec<f32>)>, String> {
+    let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+
+    let vectors = if path.ends_with(".fvecs") {
+        dataset::read_fvecs(&mut BufReader::new(file))
+    } else if path.ends_with(".bvecs") {
+        dataset::read_bvecs(&mut BufReader::new(file))
+    } else if path.ends_with(".jsonl") {
+        return check_vectors(path, jsonl::read_jsonl(BufReader::new(file)).collect::<Result<Vec<_>, _>>());
+    } else {
+        return read_text_vectors(path, BufReader::new(file));
+    };
+
+    return check_vectors(path, vectors.map(|v| v.into_iter().enumerate().map(|(i, v)| (i as u64, v)).collect()));
+}
+
+fn check_vectors(path: &str, vectors: Result<Vec<(u64, Vec<f32>)>, HypernonsenseError>) -> Result<Vec<(u64, Vec<f32>)>, String> {
+    let vectors = vectors.map_err(|e| format!("failed to read {}: {}", path, e))?;
+    if vectors.is_empty() {
+        return Err(format!("{} does not contain any vectors", path));
+    }
+    let dimension = vectors[0].1.len();
+    if let Some((key, v)) = vectors.iter().find(|(_, v)| v.len() != dimension) {
+        return Err(format!("{}: vector {} has {} elements, expected {}", path, key, v.len(), dimension));
+    }
+    return Ok(vectors);
+}
+
+// `path` is only used in error messages
+fn read_text_vectors<R: BufRead>(path: &str, reader: R) -> Result<Vec<(u64, Vec<f32>)>, String> {
+    let mut vectors = Vec::new();
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let vector = line.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f32>().map_err(|_| format!("{}:{}: '{}' is not a number", path, line_number + 1, s)))
+            .collect::<Result<Vec<_>, _>>()?;
+        vectors.push((line_number as u64, vector));
+    }
+
+    if vectors.is_empty() {
+        return Err(format!("{} does not contain any vectors", path));
+    }
+    let dimension = vectors[0].1.len();
+    if let Some((key, v)) = vectors.iter().find(|(_, v)| v.len() != dimension) {
+        return Err(format!("{}:{}: expected {} elements, got {}", path, key + 1, dimension, v.len()));
+    }
+
+    return Ok(vectors);
+}
+
+// Split the arguments after the command into positional arguments and `--name value` options
+fn parse_args(args: &[String]) -> Result<(Vec<&str>, HashMap<&str, &str>), String> {
+    let mut positional = Vec::new();
+    let mut options = HashMap::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                let value = iter.next().ok_or_else(|| format!("missing value for --{}", name))?;
+                options.insert(name, value.as_str());
+            },
+            None => positional.push(arg.as_str()),
+        }
+    }
+
+    return Ok((positional, options));
+}
+
+fn option<T: std::str::FromStr>(options: &HashMap<&str, &str>, name: &str, default: T) -> Result<T, String> {
+    return match options.get(name) {
+        Some(value) => value.parse().map_err(|_| format!("invalid value for --{}: {}", name, value)),
+        None => Ok(default),
+    };
+}
+
+fn positional<'a>(args: &[&'a str], count: usize) -> Result<Vec<&'a str>, String> {
+    if args.len() != count {
+        return Err(USAGE.to_string());
+    }
+    return Ok(args.to_vec());
+}
+
+fn build(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let (args, options) = parse_args(args)?;
+    let paths = positional(&args, 2)?;
+
+    let metric = match options.get("metric").copied().unwrap_or("cosine") {
+        "cosine" => Metric::Cosine,
+        "euclidean" => Metric::Euclidean,
+        other => return Err(format!("unknown metric: {}", other)),
+    };
+
+    let vectors = read_vectors(paths[0])?;
+    let mut builder = MultiIndexBuilder::new(vectors[0].1.len())
+        .index_count(option(&options, "indices", MultiIndexBuilder::<u64>::DEFAULT_INDEX_COUNT)?)
+        .plane_count(option(&options, "planes", MultiIndexBuilder::<u64>::DEFAULT_PLANE_COUNT)?)
+        .store_vectors(true)
+        .metric(metric);
+    if options.contains_key("seed") {
+        builder = builder.seed(option(&options, "seed", 0u64)?);
+    }
+
+    let mut index: Index = builder.build().map_err(|e| e.to_string())?;
+    let count = vectors.len();
+    index.add_batch_with_progress(vectors, |p| {
+        if let Progress::Items { processed, total } = p {
+            eprint!("\rindexed {}/{}", processed, total);
+        }
+    });
+    eprintln!();
+    index.save(paths[1]).map_err(|e| format!("failed to save {}: {}", paths[1], e))?;
+
+    writeln!(out, "indexed {} vectors into {}", count, paths[1]).map_err(|e| e.to_string())?;
+    return Ok(());
+}
+
+// Options not given on the command line keep the library defaults
+fn query_options(options: &HashMap<&str, &str>) -> Result<QueryOptions, String> {
+    let defaults = QueryOptions::default();
+    let probe_radius = option(options, "probe-radius", defaults.probe_radius)?;
+    return Ok(defaults.with_probe_radius(probe_radius));
+}
+
+fn query(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let (args, options) = parse_args(args)?;
+    let paths = positional(&args, 2)?;
+    let count = option(&options, "count", 10usize)?;
+    let query_options = query_options(&options)?;
+
+    let index = Index::load(paths[0]).map_err(|e| format!("failed to load {}: {}", paths[0], e))?;
+    if !index.stores_vectors() {
+        return Err(format!("{} does not store vectors, so results cannot be ranked", paths[0]));
+    }
+
+    // One line of output per query: the query line number then `key:distance` for every result
+    for (line, vector) in read_vectors(paths[1])? {
+        if vector.len() != index.dimensions() {
+            return Err(format!("query vectors have {} elements, the index has {} dimensions", vector.len(), index.dimensions()));
+        }
+
+        let results = index.nearest_k_with(&vector, count, &query_options)
+            .iter()
+            .map(|n| format!("{}:{}", n.key, n.distance))
+            .collect::<Vec<_>>();
+        writeln!(out, "{} {}", line, results.join(" ")).map_err(|e| e.to_string())?;
+    }
+
+    return Ok(());
+}
+
+fn stats(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let (args, _) = parse_args(args)?;
+    let paths = positional(&args, 1)?;
+
+    let index = Index::load(paths[0]).map_err(|e| format!("failed to load {}: {}", paths[0], e))?;
+    let all = index.index_stats();
+    let stats = &all.combined;
+
+    let lines = [
+        format!("dimensions:     {}", index.dimensions()),
+        format!("indices:        {}", index.indices_len()),
+        format!("planes:         {}", index.planes_len()),
+        format!("hash family:    {:?}", index.hash_family()),
+        format!("metric:         {:?}", index.metric()),
+        format!("stores vectors: {}", index.stores_vectors()),
+        format!("items:          {}", index.len()),
+        format!("unique items:   {}", all.unique_items),
+        format!("groups:         {}", stats.group_count),
+        format!("group size:     min {} / mean {:.2} / max {} / std dev {:.2}", stats.min_group_size, stats.mean_group_size, stats.max_group_size, stats.group_size_std_dev),
+    ];
+    for line in lines.iter() {
+        writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+    }
+    return Ok(());
+}
+
+fn export(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let (args, _) = parse_args(args)?;
+    let paths = positional(&args, 2)?;
+
+    let index = Index::load(paths[0]).map_err(|e| format!("failed to load {}: {}", paths[0], e))?;
+    if !index.stores_vectors() {
+        return Err(format!("{} does not store vectors", paths[0]));
+    }
+
+    let file = File::create(paths[1]).map_err(|e| format!("failed to create {}: {}", paths[1], e))?;
+    let mut writer = BufWriter::new(file);
+    index.write_jsonl(&mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(|e| format!("failed to write {}: {}", paths[1], e))?;
+
+    writeln!(out, "exported {} vectors to {}", index.len(), paths[1]).map_err(|e| e.to_string())?;
+    return Ok(());
+}
+
+fn tune(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let (args, options) = parse_args(args)?;
+    let paths = positional(&args, 1)?;
+    let group_size = option(&options, "group-size", 10f32)?;
+    let candidates = option(&options, "candidates", 100usize)?;
+    let max_indices = option(&options, "max-indices", 50u8)?;
+
+    let vectors = read_vectors(paths[0])?.into_iter().map(|(_, v)| v).collect::<Vec<_>>();
+    let config = Index::autotune_with_progress(vectors[0].len(), group_size, candidates, max_indices, &vectors, &mut thread_rng(), |p| {
+        if let Progress::Candidate(c) = p {
+            eprintln!("trying --indices {} --planes {}", c.index_count, c.plane_count);
+        }
+    });
+
+    writeln!(out, "--indices {} --planes {}", config.index_count, config.plane_count).map_err(|e| e.to_string())?;
+    return Ok(());
+}
+
+// Run a command, writing its output to `out`. Progress and errors are left to the caller.
+fn run(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    return match args.first().map(|s| s.as_str()) {
+        Some("build") => build(&args[1..], out),
+        Some("query") => query(&args[1..], out),
+        Some("stats") => stats(&args[1..], out),
+        Some("export") => export(&args[1..], out),
+        Some("tune") => tune(&args[1..], out),
+        _ => Err(USAGE.to_string()),
+    };
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Err(e) = run(&args, &mut std::io::stdout().lock()) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+    use std::io::Cursor;
+
+    use rand::prelude::*;
+
+    use hypernonsense::query::QueryOptions;
+    use hypernonsense::vector::random_unit_vector;
+
+    use super::{ parse_args, query_options, read_text_vectors, run };
+
+    fn args(args: &[&str]) -> Vec<String> {
+        return args.iter().map(|s| s.to_string()).collect();
+    }
+
+    #[test]
+    fn parse_args_splits_positional_arguments_and_options() {
+        let a = args(&[ "a.txt", "--count", "5", "b.hyp", "--probe-radius", "2" ]);
+        let (positional, options) = parse_args(&a).unwrap();
+        assert_eq!(vec![ "a.txt", "b.hyp" ], positional);
+        assert_eq!(Some(&"5"), options.get("count"));
+        assert_eq!(Some(&"2"), options.get("probe-radius"));
+
+        assert!(parse_args(&args(&[ "a.txt", "--count" ])).is_err());
+    }
+
+    #[test]
+    fn probe_radius_defaults_to_the_library_default() {
+        let a = args(&[]);
+        let (_, options) = parse_args(&a).unwrap();
+        assert_eq!(QueryOptions::default().probe_radius, query_options(&options).unwrap().probe_radius);
+
+        let a = args(&[ "--probe-radius", "3" ]);
+        let (_, options) = parse_args(&a).unwrap();
+        assert_eq!(3, query_options(&options).unwrap().probe_radius);
+
+        let a = args(&[ "--probe-radius", "lots" ]);
+        let (_, options) = parse_args(&a).unwrap();
+        assert!(query_options(&options).is_err());
+    }
+
+    #[test]
+    fn text_vectors_are_keyed_by_line_number() {
+        let text = "1, 2, 3\n\n# a comment\n4 5 6\n  7,8 9  \n";
+        let vectors = read_text_vectors("test", Cursor::new(text)).unwrap();
+        assert_eq!(vec![ (0, vec![ 1.0, 2.0, 3.0 ]), (3, vec![ 4.0, 5.0, 6.0 ]), (4, vec![ 7.0, 8.0, 9.0 ]) ], vectors);
+
+        let err = read_text_vectors("test", Cursor::new("1 2\n3 x\n")).unwrap_err();
+        assert!(err.contains("test:2"), "{}", err);
+        let err = read_text_vectors("test", Cursor::new("1 2\n3 4 5\n")).unwrap_err();
+        assert!(err.contains("expected 2 elements"), "{}", err);
+        assert!(read_text_vectors("test", Cursor::new("# nothing\n")).is_err());
+    }
+
+    #[test]
+    fn build_then_query_round_trip() {
+        let dir = std::env::temp_dir().join(format!("hypernonsense_cli_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let vectors_path = dir.join("vectors.txt");
+        let queries_path = dir.join("queries.txt");
+        let index_path = dir.join("index.hyp");
+
+        let mut rng = StdRng::seed_from_u64(4);
+        let vectors = (0..200).map(|_| random_unit_vector(16, &mut rng)).collect::<Vec<_>>();
+        let lines = vectors.iter()
+            .map(|v| v.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        fs::write(&vectors_path, lines.join("\n")).unwrap();
+        fs::write(&queries_path, lines[..5].join("\n")).unwrap();
+
+        let [ vectors_path, queries_path, index_path ] = [ &vectors_path, &queries_path, &index_path ].map(|p| p.to_str().unwrap().to_string());
+
+        let mut out = Vec::new();
+        run(&args(&[ "build", &vectors_path, &index_path, "--indices", "8", "--planes", "5", "--seed", "3" ]), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("indexed 200 vectors"));
+
+        // Every query is one of the indexed vectors, so it finds itself first at (almost) zero distance
+        let mut out = Vec::new();
+        run(&args(&[ "query", &index_path, &queries_path, "--count", "3" ]), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let results = out.lines().collect::<Vec<_>>();
+        assert_eq!(5, results.len());
+        for (i, line) in results.iter().enumerate() {
+            let mut parts = line.split(' ');
+            assert_eq!(i.to_string(), parts.next().unwrap());
+            let (key, distance) = parts.next().unwrap().split_once(':').unwrap();
+            assert_eq!(i.to_string(), key);
+            assert!(distance.parse::<f32>().unwrap().abs() < 1e-4);
+        }
+
+        let mut out = Vec::new();
+        run(&args(&[ "stats", &index_path ]), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().lines().any(|l| l == "items:          200"));
+
+        assert!(run(&args(&[ "query", &index_path ]), &mut Vec::new()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng, thread_rng};
+use rand_chacha::ChaCha20Rng;
+#[cfg(feature = "rayon")]
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::error::HypernonsenseError;
+use crate::eviction::{Capacity, Eviction};
+use crate::hyperindex::HashFamily;
+use crate::metric::Metric;
+use crate::multiindex::{IndexConfig, MultiIndex};
+use crate::recorder::Recorder;
+use crate::vector::{Normalization, Scalar};
+
+/// Configures and constructs a `MultiIndex`.
+///
+/// ```
+/// use hypernonsense::builder::MultiIndexBuilder;
+/// use hypernonsense::multiindex::MultiIndex;
+///
+/// let index: MultiIndex<usize> = MultiIndexBuilder::new(300)
+///     .index_count(15)
+///     .plane_count(10)
+///     .seed(42)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct MultiIndexBuilder<K, T=f32, V=()> {
+    dimension: usize,
+    index_count: u8,
+    plane_count: u8,
+    seed: Option<u64>,
+    store_vectors: bool,
+    metric: Metric,
+    normalization: Normalization,
+    family: HashFamily,
+    recorder: Option<Arc<dyn Recorder>>,
+    capacity: Option<(usize, Eviction)>,
+    #[cfg(feature = "rayon")]
+    thread_pool: Option<Arc<ThreadPool>>,
+    #[cfg(feature = "rayon")]
+    max_threads: Option<usize>,
+    phantom: PhantomData<(K, T, V)>,
+}
+
+impl<K:Clone+Eq+Hash+Debug+Send+Sync, T:Scalar, V:Send+Sync> MultiIndexBuilder<K, T, V> {
+    pub const DEFAULT_INDEX_COUNT: u8 = 10;
+    pub const DEFAULT_PLANE_COUNT: u8 = 10;
+
+    pub fn new(dimension: usize) -> MultiIndexBuilder<K, T, V> {
+        MultiIndexBuilder {
+            dimension,
+            index_count: Self::DEFAULT_INDEX_COUNT,
+            plane_count: Self::DEFAULT_PLANE_COUNT,
+            seed: None,
+            store_vectors: false,
+            metric: Metric::default(),
+            normalization: Normalization::default(),
+            family: HashFamily::default(),
+            recorder: None,
+            capacity: None,
+            #[cfg(feature = "rayon")]
+            thread_pool: None,
+            #[cfg(feature = "rayon")]
+            max_threads: None,
+            phantom: PhantomData
+        }
+    }
+
+    /// Number of sub indices. More indices increases accuracy, but decreases speed and increases memory consumption.
+    pub fn index_count(mut self, index_count: u8) -> Self {
+        self.index_count = index_count;
+        self
+    }
+
+    /// Number of hyperplanes in each sub index. More planes increases speed but decreases accuracy.
+    pub fn plane_count(mut self, plane_count: u8) -> Self {
+        self.plane_count = plane_count;
+        self
+    }
+
+    /// Use the index and plane count from an `IndexConfig`, e.g. one recommended by `MultiIndex::autotune`
+    pub fn config(mut self, config: IndexConfig) -> Self {
+        self.index_count = config.index_count;
+        self.plane_count = config.plane_count;
+        self
+    }
+
+    /// Seed the random number generator used to generate hyperplanes. If this is not set a random seed is used.
+    ///
+    /// Planes are generated with ChaCha20, so the same seed produces the same planes on every run and every platform.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Keep a copy of every inserted vector in the index, so they can be retrieved by key
+    pub fn store_vectors(mut self, store_vectors: bool) -> Self {
+        self.store_vectors = store_vectors;
+        self
+    }
+
+    /// Metric used by `nearest_k` to rank candidates using the stored vectors
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// How vectors which are not unit length are treated. Cosine distance is only correct for unit vectors.
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// The family of hash functions used to assign vectors to groups. Defaults to random hyperplanes, use
+    /// `HashFamily::PStable` for euclidean distance or `HashFamily::CrossPolytope` for higher recall angular search.
+    pub fn hash_family(mut self, family: HashFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Send insert and query events to a recorder, e.g. a `CountingRecorder` to export metrics
+    pub fn recorder(mut self, recorder: Arc<dyn Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Limit the index to at most `capacity` items. Once it is full every insert evicts an item chosen by the policy, so the
+    /// index can be used as a similarity cache. Evictions are much cheaper with vector storage, otherwise every group has to be
+    /// searched for the evicted key.
+    pub fn capacity(mut self, capacity: usize, policy: Eviction) -> Self {
+        self.capacity = Some((capacity, policy));
+        self
+    }
+
+    /// Run inserts and queries on this thread pool instead of the global rayon pool. The pool can be shared with other indices.
+    #[cfg(feature = "rayon")]
+    pub fn thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.thread_pool = Some(pool);
+        self
+    }
+
+    /// Run inserts and queries on a new thread pool with this many threads, instead of the global rayon pool
+    #[cfg(feature = "rayon")]
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    pub fn build(self) -> Result<MultiIndex<K, T, V>, HypernonsenseError> {
+        if self.dimension == 0 {
+            return Err(HypernonsenseError::InvalidConfig("dimension must be at least one".to_string()));
+        }
+        if self.index_count == 0 {
+            return Err(HypernonsenseError::InvalidConfig("index count must be at least one".to_string()));
+        }
+        self.family.validate(self.dimension).map_err(HypernonsenseError::InvalidConfig)?;
+
+        if let Some((0, _)) = self.capacity {
+            return Err(HypernonsenseError::InvalidConfig("capacity must be at least one".to_string()));
+        }
+
+        #[cfg(feature = "rayon")]
+        let pool = match (self.thread_pool, self.max_threads) {
+            (Some(_), Some(_)) => return Err(HypernonsenseError::InvalidConfig("set either a thread pool or max threads, not both".to_string())),
+            (Some(pool), None) => Some(pool),
+            (None, Some(0)) => return Err(HypernonsenseError::InvalidConfig("max threads must be at least one".to_string())),
+            (None, Some(threads)) => {
+                let pool = ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|e| HypernonsenseError::InvalidConfig(format!("failed to build thread pool: {}", e)))?;
+                Some(Arc::new(pool))
+            },
+            (None, None) => None,
+        };
+
+        // Always pick a seed, so that the index can report it even if one was not chosen
+        let seed = self.seed.unwrap_or_else(|| thread_rng().gen());
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+
+        let mut index = MultiIndex::create(self.dimension, self.index_count, self.plane_count, self.family, self.store_vectors, &mut rng);
+        index.set_metric(self.metric);
+        index.set_seed(Some(seed));
+        index.set_normalization(self.normalization);
+        index.set_recorder(self.recorder);
+        index.set_capacity(self.capacity.map(|(limit, policy)| Capacity::new(limit, policy)));
+        #[cfg(feature = "rayon")]
+        index.set_thread_pool(pool);
+
+        return Ok(index);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::builder::MultiIndexBuilder;
+    use crate::error::HypernonsenseError;
+    use crate::multiindex::MultiIndex;
+    #[cfg(feature = "rayon")]
+    use crate::vector::random_unit_vector;
+    #[cfg(feature = "rayon")]
+    use rand::thread_rng;
+
+    #[test]
+    fn builder_uses_defaults() {
+        let a: MultiIndex<usize> = MultiIndexBuilder::new(300).build().unwrap();
+
+        assert_eq!(300, a.dimensions());
+        assert_eq!(MultiIndexBuilder::<usize>::DEFAULT_INDEX_COUNT as usize, a.indices_len());
+        assert_eq!(MultiIndexBuilder::<usize>::DEFAULT_PLANE_COUNT as usize, a.planes_len());
+    }
+
+    #[test]
+    fn builder_applies_settings() {
+        let a: MultiIndex<usize> = MultiIndexBuilder::new(30).index_count(3).plane_count(7).build().unwrap();
+
+        assert_eq!(30, a.dimensions());
+        assert_eq!(3, a.indices_len());
+        assert_eq!(7, a.planes_len());
+        assert!(!a.stores_vectors());
+
+        let b: MultiIndex<usize> = MultiIndexBuilder::new(30).store_vectors(true).build().unwrap();
+        assert!(b.stores_vectors());
+    }
+
+    #[test]
+    fn builder_rejects_invalid_config() {
+        let a = MultiIndexBuilder::<usize>::new(0).build();
+        assert!(matches!(a, Err(HypernonsenseError::InvalidConfig(_))));
+
+        let b = MultiIndexBuilder::<usize>::new(10).index_count(0).build();
+        assert!(matches!(b, Err(HypernonsenseError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn seeded_builders_are_identical() {
+        let mut a: MultiIndex<usize> = MultiIndexBuilder::new(30).seed(7).build().unwrap();
+        let mut b: MultiIndex<usize> = MultiIndexBuilder::new(30).seed(7).build().unwrap();
+
+        let v = vec![1f32; 30];
+        a.add(1, &v);
+        b.add(1, &v);
+        let u = vec![0.5f32; 30];
+        assert_eq!(a.nearest_points(&u), b.nearest_points(&u));
+        assert_eq!(Some(7), a.seed());
+    }
+
+    #[test]
+    fn seeded_planes_are_stable() {
+        // If this changes then indices built with the same seed will no longer match indices built by older versions
+        let a: MultiIndex<usize> = MultiIndexBuilder::new(4).index_count(1).plane_count(1).seed(7).build().unwrap();
+        let plane = &a.indices()[0].planes()[0];
+
+        assert_eq!(vec![-0.8752256f32, 0.03612562, 0.47559676, -0.08051564], *plane);
+    }
+
+    #[test]
+    fn unseeded_builder_reports_seed() {
+        let a: MultiIndex<usize> = MultiIndexBuilder::new(30).build().unwrap();
+        let b: MultiIndex<usize> = MultiIndexBuilder::new(30).seed(a.seed().unwrap()).build().unwrap();
+
+        assert_eq!(a.indices()[0].planes(), b.indices()[0].planes());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn builder_binds_thread_pool() {
+        let mut a: MultiIndex<usize> = MultiIndexBuilder::new(30).max_threads(2).build().unwrap();
+        let pool = a.thread_pool().unwrap().clone();
+        assert_eq!(2, pool.current_num_threads());
+
+        let v = random_unit_vector(30, &mut thread_rng());
+        a.add(0, &v);
+
+        // Ranking happens inside the query, so it should be running on one of the pool threads
+        let result = a.nearest(&v, 5, |_, _| {
+            assert!(pool.current_thread_index().is_some());
+            0f32
+        });
+        assert_eq!(1, result.len());
+
+        let b = MultiIndexBuilder::<usize>::new(30).max_threads(2).thread_pool(pool.clone()).build();
+        assert!(matches!(b, Err(HypernonsenseError::InvalidConfig(_))));
+        let c = MultiIndexBuilder::<usize>::new(30).max_threads(0).build();
+        assert!(matches!(c, Err(HypernonsenseError::InvalidConfig(_))));
+    }
+}
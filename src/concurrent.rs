@@ -0,0 +1,384 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bit_vec::BitVec;
+use rand::Rng;
+use crate::par::prelude::*;
+
+use crate::hyperindex::HyperIndex;
+use crate::metric::Metric;
+use crate::multiindex::{DistanceNode, MultiIndex};
+use crate::query::{Groups, QueryOptions, for_each_probe, hamming_distance, probe_count, rank_candidates};
+use crate::vector::{Normalization, Scalar};
+
+// The groups are shared with any snapshots taken of the index, and copied the first time they are written to afterwards
+type Shard<K> = RwLock<Arc<Groups<K>>>;
+
+// A panic while holding a lock can only happen part way through pushing or removing keys, the groups are still usable
+fn read<K>(shard: &Shard<K>) -> RwLockReadGuard<'_, Arc<Groups<K>>> {
+    shard.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<K>(shard: &Shard<K>) -> RwLockWriteGuard<'_, Arc<Groups<K>>> {
+    shard.write().unwrap_or_else(|e| e.into_inner())
+}
+
+// A single sub index. The planes are kept in a `HyperIndex` which never has anything added to it, the groups are split
+// between shards (by the hash of the group key) which are locked separately.
+struct ConcurrentHyperIndex<K:Send, T:Scalar> {
+    hasher: HyperIndex<K, T>,
+    shards: Vec<Shard<K>>,
+}
+
+impl<K:Send, T:Scalar> ConcurrentHyperIndex<K, T> {
+    fn shard(&self, key: &BitVec) -> &Shard<K> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        return &self.shards[hasher.finish() as usize % self.shards.len()];
+    }
+}
+
+/// A `MultiIndex` which can be added to and queried at the same time from multiple threads, through a shared reference.
+///
+/// The groups of every sub index are split into shards with a lock each, so an `add` only blocks queries which touch the
+/// same shard, and only for as long as it takes to push a key.
+pub struct ConcurrentMultiIndex<K:Send+Sync, T:Scalar=f32> {
+    indices: Vec<ConcurrentHyperIndex<K, T>>,
+
+    // Number of items added (minus the number removed)
+    len: AtomicUsize,
+
+    // Held shared by every add and remove, and exclusively while taking a snapshot, so a snapshot never sees an item which
+    // has only been added to some of the sub indices
+    writers: RwLock<()>,
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync, T:Scalar> ConcurrentMultiIndex<K, T> {
+    pub const DEFAULT_SHARD_COUNT: usize = 16;
+
+    pub fn new<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, rng: &mut R) -> ConcurrentMultiIndex<K, T> {
+        Self::with_shards(dimension, index_count, hyperplane_count, Self::DEFAULT_SHARD_COUNT, rng)
+    }
+
+    /// Create a new index with the given number of shards in each sub index. More shards means less contention between
+    /// writers, but each shard has a small fixed cost.
+    pub fn with_shards<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, shard_count: usize, mut rng: &mut R) -> ConcurrentMultiIndex<K, T> {
+        assert!(shard_count > 0, "shard count must be at least one");
+
+        ConcurrentMultiIndex {
+            indices: (0..index_count).map(|_| ConcurrentHyperIndex {
+                hasher: HyperIndex::new(dimension, hyperplane_count, &mut rng),
+                shards: (0..shard_count).map(|_| RwLock::new(Arc::new(Groups::default()))).collect(),
+            }).collect(),
+            len: AtomicUsize::new(0),
+            writers: RwLock::new(()),
+        }
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.indices[0].hasher.dimensions()
+    }
+
+    pub fn planes_len(&self) -> usize {
+        self.indices[0].hasher.planes_len()
+    }
+
+    pub fn indices_len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Number of items in the index
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn add(&self, key: K, vector: &[T]) {
+        // Hash in parallel before taking the writers lock. Holding it across rayon work could deadlock: a thread waiting
+        // for the par_iter can steal another add, which then blocks on the lock behind a waiting snapshot.
+        let bits = self.indices.par_iter()
+            .map(|idx| idx.hasher.key(vector))
+            .collect::<Vec<_>>();
+
+        let _writing = self.writers.read().unwrap_or_else(|e| e.into_inner());
+        for (idx, bits) in self.indices.iter().zip(bits) {
+            Arc::make_mut(&mut write(idx.shard(&bits)))
+                .entry(bits)
+                .or_default()
+                .push(key.clone());
+        }
+
+        self.len.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Remove every occurrence of the given key from the index, returns true if anything was removed. This has to search every group.
+    pub fn remove(&self, key: &K) -> bool {
+        // The sub indices are searched one after another, not with rayon, for the same reason as in `add`
+        let _writing = self.writers.read().unwrap_or_else(|e| e.into_inner());
+        let mut removed = Vec::with_capacity(self.indices.len());
+        for idx in self.indices.iter() {
+            let mut count = 0;
+            for shard in idx.shards.iter() {
+                for group in Arc::make_mut(&mut write(shard)).values_mut() {
+                    let before = group.len();
+                    group.retain(|k| k != key);
+                    count += before - group.len();
+                }
+            }
+            removed.push(count);
+        }
+
+        // Every sub index contains the same items, so count how many were removed from the first one
+        self.len.fetch_sub(removed[0], Ordering::AcqRel);
+        return removed[0] > 0;
+    }
+
+    /// Remove the empty groups left behind by removals and release unused memory. Each shard is only locked while it is compacted.
+    pub fn compact(&self) {
+        self.indices.par_iter()
+            .flat_map_iter(|idx| idx.shards.iter())
+            .for_each(|shard| {
+                let mut shard = write(shard);
+                let groups = Arc::make_mut(&mut shard);
+                groups.retain(|_, g| !g.is_empty());
+                for group in groups.values_mut() {
+                    group.shrink_to_fit();
+                }
+                groups.shrink_to_fit();
+            });
+    }
+
+    /// Take a consistent point in time copy of the index as a `MultiIndex`, e.g. to save it or to serve queries from while
+    /// this index keeps changing. Every add or remove either happened before the snapshot or not at all. Writers are only
+    /// blocked while every shard is shared with the snapshot, which doesn't copy anything. Each shard is then copied the
+    /// first time it is written to, or by the snapshot itself if it isn't. The snapshot shares the planes with this index.
+    pub fn snapshot(&self) -> MultiIndex<K, T> {
+        let shards = {
+            let _frozen = self.writers.write().unwrap_or_else(|e| e.into_inner());
+            self.indices.iter()
+                .map(|idx| idx.shards.iter().map(|shard| read(shard).clone()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let indices = self.indices.par_iter().zip(shards.into_par_iter())
+            .map(|(idx, shards)| {
+                let mut groups = Groups::default();
+                for shard in shards {
+                    let shard = Arc::try_unwrap(shard).unwrap_or_else(|shared| (*shared).clone());
+                    groups.extend(shard.into_iter().filter(|(_, members)| !members.is_empty()));
+                }
+
+                HyperIndex::from_shared_parts(idx.hasher.export_planes(), groups)
+            })
+            .collect();
+
+        return MultiIndex::from_parts(indices, None, HashMap::new(), Metric::default(), None, Normalization::default());
+    }
+
+    pub fn nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_with(point, count, &QueryOptions::default(), get_dist);
+    }
+
+    pub fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return rank_candidates(self.nearest_points_set_with(point, options), count, |k| get_dist(point, k));
+    }
+
+    pub fn nearest_points_set(&self, point: &[T]) -> HashSet<K>
+    {
+        return self.nearest_points_set_with(point, &QueryOptions::default());
+    }
+
+    pub fn nearest_points_set_with(&self, point: &[T], options: &QueryOptions) -> HashSet<K>
+    {
+        return self.indices.par_iter()
+            .flat_map_iter(|idx| {
+                let mut key = idx.hasher.key(point);
+                let mut found = Vec::new();
+
+                // Like `groups_within_keyed`, check the distance to every group when that's cheaper than probing
+                let groups = idx.shards.iter().map(|shard| read(shard).len()).sum::<usize>();
+                if probe_count(key.len(), options.probe_radius) > groups {
+                    for shard in idx.shards.iter() {
+                        for (k, group) in read(shard).iter() {
+                            if hamming_distance(k, &key) <= options.probe_radius as u32 {
+                                found.extend(group.iter().cloned());
+                            }
+                        }
+                    }
+                } else {
+                    for_each_probe(&mut key, options.probe_radius, &mut |k| {
+                        if let Some(group) = read(idx.shard(k)).get(k) {
+                            found.extend(group.iter().cloned());
+                        }
+                    });
+                }
+                found.into_iter()
+            })
+            .collect::<HashSet<K>>();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::concurrent::ConcurrentMultiIndex;
+    use crate::par::prelude::*;
+    use crate::query::QueryOptions;
+    use crate::vector::{ random_unit_vector, cosine_distance };
+
+    #[test]
+    fn add_and_query_from_many_threads() {
+        let a = Arc::new(ConcurrentMultiIndex::<usize>::new(30, 5, 6, &mut thread_rng()));
+
+        let mut rng = thread_rng();
+        let vectors = Arc::new((0..2000usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>());
+
+        let handles = (0..4).map(|t| {
+            let a = a.clone();
+            let vectors = vectors.clone();
+            thread::spawn(move || {
+                for key in (t..vectors.len()).step_by(4) {
+                    a.add(key, &vectors[key]);
+
+                    // Queries run at the same time as other threads are adding
+                    let result = a.nearest(&vectors[key], 1, |p, k| cosine_distance(p, &vectors[*k]));
+                    assert_eq!(key, result[0].key);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(2000, a.len());
+        for (key, v) in vectors.iter().enumerate() {
+            assert!(a.nearest_points_set(v).contains(&key));
+        }
+    }
+
+    #[test]
+    fn remove_removes_points() {
+        let a = ConcurrentMultiIndex::<usize>::with_shards(30, 3, 4, 2, &mut thread_rng());
+        let v = random_unit_vector(30, &mut thread_rng());
+        a.add(0, &v);
+        a.add(1, &v);
+
+        assert!(a.remove(&0));
+        assert!(!a.remove(&0));
+        assert_eq!(1, a.len());
+        assert!(!a.nearest_points_set(&v).contains(&0));
+
+        a.remove(&1);
+        a.compact();
+        assert!(a.nearest_points_set(&v).is_empty());
+        a.add(2, &v);
+        assert!(a.nearest_points_set(&v).contains(&2));
+    }
+
+    #[test]
+    fn snapshot_is_consistent_while_adding() {
+        let a = Arc::new(ConcurrentMultiIndex::<usize>::new(20, 4, 5, &mut thread_rng()));
+
+        let mut rng = thread_rng();
+        let vectors = Arc::new((0..1000usize).map(|_| random_unit_vector(20, &mut rng)).collect::<Vec<_>>());
+
+        let writer = {
+            let a = a.clone();
+            let vectors = vectors.clone();
+            thread::spawn(move || {
+                for (key, v) in vectors.iter().enumerate() {
+                    a.add(key, v);
+                }
+            })
+        };
+
+        // Every sub index of a snapshot must contain exactly the same keys, even while items are being added
+        while !writer.is_finished() {
+            let snapshot = a.snapshot();
+            let keys = snapshot.indices().iter()
+                .map(|idx| idx.groups_iter().flat_map(|(_, g)| g.iter().copied()).collect::<HashSet<_>>())
+                .collect::<Vec<_>>();
+            assert!(keys.iter().all(|k| *k == keys[0]));
+        }
+        writer.join().unwrap();
+
+        let snapshot = a.snapshot();
+        assert_eq!(1000, snapshot.len());
+        assert_eq!(7, snapshot.nearest(&vectors[7], 1, |p, k| cosine_distance(p, &vectors[*k]))[0].key);
+
+        // The plane matrix is shared rather than copied
+        assert_eq!(a.indices[0].hasher.planes()[0].as_ptr(), snapshot.indices()[0].planes()[0].as_ptr());
+
+        // Later changes don't affect the snapshot
+        a.remove(&7);
+        assert!(snapshot.nearest_points(&vectors[7]).contains(&7));
+        assert!(!a.nearest_points_set(&vectors[7]).contains(&7));
+    }
+
+    #[test]
+    fn snapshot_while_adding_from_parallel_iterators() {
+        let a = Arc::new(ConcurrentMultiIndex::<usize>::new(20, 8, 5, &mut thread_rng()));
+
+        let mut rng = thread_rng();
+        let vectors = Arc::new((0..4000usize).map(|_| random_unit_vector(20, &mut rng)).collect::<Vec<_>>());
+
+        // Adds and removes are called from inside rayon tasks while snapshots keep taking the writers lock exclusively
+        let writer = {
+            let a = a.clone();
+            let vectors = vectors.clone();
+            thread::spawn(move || {
+                (0..vectors.len()).into_par_iter().for_each(|key| {
+                    a.add(key, &vectors[key]);
+                    if key % 10 == 0 {
+                        a.remove(&key);
+                    }
+                });
+            })
+        };
+
+        while !writer.is_finished() {
+            a.snapshot();
+        }
+        writer.join().unwrap();
+
+        assert_eq!(3600, a.len());
+        assert_eq!(3600, a.snapshot().len());
+    }
+
+    #[test]
+    fn probing_past_the_group_count_scans_the_groups() {
+        let a = ConcurrentMultiIndex::<usize>::with_shards(30, 2, 20, 4, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let vectors = (0..50usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            a.add(key, v);
+        }
+
+        // Probing every key within 20 bits would visit a million keys per sub index, scanning the groups finds the same items
+        let options = QueryOptions { probe_radius: 20, ..QueryOptions::default() };
+        let start = Instant::now();
+        let found = a.nearest_points_set_with(&vectors[0], &options);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!((0..50).collect::<HashSet<_>>(), found);
+
+        let options = QueryOptions { probe_radius: 1, ..QueryOptions::default() };
+        assert!(a.nearest_points_set_with(&vectors[0], &options).contains(&0));
+    }
+}
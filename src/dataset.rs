@@ -0,0 +1,133 @@
+// Readers and writers for the vector file formats used by the standard ANN benchmark datasets (e.g. SIFT1M and GIST1M from
+// http://corpus-texmex.irisa.fr/). Every vector is stored as its dimension (i32, little endian) followed by that many
+// elements: f32 for `.fvecs`, u8 for `.bvecs` and i32 for `.ivecs` (usually the ground truth neighbour ids of each query).
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use crate::error::HypernonsenseError;
+use crate::vector::Scalar;
+
+// Read every vector from a file of `size` byte elements, checking they all have the same dimension
+fn read_vecs<R: Read, E, F>(reader: &mut R, size: usize, parse: F) -> Result<Vec<Vec<E>>, HypernonsenseError>
+    where F : Fn(&[u8]) -> E
+{
+    let mut vectors = Vec::new();
+    let mut header = [0u8; 4];
+    let mut bytes = Vec::new();
+    loop {
+        // A clean end of file can only happen between vectors
+        match reader.read(&mut header[..1])? {
+            0 => return Ok(vectors),
+            _ => read_exact(reader, &mut header[1..])?,
+        }
+
+        let dims = i32::from_le_bytes(header);
+        if dims <= 0 {
+            return Err(HypernonsenseError::InvalidFormat(format!("vector {} has dimension {}", vectors.len(), dims)));
+        }
+        if let Some(first) = vectors.first().map(Vec::len) {
+            if first != dims as usize {
+                return Err(HypernonsenseError::InvalidFormat(format!("vector {} has dimension {}, expected {}", vectors.len(), dims, first)));
+            }
+        }
+
+        bytes.resize(dims as usize * size, 0);
+        read_exact(reader, &mut bytes)?;
+        vectors.push(bytes.chunks_exact(size).map(&parse).collect());
+    }
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), HypernonsenseError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => HypernonsenseError::InvalidFormat("unexpected end of data".to_string()),
+        _ => HypernonsenseError::Io(e),
+    })
+}
+
+fn write_vecs<W: Write, E, F>(writer: &mut W, vectors: &[Vec<E>], write: F) -> Result<(), HypernonsenseError>
+    where F : Fn(&mut W, &E) -> Result<(), HypernonsenseError>
+{
+    for vector in vectors.iter() {
+        let dims = i32::try_from(vector.len())
+            .map_err(|_| HypernonsenseError::InvalidConfig(format!("a vector with {} elements is too long for the format", vector.len())))?;
+        writer.write_all(&dims.to_le_bytes())?;
+        for e in vector.iter() {
+            write(writer, e)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Read every vector from an `.fvecs` file, converting the elements to `T`
+pub fn read_fvecs<T:Scalar, R: Read>(reader: &mut R) -> Result<Vec<Vec<T>>, HypernonsenseError> {
+    return read_vecs(reader, 4, |b| T::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])).unwrap());
+}
+
+/// Write vectors as an `.fvecs` file, the elements are converted to `f32`
+pub fn write_fvecs<T:Scalar, W: Write>(writer: &mut W, vectors: &[Vec<T>]) -> Result<(), HypernonsenseError> {
+    return write_vecs(writer, vectors, |w, e| Ok(w.write_all(&e.to_f32().unwrap_or(f32::NAN).to_le_bytes())?));
+}
+
+/// Read every vector from a `.bvecs` file, converting the bytes to `T`
+pub fn read_bvecs<T:Scalar, R: Read>(reader: &mut R) -> Result<Vec<Vec<T>>, HypernonsenseError> {
+    return read_vecs(reader, 1, |b| T::from(b[0]).unwrap());
+}
+
+/// Write vectors as a `.bvecs` file. Returns an error if an element is not a whole number from 0 to 255.
+pub fn write_bvecs<T:Scalar, W: Write>(writer: &mut W, vectors: &[Vec<T>]) -> Result<(), HypernonsenseError> {
+    return write_vecs(writer, vectors, |w, e| {
+        let byte = e.to_u8().filter(|b| T::from(*b).unwrap() == *e)
+            .ok_or_else(|| HypernonsenseError::InvalidConfig(format!("{:?} cannot be stored as a byte", e)))?;
+        Ok(w.write_all(&[byte])?)
+    });
+}
+
+/// Read every vector from an `.ivecs` file, e.g. the ids of the true nearest neighbours of each query in a benchmark dataset
+pub fn read_ivecs<R: Read>(reader: &mut R) -> Result<Vec<Vec<i32>>, HypernonsenseError> {
+    return read_vecs(reader, 4, |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+}
+
+pub fn write_ivecs<W: Write>(writer: &mut W, vectors: &[Vec<i32>]) -> Result<(), HypernonsenseError> {
+    return write_vecs(writer, vectors, |w, e| Ok(w.write_all(&e.to_le_bytes())?));
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::dataset::{ read_bvecs, read_fvecs, read_ivecs, write_bvecs, write_fvecs, write_ivecs };
+
+    #[test]
+    fn round_trip_preserves_vectors() {
+        let floats = vec![vec![1.5f32, -2.0, 3.25], vec![0.0, 1.0, 2.0]];
+        let mut bytes = Vec::new();
+        write_fvecs(&mut bytes, &floats).unwrap();
+        assert_eq!(2 * (4 + 3 * 4), bytes.len());
+        assert_eq!(floats, read_fvecs::<f32, _>(&mut bytes.as_slice()).unwrap());
+
+        let small = vec![vec![0f64, 255.0], vec![7.0, 8.0]];
+        let mut bytes = Vec::new();
+        write_bvecs(&mut bytes, &small).unwrap();
+        assert_eq!(small, read_bvecs::<f64, _>(&mut bytes.as_slice()).unwrap());
+        assert!(write_bvecs(&mut Vec::new(), &[vec![256f32]]).is_err());
+        assert!(write_bvecs(&mut Vec::new(), &[vec![0.5f32]]).is_err());
+
+        let ids = vec![vec![3, 1, 4], vec![1, 5, 9]];
+        let mut bytes = Vec::new();
+        write_ivecs(&mut bytes, &ids).unwrap();
+        assert_eq!(ids, read_ivecs(&mut bytes.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn read_rejects_bad_files() {
+        let mut bytes = Vec::new();
+        write_ivecs(&mut bytes, &[vec![1, 2], vec![3, 4, 5]]).unwrap();
+        assert!(read_ivecs(&mut bytes.as_slice()).is_err());
+
+        let mut bytes = Vec::new();
+        write_fvecs(&mut bytes, &[vec![1f32, 2.0]]).unwrap();
+        assert!(read_fvecs::<f32, _>(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(read_fvecs::<f32, _>(&mut &bytes[..2]).is_err());
+        assert!(read_fvecs::<f32, _>(&mut [0u8; 0].as_ref()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum HypernonsenseError {
+    /// An IO error occurred while reading or writing an index
+    Io(io::Error),
+
+    /// The data being loaded is not a valid index
+    InvalidFormat(String),
+
+    /// The data being loaded was written by an unsupported version of the format
+    UnsupportedVersion(u32),
+
+    /// An index was configured with invalid parameters
+    InvalidConfig(String),
+
+    /// A vector did not have the same number of dimensions as the index
+    DimensionMismatch { expected: usize, actual: usize },
+
+    /// The operation needs at least one item in the index
+    EmptyIndex,
+
+    /// A vector contained a NaN or infinite element
+    NonFiniteInput,
+
+    /// A vector was not unit length and the index is configured to reject non unit vectors
+    NonUnitVector,
+
+    /// The checksum of a section of a saved index did not match its contents
+    CorruptSection(Section),
+}
+
+/// A section of the binary index format, each section has its own checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// The dimension, sub index count and plane count
+    Header,
+
+    /// The hash family and planes of one sub index
+    Planes { index: usize },
+
+    /// The groups of one sub index
+    Groups { index: usize },
+
+    /// The stored vectors
+    Vectors,
+
+    /// The metric, payloads, seed and normalization
+    Metadata,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Header => write!(f, "header"),
+            Section::Planes { index } => write!(f, "planes of sub index {}", index),
+            Section::Groups { index } => write!(f, "groups of sub index {}", index),
+            Section::Vectors => write!(f, "vectors"),
+            Section::Metadata => write!(f, "metadata"),
+        }
+    }
+}
+
+impl fmt::Display for HypernonsenseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HypernonsenseError::Io(e) => write!(f, "io error: {}", e),
+            HypernonsenseError::InvalidFormat(msg) => write!(f, "invalid index format: {}", msg),
+            HypernonsenseError::UnsupportedVersion(v) => write!(f, "unsupported index format version: {}", v),
+            HypernonsenseError::InvalidConfig(msg) => write!(f, "invalid index configuration: {}", msg),
+            HypernonsenseError::DimensionMismatch { expected, actual } => write!(f, "expected a vector with {} dimensions, got {}", expected, actual),
+            HypernonsenseError::EmptyIndex => write!(f, "index is empty"),
+            HypernonsenseError::NonFiniteInput => write!(f, "vector contains a non finite element"),
+            HypernonsenseError::NonUnitVector => write!(f, "vector is not unit length"),
+            HypernonsenseError::CorruptSection(section) => write!(f, "checksum mismatch, the {} section of the index is corrupt", section),
+        }
+    }
+}
+
+impl std::error::Error for HypernonsenseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HypernonsenseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HypernonsenseError {
+    fn from(e: io::Error) -> Self {
+        HypernonsenseError::Io(e)
+    }
+}
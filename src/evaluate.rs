@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use crate::par::prelude::*;
+
+use crate::ivf::IvfIndex;
+use crate::metric::Metric;
+use crate::multiindex::{DistanceNode, MultiIndex};
+use crate::query::QueryOptions;
+use crate::vector::Scalar;
+
+/// How well an index performed over a set of sample queries
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Evaluation {
+    /// Average fraction of the true `k` nearest neighbours found in the top `k` results
+    pub recall: f32,
+
+    /// Average number of candidates each query had to compare against. This is the main cost of a query.
+    pub mean_candidates: f32,
+}
+
+/// An index which can be evaluated with `recall_at_k`, so different kinds of index can be compared on the same queries
+pub trait Searchable<K:Eq+Hash, T:Scalar> : Sync {
+    /// Every candidate which a query would rank
+    fn nearest_points_set_with(&self, point: &[T], options: &QueryOptions) -> HashSet<K>;
+
+    fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync;
+}
+
+impl<K:Clone+Eq+Hash+Debug+Send+Sync, T:Scalar, V:Send+Sync> Searchable<K, T> for MultiIndex<K, T, V> {
+    fn nearest_points_set_with(&self, point: &[T], options: &QueryOptions) -> HashSet<K> {
+        return MultiIndex::nearest_points_set_with(self, point, options);
+    }
+
+    fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return MultiIndex::nearest_with(self, point, count, options, get_dist);
+    }
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync, T:Scalar> Searchable<K, T> for IvfIndex<K, T> {
+    fn nearest_points_set_with(&self, point: &[T], options: &QueryOptions) -> HashSet<K> {
+        return IvfIndex::nearest_points_set_with(self, point, options);
+    }
+
+    fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return IvfIndex::nearest_with(self, point, count, options, get_dist);
+    }
+}
+
+/// Find the true `k` nearest neighbours of every query by comparing against every item
+pub fn brute_force_nearest<K:Clone+Send+Sync, T:Scalar>(items: &[(K, Vec<T>)], queries: &[Vec<T>], k: usize, metric: Metric) -> Vec<Vec<K>>
+{
+    return queries.par_iter()
+        .map(|q| {
+            let mut ranked = items.iter()
+                .enumerate()
+                .map(|(i, (_, v))| DistanceNode { distance: metric.distance(q, v), key: i })
+                .collect::<Vec<_>>();
+            ranked.sort_unstable();
+            ranked.truncate(k);
+            ranked.iter().map(|n| items[n.key].0.clone()).collect()
+        })
+        .collect();
+}
+
+/// Measure recall@k of an index over some sample queries. `ground_truth` must contain the true nearest neighbours of each
+/// query, e.g. from `brute_force_nearest`.
+pub fn recall_at_k<K, T, I, F>(index: &I, queries: &[Vec<T>], ground_truth: &[Vec<K>], k: usize, get_dist: F) -> Evaluation
+    where K : Clone + Eq + Hash + Send + Sync, T : Scalar, I : Searchable<K, T>, F : Fn(&[T], &K) -> T + Send + Sync
+{
+    return recall_at_k_with(index, queries, ground_truth, k, &QueryOptions::default(), get_dist);
+}
+
+pub fn recall_at_k_with<K, T, I, F>(index: &I, queries: &[Vec<T>], ground_truth: &[Vec<K>], k: usize, options: &QueryOptions, get_dist: F) -> Evaluation
+    where K : Clone + Eq + Hash + Send + Sync, T : Scalar, I : Searchable<K, T>, F : Fn(&[T], &K) -> T + Send + Sync
+{
+    assert_eq!(queries.len(), ground_truth.len(), "every query must have a ground truth");
+
+    let (recall, candidates) = queries.par_iter()
+        .zip(ground_truth.par_iter())
+        .map(|(q, truth)| {
+            let candidates = index.nearest_points_set_with(q, options).len();
+            let found = index.nearest_with(q, k, options, &get_dist)
+                .into_iter()
+                .map(|n| n.key)
+                .collect::<Vec<_>>();
+            (recall(&found, truth, k), candidates as f32)
+        })
+        .reduce_with(|a, b| (a.0 + b.0, a.1 + b.1))
+        .unwrap_or((0f32, 0f32));
+
+    let count = queries.len().max(1) as f32;
+    return Evaluation {
+        recall: recall / count,
+        mean_candidates: candidates / count
+    };
+}
+
+/// Fraction of the first `k` items of `truth` which appear in the first `k` items of `found`
+pub(crate) fn recall<K:PartialEq>(found: &[K], truth: &[K], k: usize) -> f32 {
+    let truth = &truth[..truth.len().min(k)];
+    if truth.is_empty() {
+        return 1f32;
+    }
+
+    let hits = found.iter().take(k).filter(|f| truth.contains(f)).count();
+    return hits as f32 / truth.len() as f32;
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::evaluate::{ brute_force_nearest, recall, recall_at_k };
+    use crate::metric::Metric;
+    use crate::multiindex::MultiIndex;
+    use crate::vector::{ random_unit_vector, cosine_distance };
+
+    #[test]
+    fn recall_counts_hits() {
+        assert_eq!(1f32, recall(&[1, 2, 3], &[3, 2, 1], 3));
+        assert_eq!(0.5f32, recall(&[1, 5], &[1, 2], 2));
+        assert_eq!(1f32, recall::<usize>(&[], &[], 2));
+    }
+
+    #[test]
+    fn brute_force_finds_self() {
+        let mut rng = thread_rng();
+        let items = (0..100usize).map(|k| (k, random_unit_vector(20, &mut rng))).collect::<Vec<_>>();
+        let queries = items.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>();
+
+        let truth = brute_force_nearest(&items, &queries, 5, Metric::Cosine);
+        for (i, t) in truth.iter().enumerate() {
+            assert_eq!(5, t.len());
+            assert_eq!(i, t[0]);
+        }
+    }
+
+    #[test]
+    fn evaluate_index() {
+        let mut a = MultiIndex::new(50, 10, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let items = (0..2000usize).map(|k| (k, random_unit_vector(50, &mut rng))).collect::<Vec<_>>();
+        a.add_batch(items.clone());
+
+        let queries = items[..50].iter().map(|(_, v)| v.clone()).collect::<Vec<_>>();
+        let truth = brute_force_nearest(&items, &queries, 10, Metric::Cosine);
+        let result = recall_at_k(&a, &queries, &truth, 10, |p, k| cosine_distance(p, &items[*k].1));
+        println!("{:?}", result);
+
+        assert!(result.recall > 0.0 && result.recall <= 1.0);
+        assert!(result.mean_candidates >= 10.0);
+    }
+}
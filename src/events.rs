@@ -0,0 +1,33 @@
+use crate::hyperindex::Planes;
+use crate::vector::Scalar;
+
+/// A change made to a `MultiIndex`, sent to its listener (see `MultiIndex::set_listener`) after the change has been made.
+/// Applying every event to another index with `MultiIndex::apply` keeps it in step, e.g. a warm standby replica.
+#[derive(Clone, Debug)]
+pub enum IndexEvent<K, T:Scalar> {
+    /// An item was added. The vector is the one which was inserted, after the normalization policy was applied.
+    KeyAdded { key: K, vector: Vec<T> },
+
+    /// Every item with this key was removed, either explicitly or by eviction or expiry
+    KeyRemoved { key: K },
+
+    /// The planes of the index were replaced (by `rebuild` or `fit_offsets`) and every item was hashed again. These are the
+    /// new planes of every sub index, in order.
+    Rebuilt { planes: Vec<Planes<T>> },
+}
+
+/// Receives an `IndexEvent` for every change made to an index. This is implemented for closures, and
+/// `MultiIndex::subscribe` sends the events to a channel instead.
+///
+/// Events are sent from the thread making the change, while the index is still borrowed, so keep this quick.
+pub trait Listener<K, T:Scalar> : Send + Sync {
+    fn event(&self, event: IndexEvent<K, T>);
+}
+
+impl<K, T:Scalar, F> Listener<K, T> for F
+    where F : Fn(IndexEvent<K, T>) + Send + Sync
+{
+    fn event(&self, event: IndexEvent<K, T>) {
+        self(event);
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard};
+
+/// Which item a capacity limited index removes when it is full, see `MultiIndexBuilder::capacity`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eviction {
+    /// Remove the item which was inserted first
+    Fifo,
+
+    /// Remove the item which was least recently inserted or returned as a result of a ranked query (e.g. `nearest`)
+    Lru,
+}
+
+// Order in which keys were last used. Every use takes the next tick, the key with the smallest tick is the oldest.
+struct Recency<K> {
+    tick: u64,
+    by_key: HashMap<K, u64>,
+    by_tick: BTreeMap<u64, K>,
+}
+
+impl<K:Clone+Eq+Hash> Recency<K> {
+    fn touch(&mut self, key: &K) {
+        if let Some(old) = self.by_key.insert(key.clone(), self.tick) {
+            self.by_tick.remove(&old);
+        }
+        self.by_tick.insert(self.tick, key.clone());
+        self.tick += 1;
+    }
+}
+
+// The capacity limit of a `MultiIndex` and the order keys will be evicted in. Queries only have a shared reference to the
+// index, so the order is behind a lock.
+pub(crate) struct Capacity<K> {
+    limit: usize,
+    policy: Eviction,
+    recency: Mutex<Recency<K>>,
+}
+
+impl<K:Clone+Eq+Hash> Capacity<K> {
+    pub(crate) fn new(limit: usize, policy: Eviction) -> Capacity<K> {
+        return Capacity {
+            limit,
+            policy,
+            recency: Mutex::new(Recency { tick: 0, by_key: HashMap::new(), by_tick: BTreeMap::new() }),
+        };
+    }
+
+    fn recency(&self) -> MutexGuard<'_, Recency<K>> {
+        return self.recency.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        return self.limit;
+    }
+
+    pub(crate) fn policy(&self) -> Eviction {
+        return self.policy;
+    }
+
+    pub(crate) fn inserted<'a, I>(&self, keys: I)
+        where I : IntoIterator<Item=&'a K>, K : 'a
+    {
+        let mut recency = self.recency();
+        for key in keys {
+            recency.touch(key);
+        }
+    }
+
+    /// Keys were returned by a query, with LRU eviction this makes them the most recently used
+    pub(crate) fn hit<'a, I>(&self, keys: I)
+        where I : IntoIterator<Item=&'a K>, K : 'a
+    {
+        if self.policy == Eviction::Lru {
+            let mut recency = self.recency();
+            for key in keys {
+                if recency.by_key.contains_key(key) {
+                    recency.touch(key);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn forget(&self, key: &K) {
+        let mut recency = self.recency();
+        if let Some(tick) = recency.by_key.remove(key) {
+            recency.by_tick.remove(&tick);
+        }
+    }
+
+    pub(crate) fn forget_where<F>(&self, predicate: F)
+        where F : Fn(&K) -> bool
+    {
+        let mut recency = self.recency();
+        recency.by_tick.retain(|_, k| !predicate(k));
+        recency.by_key.retain(|k, _| !predicate(k));
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut recency = self.recency();
+        recency.by_key.clear();
+        recency.by_tick.clear();
+    }
+
+    /// Remove and return the next key to evict
+    pub(crate) fn pop_oldest(&self) -> Option<K> {
+        let mut recency = self.recency();
+        let (_, key) = recency.by_tick.pop_first()?;
+        recency.by_key.remove(&key);
+        return Some(key);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::eviction::{ Capacity, Eviction };
+
+    #[test]
+    fn fifo_ignores_hits() {
+        let a = Capacity::new(2, Eviction::Fifo);
+        a.inserted(&[1, 2, 3]);
+        a.hit(&[1]);
+
+        assert_eq!(Some(1), a.pop_oldest());
+        assert_eq!(Some(2), a.pop_oldest());
+    }
+
+    #[test]
+    fn lru_moves_hits_to_the_back() {
+        let a = Capacity::new(2, Eviction::Lru);
+        a.inserted(&[1, 2, 3]);
+        a.hit(&[1, 4]);
+        a.forget(&2);
+
+        assert_eq!(Some(3), a.pop_oldest());
+        assert_eq!(Some(1), a.pop_oldest());
+        assert_eq!(None, a.pop_oldest());
+    }
+}
@@ -0,0 +1,90 @@
+// Insertion timestamps for `MultiIndex::add_with_timestamp`, so the oldest items can be found without searching every key.
+//
+// Keys are only removed from `by_key` when they are removed from the index, the matching entry in `by_time` is left behind
+// and skipped when it is reached (the timestamp no longer matches).
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::Instant;
+
+pub(crate) struct Timestamps<K> {
+    by_key: HashMap<K, Instant>,
+    by_time: BTreeMap<Instant, Vec<K>>,
+}
+
+impl<K> Timestamps<K> {
+    pub(crate) fn new() -> Timestamps<K> {
+        return Timestamps { by_key: HashMap::new(), by_time: BTreeMap::new() };
+    }
+}
+
+impl<K:Clone+Eq+Hash> Timestamps<K> {
+    /// Set the timestamp of a key, replacing any previous timestamp
+    pub(crate) fn insert(&mut self, key: K, timestamp: Instant) {
+        self.by_key.insert(key.clone(), timestamp);
+        self.by_time.entry(timestamp).or_default().push(key);
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<Instant> {
+        return self.by_key.get(key).copied();
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        self.by_key.remove(key);
+    }
+
+    pub(crate) fn retain<F>(&mut self, keep: F)
+        where F : Fn(&K) -> bool
+    {
+        self.by_key.retain(|k, _| keep(k));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.by_key.clear();
+        self.by_time.clear();
+    }
+
+    /// Remove and return every key with a timestamp before the cutoff
+    pub(crate) fn take_older_than(&mut self, cutoff: Instant) -> Vec<K> {
+        let newer = self.by_time.split_off(&cutoff);
+        let older = std::mem::replace(&mut self.by_time, newer);
+
+        let mut expired = Vec::new();
+        for (timestamp, keys) in older {
+            for key in keys {
+                if self.by_key.get(&key) == Some(&timestamp) {
+                    self.by_key.remove(&key);
+                    expired.push(key);
+                }
+            }
+        }
+
+        return expired;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::{Duration, Instant};
+
+    use crate::expiry::Timestamps;
+
+    #[test]
+    fn take_older_than_skips_replaced_timestamps() {
+        let start = Instant::now();
+        let mut a = Timestamps::new();
+        a.insert(1, start);
+        a.insert(2, start + Duration::from_secs(1));
+        a.insert(3, start + Duration::from_secs(2));
+
+        // Key 2 is refreshed and key 3 is removed, neither should expire at their old time
+        a.insert(2, start + Duration::from_secs(10));
+        a.retain(|k| *k != 3);
+
+        assert_eq!(vec![1], a.take_older_than(start + Duration::from_secs(5)));
+        assert_eq!(Vec::<i32>::new(), a.take_older_than(start + Duration::from_secs(5)));
+        assert_eq!(vec![2], a.take_older_than(start + Duration::from_secs(11)));
+        assert_eq!(None, a.get(&2));
+    }
+}
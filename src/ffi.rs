@@ -0,0 +1,250 @@
+// C interface to a `MultiIndex<u64, f32>` with vector storage, so the index can be embedded in applications written in
+// other languages. The matching header is `include/hypernonsense.h`.
+//
+// Every function returns a `HypernonsenseStatus`, panics are caught and reported as `HYPERNONSENSE_PANIC` instead of
+// unwinding into the caller.
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::ptr;
+use std::slice;
+
+use crate::builder::MultiIndexBuilder;
+use crate::error::HypernonsenseError;
+use crate::multiindex::MultiIndex;
+use crate::vector::check_vector;
+
+/// The index type behind the pointers handed out to C
+pub type HypernonsenseIndex = MultiIndex<u64, f32>;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HypernonsenseStatus {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidArgument = 2,
+    DimensionMismatch = 3,
+    NonFiniteInput = 4,
+    Io = 5,
+    InvalidFormat = 6,
+    Panic = 7,
+}
+
+impl From<HypernonsenseError> for HypernonsenseStatus {
+    fn from(e: HypernonsenseError) -> Self {
+        match e {
+            HypernonsenseError::Io(_) => HypernonsenseStatus::Io,
+            HypernonsenseError::InvalidFormat(_) | HypernonsenseError::UnsupportedVersion(_) | HypernonsenseError::CorruptSection(_) => HypernonsenseStatus::InvalidFormat,
+            HypernonsenseError::DimensionMismatch { .. } => HypernonsenseStatus::DimensionMismatch,
+            HypernonsenseError::NonFiniteInput => HypernonsenseStatus::NonFiniteInput,
+            _ => HypernonsenseStatus::InvalidArgument,
+        }
+    }
+}
+
+// Run the body of an exported function, turning errors and panics into a status
+fn guard<F>(f: F) -> HypernonsenseStatus
+    where F : FnOnce() -> Result<(), HypernonsenseStatus>
+{
+    return match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => HypernonsenseStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => HypernonsenseStatus::Panic,
+    };
+}
+
+unsafe fn index_ref<'a>(index: *const HypernonsenseIndex) -> Result<&'a HypernonsenseIndex, HypernonsenseStatus> {
+    return index.as_ref().ok_or(HypernonsenseStatus::NullPointer);
+}
+
+unsafe fn vector<'a>(vector: *const f32, len: usize) -> Result<&'a [f32], HypernonsenseStatus> {
+    if vector.is_null() {
+        return Err(HypernonsenseStatus::NullPointer);
+    }
+    return Ok(slice::from_raw_parts(vector, len));
+}
+
+unsafe fn path<'a>(path: *const c_char) -> Result<&'a str, HypernonsenseStatus> {
+    if path.is_null() {
+        return Err(HypernonsenseStatus::NullPointer);
+    }
+    return CStr::from_ptr(path).to_str().map_err(|_| HypernonsenseStatus::InvalidArgument);
+}
+
+/// Create a new index which stores vectors and ranks results by cosine distance. The planes are generated from the seed.
+///
+/// # Safety
+/// `out` must be a valid pointer, on success it receives an index which must be freed with `hypernonsense_free`.
+#[no_mangle]
+pub unsafe extern "C" fn hypernonsense_create(dimension: usize, index_count: u8, plane_count: u8, seed: u64, out: *mut *mut HypernonsenseIndex) -> HypernonsenseStatus {
+    return guard(|| {
+        let out = out.as_mut().ok_or(HypernonsenseStatus::NullPointer)?;
+        let index = MultiIndexBuilder::new(dimension)
+            .index_count(index_count)
+            .plane_count(plane_count)
+            .seed(seed)
+            .store_vectors(true)
+            .build()?;
+
+        *out = Box::into_raw(Box::new(index));
+        return Ok(());
+    });
+}
+
+/// Free an index. Passing null does nothing.
+///
+/// # Safety
+/// `index` must be null or a pointer returned by `hypernonsense_create` or `hypernonsense_load` which has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn hypernonsense_free(index: *mut HypernonsenseIndex) {
+    if !index.is_null() {
+        drop(Box::from_raw(index));
+    }
+}
+
+/// Add a vector with `len` elements to the index. Adding a key which is already in the index replaces it.
+///
+/// # Safety
+/// `index` must be a live index and `vector_ptr` must point to `len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn hypernonsense_add(index: *mut HypernonsenseIndex, key: u64, vector_ptr: *const f32, len: usize) -> HypernonsenseStatus {
+    return guard(|| {
+        let index = index.as_mut().ok_or(HypernonsenseStatus::NullPointer)?;
+        let v = vector(vector_ptr, len)?.to_vec();
+
+        // Check the vector before the old one is removed, so a bad vector leaves the index unchanged
+        check_vector(&v, index.dimensions())?;
+        index.upsert(key, &v);
+        return Ok(());
+    });
+}
+
+/// Number of items in the index, zero if the index is null
+///
+/// # Safety
+/// `index` must be null or a live index.
+#[no_mangle]
+pub unsafe extern "C" fn hypernonsense_len(index: *const HypernonsenseIndex) -> usize {
+    return index.as_ref().map(|i| i.len()).unwrap_or(0);
+}
+
+/// Find the nearest `count` items to a query vector, sorted by distance (small->large). Writes up to `count` keys to
+/// `out_keys` and distances to `out_distances` (which may be null), and the number of results to `out_found`.
+///
+/// # Safety
+/// `index` must be a live index, `vector_ptr` must point to `len` floats, `out_keys` (and `out_distances` if not null) must have
+/// room for `count` elements and `out_found` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn hypernonsense_query(index: *const HypernonsenseIndex, vector_ptr: *const f32, len: usize, count: usize, out_keys: *mut u64, out_distances: *mut f32, out_found: *mut usize) -> HypernonsenseStatus {
+    return guard(|| {
+        let index = index_ref(index)?;
+        let v = vector(vector_ptr, len)?.to_vec();
+        let found = out_found.as_mut().ok_or(HypernonsenseStatus::NullPointer)?;
+        if out_keys.is_null() {
+            return Err(HypernonsenseStatus::NullPointer);
+        }
+
+        let result = match index.try_nearest(&v, count, |p, k| index.metric().distance(p, index.get(k).unwrap_or(&[]))) {
+            Ok(result) => result,
+            Err(HypernonsenseError::EmptyIndex) => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        for (i, node) in result.iter().enumerate() {
+            ptr::write(out_keys.add(i), node.key);
+            if !out_distances.is_null() {
+                ptr::write(out_distances.add(i), node.distance);
+            }
+        }
+        *found = result.len();
+        return Ok(());
+    });
+}
+
+/// Save the index to a file
+///
+/// # Safety
+/// `index` must be a live index and `path_ptr` a null terminated UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn hypernonsense_save(index: *const HypernonsenseIndex, path_ptr: *const c_char) -> HypernonsenseStatus {
+    return guard(|| {
+        let index = index_ref(index)?;
+        index.save(path(path_ptr)?)?;
+        return Ok(());
+    });
+}
+
+/// Load an index which was saved with `hypernonsense_save` (or `MultiIndex::<u64>::save`)
+///
+/// # Safety
+/// `path_ptr` must be a null terminated UTF-8 string and `out` a valid pointer, on success it receives an index which must be
+/// freed with `hypernonsense_free`.
+#[no_mangle]
+pub unsafe extern "C" fn hypernonsense_load(path_ptr: *const c_char, out: *mut *mut HypernonsenseIndex) -> HypernonsenseStatus {
+    return guard(|| {
+        let out = out.as_mut().ok_or(HypernonsenseStatus::NullPointer)?;
+        let index = HypernonsenseIndex::load(path(path_ptr)?)?;
+
+        *out = Box::into_raw(Box::new(index));
+        return Ok(());
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    use crate::ffi::*;
+    use crate::vector::random_unit_vector;
+
+    #[test]
+    fn create_add_query_save_load() {
+        unsafe {
+            let mut index = ptr::null_mut();
+            assert_eq!(HypernonsenseStatus::Ok, hypernonsense_create(30, 5, 6, 7, &mut index));
+
+            let mut rng = thread_rng();
+            let vectors = (0..200).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+            for (key, v) in vectors.iter().enumerate() {
+                assert_eq!(HypernonsenseStatus::Ok, hypernonsense_add(index, key as u64, v.as_ptr(), v.len()));
+            }
+            assert_eq!(200, hypernonsense_len(index));
+
+            let mut keys = [0u64; 3];
+            let mut distances = [0f32; 3];
+            let mut found = 0;
+            assert_eq!(HypernonsenseStatus::Ok, hypernonsense_query(index, vectors[5].as_ptr(), 30, 3, keys.as_mut_ptr(), distances.as_mut_ptr(), &mut found));
+            assert!(found >= 1);
+            assert_eq!(5, keys[0]);
+            assert!(distances[0].abs() < 1e-5);
+
+            assert_eq!(HypernonsenseStatus::DimensionMismatch, hypernonsense_add(index, 0, vectors[0].as_ptr(), 10));
+
+            let path = std::env::temp_dir().join(format!("hypernonsense_ffi_{}.hyp", std::process::id()));
+            let c_path = CString::new(path.to_str().unwrap()).unwrap();
+            assert_eq!(HypernonsenseStatus::Ok, hypernonsense_save(index, c_path.as_ptr()));
+            hypernonsense_free(index);
+
+            let mut loaded = ptr::null_mut();
+            assert_eq!(HypernonsenseStatus::Ok, hypernonsense_load(c_path.as_ptr(), &mut loaded));
+            assert_eq!(200, hypernonsense_len(loaded));
+            hypernonsense_free(loaded);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn null_pointers_are_rejected() {
+        unsafe {
+            let v = [0f32; 3];
+            assert_eq!(HypernonsenseStatus::NullPointer, hypernonsense_add(ptr::null_mut(), 0, v.as_ptr(), 3));
+            assert_eq!(HypernonsenseStatus::NullPointer, hypernonsense_create(3, 1, 1, 0, ptr::null_mut()));
+            assert_eq!(0, hypernonsense_len(ptr::null()));
+            hypernonsense_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind, resume_unwind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use rayon::ThreadPool;
+
+struct State<R> {
+    result: Option<thread::Result<R>>,
+    waker: Option<Waker>,
+}
+
+/// A query running in the background on a rayon thread pool, which completes when the query is finished.
+///
+/// This does not depend on any particular async runtime and polling it never blocks, so it can be awaited inside an async
+/// handler without holding up the executor threads.
+pub struct QueryFuture<R> {
+    state: Arc<Mutex<State<R>>>,
+}
+
+impl<R:Send+'static> QueryFuture<R> {
+    // Start running the work on the given pool, or the global rayon pool
+    pub(crate) fn spawn<F>(pool: Option<&Arc<ThreadPool>>, work: F) -> QueryFuture<R>
+        where F : FnOnce() -> R + Send + 'static
+    {
+        let state = Arc::new(Mutex::new(State { result: None, waker: None }));
+
+        let shared = state.clone();
+        let job = move || {
+            // A panic is caught and raised again in the task awaiting the query, instead of taking down the pool
+            let result = catch_unwind(AssertUnwindSafe(work));
+
+            let waker = {
+                let mut state = shared.lock().unwrap_or_else(|e| e.into_inner());
+                state.result = Some(result);
+                state.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        };
+
+        match pool {
+            Some(pool) => pool.spawn(job),
+            None => rayon::spawn(job),
+        }
+
+        return QueryFuture { state };
+    }
+}
+
+impl<R> Future for QueryFuture<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        return match state.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(panic)) => resume_unwind(panic),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+
+    use crate::multiindex::MultiIndex;
+    use crate::vector::{ random_unit_vector, cosine_distance };
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // Minimal executor, parks the current thread until the future wakes it
+    fn block_on<F:Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(result) => return result,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_async_matches_nearest() {
+        let mut a = MultiIndex::new_with_storage(30, 5, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let vectors = (0..500usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let expected = a.nearest_k(&vectors[3], 5);
+        let actual = block_on(a.nearest_k_async(vectors[3].clone(), 5));
+        assert_eq!(expected.iter().map(|n| n.key).collect::<Vec<_>>(), actual.iter().map(|n| n.key).collect::<Vec<_>>());
+
+        let vectors = Arc::new(vectors);
+        let v = vectors.clone();
+        let result = block_on(a.nearest_async(vectors[7].clone(), 1, move |p, k| cosine_distance(p, &v[*k])));
+        assert_eq!(7, result[0].key);
+    }
+
+    #[test]
+    #[should_panic(expected = "distance failed")]
+    fn panics_are_raised_when_awaited() {
+        let mut a = MultiIndex::new(30, 5, 6, &mut thread_rng());
+        let v = random_unit_vector(30, &mut thread_rng());
+        a.add(0usize, &v);
+
+        block_on(a.nearest_async(v, 1, |_, _| -> f32 { panic!("distance failed") }));
+    }
+}
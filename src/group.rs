@@ -0,0 +1,250 @@
+// The members of one group. With a well tuned plane count most groups only hold a handful of keys, so the first
+// `INLINE` keys are stored inside the group itself and only larger groups allocate. Probing then reads the keys straight
+// out of the group map instead of following a pointer to a separate allocation for every group.
+
+use std::fmt;
+use std::iter::FromIterator;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+
+// Number of keys stored without allocating
+pub(crate) const INLINE: usize = 8;
+
+enum Storage<K> {
+    // The first `len` items are initialised
+    Inline(usize, [MaybeUninit<K>; INLINE]),
+    Heap(Vec<K>),
+}
+
+pub(crate) struct Group<K> {
+    storage: Storage<K>,
+}
+
+fn inline<K>() -> Storage<K> {
+    return Storage::Inline(0, std::array::from_fn(|_| MaybeUninit::uninit()));
+}
+
+impl<K> Group<K> {
+    pub(crate) fn new() -> Group<K> {
+        return Group { storage: inline() };
+    }
+
+    pub(crate) fn push(&mut self, key: K) {
+        match &mut self.storage {
+            Storage::Inline(len, items) if *len < INLINE => {
+                items[*len].write(key);
+                *len += 1;
+            }
+            Storage::Inline(len, items) => {
+                // Move the inline items to the heap, the length is cleared first so they are never dropped twice
+                let mut heap = Vec::with_capacity(INLINE * 2);
+                let count = std::mem::replace(len, 0);
+                for item in items[..count].iter() {
+                    heap.push(unsafe { item.assume_init_read() });
+                }
+                heap.push(key);
+                self.storage = Storage::Heap(heap);
+            }
+            Storage::Heap(heap) => heap.push(key),
+        }
+    }
+
+    /// Keep only the keys for which `f` returns true, in the same order
+    pub(crate) fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        // Kept keys are swapped towards the front, so every key is still initialised if `f` panics
+        let keys = self.as_mut_slice();
+        let mut kept = 0;
+        for i in 0..keys.len() {
+            if f(&keys[i]) {
+                keys.swap(kept, i);
+                kept += 1;
+            }
+        }
+        self.truncate(kept);
+    }
+
+    pub(crate) fn truncate(&mut self, count: usize) {
+        match &mut self.storage {
+            Storage::Inline(len, items) => {
+                if count < *len {
+                    let old = std::mem::replace(len, count);
+                    unsafe {
+                        std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(items[count..old].as_mut_ptr() as *mut K, old - count));
+                    }
+                }
+            }
+            Storage::Heap(heap) => heap.truncate(count),
+        }
+    }
+
+    /// Release unused memory, moving the keys back inside the group if they fit
+    pub(crate) fn shrink_to_fit(&mut self) {
+        if let Storage::Heap(heap) = &mut self.storage {
+            if heap.len() > INLINE {
+                heap.shrink_to_fit();
+                return;
+            }
+
+            let heap = std::mem::take(heap);
+            self.storage = inline();
+            for key in heap {
+                self.push(key);
+            }
+        }
+    }
+
+    /// Move every key out of `other` onto the end of this group
+    pub(crate) fn append(&mut self, other: &mut Group<K>) {
+        self.extend(std::mem::take(other).into_vec());
+    }
+
+    pub(crate) fn into_vec(mut self) -> Vec<K> {
+        return match &mut self.storage {
+            Storage::Inline(len, items) => {
+                // The length is cleared first, so dropping the group afterwards doesn't drop the moved keys
+                let count = std::mem::replace(len, 0);
+                items[..count].iter().map(|item| unsafe { item.assume_init_read() }).collect()
+            }
+            Storage::Heap(heap) => std::mem::take(heap),
+        };
+    }
+
+    pub(crate) fn as_slice(&self) -> &[K] {
+        return match &self.storage {
+            Storage::Inline(len, items) => unsafe { std::slice::from_raw_parts(items.as_ptr() as *const K, *len) },
+            Storage::Heap(heap) => heap,
+        };
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [K] {
+        return match &mut self.storage {
+            Storage::Inline(len, items) => unsafe { std::slice::from_raw_parts_mut(items.as_mut_ptr() as *mut K, *len) },
+            Storage::Heap(heap) => heap,
+        };
+    }
+}
+
+impl<K> Drop for Group<K> {
+    fn drop(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<K> Default for Group<K> {
+    fn default() -> Self {
+        return Group::new();
+    }
+}
+
+impl<K> Deref for Group<K> {
+    type Target = [K];
+
+    fn deref(&self) -> &[K] {
+        return self.as_slice();
+    }
+}
+
+impl<K> DerefMut for Group<K> {
+    fn deref_mut(&mut self) -> &mut [K] {
+        return self.as_mut_slice();
+    }
+}
+
+impl<K:Clone> Clone for Group<K> {
+    fn clone(&self) -> Self {
+        return self.iter().cloned().collect();
+    }
+}
+
+impl<K:fmt::Debug> fmt::Debug for Group<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.debug_list().entries(self.iter()).finish();
+    }
+}
+
+impl<K:PartialEq> PartialEq for Group<K> {
+    fn eq(&self, other: &Self) -> bool {
+        return self.as_slice() == other.as_slice();
+    }
+}
+
+impl<K:Eq> Eq for Group<K> {
+}
+
+impl<K> Extend<K> for Group<K> {
+    fn extend<I: IntoIterator<Item=K>>(&mut self, iter: I) {
+        for key in iter {
+            self.push(key);
+        }
+    }
+}
+
+impl<K> FromIterator<K> for Group<K> {
+    fn from_iter<I: IntoIterator<Item=K>>(iter: I) -> Self {
+        let mut group = Group::new();
+        group.extend(iter);
+        return group;
+    }
+}
+
+impl<K> From<Vec<K>> for Group<K> {
+    fn from(keys: Vec<K>) -> Self {
+        if keys.len() > INLINE {
+            return Group { storage: Storage::Heap(keys) };
+        }
+        return keys.into_iter().collect();
+    }
+}
+
+impl<'a, K> IntoIterator for &'a Group<K> {
+    type Item = &'a K;
+    type IntoIter = std::slice::Iter<'a, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        return self.iter();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::rc::Rc;
+
+    use crate::group::{ Group, INLINE };
+
+    #[test]
+    fn keys_move_to_the_heap_and_back() {
+        let mut group = Group::new();
+        for i in 0..INLINE * 3 {
+            group.push(i);
+            assert_eq!((0..=i).collect::<Vec<_>>(), group.as_slice());
+        }
+
+        group.retain(|k| k % 4 == 1);
+        assert_eq!((0..INLINE * 3).filter(|k| k % 4 == 1).collect::<Vec<_>>(), group.as_slice());
+
+        group.shrink_to_fit();
+        group.push(100);
+        assert_eq!(Some(&100), group.last());
+        assert_eq!(group, group.clone());
+    }
+
+    #[test]
+    fn every_key_is_dropped_once() {
+        let key = Rc::new(());
+        for count in [ 0, 3, INLINE, INLINE + 1, INLINE * 3 ] {
+            let mut group = (0..count).map(|_| key.clone()).collect::<Group<_>>();
+            assert_eq!(count + 1, Rc::strong_count(&key));
+
+            let mut toggle = false;
+            group.retain(|_| { toggle = !toggle; toggle });
+            assert_eq!(count.div_ceil(2) + 1, Rc::strong_count(&key));
+
+            group.shrink_to_fit();
+            assert_eq!(count.div_ceil(2) + 1, Rc::strong_count(&key));
+
+            drop(group);
+            assert_eq!(1, Rc::strong_count(&key));
+        }
+    }
+}
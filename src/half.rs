@@ -0,0 +1,412 @@
+// 16 bit floating point types, so planes and stored vectors take half the memory of `f32`. Use them as the element type of
+// an index, e.g. `MultiIndex<u64, f16>`.
+//
+// Values are converted to `f32` for arithmetic. Dot products and distances accumulate in `f32` and are only rounded to 16
+// bits at the end, so long vectors don't lose precision to the sum.
+
+#![allow(non_camel_case_types)]
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::iter::Sum;
+use std::num::FpCategory;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
+
+use num_traits::{Float, Num, NumCast, One, ToPrimitive, Zero};
+use rand::Rng;
+use rand_distr::StandardNormal;
+
+use crate::error::HypernonsenseError;
+use crate::persist::Persist;
+use crate::vector::{Scalar, ScalarKind};
+
+/// IEEE 754 half precision float: 5 exponent bits and 10 mantissa bits, about 3 significant digits with a maximum of 65504
+#[derive(Clone, Copy, Default)]
+#[repr(transparent)]
+pub struct f16(u16);
+
+/// bfloat16: the 8 exponent bits of an `f32` with only 7 mantissa bits, about 2 significant digits with the range of `f32`
+#[derive(Clone, Copy, Default)]
+#[repr(transparent)]
+pub struct bf16(u16);
+
+// Round to nearest, ties to even
+fn f32_to_f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    // Infinity and NaN (keeping NaN quiet)
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 | (mantissa >> 13) as u16 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    // Too small for a normal half, shift the mantissa (with its implicit leading bit) down into a subnormal
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round = rest > halfway || (rest == halfway && half & 1 == 1);
+        return sign | (half + round as u32) as u16;
+    }
+
+    // Rounding up may carry into the exponent, which is still correct (up to infinity)
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    let round = rest > 0x1000 || (rest == 0x1000 && half & 1 == 1);
+    return sign | (half + round as u32) as u16;
+}
+
+fn f16_bits_to_f32(bits: u16) -> f32 {
+    let sign = ((bits & 0x8000) as u32) << 16;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    return match exponent {
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        0 => {
+            // Zero or subnormal, which is mantissa * 2^-24 (exact in f32)
+            let magnitude = mantissa as f32 * f32::from_bits(0x3380_0000);
+            if sign != 0 { -magnitude } else { magnitude }
+        },
+        _ => f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)),
+    };
+}
+
+fn f32_to_bf16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    if value.is_nan() {
+        return ((bits >> 16) | 0x40) as u16;
+    }
+
+    // Round to nearest, ties to even
+    let round = 0x7fff + ((bits >> 16) & 1);
+    return (bits.wrapping_add(round) >> 16) as u16;
+}
+
+fn bf16_bits_to_f32(bits: u16) -> f32 {
+    return f32::from_bits((bits as u32) << 16);
+}
+
+macro_rules! half_type {
+    ($t:ident, $kind:ident, $to_bits:ident, $from_bits:ident) => {
+        impl $t {
+            pub const fn from_bits(bits: u16) -> $t {
+                $t(bits)
+            }
+
+            pub const fn to_bits(self) -> u16 {
+                self.0
+            }
+
+            /// Convert from an `f32`, rounding to the nearest representable value
+            pub fn from_f32(value: f32) -> $t {
+                $t($to_bits(value))
+            }
+
+            pub fn to_f32(self) -> f32 {
+                $from_bits(self.0)
+            }
+        }
+
+        impl From<$t> for f32 {
+            fn from(value: $t) -> f32 {
+                value.to_f32()
+            }
+        }
+
+        impl fmt::Debug for $t {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&$t::to_f32(*self), f)
+            }
+        }
+
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&$t::to_f32(*self), f)
+            }
+        }
+
+        impl PartialEq for $t {
+            fn eq(&self, other: &$t) -> bool {
+                $t::to_f32(*self) == $t::to_f32(*other)
+            }
+        }
+
+        impl PartialOrd for $t {
+            fn partial_cmp(&self, other: &$t) -> Option<Ordering> {
+                $t::to_f32(*self).partial_cmp(&$t::to_f32(*other))
+            }
+        }
+
+        half_type!(@binary $t, Add, add);
+        half_type!(@binary $t, Sub, sub);
+        half_type!(@binary $t, Mul, mul);
+        half_type!(@binary $t, Div, div);
+        half_type!(@binary $t, Rem, rem);
+
+        impl Neg for $t {
+            type Output = $t;
+
+            fn neg(self) -> $t {
+                $t(self.0 ^ 0x8000)
+            }
+        }
+
+        impl Sum for $t {
+            fn sum<I: Iterator<Item=$t>>(iter: I) -> $t {
+                $t::from_f32(iter.map(|x| x.to_f32()).sum())
+            }
+        }
+
+        impl Zero for $t {
+            fn zero() -> $t {
+                $t(0)
+            }
+
+            fn is_zero(&self) -> bool {
+                self.0 & 0x7fff == 0
+            }
+        }
+
+        impl One for $t {
+            fn one() -> $t {
+                $t::from_f32(1f32)
+            }
+        }
+
+        impl Num for $t {
+            type FromStrRadixErr = <f32 as Num>::FromStrRadixErr;
+
+            fn from_str_radix(s: &str, radix: u32) -> Result<$t, Self::FromStrRadixErr> {
+                f32::from_str_radix(s, radix).map($t::from_f32)
+            }
+        }
+
+        impl ToPrimitive for $t {
+            fn to_i64(&self) -> Option<i64> {
+                $t::to_f32(*self).to_i64()
+            }
+
+            fn to_u64(&self) -> Option<u64> {
+                $t::to_f32(*self).to_u64()
+            }
+
+            fn to_f32(&self) -> Option<f32> {
+                Some($t::to_f32(*self))
+            }
+
+            fn to_f64(&self) -> Option<f64> {
+                Some($t::to_f32(*self) as f64)
+            }
+        }
+
+        impl NumCast for $t {
+            fn from<N: ToPrimitive>(n: N) -> Option<$t> {
+                n.to_f32().map($t::from_f32)
+            }
+        }
+
+        impl Float for $t {
+            half_type!(@constant nan, infinity, neg_infinity, neg_zero, min_value, min_positive_value, epsilon, max_value);
+            half_type!(@predicate is_nan, is_infinite, is_finite, is_normal, is_sign_positive, is_sign_negative);
+            half_type!(@unary floor, ceil, round, trunc, fract, abs, signum, recip, sqrt, exp, exp2, ln, log2, log10, cbrt,
+                sin, cos, tan, asin, acos, atan, exp_m1, ln_1p, sinh, cosh, tanh, asinh, acosh, atanh, to_degrees, to_radians);
+            half_type!(@with_other powf, log, max, min, hypot, atan2);
+
+            fn classify(self) -> FpCategory {
+                self.to_f32().classify()
+            }
+
+            fn mul_add(self, a: $t, b: $t) -> $t {
+                $t::from_f32(self.to_f32().mul_add(a.to_f32(), b.to_f32()))
+            }
+
+            fn powi(self, n: i32) -> $t {
+                $t::from_f32(self.to_f32().powi(n))
+            }
+
+            fn abs_sub(self, other: $t) -> $t {
+                $t::from_f32((self.to_f32() - other.to_f32()).max(0f32))
+            }
+
+            fn sin_cos(self) -> ($t, $t) {
+                let (sin, cos) = self.to_f32().sin_cos();
+                ($t::from_f32(sin), $t::from_f32(cos))
+            }
+
+            fn integer_decode(self) -> (u64, i16, i8) {
+                Float::integer_decode(self.to_f32())
+            }
+        }
+
+        impl Scalar for $t {
+            const KIND: ScalarKind = ScalarKind::$kind;
+
+            fn standard_normal<R:Rng>(rng: &mut R) -> Self {
+                $t::from_f32(rng.sample(StandardNormal))
+            }
+
+            fn dot(a: &[Self], b: &[Self]) -> Self {
+                assert_eq!(a.len(), b.len());
+                $t::from_f32(a.iter().zip(b.iter()).map(|(a, b)| $t::to_f32(*a) * $t::to_f32(*b)).sum())
+            }
+
+            fn squared_euclidean(a: &[Self], b: &[Self]) -> Self {
+                assert_eq!(a.len(), b.len());
+                $t::from_f32(a.iter().zip(b.iter()).map(|(a, b)| ($t::to_f32(*a) - $t::to_f32(*b)).powi(2)).sum())
+            }
+        }
+
+        impl Persist for $t {
+            fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                self.0.persist(writer)
+            }
+
+            fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+                Ok($t(u16::restore(reader)?))
+            }
+        }
+    };
+
+    (@binary $t:ident, $trait:ident, $method:ident) => {
+        impl $trait for $t {
+            type Output = $t;
+
+            fn $method(self, other: $t) -> $t {
+                $t::from_f32(self.to_f32().$method(other.to_f32()))
+            }
+        }
+    };
+
+    (@constant $($name:ident),*) => {
+        $(
+            fn $name() -> Self {
+                Self::from_f32(<f32 as Float>::$name())
+            }
+        )*
+    };
+
+    (@predicate $($name:ident),*) => {
+        $(
+            fn $name(self) -> bool {
+                self.to_f32().$name()
+            }
+        )*
+    };
+
+    (@unary $($name:ident),*) => {
+        $(
+            fn $name(self) -> Self {
+                Self::from_f32(self.to_f32().$name())
+            }
+        )*
+    };
+
+    (@with_other $($name:ident),*) => {
+        $(
+            fn $name(self, other: Self) -> Self {
+                Self::from_f32(self.to_f32().$name(other.to_f32()))
+            }
+        )*
+    };
+}
+
+half_type!(f16, F16, f32_to_f16_bits, f16_bits_to_f32);
+half_type!(bf16, Bf16, f32_to_bf16_bits, bf16_bits_to_f32);
+
+#[cfg(test)]
+mod tests
+{
+    use num_traits::Float;
+    use rand::prelude::*;
+
+    use crate::error::HypernonsenseError;
+    use crate::half::{ bf16, f16 };
+    use crate::multiindex::MultiIndex;
+    use crate::wal::WriteAheadLog;
+    use crate::vector::random_unit_vector_of;
+
+    #[test]
+    fn f16_converts_exactly_representable_values() {
+        for value in [0f32, -0f32, 1f32, -2.5f32, 65504f32, 0.000061035156f32, 5.9604645e-8f32] {
+            assert_eq!(value.to_bits(), f16::from_f32(value).to_f32().to_bits());
+        }
+
+        assert_eq!(f32::INFINITY, f16::from_f32(1e6).to_f32());
+        assert_eq!(0f32, f16::from_f32(1e-9).to_f32());
+        assert!(f16::from_f32(f32::NAN).is_nan());
+        assert_eq!(0x3c00, f16::from_f32(1f32).to_bits());
+
+        // 1 + 2^-11 is exactly halfway between 1 and the next half, ties round to even
+        assert_eq!(1f32, f16::from_f32(1f32 + 2f32.powi(-11)).to_f32());
+        assert_eq!(0.33325195f32, f16::from_f32(1f32 / 3f32).to_f32());
+    }
+
+    #[test]
+    fn bf16_keeps_the_f32_range() {
+        assert_eq!(1f32, bf16::from_f32(1f32).to_f32());
+        assert_eq!(0.33398438f32, bf16::from_f32(1f32 / 3f32).to_f32());
+        assert!((bf16::from_f32(1e30).to_f32() / 1e30 - 1f32).abs() < 0.01);
+        assert!(bf16::from_f32(f32::NAN).is_nan());
+        assert!(-bf16::from_f32(2f32) < bf16::from_f32(1f32));
+    }
+
+    #[test]
+    fn half_index_finds_neighbours() {
+        let mut a = MultiIndex::<usize, f16>::builder(50).index_count(5).plane_count(6).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..500).map(|_| random_unit_vector_of::<f16, _>(50, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let result = a.nearest_k(&vectors[7], 3);
+        assert_eq!(7, result[0].key);
+        assert!(result[0].distance.to_f32() < 1e-2);
+    }
+
+    #[test]
+    fn f16_files_do_not_load_as_bf16() {
+        let path = std::env::temp_dir().join(format!("hypernonsense_half_kind_{}", std::process::id()));
+
+        let mut a = MultiIndex::<u64, f16>::builder(10).index_count(2).plane_count(4).store_vectors(true).build().unwrap();
+        a.add(1, &random_unit_vector_of::<f16, _>(10, &mut thread_rng()));
+
+        // Both types are two bytes, so only the recorded scalar kind tells them apart
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        assert!(matches!(MultiIndex::<u64, bf16>::read_from(&mut buf.as_slice()), Err(HypernonsenseError::InvalidFormat(_))));
+        assert!(MultiIndex::<u64, f16>::read_from(&mut buf.as_slice()).is_ok());
+
+        WriteAheadLog::create(&path, &a).unwrap();
+        assert!(matches!(MultiIndex::<u64, bf16>::recover(Vec::new(), &path), Err(HypernonsenseError::InvalidFormat(_))));
+
+        #[cfg(all(unix, target_endian = "little"))]
+        {
+            use crate::mmap::MappedMultiIndex;
+            use crate::vectorstore::MappedVectorStore;
+
+            a.save_mapped(&path).unwrap();
+            assert!(matches!(MappedMultiIndex::<u64, bf16>::open(&path), Err(HypernonsenseError::InvalidFormat(_))));
+            assert!(MappedMultiIndex::<u64, f16>::open(&path).is_ok());
+
+            a.save_vectors(&path).unwrap();
+            assert!(matches!(MappedVectorStore::<u64, bf16>::open(&path), Err(HypernonsenseError::InvalidFormat(_))));
+            assert!(MappedVectorStore::<u64, f16>::open(&path).is_ok());
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
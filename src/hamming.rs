@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use bit_vec::BitVec;
+use rand::Rng;
+use rand::seq::index::sample;
+use crate::par::prelude::*;
+
+use crate::multiindex::tie_break;
+use crate::query::{Groups, QueryOptions, groups_within};
+
+/// Number of bits which differ between two binary codes
+pub fn code_hamming_distance(a: &[u64], b: &[u64]) -> u32 {
+    assert_eq!(a.len(), b.len());
+
+    return a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+}
+
+/// Number of `u64` words needed to hold a code with this many bits
+pub fn code_words(code_bits: usize) -> usize {
+    code_bits.div_ceil(64)
+}
+
+/// An index over binary codes (packed into `u64` words). Where a `HyperIndex` splits space with random hyperplanes this
+/// samples a random subset of the code bits, so codes which are close in hamming space are likely to get the same key.
+pub struct HammingIndex<K:Send> {
+    sampled: Vec<usize>,
+    groups: Groups<K>,
+    code_bits: usize,
+    len: usize
+}
+
+impl<K:Send> HammingIndex<K> {
+    pub fn new<R : Rng + Sized>(code_bits: usize, sample_count: u8, rng: &mut R) -> HammingIndex<K>
+    {
+        // Sample without replacement where possible, sampling the same bit twice adds nothing
+        let sampled = if (sample_count as usize) <= code_bits {
+            sample(rng, code_bits, sample_count as usize).into_vec()
+        } else {
+            (0..sample_count).map(|_| rng.gen_range(0..code_bits)).collect()
+        };
+
+        return HammingIndex {
+            sampled,
+            groups: Groups::default(),
+            code_bits,
+            len: 0
+        }
+    }
+
+    pub fn code_bits(&self) -> usize {
+        return self.code_bits;
+    }
+
+    pub fn sample_len(&self) -> usize {
+        return self.sampled.len();
+    }
+
+    pub fn groups_len(&self) -> usize {
+        return self.groups.len();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn key(&self, code: &[u64]) -> BitVec
+    {
+        assert_eq!(code_words(self.code_bits), code.len(), "code has the wrong number of words");
+
+        let mut key = BitVec::with_capacity(self.sampled.len());
+        for bit in self.sampled.iter() {
+            key.push((code[bit / 64] >> (bit % 64)) & 1 == 1);
+        }
+
+        return key;
+    }
+
+    pub fn add(&mut self, key: K, code: &[u64]) {
+        let bits = self.key(code);
+        self.groups
+            .entry(bits)
+            .or_default()
+            .push(key);
+        self.len += 1;
+    }
+
+    pub fn group(&self, key: &BitVec) -> Option<&[K]> {
+        return self.groups.get(key).map(|g| g.as_slice());
+    }
+
+    /// Get every non empty group whose key is within `radius` bits of the given key
+    pub fn groups_within(&self, key: &BitVec, radius: u8) -> Vec<&[K]> {
+        return groups_within(&self.groups, key, radius);
+    }
+
+    /// Remove a key which was added with the given code, only the group the code falls into is checked
+    pub fn remove_code(&mut self, key: &K, code: &[u64]) -> bool
+        where K : PartialEq
+    {
+        let bits = self.key(code);
+        if let Some(group) = self.groups.get_mut(&bits) {
+            let before = group.len();
+            group.retain(|k| k != key);
+            self.len -= before - group.len();
+            return group.len() < before;
+        }
+
+        return false;
+    }
+
+    /// Remove every key which matches the predicate, returns the number of items removed
+    pub fn remove_where<F>(&mut self, predicate: F) -> usize
+        where F : Fn(&K) -> bool
+    {
+        let mut removed = 0;
+        for group in self.groups.values_mut() {
+            let before = group.len();
+            group.retain(|k| !predicate(k));
+            removed += before - group.len();
+        }
+        self.len -= removed;
+
+        return removed;
+    }
+}
+
+/// Multiple `HammingIndex` instances queried together, ranking candidates by their exact hamming distance to the query
+pub struct MultiHammingIndex<K:Send+Sync> {
+    indices: Vec<HammingIndex<K>>,
+
+    // Codes are small, so unlike `MultiIndex` they are always stored for ranking
+    codes: HashMap<K, Vec<u64>>,
+    code_bits: usize
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync> MultiHammingIndex<K> {
+    pub fn new<R : Rng + Sized>(code_bits: usize, index_count: u8, sample_count: u8, rng: &mut R) -> MultiHammingIndex<K> {
+        MultiHammingIndex {
+            indices: (0..index_count).map(|_| HammingIndex::new(code_bits, sample_count, rng)).collect(),
+            codes: HashMap::new(),
+            code_bits
+        }
+    }
+
+    pub fn code_bits(&self) -> usize {
+        self.code_bits
+    }
+
+    pub fn indices_len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Number of distinct keys in the index
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn add(&mut self, key: K, code: &[u64])
+    {
+        // Adding a key twice would leave it in the old groups
+        self.remove(&key);
+
+        self.indices.par_iter_mut()
+            .for_each(|idx| idx.add(key.clone(), code));
+        self.codes.insert(key, code.to_vec());
+    }
+
+    pub fn get(&self, key: &K) -> Option<&[u64]>
+    {
+        self.codes.get(key).map(|c| c.as_slice())
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool
+    {
+        let code = match self.codes.remove(key) {
+            Some(code) => code,
+            None => return false,
+        };
+
+        self.indices.par_iter_mut()
+            .for_each(|idx| { idx.remove_code(key, &code); });
+        return true;
+    }
+
+    pub fn nearest_points_set_with(&self, code: &[u64], options: &QueryOptions) -> HashSet<K>
+    {
+        return self.indices.par_iter()
+            .flat_map_iter(|i| i.groups_within(&i.key(code), options.probe_radius))
+            .flat_map_iter(|r| r.iter().cloned())
+            .collect::<HashSet<K>>();
+    }
+
+    /// Find the nearest `count` codes, returns keys and hamming distances sorted by distance (small->large). Ties are ordered the same way as `DistanceNode`.
+    pub fn nearest(&self, code: &[u64], count: usize) -> Vec<(K, u32)>
+    {
+        return self.nearest_with(code, count, &QueryOptions::default());
+    }
+
+    pub fn nearest_with(&self, code: &[u64], count: usize, options: &QueryOptions) -> Vec<(K, u32)>
+    {
+        let mut result = self.nearest_points_set_with(code, options)
+            .into_par_iter()
+            .map(|k| {
+                let d = code_hamming_distance(code, &self.codes[&k]);
+                (k, d)
+            })
+            .collect::<Vec<_>>();
+
+        result.sort_unstable_by_key(|a| (a.1, tie_break(&a.0)));
+        result.truncate(count);
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::hamming::{ HammingIndex, MultiHammingIndex, code_hamming_distance };
+
+    fn random_code(rng: &mut ThreadRng) -> Vec<u64> {
+        (0..4).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn hamming_distance_counts_bits() {
+        assert_eq!(0, code_hamming_distance(&[5, 7], &[5, 7]));
+        assert_eq!(3, code_hamming_distance(&[0b111, 0], &[0, 0]));
+        assert_eq!(128, code_hamming_distance(&[0, 0], &[u64::MAX, u64::MAX]));
+    }
+
+    #[test]
+    fn index_samples_bits() {
+        let mut a = HammingIndex::new(256, 12, &mut thread_rng());
+        assert_eq!(12, a.sample_len());
+
+        let code = random_code(&mut thread_rng());
+        a.add(1, &code);
+        assert_eq!(&vec![1], a.group(&a.key(&code)).unwrap());
+    }
+
+    #[test]
+    fn nearest_finds_close_codes() {
+        let mut a = MultiHammingIndex::new(256, 10, 12, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..2000usize {
+            a.add(key, &random_code(&mut rng));
+        }
+
+        // A copy of an existing code with a few bits flipped should find the original
+        let mut query = a.get(&7).unwrap().to_vec();
+        query[0] ^= 0b1011;
+        let result = a.nearest(&query, 5);
+
+        assert_eq!((7, 3), result[0]);
+        assert!(result.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+
+    #[test]
+    fn add_replaces_existing_key() {
+        let mut a = MultiHammingIndex::new(256, 5, 8, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        a.add(1, &random_code(&mut rng));
+        let code = random_code(&mut rng);
+        a.add(1, &code);
+
+        assert_eq!(1, a.len());
+        assert_eq!(vec![(1, 0)], a.nearest(&code, 10));
+    }
+}
@@ -1,108 +1,1120 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
 
 use rand::Rng;
 use bit_vec::BitVec;
+use crate::par::prelude::*;
 
-use crate::vector::{ dot, random_unit_vector };
+use crate::error::HypernonsenseError;
+use crate::group::Group;
+use crate::multiindex::DistanceNode;
+use crate::persist::{Persist, read_single_hyperindex, write_single_hyperindex};
+use crate::query::{Groups, QueryOptions, groups_at, groups_within, groups_within_keyed, rank_borrowed};
+use crate::stats::IndexStats;
+use crate::vector::{ Scalar, SparseVector, check_vector, dot, random_unit_vector_of, sparse_dense_dot };
 
-pub struct HyperIndex<K:Send> {
-    planes: Vec<Vec<f32>>,
-    groups: HashMap<BitVec, Vec<K>>,
-    dims: usize
+/// Number of key bits each p-stable hash contributes. Buckets wrap around after `2^BUCKET_BITS` buckets.
+pub const BUCKET_BITS: usize = 4;
+
+/// The family of hash functions an index uses to assign vectors to groups
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum HashFamily {
+    /// Random hyperplanes, each plane contributes one bit (which side of the plane the vector is on). Vectors separated by a
+    /// small angle are likely to get the same key.
+    #[default]
+    Hyperplane,
+
+    /// p-stable hashing, each plane is a gaussian projection quantized into buckets `width` wide. Vectors separated by a
+    /// small euclidean distance are likely to get the same key. Each plane contributes `BUCKET_BITS` bits to the key.
+    PStable { width: f32 },
+
+    /// Cross-polytope hashing, each plane is a pseudo-random rotation onto `rotation_dims` dimensions and the key is the
+    /// closest axis (and direction) of the rotated vector. Like hyperplanes this approximates angular distance, but with
+    /// better recall for the same number of key bits. `rotation_dims` must be no larger than the index dimension.
+    CrossPolytope { rotation_dims: u8 },
+}
+
+impl HashFamily {
+    /// Number of key bits produced by each plane
+    pub fn bits_per_plane(&self) -> usize {
+        match self {
+            HashFamily::Hyperplane => 1,
+            HashFamily::PStable { .. } => BUCKET_BITS,
+            HashFamily::CrossPolytope { rotation_dims } => (2 * *rotation_dims as usize).next_power_of_two().trailing_zeros() as usize,
+        }
+    }
+
+    /// Check the parameters of the family can be used by an index with the given dimension, returns why not if they can't.
+    /// Every way of creating or loading an index checks this, so a bad width or rotation can't produce meaningless keys.
+    pub(crate) fn validate(&self, dimension: usize) -> Result<(), String> {
+        match self {
+            HashFamily::PStable { width } if !(width.is_finite() && *width > 0f32) => {
+                return Err(format!("bucket width must be positive, got {}", width));
+            }
+            HashFamily::CrossPolytope { rotation_dims } if *rotation_dims == 0 || *rotation_dims as usize > dimension => {
+                return Err(format!("rotation dimensions must be between one and the index dimension ({}), got {}", dimension, rotation_dims));
+            }
+            _ => return Ok(()),
+        }
+    }
+
+    /// Number of rows (each `dimension` long) in each plane
+    pub fn rows_per_plane(&self) -> usize {
+        match self {
+            HashFamily::CrossPolytope { rotation_dims } => *rotation_dims as usize,
+            _ => 1,
+        }
+    }
+}
+
+// Generate `count` random orthonormal rows (Gram-Schmidt on gaussian vectors), flattened into one vector
+pub(crate) fn random_rotation<T:Scalar, R:Rng>(dimension: usize, count: usize, rng: &mut R) -> Vec<T> {
+    assert!(count <= dimension, "cannot rotate onto more dimensions than the index has");
+
+    let mut rows = Vec::<Vec<T>>::with_capacity(count);
+    while rows.len() < count {
+        // A row which is (almost) parallel to the previous rows is thrown away and drawn again
+        let mut row = random_unit_vector_of::<T, _>(dimension, rng);
+        if orthonormalize(&mut row, &rows) {
+            rows.push(row);
+        }
+    }
+
+    return rows.concat();
 }
 
-impl<K:Send> HyperIndex<K> {
-    pub fn new<R : Rng + Sized>(dimension: usize, hyperplane_count: u8, mut rng: &mut R) -> HyperIndex<K>
+// Remove the components of a vector along each of the (orthonormal) previous vectors, then normalize it. Returns false if
+// nothing is left.
+fn orthonormalize<T:Scalar>(vector: &mut [T], previous: &[Vec<T>]) -> bool {
+    for prev in previous.iter() {
+        let d = dot(prev, vector);
+        for (v, p) in vector.iter_mut().zip(prev.iter()) {
+            *v = *v - d * *p;
+        }
+    }
+
+    let len = dot(vector, vector).sqrt();
+    if len <= T::from(1e-6).unwrap() {
+        return false;
+    }
+    for v in vector.iter_mut() {
+        *v = *v / len;
+    }
+    return true;
+}
+
+// Find the direction of greatest variance in centered data which is orthogonal to the components already found, with power
+// iteration. If the data has no variance left a random orthogonal direction is returned.
+pub(crate) fn principal_component<T:Scalar, R:Rng>(centered: &[Vec<T>], found: &[Vec<T>], rng: &mut R) -> Vec<T> {
+    let dimension = centered[0].len();
+    assert!(found.len() < dimension, "the components already found span every dimension");
+
+    let mut component = random_unit_vector_of::<T, _>(dimension, rng);
+    while !orthonormalize(&mut component, found) {
+        component = random_unit_vector_of(dimension, rng);
+    }
+
+    for _ in 0..POWER_ITERATIONS {
+        let mut next = vec![T::zero(); dimension];
+        for x in centered.iter() {
+            let d = dot(x, &component);
+            for (n, v) in next.iter_mut().zip(x.iter()) {
+                *n = *n + d * *v;
+            }
+        }
+
+        if !orthonormalize(&mut next, found) {
+            break;
+        }
+        component = next;
+    }
+
+    return component;
+}
+
+fn centroid<'a, K, T:Scalar, F>(dims: usize, members: &[K], get_vector: &F) -> Option<Vec<T>>
+    where F : Fn(&K) -> Option<&'a [T]>
+{
+    let mut sum = vec![T::zero(); dims];
+    let mut count = 0usize;
+    for v in members.iter().filter_map(get_vector) {
+        for (s, x) in sum.iter_mut().zip(v.iter()) {
+            *s = *s + *x;
+        }
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+
+    let count = T::from(count).unwrap();
+    return Some(sum.into_iter().map(|s| s / count).collect());
+}
+
+// Number of power iterations used to find each principal component
+const POWER_ITERATIONS: usize = 50;
+
+// Reflected gray code, so adjacent buckets (including the wrap around) have keys one bit apart
+fn gray_code(bucket: i64) -> u64 {
+    let b = bucket.rem_euclid(1 << BUCKET_BITS) as u64;
+    return b ^ (b >> 1);
+}
+
+/// The hash functions of a `HyperIndex`, without any of its groups. Indices created from the same planes with
+/// `HyperIndex::with_planes` share one copy of the plane matrix and always give a vector the same key, including in
+/// other processes if the planes are saved and loaded.
+#[derive(Clone, Debug)]
+pub struct Planes<T:Scalar=f32> {
+    matrix: Arc<Vec<T>>,
+    offsets: Vec<T>,
+    family: HashFamily,
+    dims: usize,
+}
+
+impl<T:Scalar> Planes<T> {
+    /// Create planes from a row major matrix. Each plane is `family.rows_per_plane()` rows of `dimension` values, and has
+    /// one offset which is added to the projection onto it before it is hashed (zero for planes through the origin).
+    pub fn new(dimension: usize, family: HashFamily, matrix: Vec<T>, offsets: Vec<T>) -> Result<Planes<T>, HypernonsenseError> {
+        if dimension == 0 {
+            return Err(HypernonsenseError::InvalidConfig("dimension must be at least one".to_string()));
+        }
+        if offsets.len() > u8::MAX as usize {
+            return Err(HypernonsenseError::InvalidConfig(format!("at most {} planes are supported", u8::MAX)));
+        }
+        if matrix.len() != offsets.len() * family.rows_per_plane() * dimension {
+            return Err(HypernonsenseError::InvalidConfig(format!("expected {} plane values for {} planes, got {}", offsets.len() * family.rows_per_plane() * dimension, offsets.len(), matrix.len())));
+        }
+        family.validate(dimension).map_err(HypernonsenseError::InvalidConfig)?;
+        if matrix.iter().chain(offsets.iter()).any(|v| !v.is_finite()) {
+            return Err(HypernonsenseError::NonFiniteInput);
+        }
+
+        return Ok(Planes { matrix: Arc::new(matrix), offsets, family, dims: dimension });
+    }
+
+    /// Generate random planes, the same way as `HyperIndex::new_with_family`
+    pub fn random<R : Rng + Sized>(dimension: usize, hyperplane_count: u8, family: HashFamily, rng: &mut R) -> Planes<T> {
+        return HyperIndex::<(), T>::new_with_family(dimension, hyperplane_count, family, rng).export_planes();
+    }
+
+    pub fn dimensions(&self) -> usize {
+        return self.dims;
+    }
+
+    pub fn planes_len(&self) -> usize {
+        return self.offsets.len();
+    }
+
+    pub fn family(&self) -> HashFamily {
+        return self.family;
+    }
+
+    /// Every plane in one row major matrix
+    pub fn matrix(&self) -> &[T] {
+        return &self.matrix;
+    }
+
+    pub fn offsets(&self) -> &[T] {
+        return &self.offsets;
+    }
+}
+
+impl<T:Scalar+Persist> Planes<T> {
+    /// Write the planes to a file in the binary index format, as an index with one sub index and no groups. The file can
+    /// also be loaded as an empty `HyperIndex` or `MultiIndex`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HypernonsenseError> {
+        return HyperIndex::<(), T>::with_planes(self.clone()).save(path);
+    }
+
+    /// Load planes from a file previously written with `save`, or the planes of any single `HyperIndex` file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Planes<T>, HypernonsenseError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        return Self::read_from(&mut reader);
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), HypernonsenseError> {
+        return HyperIndex::<(), T>::with_planes(self.clone()).write_to(writer);
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Planes<T>, HypernonsenseError> {
+        return Ok(HyperIndex::<(), T>::read_from(reader)?.export_planes());
+    }
+}
+
+#[derive(Clone)]
+pub struct HyperIndex<K:Send, T:Scalar=f32> {
+    // Every plane in one row major matrix, so a key is one pass over the matrix. Each plane is `rows_per_plane` rows of
+    // `dims` values. The matrix is shared with every index created from the same `Planes`.
+    planes: Arc<Vec<T>>,
+
+    // Added to the projection onto each plane before it is hashed
+    offsets: Vec<T>,
+    family: HashFamily,
+
+    groups: Groups<K>,
+    dims: usize,
+
+    // Total number of items in all groups
+    len: usize
+}
+
+impl<K:Send, T:Scalar> HyperIndex<K, T> {
+    pub fn new<R : Rng + Sized>(dimension: usize, hyperplane_count: u8, rng: &mut R) -> HyperIndex<K, T>
+    {
+        return Self::new_with_family(dimension, hyperplane_count, HashFamily::Hyperplane, rng);
+    }
+
+    /// Create a new index which hashes vectors with the given family of hash functions
+    pub fn new_with_family<R : Rng + Sized>(dimension: usize, hyperplane_count: u8, family: HashFamily, mut rng: &mut R) -> HyperIndex<K, T>
     {
-        let mut planes = Vec::<Vec<f32>>::with_capacity(hyperplane_count as usize);
+        let mut planes = Vec::<T>::with_capacity(hyperplane_count as usize * dimension * family.rows_per_plane());
+        let mut offsets = Vec::<T>::with_capacity(hyperplane_count as usize);
         for _ in 0..hyperplane_count {
-            planes.push(random_unit_vector(dimension, &mut rng));
+            match family {
+                HashFamily::Hyperplane => {
+                    planes.extend(random_unit_vector_of::<T, _>(dimension, &mut rng));
+                    offsets.push(T::zero());
+                }
+                HashFamily::PStable { width } => {
+                    // Projections must not be normalized, so the projected distance scales with the euclidean distance
+                    planes.extend((0..dimension).map(|_| T::standard_normal(&mut rng)));
+                    offsets.push(T::from(rng.gen::<f32>() * width).unwrap());
+                }
+                HashFamily::CrossPolytope { rotation_dims } => {
+                    planes.extend(random_rotation::<T, _>(dimension, rotation_dims as usize, &mut rng));
+                    offsets.push(T::zero());
+                }
+            }
         }
 
         return HyperIndex {
-            planes,
-            groups: HashMap::new(),
-            dims: dimension
+            planes: Arc::new(planes),
+            offsets,
+            family,
+            groups: Groups::default(),
+            dims: dimension,
+            len: 0
+        }
+    }
+
+    /// Create a new index which hashes vectors with the given planes, the plane matrix is shared instead of copied
+    pub fn with_planes(planes: Planes<T>) -> HyperIndex<K, T>
+    {
+        return HyperIndex {
+            planes: planes.matrix,
+            offsets: planes.offsets,
+            family: planes.family,
+            groups: Groups::default(),
+            dims: planes.dims,
+            len: 0
         }
     }
 
-    pub fn stats(&self) -> (usize, f32, usize)
+    /// The planes of this index without its groups, for creating other indices which hash vectors the same way (see
+    /// `with_planes`). This doesn't copy the plane matrix.
+    pub fn export_planes(&self) -> Planes<T>
     {
-        let min = self.groups.iter().min_by_key(|a| a.1.len()).map(|a| a.1.len()).unwrap_or(0);
-        let average = self.groups.iter().map(|a| a.1.len() as f32).sum::<f32>() / (self.groups.len() as f32);
-        let max = self.groups.iter().max_by_key(|a| a.1.len()).map(|a| a.1.len()).unwrap_or(0);
-        return (min, average, max);
+        return Planes {
+            matrix: self.planes.clone(),
+            offsets: self.offsets.clone(),
+            family: self.family,
+            dims: self.dims,
+        };
+    }
+
+    /// Create a new index with hyperplanes fitted to a sample of the data, instead of random planes. The planes are the
+    /// principal components of the sample and each one passes through the mean of the sample, so every plane splits the
+    /// data along a direction in which it actually varies. This produces much more balanced groups when the data lies on a
+    /// low dimensional manifold or is not centered on the origin.
+    ///
+    /// The principal components are almost the same for every sample, so this is mostly useful for a single index. There
+    /// are only as many principal components as dimensions, so the plane count must be no larger than the dimension.
+    pub fn new_fitted<R : Rng + Sized>(sample_vectors: &[Vec<T>], hyperplane_count: u8, rng: &mut R) -> Result<HyperIndex<K, T>, HypernonsenseError>
+    {
+        if sample_vectors.is_empty() {
+            return Err(HypernonsenseError::EmptyIndex);
+        }
+        let dimension = sample_vectors[0].len();
+        for v in sample_vectors.iter() {
+            check_vector(v, dimension)?;
+        }
+        if hyperplane_count as usize > dimension {
+            return Err(HypernonsenseError::InvalidConfig("cannot fit more planes than the sample has dimensions".to_string()));
+        }
+
+        let count = T::from(sample_vectors.len()).unwrap();
+        let mut mean = vec![T::zero(); dimension];
+        for v in sample_vectors.iter() {
+            for (m, x) in mean.iter_mut().zip(v.iter()) {
+                *m = *m + *x / count;
+            }
+        }
+        let centered = sample_vectors.iter()
+            .map(|v| v.iter().zip(mean.iter()).map(|(x, m)| *x - *m).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let mut planes = Vec::<Vec<T>>::with_capacity(hyperplane_count as usize);
+        for _ in 0..hyperplane_count {
+            let plane = principal_component(&centered, &planes, rng);
+            planes.push(plane);
+        }
+        let offsets = planes.iter().map(|p| -dot(p, &mean)).collect();
+
+        return Ok(HyperIndex {
+            planes: Arc::new(planes.concat()),
+            offsets,
+            family: HashFamily::Hyperplane,
+            groups: Groups::default(),
+            dims: dimension,
+            len: 0
+        });
+    }
+
+    /// Give every plane a bias, chosen so the plane passes through the median of the sample vectors. Each plane then splits
+    /// the data roughly in half, even if it isn't centered on the origin. Only hyperplane indices have a bias, and it must
+    /// be set before any items are added.
+    pub fn fit_offsets(&mut self, sample_vectors: &[Vec<T>]) -> Result<(), HypernonsenseError>
+    {
+        if self.family != HashFamily::Hyperplane {
+            return Err(HypernonsenseError::InvalidConfig("only hyperplane indices have a bias".to_string()));
+        }
+        if !self.is_empty() {
+            return Err(HypernonsenseError::InvalidConfig("offsets must be fitted before adding items".to_string()));
+        }
+        if sample_vectors.is_empty() {
+            return Err(HypernonsenseError::EmptyIndex);
+        }
+        for v in sample_vectors.iter() {
+            check_vector(v, self.dims)?;
+        }
+
+        let projections = sample_vectors.iter().map(|v| self.projections(v)).collect::<Vec<_>>();
+        for (i, offset) in self.offsets.iter_mut().enumerate() {
+            let mut plane = projections.iter().map(|p| p[i]).collect::<Vec<_>>();
+            let mid = plane.len() / 2;
+            let (_, median, _) = plane.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap());
+            *offset = -*median;
+        }
+
+        return Ok(());
+    }
+
+    pub(crate) fn from_parts(dims: usize, planes: Vec<Vec<T>>, offsets: Vec<T>, family: HashFamily, groups: Groups<K>) -> HyperIndex<K, T>
+    {
+        let len = groups.values().map(|g| g.len()).sum();
+        return HyperIndex {
+            planes: Arc::new(planes.concat()),
+            offsets,
+            family,
+            groups,
+            dims,
+            len
+        }
+    }
+
+    /// Same as `from_parts`, sharing the plane matrix of `planes` instead of copying it
+    pub(crate) fn from_shared_parts(planes: Planes<T>, groups: Groups<K>) -> HyperIndex<K, T>
+    {
+        let len = groups.values().map(|g| g.len()).sum();
+        return HyperIndex {
+            planes: planes.matrix,
+            offsets: planes.offsets,
+            family: planes.family,
+            groups,
+            dims: planes.dims,
+            len
+        }
+    }
+
+    pub fn stats(&self) -> IndexStats
+    {
+        return IndexStats::from_histogram(self.group_size_histogram());
+    }
+
+    /// Number of groups of each size (group size -> group count)
+    pub fn group_size_histogram(&self) -> BTreeMap<usize, usize>
+    {
+        let mut histogram = BTreeMap::new();
+        for group in self.groups.values() {
+            *histogram.entry(group.len()).or_insert(0) += 1;
+        }
+
+        return histogram;
     }
 
     pub fn dimensions(&self) -> usize {
         return self.dims;
     }
 
+    /// Number of items in the index
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    /// Check if a key is in the index. This has to search every group.
+    pub fn contains_key(&self, key: &K) -> bool
+        where K : PartialEq
+    {
+        return self.groups.values().any(|g| g.contains(key));
+    }
+
     pub fn groups_len(&self) -> usize {
         return self.groups.len();
     }
 
     pub fn planes_len(&self) -> usize {
-        return self.planes.len();
+        return self.offsets.len();
+    }
+
+    /// Number of bits in each key
+    pub fn key_len(&self) -> usize {
+        return self.planes_len() * self.family.bits_per_plane();
+    }
+
+    pub fn family(&self) -> HashFamily {
+        return self.family;
+    }
+
+    // Each plane as a slice of the plane matrix
+    pub(crate) fn planes(&self) -> Vec<&[T]> {
+        let len = self.dims * self.family.rows_per_plane();
+        return (0..self.planes_len()).map(|i| &self.planes[i * len..(i + 1) * len]).collect();
+    }
+
+    pub(crate) fn offsets(&self) -> &[T] {
+        return &self.offsets;
+    }
+
+    pub(crate) fn groups_iter(&self) -> impl Iterator<Item=(&BitVec, &Group<K>)> {
+        return self.groups.iter();
+    }
+
+    /// Every non empty group and its members, in no particular order
+    pub fn groups(&self) -> impl Iterator<Item=(&BitVec, &[K])> {
+        return self.groups.iter().filter(|(_, g)| !g.is_empty()).map(|(k, g)| (k, g.as_slice()));
+    }
+
+    pub fn key(&self, vector: &[T]) -> BitVec
+    {
+        return self.key_from_projections(&self.projections(vector));
+    }
+
+    /// Calculate the keys for many vectors, in parallel
+    pub fn keys(&self, vectors: &[Vec<T>]) -> Vec<BitVec>
+        where K : Sync
+    {
+        return vectors.par_iter().map(|v| self.key(v)).collect();
+    }
+
+    /// Calculate the key for a sparse vector, without converting it to a dense vector
+    pub fn key_sparse(&self, vector: &SparseVector<T>) -> BitVec
+    {
+        let projections = self.planes.chunks_exact(self.dims.max(1))
+            .map(|row| sparse_dense_dot(vector, row))
+            .collect::<Vec<_>>();
+        return self.key_from_projections(&projections);
+    }
+
+    // Dot product of a vector with every row of the plane matrix
+    fn projections(&self, vector: &[T]) -> Vec<T>
+    {
+        assert_eq!(self.dims, vector.len(), "vector does not have the dimension of the index");
+        if self.dims == 0 {
+            return vec![T::zero(); self.planes_len() * self.family.rows_per_plane()];
+        }
+        return T::dot_rows(&self.planes, vector);
     }
 
-    pub fn key(&self, vector: &Vec<f32>) -> BitVec
+    // Build a key from the projection of a vector onto each row of the plane matrix
+    fn key_from_projections(&self, projections: &[T]) -> BitVec
     {
-        let mut key = BitVec::with_capacity(self.planes.len());
+        let mut key = BitVec::with_capacity(self.key_len());
+        let rows = self.family.rows_per_plane();
 
-        for plane in self.planes.iter() {
-            let d = dot(&plane, vector);
-            let b = d > 0f32;
-            key.push(b);
+        for (i, offset) in self.offsets.iter().enumerate() {
+            let plane = &projections[i * rows..(i + 1) * rows];
+            match self.family {
+                HashFamily::Hyperplane => key.push(plane[0] + *offset > T::zero()),
+                HashFamily::PStable { width } => {
+                    let bucket = ((plane[0] + *offset) / T::from(width).unwrap()).floor().to_i64().unwrap_or(0);
+                    let code = gray_code(bucket);
+                    for bit in 0..BUCKET_BITS {
+                        key.push((code >> bit) & 1 == 1);
+                    }
+                }
+                HashFamily::CrossPolytope { .. } => {
+                    // Find the closest axis, the lowest bit of the hash is the direction along that axis
+                    let mut best = 0;
+                    let mut best_abs = T::neg_infinity();
+                    for (i, c) in plane.iter().enumerate() {
+                        if c.abs() > best_abs {
+                            best_abs = c.abs();
+                            best = i * 2 + (*c < T::zero()) as usize;
+                        }
+                    }
+                    for bit in 0..self.family.bits_per_plane() {
+                        key.push((best >> bit) & 1 == 1);
+                    }
+                }
+            }
         }
 
         return key;
     }
 
-    pub fn add(&mut self, key: K, vector: &Vec<f32>) {
+    /// Calculate the key for a vector, returns an error instead of panicking if the vector is not valid for this index
+    pub fn try_key(&self, vector: &[T]) -> Result<BitVec, HypernonsenseError>
+    {
+        check_vector(vector, self.dims)?;
+        return Ok(self.key(vector));
+    }
+
+    /// Add an item, returns an error instead of panicking if the vector is not valid for this index
+    pub fn try_add(&mut self, key: K, vector: &[T]) -> Result<(), HypernonsenseError>
+    {
+        check_vector(vector, self.dims)?;
+        self.add(key, vector);
+        return Ok(());
+    }
+
+    pub fn add(&mut self, key: K, vector: &[T]) {
 
         // Build bit vector, each bit indicates which side of the hyperplane the point is on
-        let bits = self.key(&vector);
+        let bits = self.key(vector);
 
+        self.add_to_group(key, bits);
+    }
+
+    /// Add an item with a sparse vector
+    pub fn add_sparse(&mut self, key: K, vector: &SparseVector<T>) {
+        let bits = self.key_sparse(vector);
+        self.add_to_group(key, bits);
+    }
+
+    fn add_to_group(&mut self, key: K, bits: BitVec) {
         // Insert this item into the appropriate group
         self.groups
             .entry(bits)
-            .or_insert(Vec::new())
+            .or_default()
             .push(key);
+        self.len += 1;
+    }
+
+    /// Add many items at once. Keys are calculated in parallel and merged into the groups in one pass.
+    pub fn add_batch(&mut self, items: &[(K, Vec<T>)])
+        where K : Clone + Sync
+    {
+        let batch = items.par_iter()
+            .fold_with(Groups::<K>::default(), |mut groups, (key, vector)| {
+                groups.entry(self.key(vector)).or_default().push(key.clone());
+                groups
+            })
+            .reduce_with(|mut a, b| {
+                for (bits, mut keys) in b {
+                    a.entry(bits).or_default().append(&mut keys);
+                }
+                a
+            })
+            .unwrap_or_default();
+
+        self.len += items.len();
+        self.groups.reserve(batch.len());
+        for (bits, mut keys) in batch {
+            self.groups.entry(bits).or_default().append(&mut keys);
+        }
     }
 
-    pub fn group(&self, key: &BitVec) -> Option<&Vec<K>> {
-        return self.groups.get(&key);
+    pub fn group(&self, key: &BitVec) -> Option<&[K]> {
+        return self.groups.get(key).map(|g| g.as_slice());
+    }
+
+    /// Mean of the vectors in a group. The index doesn't keep vectors, so `get_vector` must look them up (e.g. `MultiIndex::get`),
+    /// members it returns None for are skipped. Returns None if the group is empty or none of its vectors were found.
+    pub fn group_centroid<'a, F>(&self, key: &BitVec, get_vector: F) -> Option<Vec<T>>
+        where F : Fn(&K) -> Option<&'a [T]>, T : 'a
+    {
+        return centroid(self.dims, self.groups.get(key)?, &get_vector);
+    }
+
+    /// The key and centroid of every group with at least one vector found by `get_vector`, see `group_centroid`
+    pub fn group_centroids<'a, 'b, F>(&'b self, get_vector: F) -> impl Iterator<Item=(&'b BitVec, Vec<T>)> + 'b
+        where F : Fn(&K) -> Option<&'a [T]> + 'b, T : 'a
+    {
+        return self.groups.iter().filter_map(move |(key, members)| Some((key, centroid(self.dims, members, &get_vector)?)));
+    }
+
+    /// Get every non empty group whose key is within `radius` bits of the given key
+    pub fn groups_within(&self, key: &BitVec, radius: u8) -> Vec<&[K]> {
+        return groups_within(&self.groups, key, radius);
+    }
+
+    pub(crate) fn groups_within_keyed(&self, key: &BitVec, radius: u8) -> Vec<(&BitVec, &Group<K>)> {
+        return groups_within_keyed(&self.groups, key, radius);
+    }
+
+    /// Get every non empty group whose key is exactly `radius` bits away from the given key
+    pub fn groups_at(&self, key: &BitVec, radius: u8) -> Vec<&[K]> {
+        return groups_at(&self.groups, key, radius);
+    }
+
+    /// Remove every occurrence of the given key from the index, returns true if anything was removed
+    pub fn remove(&mut self, key: &K) -> bool
+        where K : PartialEq
+    {
+        return self.remove_where(|k| k == key) > 0;
+    }
+
+    /// Remove a key which was added with the given vector. Only the group the vector falls into is checked, so this is much faster than `remove`.
+    pub fn remove_vector(&mut self, key: &K, vector: &[T]) -> bool
+        where K : PartialEq
+    {
+        let bits = self.key(vector);
+        if let Some(group) = self.groups.get_mut(&bits) {
+            let before = group.len();
+            group.retain(|k| k != key);
+            self.len -= before - group.len();
+            return group.len() < before;
+        }
+
+        return false;
+    }
+
+    /// Remove every key which matches the predicate, returns the number of items removed
+    pub fn remove_where<F>(&mut self, predicate: F) -> usize
+        where F : Fn(&K) -> bool
+    {
+        // We don't know which group a key is in (the vector isn't stored) so every group must be checked
+        let mut removed = 0;
+        for group in self.groups.values_mut() {
+            let before = group.len();
+            group.retain(|k| !predicate(k));
+            removed += before - group.len();
+        }
+        self.len -= removed;
+
+        return removed;
+    }
+
+    /// Remove the empty groups left behind by removals and release any unused memory held by the groups
+    pub fn compact(&mut self) {
+        self.groups.retain(|_, g| !g.is_empty());
+        for group in self.groups.values_mut() {
+            group.shrink_to_fit();
+        }
+        self.groups.shrink_to_fit();
+    }
+
+    /// Remove every item, keeping the planes
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.len = 0;
+    }
+
+    // A copy of this index with the same planes and no items
+    pub(crate) fn empty_copy(&self) -> HyperIndex<K, T> {
+        return Self::with_planes(self.export_planes());
+    }
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync, T:Scalar> HyperIndex<K, T> {
+    pub fn nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_with(point, count, &QueryOptions::default(), get_dist);
+    }
+
+    /// Find the nearest `count` points in the groups within the probe radius of the query key. Only `probe_radius` is used from the options.
+    pub fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let candidates = self.groups_within(&self.key(point), options.probe_radius)
+            .into_iter()
+            .flatten()
+            .collect::<HashSet<_>>();
+
+        return rank_borrowed(candidates, count, |k| get_dist(point, k));
+    }
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync+Persist, T:Scalar+Persist> HyperIndex<K, T> {
+    /// Write this index to the given file in the binary index format. The file can also be loaded as a `MultiIndex` with one sub index.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HypernonsenseError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load an index from a file previously written with `save`, or a `MultiIndex` file with exactly one sub index
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<HyperIndex<K, T>, HypernonsenseError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::read_from(&mut reader)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), HypernonsenseError> {
+        write_single_hyperindex(self, writer)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<HyperIndex<K, T>, HypernonsenseError> {
+        read_single_hyperindex(reader)
     }
 }
 
 #[cfg(test)]
 mod tests
 {
+    use bit_vec::BitVec;
     use rand::prelude::*;
 
-    use crate::hyperindex::HyperIndex;
-    use crate::vector::{ random_unit_vector, modified_cosine_distance };
+    use crate::error::HypernonsenseError;
+    use crate::hyperindex::{ BUCKET_BITS, HashFamily, HyperIndex, Planes };
+    use crate::vector::{ SparseVector, dot, euclidean_distance, random_unit_vector, random_unit_vector_of, modified_cosine_distance };
 
     #[test]
-    fn new_creates_index<'a>() {
+    fn new_creates_index() {
         let a = HyperIndex::<usize>::new(300, 10, &mut thread_rng());
 
         assert_eq!(300, a.dimensions());
         assert_eq!(0, a.groups_len());
+        assert!(a.is_empty());
         assert_eq!(10, a.planes_len());
     }
 
     #[test]
-    fn add_adds_points<'a>() {
+    fn add_adds_points() {
+        let mut a = HyperIndex::new(300, 10, &mut thread_rng());
+
+        let v = random_unit_vector(300, &mut thread_rng());
+        a.add(0, &v);
+    }
+
+    #[test]
+    fn add_batch_matches_add() {
+        let mut a = HyperIndex::new(300, 10, &mut thread_rng());
+        let mut rng = thread_rng();
+        let items = (0..1000usize).map(|k| (k, random_unit_vector(300, &mut rng))).collect::<Vec<_>>();
+
+        a.add_batch(&items);
+
+        assert_eq!(1000, a.groups_iter().map(|(_, g)| g.len()).sum::<usize>());
+        for (key, vector) in items.iter() {
+            assert!(a.group(&a.key(vector)).unwrap().contains(key));
+        }
+    }
+
+    #[test]
+    fn keys_match_each_plane() {
+        let a = HyperIndex::<usize>::new(37, 11, &mut thread_rng());
+        let mut rng = thread_rng();
+        let vectors = (0..100).map(|_| random_unit_vector(37, &mut rng)).collect::<Vec<_>>();
+
+        let keys = a.keys(&vectors);
+        for (key, vector) in keys.iter().zip(vectors.iter()) {
+            let expected = a.planes().iter().map(|p| dot(p, vector) > 0f32).collect::<BitVec>();
+            assert_eq!(expected, *key);
+            assert_eq!(a.key(vector), *key);
+        }
+    }
+
+    #[test]
+    fn try_add_rejects_bad_vectors() {
+        let mut a = HyperIndex::new(3, 10, &mut thread_rng());
+
+        assert!(matches!(a.try_add(0, &[1f32, 2f32]), Err(HypernonsenseError::DimensionMismatch { expected: 3, actual: 2 })));
+        assert!(matches!(a.try_key(&[1f32, f32::NAN, 2f32]), Err(HypernonsenseError::NonFiniteInput)));
+        assert!(a.try_add(0, &[1f32, 2f32, 3f32]).is_ok());
+        assert_eq!(1, a.len());
+    }
+
+    #[test]
+    fn sparse_key_matches_dense() {
+        let mut a = HyperIndex::new(100, 10, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            // Only a few dimensions are non zero
+            let dense = random_unit_vector(100, &mut rng).iter().enumerate().map(|(i, v)| if i % 10 == 0 { *v } else { 0f32 }).collect::<Vec<_>>();
+            let sparse = SparseVector::from_dense(&dense);
+
+            assert_eq!(a.key(&dense), a.key_sparse(&sparse));
+            a.add_sparse(key, &sparse);
+            assert!(a.group(&a.key(&dense)).unwrap().contains(&key));
+        }
+    }
+
+    #[test]
+    fn remove_removes_points() {
         let mut a = HyperIndex::new(300, 10, &mut thread_rng());
 
         let v = random_unit_vector(300, &mut thread_rng());
         a.add(0, &v);
+        a.add(1, &v);
+
+        assert!(a.remove(&0));
+        assert!(!a.remove(&0));
+        assert_eq!(&vec![1], a.group(&a.key(&v)).unwrap());
+        assert_eq!(1, a.len());
+        assert!(a.contains_key(&1));
+        assert!(!a.contains_key(&0));
+    }
+
+    #[test]
+    fn remove_vector_removes_points() {
+        let mut a = HyperIndex::new(300, 10, &mut thread_rng());
+
+        let v = random_unit_vector(300, &mut thread_rng());
+        a.add(0, &v);
+        a.add(1, &v);
+
+        assert!(a.remove_vector(&0, &v));
+        assert!(!a.remove_vector(&0, &v));
+        assert_eq!(&vec![1], a.group(&a.key(&v)).unwrap());
+    }
+
+    #[test]
+    fn remove_where_removes_matching_points() {
+        let mut a = HyperIndex::new(300, 10, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            a.add(key, &random_unit_vector(300, &mut rng));
+        }
+
+        assert_eq!(50, a.remove_where(|k| k % 2 == 0));
+        assert_eq!(0, a.remove_where(|k| k % 2 == 0));
+    }
+
+    #[test]
+    fn compact_removes_empty_groups() {
+        let mut a = HyperIndex::new(300, 10, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let vectors = (0..100usize).map(|_| random_unit_vector(300, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            a.add(key, v);
+        }
+        let groups = a.groups_len();
+
+        a.remove_where(|k| *k != 0);
+        assert_eq!(groups, a.groups_len());
+
+        a.compact();
+        assert_eq!(1, a.groups_len());
+        assert_eq!(1, a.len());
+        assert_eq!(Some(&[0][..]), a.group(&a.key(&vectors[0])));
+    }
+
+    #[test]
+    fn groups_skips_empty_groups() {
+        let mut rng = thread_rng();
+        let mut a = HyperIndex::new(30, 6, &mut rng);
+        let vectors = (0..100usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            a.add(key, v);
+        }
+        assert_eq!(100, a.groups().map(|(_, g)| g.len()).sum::<usize>());
+        assert_eq!(a.groups_len(), a.groups().count());
+
+        a.remove_where(|k| *k != 0);
+        let groups = a.groups().collect::<Vec<_>>();
+        assert_eq!(vec![(&a.key(&vectors[0]), &[0usize][..])], groups);
+    }
+
+    #[test]
+    fn stats_count_items() {
+        let mut a = HyperIndex::new(300, 10, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            a.add(key, &random_unit_vector(300, &mut rng));
+        }
+
+        let stats = a.stats();
+        assert_eq!(100, stats.item_count);
+        assert_eq!(a.groups_len(), stats.group_count);
+        assert!(stats.min_group_size <= stats.max_group_size);
+    }
+
+    #[test]
+    fn groups_within_finds_neighbours() {
+        let mut a = HyperIndex::new(300, 10, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..1000usize {
+            a.add(key, &random_unit_vector(300, &mut rng));
+        }
+
+        let key = a.key(&random_unit_vector(300, &mut rng));
+        let r0 = a.groups_within(&key, 0).iter().map(|g| g.len()).sum::<usize>();
+        let r1 = a.groups_within(&key, 1).iter().map(|g| g.len()).sum::<usize>();
+        let r2 = a.groups_within(&key, 2).iter().map(|g| g.len()).sum::<usize>();
+        let r10 = a.groups_within(&key, 10).iter().map(|g| g.len()).sum::<usize>();
+
+        assert!(r0 <= r1);
+        assert!(r1 <= r2);
+        assert_eq!(1000, r10);
+
+        // The rings at each exact distance partition the groups within a radius
+        let ring = |r| a.groups_at(&key, r).iter().map(|g| g.len()).sum::<usize>();
+        assert_eq!(r2, ring(0) + ring(1) + ring(2));
+        assert_eq!(1000, (0..=10).map(ring).sum::<usize>());
+    }
+
+    #[test]
+    fn pstable_groups_close_points() {
+        let mut a = HyperIndex::new_with_family(50, 6, HashFamily::PStable { width: 4f32 }, &mut thread_rng());
+        assert_eq!(6 * BUCKET_BITS, a.key_len());
+
+        // Points much closer together than the bucket width usually share a key, distant points usually don't
+        let mut rng = thread_rng();
+        let mut near = 0;
+        let mut far = 0;
+        for key in 0..200usize {
+            let v = (0..50).map(|_| rng.gen_range(-10f32..10f32)).collect::<Vec<_>>();
+            let close = v.iter().map(|x| x + rng.gen_range(-0.01f32..0.01f32)).collect::<Vec<_>>();
+            let distant = v.iter().map(|x| x + 5f32).collect::<Vec<_>>();
+            assert!(euclidean_distance(&v, &close) < euclidean_distance(&v, &distant));
+
+            a.add(key, &v);
+            near += (a.key(&v) == a.key(&close)) as usize;
+            far += (a.key(&v) == a.key(&distant)) as usize;
+        }
+
+        assert!(near > 150);
+        assert!(far < 20);
+    }
+
+    #[test]
+    fn cross_polytope_groups_close_points() {
+        let mut a = HyperIndex::<usize>::new_with_family(50, 3, HashFamily::CrossPolytope { rotation_dims: 8 }, &mut thread_rng());
+        assert_eq!(12, a.key_len());
+
+        // Rotation rows are orthonormal
+        let rows = a.planes()[0].chunks_exact(50).collect::<Vec<_>>();
+        assert!((dot(rows[0], rows[0]) - 1f32).abs() < 1e-4);
+        assert!(dot(rows[0], rows[1]).abs() < 1e-4);
+
+        let mut rng = thread_rng();
+        let mut near = 0;
+        let mut far = 0;
+        for key in 0..200usize {
+            let v = random_unit_vector(50, &mut rng);
+            let close = v.iter().map(|x| x + rng.gen_range(-0.001f32..0.001f32)).collect::<Vec<_>>();
+
+            a.add(key, &v);
+            near += (a.key(&v) == a.key(&close)) as usize;
+            far += (a.key(&v) == a.key(&random_unit_vector(50, &mut rng))) as usize;
+        }
+
+        assert!(near > 150);
+        assert!(far < 20);
+    }
+
+    #[test]
+    fn fitted_planes_balance_groups() {
+        // Points on a small 3D patch, a long way from the origin
+        let mut rng = thread_rng();
+        let basis = (0..3).map(|_| random_unit_vector(50, &mut rng)).collect::<Vec<_>>();
+        let vectors = (0..1000).map(|_| {
+            let coords = (0..3).map(|_| rng.gen_range(-1f32..1f32)).collect::<Vec<_>>();
+            (0..50).map(|i| 2f32 + (0..3).map(|b| coords[b] * basis[b][i]).sum::<f32>()).collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+
+        let mut a = HyperIndex::new_fitted(&vectors, 3, &mut rng).unwrap();
+        assert_eq!(3, a.planes_len());
+        for (k, v) in vectors.iter().enumerate() {
+            a.add(k, v);
+        }
+
+        let stats = a.stats();
+        assert_eq!(8, stats.group_count);
+        assert!(stats.max_group_size < 1000 / 4);
+    }
+
+    #[test]
+    fn new_fitted_validates_sample() {
+        let mut rng = thread_rng();
+        let vectors = (0..100).map(|_| random_unit_vector(4, &mut rng)).collect::<Vec<_>>();
+
+        // Four planes span the whole space, there is no fifth principal component to find
+        assert_eq!(4, HyperIndex::<usize>::new_fitted(&vectors, 4, &mut rng).unwrap().planes_len());
+        assert!(matches!(HyperIndex::<usize>::new_fitted(&vectors, 5, &mut rng), Err(HypernonsenseError::InvalidConfig(_))));
+
+        assert!(matches!(HyperIndex::<usize>::new_fitted(&[], 2, &mut rng), Err(HypernonsenseError::EmptyIndex)));
+        let short = vec![vectors[0].clone(), vec![1f32, 0f32]];
+        assert!(matches!(HyperIndex::<usize>::new_fitted(&short, 2, &mut rng), Err(HypernonsenseError::DimensionMismatch { expected: 4, actual: 2 })));
+        let nan = vec![vectors[0].clone(), vec![f32::NAN; 4]];
+        assert!(matches!(HyperIndex::<usize>::new_fitted(&nan, 2, &mut rng), Err(HypernonsenseError::NonFiniteInput)));
+    }
+
+    #[test]
+    fn fit_offsets_splits_data_in_half() {
+        // Not centered on the origin, so random planes mostly put every point on the same side
+        let mut rng = thread_rng();
+        let vectors = (0..1000).map(|_| (0..50).map(|_| rng.gen_range(0f32..1f32)).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+        let mut a = HyperIndex::new(50, 8, &mut rng);
+        a.fit_offsets(&vectors).unwrap();
+        for (k, v) in vectors.iter().enumerate() {
+            a.add(k, v);
+        }
+
+        for plane in 0..8 {
+            let set = vectors.iter().filter(|v| a.key(v)[plane]).count();
+            assert!((400..600).contains(&set));
+        }
+
+        assert!(matches!(a.fit_offsets(&vectors), Err(HypernonsenseError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn indices_with_shared_planes_hash_identically() {
+        let mut rng = thread_rng();
+        let planes = Planes::<f32>::random(50, 8, HashFamily::PStable { width: 0.5 }, &mut rng);
+        let a = HyperIndex::<usize>::with_planes(planes.clone());
+        let b = HyperIndex::<String>::with_planes(planes);
+        assert!(std::ptr::eq(a.export_planes().matrix(), b.export_planes().matrix()));
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_planes_{}.hyp", std::process::id()));
+        a.export_planes().save(&path).unwrap();
+        let c = HyperIndex::<u32>::with_planes(Planes::load(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+
+        for _ in 0..20 {
+            let v = random_unit_vector(50, &mut rng);
+            assert_eq!(a.key(&v), b.key(&v));
+            assert_eq!(a.key(&v), c.key(&v));
+        }
+    }
+
+    #[test]
+    fn planes_are_validated() {
+        assert!(Planes::<f32>::new(3, HashFamily::Hyperplane, vec![1f32, 0f32, 0f32], vec![0f32]).is_ok());
+        assert!(matches!(Planes::<f32>::new(3, HashFamily::Hyperplane, vec![1f32, 0f32], vec![0f32]), Err(HypernonsenseError::InvalidConfig(_))));
+        assert!(matches!(Planes::<f32>::new(3, HashFamily::Hyperplane, vec![f32::NAN, 0f32, 0f32], vec![0f32]), Err(HypernonsenseError::NonFiniteInput)));
+    }
+
+    #[test]
+    fn f64_index_works() {
+        let mut a = HyperIndex::<usize, f64>::new(300, 10, &mut thread_rng());
+
+        let v = random_unit_vector_of::<f64, _>(300, &mut thread_rng());
+        a.add(0, &v);
+
+        assert_eq!(&vec![0], a.group(&a.key(&v)).unwrap());
     }
 
     #[test]
-    fn it_works<'a>() {
+    fn it_works() {
         let mut a = HyperIndex::new(300, 10, &mut thread_rng());
 
         let mut vectors = Vec::new();
@@ -122,8 +1134,8 @@ mod tests
         let query_point = vectors[0].clone();
         let mut nearest_linear: Vec<(f32, &(usize, Vec<f32>))> = vectors.iter().map(|item| (modified_cosine_distance(&item.1, &query_point.1), item)).collect();
         nearest_linear.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-        for i in 0..20 {
-            println!("idx:{:?}\t\tdist:{:?}", (nearest_linear[i].1).0, nearest_linear[i].0);
+        for (dist, item) in nearest_linear.iter().take(20) {
+            println!("idx:{:?}\t\tdist:{:?}", item.0, dist);
         }
             
         //Use the index
@@ -137,8 +1149,8 @@ mod tests
 
         let mut results: Vec<(f32, &(usize, Vec<f32>))> = near.iter().map(|i| &vectors[*i]).map(|item| (modified_cosine_distance(&item.1, &query_point.1), item)).collect();
         results.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-        for i in 0.. results.len().min(20) {
-            println!("idx:{:?}\t\tdist:{:?}", (results[i].1).0, results[i].0);
+        for (dist, item) in results.iter().take(20) {
+            println!("idx:{:?}\t\tdist:{:?}", item.0, dist);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+use rand::Rng;
+
+use crate::builder::MultiIndexBuilder;
+use crate::error::HypernonsenseError;
+use crate::multiindex::{DistanceNode, MultiIndex};
+use crate::query::QueryOptions;
+use crate::vector::Scalar;
+
+/// A `MultiIndex` which stores a compact `u32` id for each item in its groups, instead of a copy of the key in every sub
+/// index. Keys are mapped to ids when items are added and back again when results are returned, so each key is only kept
+/// twice (once in each direction) however many sub indices there are. This saves a lot of memory when keys are large or
+/// expensive to clone (e.g. `String`).
+///
+/// The ids of removed items are reused. There can be at most `u32::MAX` items in the index at once.
+pub struct InternedMultiIndex<K, T:Scalar=f32> {
+    index: MultiIndex<u32, T>,
+
+    // Key of each id, `None` for ids which have been removed and not reused yet
+    keys: Vec<Option<K>>,
+    ids: HashMap<K, u32>,
+
+    // Ids of removed items, which are handed out again before any new ones
+    free: Vec<u32>,
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync, T:Scalar> InternedMultiIndex<K, T> {
+    pub fn new<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, rng: &mut R) -> InternedMultiIndex<K, T> {
+        return Self::wrap(MultiIndex::new(dimension, index_count, hyperplane_count, rng));
+    }
+
+    pub fn new_with_storage<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, rng: &mut R) -> InternedMultiIndex<K, T> {
+        return Self::wrap(MultiIndex::new_with_storage(dimension, index_count, hyperplane_count, rng));
+    }
+
+    /// Create an index configured by a builder. Capacities are not supported, the inner index would evict ids without
+    /// their keys being forgotten.
+    pub fn from_builder(builder: MultiIndexBuilder<u32, T>) -> Result<InternedMultiIndex<K, T>, HypernonsenseError> {
+        let index = builder.build()?;
+        if index.capacity().is_some() {
+            return Err(HypernonsenseError::InvalidConfig("an interned index cannot have a capacity".to_string()));
+        }
+
+        return Ok(Self::wrap(index));
+    }
+
+    fn wrap(index: MultiIndex<u32, T>) -> InternedMultiIndex<K, T> {
+        return InternedMultiIndex {
+            index,
+            keys: Vec::new(),
+            ids: HashMap::new(),
+            free: Vec::new(),
+        };
+    }
+
+    /// The inner index, whose keys are the ids of the items
+    pub fn inner(&self) -> &MultiIndex<u32, T> {
+        return &self.index;
+    }
+
+    /// Id of a key in the inner index
+    pub fn id(&self, key: &K) -> Option<u32> {
+        return self.ids.get(key).copied();
+    }
+
+    /// Key of an id from the inner index
+    pub fn key(&self, id: u32) -> Option<&K> {
+        return self.keys.get(id as usize).and_then(|k| k.as_ref());
+    }
+
+    // Id for a key, assigning one if the key isn't in the index
+    fn intern(&mut self, key: K) -> u32 {
+        if let Some(id) = self.ids.get(&key) {
+            return *id;
+        }
+
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.keys[id as usize] = Some(key.clone());
+                id
+            },
+            None => {
+                let id = u32::try_from(self.keys.len()).expect("an interned index cannot hold more than u32::MAX items");
+                self.keys.push(Some(key.clone()));
+                id
+            },
+        };
+        self.ids.insert(key, id);
+        return id;
+    }
+
+    // Map the results of the inner index back to keys
+    fn resolve(&self, result: Vec<DistanceNode<u32, T>>) -> Vec<DistanceNode<K, T>> {
+        return result.into_iter()
+            .filter_map(|n| self.key(n.key).map(|k| DistanceNode { key: k.clone(), distance: n.distance }))
+            .collect();
+    }
+
+    /// Add an item, adding a key which is already in the index adds another copy of it like `MultiIndex::add`
+    pub fn add(&mut self, key: K, vector: &[T]) {
+        let id = self.intern(key);
+        self.index.add(id, vector);
+    }
+
+    /// Remove a key from every sub index, returns true if anything was removed. The id of the key is freed to be reused.
+    pub fn remove(&mut self, key: &K) -> bool {
+        let id = match self.ids.remove(key) {
+            Some(id) => id,
+            None => return false,
+        };
+
+        self.keys[id as usize] = None;
+        self.free.push(id);
+        return self.index.remove(&id);
+    }
+
+    pub fn nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_with(point, count, &QueryOptions::default(), get_dist);
+    }
+
+    /// Find the nearest `count` points, using the given query options. `get_dist` is given the keys, not the ids.
+    pub fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        // Every id in the groups has a key, ids are only freed once they have been removed from every sub index
+        let keys = &self.keys;
+        let result = self.index.nearest_with(point, count, options, |p, id| get_dist(p, keys[*id as usize].as_ref().unwrap()));
+        return self.resolve(result);
+    }
+
+    /// Find the nearest `count` points, ranked by the index metric using the stored vectors.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_k(&self, point: &[T], count: usize) -> Vec<DistanceNode<K, T>> {
+        return self.nearest_k_with(point, count, &QueryOptions::default());
+    }
+
+    pub fn nearest_k_with(&self, point: &[T], count: usize, options: &QueryOptions) -> Vec<DistanceNode<K, T>> {
+        return self.resolve(self.index.nearest_k_with(point, count, options));
+    }
+
+    /// Get every candidate in the groups within the probe radius of the query, the keys are borrowed from the index
+    pub fn nearest_points(&self, point: &[T]) -> Vec<&K> {
+        return self.nearest_points_with(point, &QueryOptions::default());
+    }
+
+    pub fn nearest_points_with(&self, point: &[T], options: &QueryOptions) -> Vec<&K> {
+        return self.index.nearest_point_refs_with(point, options)
+            .into_iter()
+            .filter_map(|id| self.key(*id))
+            .collect();
+    }
+
+    /// Get the stored vector of a key, `None` if the key is not in the index or vector storage is not enabled
+    pub fn get(&self, key: &K) -> Option<&[T]> {
+        return self.index.get(&self.id(key)?);
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        return self.ids.contains_key(key);
+    }
+
+    /// Every key in the index, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item=&K> {
+        return self.ids.keys();
+    }
+
+    /// Number of items in the index
+    pub fn len(&self) -> usize {
+        return self.index.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.index.is_empty();
+    }
+
+    pub fn dimensions(&self) -> usize {
+        return self.index.dimensions();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::builder::MultiIndexBuilder;
+    use crate::eviction::Eviction;
+    use crate::interned::InternedMultiIndex;
+    use crate::vector::{ cosine_distance, random_unit_vector };
+
+    #[test]
+    fn results_are_mapped_back_to_keys() {
+        let mut rng = thread_rng();
+        let vectors = (0..200).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+
+        let mut a = InternedMultiIndex::new_with_storage(30, 8, 6, &mut rng);
+        for (i, v) in vectors.iter().enumerate() {
+            a.add(format!("item {}", i), v);
+        }
+
+        assert_eq!(200, a.len());
+        assert_eq!("item 7", a.nearest_k(&vectors[7], 1)[0].key);
+        assert_eq!("item 7", a.nearest(&vectors[7], 1, |p, k| cosine_distance(p, a.get(k).unwrap()))[0].key);
+        assert!(a.nearest_points(&vectors[7]).contains(&&"item 7".to_string()));
+        assert_eq!(Some(7), a.id(&"item 7".to_string()));
+    }
+
+    #[test]
+    fn removed_ids_are_reused() {
+        let mut rng = thread_rng();
+        let mut a = InternedMultiIndex::new(30, 4, 6, &mut rng);
+        let v = random_unit_vector(30, &mut rng);
+        a.add("a", &v);
+        a.add("b", &v);
+
+        assert!(a.remove(&"a"));
+        assert!(!a.remove(&"a"));
+        assert!(!a.contains_key(&"a"));
+        assert_eq!(vec![&"b"], a.nearest_points(&v));
+
+        a.add("c", &v);
+        assert_eq!(Some(0), a.id(&"c"));
+        assert_eq!(Some(&"c"), a.key(0));
+        assert_eq!(2, a.len());
+    }
+
+    #[test]
+    fn capacity_is_rejected() {
+        let builder = MultiIndexBuilder::<u32>::new(30).capacity(10, Eviction::Fifo);
+        assert!(InternedMultiIndex::<String>::from_builder(builder).is_err());
+    }
+}
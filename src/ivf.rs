@@ -0,0 +1,287 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use rand::Rng;
+use crate::par::prelude::*;
+
+use crate::error::HypernonsenseError;
+use crate::metric::Metric;
+use crate::multiindex::DistanceNode;
+use crate::pq::{kmeans, nearest_centroid};
+use crate::query::{QueryOptions, rank_candidates};
+use crate::vector::{Scalar, check_vector};
+
+// Number of k-means iterations used to train the centroids
+const TRAIN_ITERATIONS: usize = 20;
+
+/// An inverted file index (IVF), which groups vectors into lists by their nearest centroid instead of by hyperplanes.
+///
+/// The centroids are found by k-means over a sample of vectors, so the lists follow the distribution of the data. A query
+/// compares itself against every centroid and probes the lists of the nearest ones, then ranks their members the same way
+/// as a `MultiIndex`. This makes it easy to compare recall against a `MultiIndex` on the same data, see `recall_at_k`.
+pub struct IvfIndex<K, T:Scalar=f32> {
+    centroids: Vec<Vec<T>>,
+    lists: Vec<Vec<K>>,
+
+    // List which each key is in, so keys can be removed without searching every list
+    assignments: HashMap<K, usize>,
+
+    // Copies of the inserted vectors, only present if the index was trained with storage
+    vectors: Option<HashMap<K, Vec<T>>>,
+
+    // Metric used to rank candidates using the stored vectors
+    metric: Metric,
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync, T:Scalar> IvfIndex<K, T> {
+    /// Train an index with `list_count` lists on a sample of vectors. The sample should be representative of the vectors which
+    /// will be added and should contain at least `list_count` vectors.
+    pub fn train<R : Rng + Sized>(sample_vectors: &[Vec<T>], list_count: usize, rng: &mut R) -> Result<IvfIndex<K, T>, HypernonsenseError> {
+        return Self::create(sample_vectors, list_count, false, rng);
+    }
+
+    /// Train an index which also keeps a copy of every vector, so results can be ranked with `nearest_k`
+    pub fn train_with_storage<R : Rng + Sized>(sample_vectors: &[Vec<T>], list_count: usize, rng: &mut R) -> Result<IvfIndex<K, T>, HypernonsenseError> {
+        return Self::create(sample_vectors, list_count, true, rng);
+    }
+
+    fn create<R : Rng + Sized>(sample_vectors: &[Vec<T>], list_count: usize, store_vectors: bool, rng: &mut R) -> Result<IvfIndex<K, T>, HypernonsenseError> {
+        if sample_vectors.is_empty() {
+            return Err(HypernonsenseError::EmptyIndex);
+        }
+        let dims = sample_vectors[0].len();
+        for v in sample_vectors.iter() {
+            check_vector(v, dims)?;
+        }
+        if list_count == 0 {
+            return Err(HypernonsenseError::InvalidConfig("list_count must be at least one".to_string()));
+        }
+
+        let parts = sample_vectors.iter().map(|v| v.as_slice()).collect::<Vec<_>>();
+        let centroids = kmeans(&parts, list_count, TRAIN_ITERATIONS, rng);
+
+        return Ok(IvfIndex {
+            lists: vec![Vec::new(); centroids.len()],
+            centroids,
+            assignments: HashMap::new(),
+            vectors: if store_vectors { Some(HashMap::new()) } else { None },
+            metric: Metric::default(),
+        });
+    }
+
+    pub fn dimensions(&self) -> usize {
+        return self.centroids[0].len();
+    }
+
+    /// Number of lists, this is less than requested if the training sample had fewer vectors
+    pub fn list_count(&self) -> usize {
+        return self.lists.len();
+    }
+
+    pub fn centroids(&self) -> &[Vec<T>] {
+        return &self.centroids;
+    }
+
+    pub fn stores_vectors(&self) -> bool {
+        return self.vectors.is_some();
+    }
+
+    pub fn metric(&self) -> Metric {
+        return self.metric;
+    }
+
+    pub fn set_metric(&mut self, metric: Metric) {
+        self.metric = metric;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.assignments.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.assignments.is_empty();
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        return self.assignments.contains_key(key);
+    }
+
+    /// Get the stored vector of a key, returns None if the key is not in the index or vector storage is not enabled
+    pub fn get(&self, key: &K) -> Option<&[T]> {
+        return self.vectors.as_ref()?.get(key).map(|v| v.as_slice());
+    }
+
+    /// Add a point to the index, replacing it if the key is already present
+    pub fn add(&mut self, key: K, vector: &[T]) {
+        assert_eq!(self.dimensions(), vector.len(), "vector does not have the dimension of the index");
+
+        let list = nearest_centroid(&self.centroids, vector);
+        self.insert(key, list, vector.to_vec());
+    }
+
+    /// Add many points to the index, finding their lists in parallel
+    pub fn add_batch<I>(&mut self, items: I)
+        where I : IntoIterator<Item=(K, Vec<T>)>
+    {
+        let items = items.into_iter().collect::<Vec<_>>();
+        let dims = self.dimensions();
+        let centroids = &self.centroids;
+        let lists = items.par_iter()
+            .map(|(_, v)| {
+                assert_eq!(dims, v.len(), "vector does not have the dimension of the index");
+                nearest_centroid(centroids, v)
+            })
+            .collect::<Vec<_>>();
+
+        for ((key, vector), list) in items.into_iter().zip(lists) {
+            self.insert(key, list, vector);
+        }
+    }
+
+    fn insert(&mut self, key: K, list: usize, vector: Vec<T>) {
+        self.remove(&key);
+        self.lists[list].push(key.clone());
+        if let Some(vectors) = self.vectors.as_mut() {
+            vectors.insert(key.clone(), vector);
+        }
+        self.assignments.insert(key, list);
+    }
+
+    /// Remove a point from the index, returns true if it was present
+    pub fn remove(&mut self, key: &K) -> bool {
+        let list = match self.assignments.remove(key) {
+            Some(list) => &mut self.lists[list],
+            None => return false,
+        };
+        if let Some(position) = list.iter().position(|k| k == key) {
+            list.swap_remove(position);
+        }
+        if let Some(vectors) = self.vectors.as_mut() {
+            vectors.remove(key);
+        }
+        return true;
+    }
+
+    // Lists to probe for a query, the nearest centroid and then the `probe_radius` next nearest
+    fn probed_lists(&self, point: &[T], options: &QueryOptions) -> Vec<usize> {
+        assert_eq!(self.dimensions(), point.len(), "query does not have the dimension of the index");
+
+        let mut order = self.centroids.par_iter()
+            .enumerate()
+            .map(|(i, c)| DistanceNode { distance: T::squared_euclidean(point, c), key: i })
+            .collect::<Vec<_>>();
+        order.sort_unstable();
+        order.truncate(1 + options.probe_radius as usize);
+
+        return order.into_iter().map(|n| n.key).collect();
+    }
+
+    pub fn nearest_points_set(&self, point: &[T]) -> HashSet<K> {
+        return self.nearest_points_set_with(point, &QueryOptions::default());
+    }
+
+    /// Get every candidate in the probed lists. `probe_radius` is the number of lists probed after the nearest one, so with
+    /// the default options the two nearest lists are probed.
+    pub fn nearest_points_set_with(&self, point: &[T], options: &QueryOptions) -> HashSet<K> {
+        return self.probed_lists(point, options)
+            .into_iter()
+            .flat_map(|l| self.lists[l].iter().cloned())
+            .collect();
+    }
+
+    pub fn nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_with(point, count, &QueryOptions::default(), get_dist);
+    }
+
+    /// Find the nearest `count` points in the probed lists, see `nearest_points_set_with`. Only `probe_radius` is used from the options.
+    pub fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let candidates = self.nearest_points_set_with(point, options);
+        return rank_candidates(candidates, count, |k| get_dist(point, k));
+    }
+
+    /// Find the nearest `count` points, ranked by the index metric using the stored vectors.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_k(&self, point: &[T], count: usize) -> Vec<DistanceNode<K, T>> {
+        return self.nearest_k_with(point, count, &QueryOptions::default());
+    }
+
+    pub fn nearest_k_with(&self, point: &[T], count: usize, options: &QueryOptions) -> Vec<DistanceNode<K, T>> {
+        let vectors = self.vectors.as_ref().expect("nearest_k requires vector storage to be enabled");
+        let metric = self.metric;
+
+        return self.nearest_with(point, count, options, |p, k| metric.distance(p, &vectors[k]));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::error::HypernonsenseError;
+    use crate::evaluate::{ brute_force_nearest, recall_at_k };
+    use crate::ivf::IvfIndex;
+    use crate::multiindex::MultiIndex;
+    use crate::query::QueryOptions;
+    use crate::vector::{ cosine_distance, random_unit_vector };
+
+    #[test]
+    fn ivf_finds_neighbours() {
+        let mut rng = thread_rng();
+        let vectors = (0..2000).map(|_| random_unit_vector(32, &mut rng)).collect::<Vec<_>>();
+        let mut a = IvfIndex::<usize>::train_with_storage(&vectors[..500], 20, &mut rng).unwrap();
+        assert_eq!(20, a.list_count());
+
+        a.add_batch(vectors.iter().cloned().enumerate());
+        assert_eq!(2000, a.len());
+
+        for key in [0, 700, 1999] {
+            let result = a.nearest_k(&vectors[key], 5);
+            assert_eq!(key, result[0].key);
+        }
+
+        // Probing more lists finds more candidates
+        let r0 = a.nearest_points_set_with(&vectors[3], &QueryOptions::default().with_probe_radius(0)).len();
+        let r5 = a.nearest_points_set_with(&vectors[3], &QueryOptions::default().with_probe_radius(5)).len();
+        assert!(r0 < r5);
+
+        assert!(a.remove(&0));
+        assert!(!a.remove(&0));
+        assert_eq!(None, a.get(&0));
+        assert_eq!(1999, a.len());
+        assert!(a.nearest_k(&vectors[0], 5).iter().all(|n| n.key != 0));
+    }
+
+    #[test]
+    fn ivf_and_lsh_can_be_compared() {
+        let mut rng = thread_rng();
+        let items = (0..2000usize).map(|k| (k, random_unit_vector(30, &mut rng))).collect::<Vec<_>>();
+        let vectors = items.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>();
+        let queries = vectors[..50].to_vec();
+        let truth = brute_force_nearest(&items, &queries, 10, crate::metric::Metric::Cosine);
+        let get_dist = |p: &[f32], k: &usize| cosine_distance(p, &items[*k].1);
+
+        let mut ivf = IvfIndex::train(&vectors, 40, &mut rng).unwrap();
+        ivf.add_batch(items.clone());
+        let mut lsh = MultiIndex::new(30, 10, 6, &mut rng);
+        lsh.add_batch(items.clone());
+
+        for result in [recall_at_k(&ivf, &queries, &truth, 10, get_dist), recall_at_k(&lsh, &queries, &truth, 10, get_dist)] {
+            assert!(result.recall > 0.0 && result.recall <= 1.0);
+            assert!(result.mean_candidates >= 10.0);
+        }
+    }
+
+    #[test]
+    fn train_rejects_bad_config() {
+        let mut rng = thread_rng();
+        assert!(matches!(IvfIndex::<usize>::train(&[], 4, &mut rng), Err(HypernonsenseError::EmptyIndex)));
+        assert!(matches!(IvfIndex::<usize>::train(&[vec![1f32, 0f32]], 0, &mut rng), Err(HypernonsenseError::InvalidConfig(_))));
+        assert!(matches!(IvfIndex::<usize>::train(&[vec![1f32, 0f32], vec![1f32]], 1, &mut rng), Err(HypernonsenseError::DimensionMismatch { .. })));
+    }
+}
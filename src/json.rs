@@ -0,0 +1,242 @@
+// Minimal helpers for reading and writing JSON by hand, so the crate does not need a JSON library
+
+use crate::vector::Scalar;
+
+/// Quote and escape a string
+pub(crate) fn string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    return result;
+}
+
+/// Format a number, JSON has no representation of NaN or infinity so those are written as null
+pub(crate) fn number<T:Scalar>(value: T) -> String {
+    if !value.is_finite() {
+        return "null".to_string();
+    }
+    return format!("{:?}", value);
+}
+
+/// A parsed JSON value. Numbers are kept as their text, so they can be parsed as whatever type they are needed as.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Get a field of an object, `None` if this is not an object or has no field with that name
+    pub(crate) fn get(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a complete JSON document, anything other than whitespace after the value is an error
+pub(crate) fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: text.char_indices().peekable() };
+    let value = parser.value()?;
+    parser.whitespace();
+    return match parser.chars.next() {
+        None => Ok(value),
+        Some((i, c)) => Err(format!("unexpected '{}' at {}", c, i)),
+    };
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((i, c)) => Err(format!("expected '{}' at {}, found '{}'", expected, i, c)),
+            None => Err(format!("expected '{}', found the end of the input", expected)),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        return Ok(value);
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.whitespace();
+        return match self.chars.peek().copied() {
+            None => Err("unexpected end of the input".to_string()),
+            Some((_, 'n')) => self.literal("null", Value::Null),
+            Some((_, 't')) => self.literal("true", Value::Bool(true)),
+            Some((_, 'f')) => self.literal("false", Value::Bool(false)),
+            Some((_, '"')) => self.string().map(Value::String),
+            Some((_, '[')) => self.array(),
+            Some((_, '{')) => self.object(),
+            Some((_, c)) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some((i, c)) => Err(format!("unexpected '{}' at {}", c, i)),
+        };
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let mut text = String::new();
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+            text.push(c);
+        }
+        if text.parse::<f64>().is_err() {
+            return Err(format!("'{}' is not a number", text));
+        }
+        return Ok(Value::Number(text));
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err("unterminated string".to_string()),
+                Some((_, '"')) => return Ok(result),
+                Some((_, '\\')) => {
+                    let c = match self.chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'u')) => self.escaped_char()?,
+                        _ => return Err("invalid escape in string".to_string()),
+                    };
+                    result.push(c);
+                },
+                Some((_, c)) => result.push(c),
+            }
+        }
+    }
+
+    // The character of a `\u` escape, including surrogate pairs
+    fn escaped_char(&mut self) -> Result<char, String> {
+        let high = self.hex()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| "invalid unicode escape".to_string());
+        }
+
+        self.expect('\\')?;
+        self.expect('u')?;
+        let low = self.hex()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err("invalid unicode surrogate pair".to_string());
+        }
+        return char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)).ok_or_else(|| "invalid unicode escape".to_string());
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self.chars.next().and_then(|(_, c)| c.to_digit(16)).ok_or_else(|| "invalid unicode escape".to_string())?;
+            value = value * 16 + digit;
+        }
+        return Ok(value);
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+            return Ok(Value::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, ']')) => return Ok(Value::Array(items)),
+                _ => return Err("expected ',' or ']' in array".to_string()),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.whitespace();
+        if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+            return Ok(Value::Object(fields));
+        }
+
+        loop {
+            self.whitespace();
+            let name = self.string()?;
+            self.whitespace();
+            self.expect(':')?;
+            fields.push((name, self.value()?));
+            self.whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, '}')) => return Ok(Value::Object(fields)),
+                _ => return Err("expected ',' or '}' in object".to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::json::{ Value, number, parse, string };
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!("\"a\\\"b\\\\c\\n\\u0001\"", string("a\"b\\c\n\u{1}"));
+    }
+
+    #[test]
+    fn non_finite_numbers_are_null() {
+        assert_eq!("1.5", number(1.5f32));
+        assert_eq!("null", number(f32::NAN));
+        assert_eq!("null", number(f64::INFINITY));
+    }
+
+    #[test]
+    fn parse_reads_nested_values() {
+        let value = parse(" {\"key\": \"a\\\"b\\u00e9\\ud83d\\ude00\", \"vector\": [1, -2.5e1, true, null], \"empty\": {}} ").unwrap();
+        assert_eq!(Some(&Value::String("a\"b\u{e9}\u{1f600}".to_string())), value.get("key"));
+        assert_eq!(Some(&Value::Array(vec![Value::Number("1".to_string()), Value::Number("-2.5e1".to_string()), Value::Bool(true), Value::Null])), value.get("vector"));
+        assert_eq!(Some(&Value::Object(Vec::new())), value.get("empty"));
+        assert_eq!(None, value.get("missing"));
+
+        // Whatever is written can be read back
+        assert_eq!(Value::String("a\"b\\c\n\u{1}".to_string()), parse(&string("a\"b\\c\n\u{1}")).unwrap());
+
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("nul").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+// JSON Lines, one `{"key": ..., "vector": [...]}` object per line. This is the lowest common denominator for moving items
+// between the index and other tools and services, every language can read and write it.
+
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use crate::error::HypernonsenseError;
+use crate::json::{self, Value};
+use crate::vector::Scalar;
+
+/// Read items from JSON Lines, one `{"key": ..., "vector": [...]}` object per line. The key can be a JSON string or number,
+/// either way it is parsed with `FromStr`. Blank lines are skipped. Items are read one line at a time as the iterator is
+/// advanced, so a file much larger than memory can be streamed into an index.
+pub fn read_jsonl<K: FromStr, T: Scalar, R: BufRead>(reader: R) -> impl Iterator<Item=Result<(K, Vec<T>), HypernonsenseError>> {
+    return reader.lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(|(number, line)| {
+            let line = line?;
+            parse_item(&line).map_err(|e| HypernonsenseError::InvalidFormat(format!("line {}: {}", number + 1, e)))
+        });
+}
+
+fn parse_item<K: FromStr, T: Scalar>(line: &str) -> Result<(K, Vec<T>), String> {
+    let item = json::parse(line)?;
+
+    let key = match item.get("key") {
+        Some(Value::String(k)) | Some(Value::Number(k)) => k.parse::<K>().map_err(|_| format!("'{}' is not a valid key", k))?,
+        Some(_) => return Err("key must be a string or a number".to_string()),
+        None => return Err("item has no key".to_string()),
+    };
+
+    let vector = match item.get("vector") {
+        Some(Value::Array(elements)) => elements.iter()
+            .map(|e| match e {
+                Value::Number(n) => n.parse::<f64>().ok().and_then(T::from).ok_or_else(|| format!("'{}' is not a valid element", n)),
+                _ => Err("vector elements must be numbers".to_string()),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err("vector must be an array".to_string()),
+        None => return Err("item has no vector".to_string()),
+    };
+
+    return Ok((key, vector));
+}
+
+/// Write items as JSON Lines, one `{"key": ..., "vector": [...]}` object per line. Keys which display as a JSON number are
+/// written as numbers, every other key is written as a string.
+pub fn write_jsonl<'a, K, T, W, I>(writer: &mut W, items: I) -> io::Result<()>
+    where K : Display + 'a, T : Scalar, W : Write, I : IntoIterator<Item=(&'a K, &'a [T])>
+{
+    for (key, vector) in items {
+        let key = key.to_string();
+        let key = match json::parse(&key) {
+            Ok(Value::Number(_)) => key,
+            _ => json::string(&key),
+        };
+        let vector = vector.iter().map(|x| json::number(*x)).collect::<Vec<_>>();
+        writeln!(writer, "{{\"key\":{},\"vector\":[{}]}}", key, vector.join(","))?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::jsonl::{ read_jsonl, write_jsonl };
+
+    #[test]
+    fn round_trip_preserves_items() {
+        let items = vec![(7u64, vec![1.5f32, -2.0]), (8, vec![0.25, 3.0])];
+        let mut bytes = Vec::new();
+        write_jsonl(&mut bytes, items.iter().map(|(k, v)| (k, v.as_slice()))).unwrap();
+        assert_eq!("{\"key\":7,\"vector\":[1.5,-2.0]}\n{\"key\":8,\"vector\":[0.25,3.0]}\n", String::from_utf8(bytes.clone()).unwrap());
+
+        let read = read_jsonl::<u64, f32, _>(bytes.as_slice()).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(items, read);
+    }
+
+    #[test]
+    fn string_keys_are_quoted() {
+        let items = vec![("a \"b\"".to_string(), vec![1f64])];
+        let mut bytes = Vec::new();
+        write_jsonl(&mut bytes, items.iter().map(|(k, v)| (k, v.as_slice()))).unwrap();
+
+        let read = read_jsonl::<String, f64, _>(bytes.as_slice()).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(items, read);
+    }
+
+    #[test]
+    fn read_reports_bad_lines() {
+        let text = "{\"key\": \"1\", \"vector\": [1, 2]}\n\n{\"key\": 2, \"vector\": [1, null]}\n{\"vector\": []}\n";
+        let read = read_jsonl::<u32, f32, _>(text.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(3, read.len());
+        assert_eq!((1, vec![1f32, 2.0]), *read[0].as_ref().unwrap());
+        assert!(read[1].as_ref().unwrap_err().to_string().contains("line 3"));
+        assert!(read[2].is_err());
+    }
+}
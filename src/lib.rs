@@ -1,3 +1,49 @@
+// Explicit `return` is the house style
+#![allow(clippy::needless_return)]
+
+pub mod ann;
+pub mod builder;
+pub mod concurrent;
+pub mod dataset;
+pub mod error;
+pub mod evaluate;
+pub mod events;
+pub mod eviction;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "async")]
+pub mod future;
+#[cfg(feature = "half")]
+pub mod half;
+pub mod hamming;
 pub mod hyperindex;
+pub mod interned;
+pub mod ivf;
+pub mod jsonl;
+pub mod metric;
+pub mod minhash;
 pub mod multiindex;
+pub mod npy;
+pub mod pca;
+#[cfg(all(unix, target_endian = "little"))]
+pub mod mmap;
+pub mod persist;
+pub mod pq;
+pub mod projection;
+pub mod query;
+pub mod recorder;
+pub mod segment;
+pub mod stats;
 pub mod vector;
+#[cfg(all(unix, target_endian = "little"))]
+pub mod vectorstore;
+pub mod wal;
+
+mod expiry;
+mod group;
+mod json;
+mod par;
+mod simd;
+
+#[cfg(feature = "serde")]
+mod serialization;
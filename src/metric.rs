@@ -0,0 +1,21 @@
+use crate::vector::{ Scalar, cosine_distance, euclidean_distance };
+
+/// The distance metric a `MultiIndex` uses to rank candidates when it has stored vectors
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Metric {
+    /// Cosine distance (`1 - cos(angle)`), in the range [0, 2]. This is the distance random hyperplanes approximate.
+    #[default]
+    Cosine,
+
+    /// Straight line distance
+    Euclidean,
+}
+
+impl Metric {
+    pub fn distance<T:Scalar>(&self, a: &[T], b: &[T]) -> T {
+        match self {
+            Metric::Cosine => cosine_distance(a, b),
+            Metric::Euclidean => euclidean_distance(a, b),
+        }
+    }
+}
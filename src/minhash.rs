@@ -0,0 +1,272 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use bit_vec::BitVec;
+use rand::Rng;
+use crate::par::prelude::*;
+
+use crate::multiindex::DistanceNode;
+use crate::query::{Groups, QueryOptions, groups_within, rank_candidates};
+
+/// Hash every token of a set, returning the sorted and deduplicated hashes
+pub fn token_hashes<I, H>(tokens: I) -> Vec<u64>
+    where I : IntoIterator<Item=H>, H : Hash
+{
+    let mut hashes = tokens.into_iter()
+        .map(|t| {
+            let mut hasher = DefaultHasher::new();
+            t.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect::<Vec<_>>();
+    hashes.sort_unstable();
+    hashes.dedup();
+    return hashes;
+}
+
+/// Jaccard similarity (size of intersection / size of union) of two sorted and deduplicated hash sets
+pub fn jaccard_similarity(a: &[u64], b: &[u64]) -> f32 {
+    if a.is_empty() && b.is_empty() {
+        return 1f32;
+    }
+
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    return shared as f32 / (a.len() + b.len() - shared) as f32;
+}
+
+// Mix a token hash with a seed, giving an independent hash function for each seed (splitmix64 finalizer)
+fn mix(hash: u64, seed: u64) -> u64 {
+    let mut z = hash ^ seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
+}
+
+/// An index over sets of tokens. Each bit of the key is the lowest bit of the MinHash of the set under a different hash
+/// function, so sets with a high Jaccard similarity are likely to get the same key. Since keys are bit vectors the same
+/// hamming probing as `HyperIndex` works.
+pub struct MinHashIndex<K:Send> {
+    seeds: Vec<u64>,
+    groups: Groups<K>,
+    len: usize
+}
+
+impl<K:Send> MinHashIndex<K> {
+    pub fn new<R : Rng + Sized>(hash_count: u8, rng: &mut R) -> MinHashIndex<K>
+    {
+        return MinHashIndex {
+            seeds: (0..hash_count).map(|_| rng.gen()).collect(),
+            groups: Groups::default(),
+            len: 0
+        }
+    }
+
+    pub fn hashes_len(&self) -> usize {
+        return self.seeds.len();
+    }
+
+    pub fn groups_len(&self) -> usize {
+        return self.groups.len();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    /// Calculate the key for a set of token hashes (see `token_hashes`)
+    pub fn key(&self, hashes: &[u64]) -> BitVec
+    {
+        let mut key = BitVec::with_capacity(self.seeds.len());
+        for seed in self.seeds.iter() {
+            let min = hashes.iter().map(|h| mix(*h, *seed)).min().unwrap_or(0);
+            key.push(min & 1 == 1);
+        }
+
+        return key;
+    }
+
+    pub fn add(&mut self, key: K, hashes: &[u64]) {
+        let bits = self.key(hashes);
+        self.groups
+            .entry(bits)
+            .or_default()
+            .push(key);
+        self.len += 1;
+    }
+
+    pub fn group(&self, key: &BitVec) -> Option<&[K]> {
+        return self.groups.get(key).map(|g| g.as_slice());
+    }
+
+    /// Get every non empty group whose key is within `radius` bits of the given key
+    pub fn groups_within(&self, key: &BitVec, radius: u8) -> Vec<&[K]> {
+        return groups_within(&self.groups, key, radius);
+    }
+
+    /// Remove a key which was added with the given hashes, only the group the hashes fall into is checked
+    pub fn remove_hashes(&mut self, key: &K, hashes: &[u64]) -> bool
+        where K : PartialEq
+    {
+        let bits = self.key(hashes);
+        if let Some(group) = self.groups.get_mut(&bits) {
+            let before = group.len();
+            group.retain(|k| k != key);
+            self.len -= before - group.len();
+            return group.len() < before;
+        }
+
+        return false;
+    }
+}
+
+/// Multiple `MinHashIndex` instances queried together, ranking candidates by Jaccard distance (`1 - similarity`)
+pub struct MultiMinHashIndex<K:Send+Sync> {
+    indices: Vec<MinHashIndex<K>>,
+
+    // Token hashes of every set, used for ranking
+    sets: HashMap<K, Vec<u64>>
+}
+
+impl<K:Clone+Eq+Hash+Send+Sync> MultiMinHashIndex<K> {
+    pub fn new<R : Rng + Sized>(index_count: u8, hash_count: u8, rng: &mut R) -> MultiMinHashIndex<K> {
+        MultiMinHashIndex {
+            indices: (0..index_count).map(|_| MinHashIndex::new(hash_count, rng)).collect(),
+            sets: HashMap::new()
+        }
+    }
+
+    pub fn indices_len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Number of distinct keys in the index
+    pub fn len(&self) -> usize {
+        self.sets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    /// Add a set of tokens. Adding a key which is already in the index replaces the old set.
+    pub fn add<I, H>(&mut self, key: K, tokens: I)
+        where I : IntoIterator<Item=H>, H : Hash
+    {
+        self.remove(&key);
+
+        let hashes = token_hashes(tokens);
+        self.indices.par_iter_mut()
+            .for_each(|idx| idx.add(key.clone(), &hashes));
+        self.sets.insert(key, hashes);
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool
+    {
+        let hashes = match self.sets.remove(key) {
+            Some(hashes) => hashes,
+            None => return false,
+        };
+
+        self.indices.par_iter_mut()
+            .for_each(|idx| { idx.remove_hashes(key, &hashes); });
+        return true;
+    }
+
+    pub fn nearest_points_set_with(&self, hashes: &[u64], options: &QueryOptions) -> HashSet<K>
+    {
+        return self.indices.par_iter()
+            .flat_map_iter(|i| i.groups_within(&i.key(hashes), options.probe_radius))
+            .flat_map_iter(|r| r.iter().cloned())
+            .collect::<HashSet<K>>();
+    }
+
+    /// Find the nearest `count` sets, the distance of each result is the Jaccard distance (`1 - similarity`)
+    pub fn nearest<I, H>(&self, tokens: I, count: usize) -> Vec<DistanceNode<K, f32>>
+        where I : IntoIterator<Item=H>, H : Hash
+    {
+        return self.nearest_with(tokens, count, &QueryOptions::default());
+    }
+
+    pub fn nearest_with<I, H>(&self, tokens: I, count: usize, options: &QueryOptions) -> Vec<DistanceNode<K, f32>>
+        where I : IntoIterator<Item=H>, H : Hash
+    {
+        let hashes = token_hashes(tokens);
+        return rank_candidates(self.nearest_points_set_with(&hashes, options), count, |k| 1f32 - jaccard_similarity(&hashes, &self.sets[k]));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::minhash::{ MultiMinHashIndex, jaccard_similarity, token_hashes };
+
+    fn shingles(text: &str) -> Vec<String> {
+        let words = text.split_whitespace().collect::<Vec<_>>();
+        words.windows(2).map(|w| w.join(" ")).collect()
+    }
+
+    #[test]
+    fn jaccard_of_sets() {
+        let a = token_hashes(vec!["a", "b", "c"]);
+        let b = token_hashes(vec!["b", "c", "d"]);
+
+        assert_eq!(0.5f32, jaccard_similarity(&a, &b));
+        assert_eq!(1f32, jaccard_similarity(&a, &a));
+        assert_eq!(0f32, jaccard_similarity(&a, &token_hashes(vec!["x"])));
+    }
+
+    #[test]
+    fn token_hashes_dedupe() {
+        assert_eq!(2, token_hashes(vec![1, 2, 1, 2, 2]).len());
+    }
+
+    #[test]
+    fn nearest_finds_similar_documents() {
+        let mut a = MultiMinHashIndex::new(10, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..500usize {
+            let words = (0..50).map(|_| rng.gen_range(0..1000).to_string()).collect::<Vec<_>>().join(" ");
+            a.add(key, shingles(&words));
+        }
+
+        let original = "the quick brown fox jumps over the lazy dog and keeps on running far away from the farmer";
+        let edited = "the quick brown fox jumps over the lazy dog and keeps on running far away from the hunter";
+        a.add(1000, shingles(original));
+
+        let result = a.nearest(shingles(edited), 3);
+        assert_eq!(1000, result[0].key);
+        assert!(result[0].distance < 0.2);
+    }
+
+    #[test]
+    fn add_replaces_existing_key() {
+        let mut a = MultiMinHashIndex::new(5, 6, &mut thread_rng());
+        a.add(1, vec!["a", "b"]);
+        a.add(1, vec!["c", "d"]);
+
+        assert_eq!(1, a.len());
+        assert_eq!(0f32, a.nearest(vec!["c", "d"], 1)[0].distance);
+        assert!(a.remove(&1));
+        assert!(a.is_empty());
+    }
+}
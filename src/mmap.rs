@@ -0,0 +1,608 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use bit_vec::BitVec;
+use crate::par::prelude::*;
+
+use crate::error::HypernonsenseError;
+use crate::hyperindex::HashFamily;
+use crate::multiindex::{DistanceNode, MultiIndex};
+use crate::query::{QueryOptions, for_each_probe, probe_count, rank_candidates};
+use crate::vector::{Scalar, check_scalar_kind, check_vector, dot};
+
+// File layout. Unlike the streaming format in `persist` every section is 8 byte aligned and stored in native (little endian)
+// byte order, so planes and group members can be read straight out of the mapped memory without copying.
+//
+//  magic         8 bytes "HYPNMMAP"
+//  version       u64
+//  dims          u64
+//  index count   u64
+//  plane count   u64
+//  key size      u64 (size_of::<K>(), checked on open)
+//  scalar        u64 (the kind of T, see `ScalarKind::code`, checked on open)
+//  for each index (index table):
+//      planes offset, group table offset, group count, members offset, member count (5 * u64)
+//  for each index (data):
+//      planes        plane count * dims * T
+//      group table   group count * (key bytes padded to 8, members start u64, members length u64), sorted by key bytes
+//      members       member count * K
+const MAGIC: &[u8; 8] = b"HYPNMMAP";
+const FORMAT_VERSION: u64 = 1;
+const HEADER_LEN: usize = 8 * 7;
+const INDEX_ENTRY_LEN: usize = 8 * 5;
+
+/// A key type which can be stored in a memory mapped index.
+///
+/// # Safety
+/// Implementors must be plain old data: `Copy`, no padding bytes, no pointers and valid for any bit pattern.
+pub unsafe trait MappedKey : Copy + Eq + Hash + Send + Sync + 'static {
+}
+
+unsafe impl MappedKey for u8 {}
+unsafe impl MappedKey for u16 {}
+unsafe impl MappedKey for u32 {}
+unsafe impl MappedKey for u64 {}
+unsafe impl MappedKey for usize {}
+unsafe impl MappedKey for i8 {}
+unsafe impl MappedKey for i16 {}
+unsafe impl MappedKey for i32 {}
+unsafe impl MappedKey for i64 {}
+unsafe impl MappedKey for isize {}
+
+/// A read only memory mapping of an entire file
+pub(crate) struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// The mapping is read only, so it's safe to share between threads
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Mmap, HypernonsenseError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(HypernonsenseError::InvalidFormat("file is empty".to_string()));
+        }
+
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(HypernonsenseError::Io(io::Error::last_os_error()));
+        }
+
+        return Ok(Mmap { ptr: ptr as *const u8, len });
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Get a typed slice of `count` items starting at `offset`, checking bounds and alignment
+    pub(crate) fn slice<T: Copy>(&self, offset: usize, count: usize) -> Result<&[T], HypernonsenseError> {
+        let size = count.checked_mul(std::mem::size_of::<T>())
+            .ok_or_else(|| HypernonsenseError::InvalidFormat("section size overflows".to_string()))?;
+        let end = offset.checked_add(size)
+            .ok_or_else(|| HypernonsenseError::InvalidFormat("section size overflows".to_string()))?;
+        if end > self.len {
+            return Err(HypernonsenseError::InvalidFormat("section extends past the end of the file".to_string()));
+        }
+
+        let ptr = unsafe { self.ptr.add(offset) };
+        if !(ptr as usize).is_multiple_of(std::mem::align_of::<T>()) {
+            return Err(HypernonsenseError::InvalidFormat("section is not aligned".to_string()));
+        }
+
+        return Ok(unsafe { std::slice::from_raw_parts(ptr as *const T, count) });
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+struct IndexEntry {
+    planes: usize,
+    groups: usize,
+    group_count: usize,
+    members: usize,
+    member_count: usize,
+}
+
+/// A read only view of a single index inside a `MappedMultiIndex`
+pub struct MappedHyperIndex<'a, K:MappedKey, T:Scalar=f32> {
+    planes: &'a [T],
+    groups: &'a [u8],
+    group_count: usize,
+    members: &'a [K],
+    dims: usize,
+    plane_count: usize,
+}
+
+impl<'a, K:MappedKey, T:Scalar> MappedHyperIndex<'a, K, T> {
+    pub fn dimensions(&self) -> usize {
+        return self.dims;
+    }
+
+    pub fn planes_len(&self) -> usize {
+        return self.plane_count;
+    }
+
+    pub fn groups_len(&self) -> usize {
+        return self.group_count;
+    }
+
+    pub fn key(&self, vector: &[T]) -> BitVec
+    {
+        assert_eq!(self.dims, vector.len(), "vector does not have the dimension of the index");
+        let mut key = BitVec::with_capacity(self.plane_count);
+
+        for plane in self.planes.chunks_exact(self.dims) {
+            let d = dot(plane, vector);
+            let b = d > T::zero();
+            key.push(b);
+        }
+
+        return key;
+    }
+
+    /// Get the keys in a group, this is a slice directly into the mapped file
+    pub fn group(&self, key: &BitVec) -> Option<&'a [K]> {
+        let stride = group_stride(self.plane_count);
+        let key_bytes = key.to_bytes();
+
+        // Group table is sorted by key bytes, binary search on it
+        let mut lo = 0;
+        let mut hi = self.group_count;
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let entry = &self.groups[mid * stride..(mid + 1) * stride];
+            match entry[..key_bytes.len()].cmp(&key_bytes) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => {
+                    let (start, len) = group_range(entry);
+                    return Some(&self.members[start..start + len]);
+                }
+            }
+        }
+
+        return None;
+    }
+
+    /// Get every group whose key is within `radius` bits of the given key. Like `MultiIndex` this checks every entry in the
+    /// group table instead of probing when there are fewer groups than keys to probe.
+    pub fn groups_within(&self, key: &BitVec, radius: u8) -> Vec<&'a [K]> {
+        let mut groups = Vec::new();
+        if probe_count(key.len(), radius) > self.group_count {
+            let stride = group_stride(self.plane_count);
+            let key_bytes = key.to_bytes();
+            for entry in self.groups.chunks_exact(stride) {
+                let distance = entry.iter().zip(key_bytes.iter()).map(|(a, b)| (a ^ b).count_ones()).sum::<u32>();
+                if distance <= radius as u32 {
+                    let (start, len) = group_range(entry);
+                    groups.push(&self.members[start..start + len]);
+                }
+            }
+        } else {
+            let mut probe = key.clone();
+            for_each_probe(&mut probe, radius, &mut |k| groups.extend(self.group(k)));
+        }
+
+        return groups;
+    }
+}
+
+/// A read only `MultiIndex` backed by a memory mapped file written with `MultiIndex::save_mapped`.
+///
+/// Opening the index only validates the file structure, planes and group members are read directly out of the page cache when queried.
+pub struct MappedMultiIndex<K:MappedKey, T:Scalar=f32> {
+    map: Mmap,
+    entries: Vec<IndexEntry>,
+    dims: usize,
+    plane_count: usize,
+    phantom: PhantomData<(K, T)>,
+}
+
+impl<K:MappedKey, T:Scalar> MappedMultiIndex<K, T> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedMultiIndex<K, T>, HypernonsenseError> {
+        let map = Mmap::open(path)?;
+
+        if map.bytes().len() < HEADER_LEN || &map.bytes()[0..8] != MAGIC {
+            return Err(HypernonsenseError::InvalidFormat("not a mapped hypernonsense index".to_string()));
+        }
+
+        let header = map.slice::<u64>(8, 6)?;
+        if header[0] != FORMAT_VERSION {
+            return Err(HypernonsenseError::UnsupportedVersion(header[0] as u32));
+        }
+        check_scalar_kind::<T>(header[5], "file")?;
+        let dims = header[1] as usize;
+        let index_count = header[2] as usize;
+        let plane_count = header[3] as usize;
+        if header[4] as usize != std::mem::size_of::<K>() {
+            return Err(HypernonsenseError::InvalidFormat(format!("file was written with {} byte keys", header[4])));
+        }
+        if index_count == 0 {
+            return Err(HypernonsenseError::InvalidFormat("index count must be at least one".to_string()));
+        }
+        if dims == 0 {
+            return Err(HypernonsenseError::InvalidFormat("dimension must be at least one".to_string()));
+        }
+
+        let table = map.slice::<u64>(HEADER_LEN, index_count.saturating_mul(5))?;
+        let mut entries = Vec::with_capacity(index_count);
+        for e in table.chunks_exact(5) {
+            let entry = IndexEntry {
+                planes: e[0] as usize,
+                groups: e[1] as usize,
+                group_count: e[2] as usize,
+                members: e[3] as usize,
+                member_count: e[4] as usize,
+            };
+
+            // Check every section is in bounds and every group points inside the members section, so queries never need to
+            map.slice::<T>(entry.planes, plane_count.saturating_mul(dims))?;
+            map.slice::<K>(entry.members, entry.member_count)?;
+            let stride = group_stride(plane_count);
+            let groups = map.slice::<u8>(entry.groups, entry.group_count.saturating_mul(stride))?;
+            let key_len = plane_count.div_ceil(8);
+            let mut previous: Option<&[u8]> = None;
+            for g in groups.chunks_exact(stride) {
+                let (start, len) = group_range(g);
+                if start.checked_add(len).map(|end| end > entry.member_count).unwrap_or(true) {
+                    return Err(HypernonsenseError::InvalidFormat("group members out of range".to_string()));
+                }
+                if previous.map(|p| p >= &g[..key_len]).unwrap_or(false) {
+                    return Err(HypernonsenseError::InvalidFormat("group table is not sorted".to_string()));
+                }
+                previous = Some(&g[..key_len]);
+            }
+
+            entries.push(entry);
+        }
+
+        return Ok(MappedMultiIndex {
+            map,
+            entries,
+            dims,
+            plane_count,
+            phantom: PhantomData
+        });
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.dims
+    }
+
+    pub fn planes_len(&self) -> usize {
+        self.plane_count
+    }
+
+    pub fn indices_len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn index(&self, i: usize) -> MappedHyperIndex<'_, K, T> {
+        let e = &self.entries[i];
+
+        // Bounds were all checked when the file was opened
+        MappedHyperIndex {
+            planes: self.map.slice(e.planes, self.plane_count * self.dims).unwrap(),
+            groups: self.map.slice(e.groups, e.group_count * group_stride(self.plane_count)).unwrap(),
+            group_count: e.group_count,
+            members: self.map.slice(e.members, e.member_count).unwrap(),
+            dims: self.dims,
+            plane_count: self.plane_count,
+        }
+    }
+
+    pub fn nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_with(point, count, &QueryOptions::default(), get_dist);
+    }
+
+    /// Find the nearest `count` points like `nearest`, checking the query vector first
+    pub fn try_nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Result<Vec<DistanceNode<K, T>>, HypernonsenseError>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        check_vector(point, self.dims)?;
+        if self.entries[0].member_count == 0 {
+            return Err(HypernonsenseError::EmptyIndex);
+        }
+
+        return Ok(self.nearest(point, count, get_dist));
+    }
+
+    /// Find the nearest `count` points, probing every key within `options.probe_radius` bits. The other options need the
+    /// full `MultiIndex` and are ignored. Results are ranked (and ties broken) the same way as `MultiIndex::nearest_with`.
+    pub fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return rank_candidates(self.nearest_points_set_with(point, options), count, |k| get_dist(point, k));
+    }
+
+    pub fn nearest_points(&self, point: &[T]) -> Vec<K>
+    {
+        return self.nearest_points_set(point)
+            .into_iter()
+            .collect::<Vec<_>>();
+    }
+
+    pub fn nearest_points_set(&self, point: &[T]) -> HashSet<K>
+    {
+        return self.nearest_points_set_with(point, &QueryOptions::default());
+    }
+
+    pub fn nearest_points_set_with(&self, point: &[T], options: &QueryOptions) -> HashSet<K>
+    {
+        return (0..self.indices_len()).collect::<Vec<_>>().par_iter()
+            .flat_map_iter(|i| {
+                let index = self.index(*i);
+                let key = index.key(point);
+                index.groups_within(&key, options.probe_radius).into_iter()
+            })
+            .flat_map_iter(|g| g.iter().copied())
+            .collect::<HashSet<K>>();
+    }
+}
+
+fn group_stride(plane_count: usize) -> usize {
+    plane_count.div_ceil(8).div_ceil(8) * 8 + 16
+}
+
+fn group_range(entry: &[u8]) -> (usize, usize) {
+    let n = entry.len();
+    let start = u64::from_ne_bytes(entry[n - 16..n - 8].try_into().unwrap()) as usize;
+    let len = u64::from_ne_bytes(entry[n - 8..].try_into().unwrap()) as usize;
+    (start, len)
+}
+
+fn pad<W: Write>(writer: &mut W, written: &mut usize) -> io::Result<()> {
+    let padding = written.div_ceil(8) * 8 - *written;
+    writer.write_all(&[0u8; 8][..padding])?;
+    *written += padding;
+    Ok(())
+}
+
+pub(crate) fn write_mapped<K:MappedKey+std::fmt::Debug, T:Scalar, V:Send+Sync, P: AsRef<Path>>(index: &MultiIndex<K, T, V>, path: P) -> Result<(), HypernonsenseError> {
+    let indices = index.indices();
+    if index.hash_family() != HashFamily::Hyperplane || indices.iter().any(|i| i.offsets().iter().any(|o| !o.is_zero())) {
+        return Err(HypernonsenseError::InvalidConfig("only indices using plain hyperplanes can be memory mapped".to_string()));
+    }
+
+    let dims = index.dimensions();
+    let plane_count = index.planes_len();
+    let stride = group_stride(plane_count);
+    let key_len = plane_count.div_ceil(8);
+
+    // Sort groups in every index by key bytes, so the mapped index can binary search them
+    let sorted = indices.iter()
+        .map(|idx| {
+            let mut groups = idx.groups_iter()
+                .map(|(k, v)| (k.to_bytes(), v))
+                .collect::<Vec<_>>();
+            groups.sort_by(|a, b| a.0.cmp(&b.0));
+            groups
+        })
+        .collect::<Vec<_>>();
+
+    // Work out where every section goes
+    let mut entries = Vec::with_capacity(indices.len());
+    let mut offset = HEADER_LEN + INDEX_ENTRY_LEN * indices.len();
+    for groups in sorted.iter() {
+        let planes = offset;
+        offset += (plane_count * dims * std::mem::size_of::<T>()).div_ceil(8) * 8;
+        let group_table = offset;
+        offset += groups.len() * stride;
+        let members = offset;
+        let member_count = groups.iter().map(|g| g.1.len()).sum::<usize>();
+        offset += (member_count * std::mem::size_of::<K>()).div_ceil(8) * 8;
+
+        entries.push(IndexEntry { planes, groups: group_table, group_count: groups.len(), members, member_count });
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    for v in [FORMAT_VERSION, dims as u64, indices.len() as u64, plane_count as u64, std::mem::size_of::<K>() as u64, T::KIND.code() as u64] {
+        writer.write_all(&v.to_ne_bytes())?;
+    }
+    for e in entries.iter() {
+        for v in [e.planes, e.groups, e.group_count, e.members, e.member_count] {
+            writer.write_all(&(v as u64).to_ne_bytes())?;
+        }
+    }
+
+    for (idx, groups) in indices.iter().zip(sorted.iter()) {
+        let mut written = 0;
+        for plane in idx.planes() {
+            let bytes = unsafe {
+                std::slice::from_raw_parts(plane.as_ptr() as *const u8, std::mem::size_of_val(plane))
+            };
+            writer.write_all(bytes)?;
+            written += bytes.len();
+        }
+        pad(&mut writer, &mut written)?;
+
+        let mut start = 0usize;
+        for (key, members) in groups.iter() {
+            let mut key_bytes = vec![0u8; stride - 16];
+            key_bytes[..key_len].copy_from_slice(key);
+            writer.write_all(&key_bytes)?;
+            writer.write_all(&(start as u64).to_ne_bytes())?;
+            writer.write_all(&(members.len() as u64).to_ne_bytes())?;
+            start += members.len();
+        }
+
+        let mut written = 0;
+        for (_, members) in groups.iter() {
+            let bytes = unsafe {
+                std::slice::from_raw_parts(members.as_ptr() as *const u8, members.len() * std::mem::size_of::<K>())
+            };
+            writer.write_all(bytes)?;
+            written += bytes.len();
+        }
+        pad(&mut writer, &mut written)?;
+    }
+
+    writer.flush()?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::error::HypernonsenseError;
+    use crate::hyperindex::HashFamily;
+    use crate::mmap::MappedMultiIndex;
+    use crate::multiindex::MultiIndex;
+    use crate::query::QueryOptions;
+    use crate::vector::{ random_unit_vector, euclidean_distance };
+
+    #[test]
+    fn mapped_index_matches_original() {
+        let mut a = MultiIndex::new(50, 5, 8, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let mut vectors = Vec::new();
+        for key in 0..1000u64 {
+            let v = random_unit_vector(50, &mut rng);
+            a.add(key, &v);
+            vectors.push(v);
+        }
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_mapped_{}.hyp", std::process::id()));
+        a.save_mapped(&path).unwrap();
+        let b = MappedMultiIndex::<u64>::open(&path).unwrap();
+
+        assert_eq!(a.dimensions(), b.dimensions());
+        assert_eq!(a.planes_len(), b.planes_len());
+        assert_eq!(a.indices_len(), b.indices_len());
+        for v in vectors.iter().take(100) {
+            let mut expected = a.nearest_points(v);
+            let mut actual = b.nearest_points(v);
+            expected.sort();
+            actual.sort();
+            assert_eq!(expected, actual);
+
+            let expected = a.nearest(v, 10, |p, k| euclidean_distance(p, &vectors[*k as usize]));
+            let actual = b.nearest(v, 10, |p, k| euclidean_distance(p, &vectors[*k as usize]));
+            assert_eq!(expected.iter().map(|n| n.key).collect::<Vec<_>>(), actual.iter().map(|n| n.key).collect::<Vec<_>>());
+        }
+
+        drop(b);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_rejects_wrong_key_size() {
+        let mut a = MultiIndex::new(10, 2, 4, &mut thread_rng());
+        a.add(1u32, &random_unit_vector(10, &mut thread_rng()));
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_mapped_keysize_{}.hyp", std::process::id()));
+        a.save_mapped(&path).unwrap();
+        let result = MappedMultiIndex::<u64>::open(&path);
+        assert!(matches!(result, Err(HypernonsenseError::InvalidFormat(_))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_rejects_pstable_index() {
+        let a = MultiIndex::<u32>::builder(10).hash_family(HashFamily::PStable { width: 1f32 }).build().unwrap();
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_mapped_pstable_{}.hyp", std::process::id()));
+        assert!(matches!(a.save_mapped(&path), Err(HypernonsenseError::InvalidConfig(_))));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn mapped_queries_use_the_probe_radius() {
+        let mut a = MultiIndex::new(20, 4, 8, &mut thread_rng());
+
+        // Duplicate vectors under several keys, so ranking has to break ties
+        let mut rng = thread_rng();
+        let mut vectors = Vec::<Vec<f32>>::new();
+        for key in 0..600u64 {
+            let v = if key % 3 == 0 { random_unit_vector(20, &mut rng) } else { vectors[(key - key % 3) as usize].clone() };
+            a.add(key, &v);
+            vectors.push(v);
+        }
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_mapped_radius_{}.hyp", std::process::id()));
+        a.save_mapped(&path).unwrap();
+        let b = MappedMultiIndex::<u64>::open(&path).unwrap();
+
+        // Radius 8 probes more keys than there are groups, so the mapped index scans the group table instead
+        for radius in [ 0, 1, 2, 8 ] {
+            let options = QueryOptions::default().with_probe_radius(radius);
+            for v in vectors.iter().step_by(7).take(30) {
+                assert_eq!(a.nearest_points_set_with(v, &options), b.nearest_points_set_with(v, &options));
+
+                let expected = a.nearest_with(v, 10, &options, |p, k| euclidean_distance(p, &vectors[*k as usize]));
+                let actual = b.nearest_with(v, 10, &options, |p, k| euclidean_distance(p, &vectors[*k as usize]));
+                assert_eq!(expected.iter().map(|n| n.key).collect::<Vec<_>>(), actual.iter().map(|n| n.key).collect::<Vec<_>>());
+            }
+        }
+
+        drop(b);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn try_nearest_checks_the_query() {
+        let mut a = MultiIndex::new(10, 2, 4, &mut thread_rng());
+        let empty = MultiIndex::<u64>::new(10, 2, 4, &mut thread_rng());
+        let v = random_unit_vector(10, &mut thread_rng());
+        a.add(1u64, &v);
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_mapped_try_{}.hyp", std::process::id()));
+        let empty_path = std::env::temp_dir().join(format!("hypernonsense_mapped_try_empty_{}.hyp", std::process::id()));
+        a.save_mapped(&path).unwrap();
+        empty.save_mapped(&empty_path).unwrap();
+        let b = MappedMultiIndex::<u64>::open(&path).unwrap();
+        let c = MappedMultiIndex::<u64>::open(&empty_path).unwrap();
+
+        assert!(matches!(b.try_nearest(&v[..9], 1, |_, _| 0f32), Err(HypernonsenseError::DimensionMismatch { expected: 10, actual: 9 })));
+        let mut nan = v.clone();
+        nan[3] = f32::NAN;
+        assert!(matches!(b.try_nearest(&nan, 1, |_, _| 0f32), Err(HypernonsenseError::NonFiniteInput)));
+        assert!(matches!(c.try_nearest(&v, 1, |_, _| 0f32), Err(HypernonsenseError::EmptyIndex)));
+        assert_eq!(1, b.try_nearest(&v, 1, |_, _| 0f32).unwrap()[0].key);
+
+        drop(b);
+        drop(c);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&empty_path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "vector does not have the dimension of the index")]
+    fn key_checks_the_dimension() {
+        let mut a = MultiIndex::new(10, 2, 4, &mut thread_rng());
+        a.add(1u64, &random_unit_vector(10, &mut thread_rng()));
+
+        let path = std::env::temp_dir().join(format!("hypernonsense_mapped_key_{}.hyp", std::process::id()));
+        a.save_mapped(&path).unwrap();
+        let b = MappedMultiIndex::<u64>::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        b.index(0).key(&[ 1f32; 9 ]);
+    }
+}
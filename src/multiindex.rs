@@ -1,61 +1,567 @@
-use std::collections::HashSet;
-use std::hash::Hash;
-use std::fmt::Debug;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fmt::{Debug, Display};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
 
 use bit_vec::BitVec;
-use rand::Rng;
-use rayon::prelude::{IntoParallelRefMutIterator, ParallelIterator, IntoParallelRefIterator, IntoParallelIterator};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use crate::par::prelude::*;
+#[cfg(feature = "rayon")]
+use rayon::ThreadPool;
 
-use crate::hyperindex::HyperIndex;
+use crate::builder::MultiIndexBuilder;
+use crate::error::HypernonsenseError;
+use crate::evaluate::recall;
+use crate::events::{IndexEvent, Listener};
+use crate::eviction::{Capacity, Eviction};
+use crate::expiry::Timestamps;
+#[cfg(feature = "async")]
+use crate::future::QueryFuture;
+use crate::hyperindex::{HashFamily, HyperIndex, Planes};
+use crate::json;
+use crate::jsonl;
+use crate::metric::Metric;
+use crate::par::{Pool, install};
+use crate::persist::{Persist, read_multiindex, write_multiindex};
+use crate::pq::ProductQuantizer;
+use crate::query::{Aggregation, QueryOptions, RankedResults, hamming_distance, probe_count, rank_borrowed, rank_candidates};
+use crate::recorder::{QueryEvent, QueryTimer, Recorder, since, stopwatch};
+use crate::stats::{IndexStats, MultiIndexStats};
+use crate::vector::{Normalization, Scalar, SparseVector, check_vector, is_unit, mean, normalize};
 
-pub struct DistanceNode<K: Eq+Hash> {
+/// A query result. Results are ordered by distance, ties are broken by a fixed hash of the key so the same candidates always
+/// end up in the same order (and the same ones are kept when results are truncated), regardless of insertion order or how
+/// the query was scheduled across threads.
+pub struct DistanceNode<K: Eq+Hash, T:Scalar=f32> {
     pub key: K,
-    pub distance: f32
+    pub distance: T
 }
 
-impl<K:Eq+Hash> PartialOrd for DistanceNode<K>
+impl<K:Clone+Eq+Hash, T:Scalar> DistanceNode<&K, T> {
+    /// Copy a result which borrows its key into one which owns it
+    pub fn cloned(&self) -> DistanceNode<K, T> {
+        return DistanceNode { key: self.key.clone(), distance: self.distance };
+    }
+}
+
+impl<K:Eq+Hash, T:Scalar> PartialOrd for DistanceNode<K, T>
 {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.distance.partial_cmp(&other.distance)
+        Some(self.cmp(other))
     }
 }
 
-impl<K:Eq+Hash> Ord for DistanceNode<K>
+impl<K:Eq+Hash, T:Scalar> Ord for DistanceNode<K, T>
 {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap_or(std::cmp::Ordering::Equal)
+        self.distance.partial_cmp(&other.distance)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| tie_break(&self.key).cmp(&tie_break(&other.key)))
     }
 }
 
-impl<K:Eq+Hash> Eq for DistanceNode<K>
+// Hash with fixed keys, unlike the hashers of `HashMap` this gives the same value for the same key every time
+pub(crate) fn tie_break<K:Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    return hasher.finish();
+}
+
+impl<K:Eq+Hash, T:Scalar> Eq for DistanceNode<K, T>
 {
 }
 
-impl<K:Eq+Hash> PartialEq for DistanceNode<K> {
+impl<K:Eq+Hash, T:Scalar> PartialEq for DistanceNode<K, T> {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key
     }
 }
 
-impl<K:Eq+Hash> Hash for DistanceNode<K> {
+impl<K:Eq+Hash, T:Scalar> Hash for DistanceNode<K, T> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.key.hash(state);
     }
 }
 
-pub struct MultiIndex<K:Send+Sync> {
-    indices: Vec<HyperIndex<K>>
+/// Where a candidate was found by a query, see `MultiIndex::nearest_explained`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CandidateSource {
+    /// Position of the sub index the candidate was found in
+    pub index: usize,
+
+    /// Key of the group the candidate is in
+    pub group: BitVec,
+
+    /// Number of bits the group key differs from the query key, this is zero for the group the query itself falls into
+    pub hamming_distance: u32,
+}
+
+/// A result with the provenance of the candidate, returned by `MultiIndex::nearest_explained`
+pub struct ExplainedNode<K:Eq+Hash, T:Scalar=f32> {
+    pub node: DistanceNode<K, T>,
+
+    /// Every probed group which contained the candidate, in sub index order
+    pub sources: Vec<CandidateSource>,
+}
+
+impl<K:Eq+Hash, T:Scalar> ExplainedNode<K, T> {
+    /// Number of sub indices which found the candidate
+    pub fn votes(&self) -> usize {
+        let mut indices = self.sources.iter().map(|s| s.index).collect::<Vec<_>>();
+        indices.dedup();
+        return indices.len();
+    }
+}
+
+/// A recommended index configuration, returned by `MultiIndex::autotune`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexConfig {
+    pub index_count: u8,
+    pub plane_count: u8,
+}
+
+/// Reported to the progress callback of long running operations such as `add_batch_with_progress` and `autotune_with_progress`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// `processed` of the `total` items have been added to the index
+    Items { processed: usize, total: usize },
+
+    /// Autotuning has started evaluating a candidate configuration. While the plane count is being tuned the index count is always one.
+    Candidate(IndexConfig),
+}
+
+/// Iterator returned by `MultiIndex::nearest_anytime`. Every item is the nearest points found so far, each one at least as
+/// good as the last.
+pub struct AnytimeQuery<'a, K:Eq+Hash+Send+Sync, T:Scalar, V, F> {
+    index: &'a MultiIndex<K, T, V>,
+    point: Vec<T>,
+    count: usize,
+    get_dist: F,
+
+    // Query key in each sub index
+    keys: Vec<BitVec>,
+
+    // The next ring of groups to probe is `radius` bits away from the key of sub index `next`
+    radius: usize,
+    max_radius: usize,
+    next: usize,
+
+    // Probing stops at this time, once every sub index has been probed at radius zero or something has been found
+    deadline: Option<Instant>,
+
+    seen: HashSet<K>,
+    best: Vec<DistanceNode<K, T>>,
+}
+
+impl<'a, K, T, V, F> Iterator for AnytimeQuery<'a, K, T, V, F>
+    where K : Clone + Eq + Hash + Send + Sync, T : Scalar, V : Send + Sync, F : Fn(&[T], &K) -> T + Send + Sync
+{
+    type Item = Vec<DistanceNode<K, T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Probe one ring of one sub index at a time, skipping probes which find nothing new
+        while self.radius <= self.max_radius {
+            // Checked before every probe, most probes of a large radius find nothing new so never yield
+            let started = self.radius > 0 || !self.best.is_empty();
+            if started && self.deadline.is_some_and(|d| Instant::now() >= d) {
+                return None;
+            }
+
+            let i = self.next;
+            let radius = self.radius as u8;
+            self.next += 1;
+            if self.next == self.keys.len() {
+                self.next = 0;
+                self.radius += 1;
+            }
+
+            let mut found = HashSet::new();
+            for group in self.index.indices[i].groups_at(&self.keys[i], radius) {
+                found.extend(group.iter().filter(|k| !self.seen.contains(k)).cloned());
+            }
+            if found.is_empty() {
+                continue;
+            }
+            self.seen.extend(found.iter().cloned());
+
+            let point = &self.point;
+            let get_dist = &self.get_dist;
+            let ranked = install(&self.index.pool, || rank_candidates(found, self.count, |k| get_dist(point, k)));
+            self.best.extend(ranked);
+            self.best.sort_unstable();
+            self.best.truncate(self.count);
+
+            return Some(self.best.iter().map(|n| DistanceNode { key: n.key.clone(), distance: n.distance }).collect());
+        }
+
+        return None;
+    }
+}
+
+// Number of vectors used as sample queries when autotuning
+const AUTOTUNE_SAMPLE_QUERIES: usize = 200;
+
+// Number of items added to every sub index between progress reports
+const PROGRESS_CHUNK: usize = 10000;
+
+// Connected components of the graph with the given edges, using union-find over the positions of the keys
+fn connected<K:Clone+Eq+Hash, I:IntoIterator<Item=(K, K)>>(pairs: I) -> Vec<Vec<K>> {
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        return i;
+    }
+
+    let mut positions = HashMap::new();
+    let mut keys = Vec::new();
+    let mut parents = Vec::new();
+    for (a, b) in pairs {
+        let mut position = |k: K| *positions.entry(k.clone()).or_insert_with(|| {
+            keys.push(k);
+            parents.push(parents.len());
+            parents.len() - 1
+        });
+        let a = position(a);
+        let b = position(b);
+
+        let (a, b) = (root(&mut parents, a), root(&mut parents, b));
+        parents[a] = b;
+    }
+
+    let mut clusters = HashMap::<usize, Vec<K>>::new();
+    for (i, key) in keys.into_iter().enumerate() {
+        clusters.entry(root(&mut parents, i)).or_default().push(key);
+    }
+
+    return clusters.into_values().collect();
+}
+
+fn mean_len(sets: &[HashSet<usize>]) -> f32 {
+    return sets.iter().map(|c| c.len()).sum::<usize>() as f32 / (sets.len().max(1) as f32);
+}
+
+pub struct MultiIndex<K:Send+Sync, T:Scalar=f32, V=()> {
+    // Sub indices and stored vectors are shared with snapshots, each one is copied the first time it is modified after a snapshot is taken
+    indices: Vec<Arc<HyperIndex<K, T>>>,
+
+    // Copies of the inserted vectors, only present if vector storage was enabled when the index was built
+    vectors: Option<Arc<HashMap<K, Vec<T>>>>,
+
+    // Payloads attached to keys with `add_with_payload`
+    payloads: HashMap<K, V>,
+
+    // Timestamps attached to keys with `add_with_timestamp`, not saved with the index
+    timestamps: Timestamps<K>,
+
+    // Maximum number of items and the order to evict them in, not saved with the index
+    capacity: Option<Capacity<K>>,
+
+    // Product quantizer and the code of every item added after it was set, not saved with the index. The codes are shared
+    // with snapshots, like the stored vectors.
+    quantizer: Option<Arc<ProductQuantizer<T>>>,
+    codes: Arc<HashMap<K, Vec<u8>>>,
+
+    // Metric used to rank candidates using the stored vectors
+    metric: Metric,
+
+    // Seed used to generate the planes, if the index was built from a seed
+    seed: Option<u64>,
+
+    // What to do with vectors which are not unit length
+    normalization: Normalization,
+
+    // Thread pool used for inserting and querying, not saved with the index
+    pool: Pool,
+
+    // Receives insert and query events, not saved with the index
+    recorder: Option<Arc<dyn Recorder>>,
+
+    // Receives an event for every change, not saved with the index or copied into snapshots
+    listener: Option<Arc<dyn Listener<K, T>>>
 }
 
-impl<K:Clone+Eq+Hash+Debug+Send+Sync> MultiIndex<K> {
-    pub fn new<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, mut rng: &mut R) -> MultiIndex<K> {
+impl<K:Send+Sync, T:Scalar, V:Send+Sync> MultiIndex<K, T, V> {
+    pub(crate) fn from_parts(indices: Vec<HyperIndex<K, T>>, vectors: Option<HashMap<K, Vec<T>>>, payloads: HashMap<K, V>, metric: Metric, seed: Option<u64>, normalization: Normalization) -> MultiIndex<K, T, V> {
+        MultiIndex {
+            indices: indices.into_iter().map(Arc::new).collect(),
+            vectors: vectors.map(Arc::new),
+            payloads,
+            timestamps: Timestamps::new(),
+            capacity: None,
+            quantizer: None,
+            codes: Arc::new(HashMap::new()),
+            metric,
+            seed,
+            normalization,
+            pool: Pool::default(),
+            recorder: None,
+            listener: None
+        }
+    }
+
+    pub(crate) fn create<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, family: HashFamily, store_vectors: bool, mut rng: &mut R) -> MultiIndex<K, T, V> {
         MultiIndex {
-            indices: (0..index_count).map(|_| HyperIndex::new(dimension, hyperplane_count, &mut rng)).collect()
+            indices: (0..index_count).map(|_| Arc::new(HyperIndex::new_with_family(dimension, hyperplane_count, family, &mut rng))).collect(),
+            vectors: if store_vectors { Some(Arc::new(HashMap::new())) } else { None },
+            payloads: HashMap::new(),
+            timestamps: Timestamps::new(),
+            capacity: None,
+            quantizer: None,
+            codes: Arc::new(HashMap::new()),
+            metric: Metric::default(),
+            seed: None,
+            normalization: Normalization::default(),
+            pool: Pool::default(),
+            recorder: None,
+            listener: None
+        }
+    }
+
+    pub(crate) fn indices(&self) -> &Vec<Arc<HyperIndex<K, T>>> {
+        &self.indices
+    }
+
+    /// Take the sub indices out of the index, copying any which are shared with a snapshot
+    pub(crate) fn into_indices(self) -> Vec<HyperIndex<K, T>>
+        where K : Clone
+    {
+        return self.indices.into_iter().map(|i| Arc::try_unwrap(i).unwrap_or_else(|i| (*i).clone())).collect();
+    }
+
+    pub(crate) fn vectors(&self) -> Option<&HashMap<K, Vec<T>>> {
+        self.vectors.as_deref()
+    }
+
+    pub(crate) fn payloads(&self) -> &HashMap<K, V> {
+        &self.payloads
+    }
+
+    /// Returns true if this index keeps a copy of every inserted vector
+    pub fn stores_vectors(&self) -> bool {
+        self.vectors.is_some()
+    }
+
+    /// The metric used to rank candidates by `nearest_k`
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn set_metric(&mut self, metric: Metric) {
+        self.metric = metric;
+    }
+
+    /// The seed used to generate the planes. This is `None` if the index was created with `new` from a caller supplied RNG.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub(crate) fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    /// How vectors which are not unit length are treated when they are inserted or queried
+    pub fn normalization(&self) -> Normalization {
+        self.normalization
+    }
+
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
+    /// The thread pool this index runs inserts and queries on, `None` if it uses the global rayon pool
+    #[cfg(feature = "rayon")]
+    pub fn thread_pool(&self) -> Option<&Arc<ThreadPool>> {
+        self.pool.as_ref()
+    }
+
+    /// Run all parallel work for inserts and queries on the given thread pool instead of the global rayon pool, or go back to
+    /// the global pool with `None`. Calls made from inside another pool block that thread until the work is done.
+    #[cfg(feature = "rayon")]
+    pub fn set_thread_pool(&mut self, pool: Option<Arc<ThreadPool>>) {
+        self.pool = pool;
+    }
+
+    /// The recorder which receives insert and query events from this index, if there is one
+    pub fn recorder(&self) -> Option<&Arc<dyn Recorder>> {
+        self.recorder.as_ref()
+    }
+
+    /// Send insert and query events to the given recorder, or stop recording with `None`. Queries are only timed while there
+    /// is a recorder.
+    pub fn set_recorder(&mut self, recorder: Option<Arc<dyn Recorder>>) {
+        self.recorder = recorder;
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: Option<Capacity<K>>) {
+        self.capacity = capacity;
+    }
+
+    // Start timing a query, if there is anything to report the time to
+    fn start_query(&self) -> QueryTimer {
+        QueryTimer::new(self.recorder.is_some())
+    }
+
+    fn record_query(&self, timer: QueryTimer, probe_radius: u8, candidates: usize, results: usize) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.query(&QueryEvent {
+                candidates,
+                probes: probe_count(self.indices[0].key_len(), probe_radius) * self.indices.len(),
+                results,
+                duration: timer.elapsed(),
+                phases: timer.phases,
+            });
+        }
+    }
+
+    /// The listener which receives an event for every change made to this index, if there is one
+    pub fn listener(&self) -> Option<&Arc<dyn Listener<K, T>>> {
+        self.listener.as_ref()
+    }
+
+    /// Send an event for every change made to this index to the given listener (e.g. a closure), or stop with `None`. Only
+    /// changes made from now on are sent, so to mirror an index start from a copy of it (e.g. saved and loaded).
+    pub fn set_listener(&mut self, listener: Option<Arc<dyn Listener<K, T>>>) {
+        self.listener = listener;
+    }
+
+    /// Send an event for every change made to this index to a channel, replacing any listener. Events sent after the receiver
+    /// has been dropped are discarded.
+    pub fn subscribe(&mut self) -> Receiver<IndexEvent<K, T>>
+        where K : 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.listener = Some(Arc::new(move |event| { let _ = sender.send(event); }));
+        return receiver;
+    }
+
+    fn emit<F>(&self, event: F)
+        where F : FnOnce() -> IndexEvent<K, T>
+    {
+        if let Some(listener) = self.listener.as_ref() {
+            listener.event(event());
+        }
+    }
+
+    fn record_insert(&self, count: usize) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.insert(count);
+        }
+    }
+
+    fn record_remove(&self, count: usize) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            if count > 0 {
+                recorder.remove(count);
+            }
+        }
+    }
+
+    /// Bias the planes of every sub index so each one splits the sample vectors in half (see `HyperIndex::fit_offsets`).
+    /// This must be done before any items are added.
+    pub fn fit_offsets(&mut self, sample_vectors: &[Vec<T>]) -> Result<(), HypernonsenseError>
+        where K : Clone
+    {
+        for index in self.indices.iter_mut().map(Arc::make_mut) {
+            index.fit_offsets(sample_vectors)?;
+        }
+
+        self.emit(|| IndexEvent::Rebuilt { planes: self.indices.iter().map(|i| i.export_planes()).collect() });
+        return Ok(());
+    }
+
+    // Apply the normalization policy to a vector
+    fn normalized<'a>(&self, vector: &'a [T]) -> Result<Cow<'a, [T]>, HypernonsenseError> {
+        match self.normalization {
+            Normalization::None => Ok(Cow::Borrowed(vector)),
+            Normalization::NormalizeOnInsert => {
+                let mut v = vector.to_vec();
+                normalize(&mut v);
+                Ok(Cow::Owned(v))
+            }
+            Normalization::RejectNonUnit => {
+                if is_unit(vector) {
+                    Ok(Cow::Borrowed(vector))
+                } else {
+                    Err(HypernonsenseError::NonUnitVector)
+                }
+            }
+        }
+    }
+
+    // Apply the normalization policy, for use in methods which cannot return an error
+    fn normalized_or_panic<'a>(&self, vector: &'a [T]) -> Cow<'a, [T]> {
+        match self.normalized(vector) {
+            Ok(v) => v,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+// Constructors for an index without payloads, use the builder to create an index with a payload type
+impl<K:Clone+Eq+Hash+Debug+Send+Sync, T:Scalar> MultiIndex<K, T> {
+    pub fn new<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, rng: &mut R) -> MultiIndex<K, T> {
+        Self::create(dimension, index_count, hyperplane_count, HashFamily::Hyperplane, false, rng)
+    }
+
+    /// Create a new index with planes generated from a seed. The same seed always generates the same planes, on every platform.
+    pub fn with_seed(dimension: usize, index_count: u8, hyperplane_count: u8, seed: u64) -> MultiIndex<K, T> {
+        let mut index = Self::create(dimension, index_count, hyperplane_count, HashFamily::Hyperplane, false, &mut ChaCha20Rng::seed_from_u64(seed));
+        index.seed = Some(seed);
+        return index;
+    }
+
+    /// Create a new index which stores a copy of every inserted vector, so they can be retrieved with `get`
+    pub fn new_with_storage<R : Rng + Sized>(dimension: usize, index_count: u8, hyperplane_count: u8, rng: &mut R) -> MultiIndex<K, T> {
+        Self::create(dimension, index_count, hyperplane_count, HashFamily::Hyperplane, true, rng)
+    }
+
+    /// Create an index from existing sub indices, e.g. ones built separately or loaded from `HyperIndex` files. Every sub
+    /// index must have the same dimension and number of planes (the hash families may differ) and contain the same items,
+    /// which is only checked by comparing their lengths. Vectors are not stored.
+    pub fn from_indices(indices: Vec<HyperIndex<K, T>>) -> Result<MultiIndex<K, T>, HypernonsenseError> {
+        let first = match indices.first() {
+            Some(first) => first,
+            None => return Err(HypernonsenseError::InvalidConfig("index count must be at least one".to_string())),
+        };
+        if indices.len() > u8::MAX as usize {
+            return Err(HypernonsenseError::InvalidConfig(format!("index count must be at most {}", u8::MAX)));
         }
+
+        for index in indices.iter() {
+            if index.dimensions() != first.dimensions() {
+                return Err(HypernonsenseError::DimensionMismatch { expected: first.dimensions(), actual: index.dimensions() });
+            }
+            if index.planes_len() != first.planes_len() {
+                return Err(HypernonsenseError::InvalidConfig("every sub index must have the same number of planes".to_string()));
+            }
+            if index.len() != first.len() {
+                return Err(HypernonsenseError::InvalidConfig("every sub index must contain the same items".to_string()));
+            }
+        }
+
+        return Ok(Self::from_parts(indices, None, HashMap::new(), Metric::default(), None, Normalization::default()));
     }
 
     /// Given a set of vectors, discover the best index count and plane count to use to achieve a particular group size
-    pub fn autotune_planes<R : Rng + Sized>(dimension: usize, group_size: f32, vectors: &Vec<Vec<f32>>, mut rng: &mut R) -> u8
+    pub fn autotune_planes<R : Rng + Sized>(dimension: usize, group_size: f32, vectors: &[Vec<T>], rng: &mut R) -> u8
+    {
+        return Self::autotune_planes_with_progress(dimension, group_size, vectors, rng, |_| {});
+    }
+
+    /// Same as `autotune_planes`, reporting every plane count which is tried to `progress`
+    pub fn autotune_planes_with_progress<R, P>(dimension: usize, group_size: f32, vectors: &[Vec<T>], mut rng: &mut R, mut progress: P) -> u8
+        where R : Rng + Sized, P : FnMut(Progress)
     {
         // Guess the best plane count to start with. This may be an underestimate if the points are very grouped up.
         // Bias down by slightly, just to be safe.
@@ -68,14 +574,14 @@ impl<K:Clone+Eq+Hash+Debug+Send+Sync> MultiIndex<K> {
         for planes in initial..255
         {
             // Build index with current plane count
-            let mut idx = HyperIndex::new(dimension, planes, &mut rng);
+            progress(Progress::Candidate(IndexConfig { index_count: 1, plane_count: planes }));
+            let mut idx = HyperIndex::<usize, T>::new(dimension, planes, &mut rng);
             for (k, v) in vectors.iter().enumerate() {
                 idx.add(k, v);
             }
 
             // Get the stats from these indices
-            let (_, avg, _) = idx.stats();
-            println!("{} => {}", planes, avg);
+            let avg = idx.stats().mean_group_size;
 
             // Keep track of the best we've found so far. Smallest that's not under the target group size
             if avg < best_group_avg && avg > group_size {
@@ -92,123 +598,2920 @@ impl<K:Clone+Eq+Hash+Debug+Send+Sync> MultiIndex<K> {
         return best_plane_count;
     }
 
-    fn vary_key<'a>(index: &'a HyperIndex<K>, key: &BitVec) -> Vec<(&'a HyperIndex<K>, BitVec)>
+    /// Given a set of vectors, discover a bucket width for `HashFamily::PStable` which achieves a particular group size with
+    /// the given number of planes
+    pub fn autotune_bucket_width<R : Rng + Sized>(dimension: usize, plane_count: u8, group_size: f32, vectors: &[Vec<T>], rng: &mut R) -> f32
     {
-        let mut result = vec![(index, key.clone())];
-        for i in 0..key.len()
-        {
-            let mut k = key.clone();
-            k.set(i, !k[i]);
-            result.push((index, k));
+        // Every candidate width uses the same projections, so wider buckets always means larger groups
+        let seed = rng.gen::<u64>();
+        let mean_group_size = |width: f32| {
+            let mut idx = HyperIndex::<usize, T>::new_with_family(dimension, plane_count, HashFamily::PStable { width }, &mut ChaCha20Rng::seed_from_u64(seed));
+            for (k, v) in vectors.iter().enumerate() {
+                idx.add(k, v);
+            }
+            idx.stats().mean_group_size
+        };
+
+        // Find widths either side of the target by doubling
+        let mut hi = 1f32;
+        while mean_group_size(hi) < group_size && hi < f32::MAX / 2f32 {
+            hi *= 2f32;
+        }
+        let mut lo = hi / 2f32;
+        while mean_group_size(lo) >= group_size && lo > f32::MIN_POSITIVE * 2f32 {
+            hi = lo;
+            lo /= 2f32;
         }
-        return result;
-    }
 
-    pub fn nearest<F>(&self, point: &Vec<f32>, count: usize, get_dist: F) -> Vec<DistanceNode<K>>
-        where F : Fn(&Vec<f32>, &K) -> f32 + Send + Sync
-    {
-        // Get a key from each hyperindex
-        // Vary that to all adjacent keys
-        // Query indices
-        // Dedupe by collecting into an intermediate hashset
-        // Get distance from each item to original query point
-        let mut result = self.nearest_points_set(point)
-            .into_par_iter()
-            .map(|a| DistanceNode { distance: get_dist(point, &a), key: a })
-            .collect::<Vec<_>>();
+        // Then bisect, keeping the smallest width that's not under the target group size
+        for _ in 0..12 {
+            let mid = (lo + hi) / 2f32;
+            if mean_group_size(mid) < group_size {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
 
-        // Sort (small->large)
-        // Truncate to the first `count` items
-        result.sort_unstable();
-        result.truncate(count);
-        result.shrink_to_fit();
+        return hi;
+    }
 
-        return result;
+    /// Given a set of vectors, discover a plane count which achieves a particular group size and then the number of indices
+    /// needed for queries to return (on average) at least `candidate_count` candidates.
+    ///
+    /// The first few hundred vectors are used as sample queries. At most `max_index_count` indices are tried.
+    pub fn autotune<R : Rng + Sized>(dimension: usize, group_size: f32, candidate_count: usize, max_index_count: u8, vectors: &[Vec<T>], rng: &mut R) -> IndexConfig
+    {
+        return Self::autotune_with_progress(dimension, group_size, candidate_count, max_index_count, vectors, rng, |_| {});
     }
 
-    pub fn nearest_points(&self, point: &Vec<f32>) -> Vec<K>
+    /// Same as `autotune`, reporting every configuration which is tried to `progress`
+    pub fn autotune_with_progress<R, P>(dimension: usize, group_size: f32, candidate_count: usize, max_index_count: u8, vectors: &[Vec<T>], mut rng: &mut R, mut progress: P) -> IndexConfig
+        where R : Rng + Sized, P : FnMut(Progress)
     {
-        // Get a key from each hyperindex
-        // Vary that to all adjacent keys
-        // Query indices
-        // Dedupe by collecting into an intermediate hashset
-        // Get distance from each item to original query point
-        let result = self.nearest_points_set(point)
-            .into_iter()
-            .collect::<Vec<_>>();
+        let plane_count = Self::autotune_planes_with_progress(dimension, group_size, vectors, &mut rng, &mut progress);
 
-        return result;
+        let queries = &vectors[..vectors.len().min(AUTOTUNE_SAMPLE_QUERIES)];
+        let index_count = Self::autotune_index_count(dimension, plane_count, max_index_count, vectors, queries, rng, progress, |candidates| {
+            mean_len(candidates) >= candidate_count as f32
+        });
+
+        return IndexConfig { index_count: index_count.unwrap_or(max_index_count), plane_count };
     }
 
-    pub fn nearest_points_set(&self, point: &Vec<f32>) -> HashSet<K>
+    /// Search plane and index counts for the cheapest configuration (smallest average candidate set) which achieves the
+    /// target recall@k over the sample queries. `ground_truth` must contain the true `k` nearest neighbours of each query,
+    /// as indices into `vectors`, ranked by `metric`. Returns `None` if no configuration reaches the target.
+    #[allow(clippy::too_many_arguments)]
+    pub fn autotune_recall<R : Rng + Sized>(dimension: usize, vectors: &[Vec<T>], queries: &[Vec<T>], ground_truth: &[Vec<usize>], k: usize, target_recall: f32, max_index_count: u8, metric: Metric, mut rng: &mut R) -> Option<IndexConfig>
     {
-        // Get a key from each hyperindex
-        // Vary that to all adjacent keys
-        // Query indices
-        // Dedupe by collecting into a hashset
-        return self.indices.par_iter()
-            .flat_map(|i| Self::vary_key(i, &i.key(&point)))
-            .flat_map(|i| i.0.group(&i.1))
-            .flat_map(|r| r)
-            .map(|a| a.clone())
-            .collect::<HashSet<K>>();
+        assert_eq!(queries.len(), ground_truth.len(), "every query must have a ground truth");
+
+        // Past this many planes almost every point is in a group on its own
+        let max_planes = (vectors.len().max(2).ilog2() + 2).min(u8::MAX as u32) as u8;
+
+        let mut best: Option<(f32, IndexConfig)> = None;
+        for plane_count in 1..=max_planes {
+            let mut cost = 0f32;
+            let index_count = Self::autotune_index_count(dimension, plane_count, max_index_count, vectors, queries, &mut rng, |_| {}, |candidates| {
+                let recall = candidates.par_iter().zip(queries.par_iter()).zip(ground_truth.par_iter())
+                    .map(|((c, q), truth)| {
+                        let mut ranked = c.iter().map(|i| DistanceNode { distance: metric.distance(q, &vectors[*i]), key: *i }).collect::<Vec<_>>();
+                        ranked.sort_unstable();
+                        ranked.truncate(k);
+                        recall(&ranked.iter().map(|n| n.key).collect::<Vec<_>>(), truth, k)
+                    })
+                    .sum::<f32>() / (queries.len().max(1) as f32);
+
+                cost = mean_len(candidates);
+                return recall >= target_recall;
+            });
+
+            if let Some(index_count) = index_count {
+                if best.map(|(c, _)| cost < c).unwrap_or(true) {
+                    best = Some((cost, IndexConfig { index_count, plane_count }));
+                }
+            }
+        }
+
+        return best.map(|(_, c)| c);
     }
 
-    pub fn add(&mut self, key: K, vector: &Vec<f32>)
+    // Add indices one at a time, accumulating the candidates each sample query gets from all of the indices so far, until `done` returns true.
+    // Returns the number of indices needed, or None if `max_index_count` was not enough.
+    #[allow(clippy::too_many_arguments)]
+    fn autotune_index_count<R, P, F>(dimension: usize, plane_count: u8, max_index_count: u8, vectors: &[Vec<T>], queries: &[Vec<T>], mut rng: &mut R, mut progress: P, mut done: F) -> Option<u8>
+        where R : Rng + Sized, P : FnMut(Progress), F : FnMut(&Vec<HashSet<usize>>) -> bool
     {
-        self.indices.par_iter_mut()
-            .for_each(|idx| idx.add(key.clone(), vector));
-    }
+        let mut candidates = vec![HashSet::new(); queries.len()];
+        for index_count in 1..=max_index_count {
+            progress(Progress::Candidate(IndexConfig { index_count, plane_count }));
+            let mut idx = HyperIndex::<usize, T>::new(dimension, plane_count, &mut rng);
+            for (k, v) in vectors.iter().enumerate() {
+                idx.add(k, v);
+            }
 
-    pub fn dimensions(&self) -> usize {
-        self.indices[0].dimensions()
+            candidates.par_iter_mut().zip(queries.par_iter()).for_each(|(set, q)| {
+                for group in idx.groups_within(&idx.key(q), QueryOptions::default().probe_radius) {
+                    set.extend(group.iter().cloned());
+                }
+            });
+
+            if done(&candidates) {
+                return Some(index_count);
+            }
+        }
+
+        return None;
     }
+}
 
-    pub fn planes_len(&self) -> usize {
-        self.indices[0].planes_len()
+impl<K:Clone+Eq+Hash+Debug+Send+Sync, T:Scalar, V:Send+Sync> MultiIndex<K, T, V> {
+    /// Start configuring a new index with a `MultiIndexBuilder`
+    pub fn builder(dimension: usize) -> MultiIndexBuilder<K, T, V> {
+        MultiIndexBuilder::new(dimension)
     }
 
-    pub fn indices_len(&self) -> usize {
-        self.indices.len()
+    /// The maximum number of items and the eviction policy, if the index was built with a capacity
+    pub fn capacity(&self) -> Option<(usize, Eviction)> {
+        return self.capacity.as_ref().map(|c| (c.limit(), c.policy()));
     }
-}
 
-#[cfg(test)]
-mod tests
-{
-    use rand::prelude::*;
-    use std::collections::HashSet;
+    // Track newly inserted keys and evict the oldest items until the index is back within its capacity
+    fn inserted<'a, I>(&mut self, keys: I)
+        where I : IntoIterator<Item=&'a K>, K : 'a
+    {
+        let limit = match self.capacity.as_ref() {
+            Some(capacity) => {
+                capacity.inserted(keys);
+                capacity.limit()
+            },
+            None => return,
+        };
 
-    extern crate time;
-    use time::Instant;
+        while self.len() > limit {
+            match self.capacity.as_ref().and_then(|c| c.pop_oldest()) {
+                Some(key) => self.evict(&key),
+                None => break,
+            }
+        }
+    }
 
-    use crate::multiindex::MultiIndex;
-    use crate::vector::{ random_unit_vector, euclidean_distance };
+    // Keys of a batch which is about to be moved into the index, only needed if there is a capacity to track them for
+    fn inserted_keys(&self, items: &[(K, Vec<T>)]) -> Vec<K> {
+        return match self.capacity {
+            Some(_) => items.iter().map(|(k, _)| k.clone()).collect(),
+            None => Vec::new(),
+        };
+    }
 
-    #[test]
-    fn new_creates_index() {
-        let a = MultiIndex::<usize>::new(300, 15, 10, &mut thread_rng());
+    fn emit_added(&self, items: &[(K, Vec<T>)]) {
+        if let Some(listener) = self.listener.as_ref() {
+            for (key, vector) in items.iter() {
+                listener.event(IndexEvent::KeyAdded { key: key.clone(), vector: vector.clone() });
+            }
+        }
+    }
 
-        assert_eq!(300, a.dimensions());
-        assert_eq!(10, a.planes_len());
-        assert_eq!(15, a.indices_len());
+    fn evict(&mut self, key: &K) {
+        // With stored vectors the groups the key is in are known, otherwise every group has to be searched
+        let vector = match self.vectors.as_mut().map(Arc::make_mut) {
+            Some(vectors) => vectors.remove(key),
+            None => {
+                self.remove_where(|k| k == key);
+                return;
+            }
+        };
+
+        if let Some(vector) = vector {
+            let indices = &mut self.indices;
+            let removed = install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+                .map(|idx| idx.remove_vector(key, &vector) as usize)
+                .max()
+                .unwrap_or(0));
+            self.payloads.remove(key);
+            self.timestamps.remove(key);
+            Arc::make_mut(&mut self.codes).remove(key);
+            self.record_remove(removed);
+            self.emit(|| IndexEvent::KeyRemoved { key: key.clone() });
+        }
     }
 
-    #[test]
-    fn autotune()
-    {
-        let mut vectors = Vec::new();
+    fn encode(&mut self, key: &K, vector: &[T]) {
+        if let Some(quantizer) = self.quantizer.as_ref() {
+            let code = quantizer.encode(vector);
+            Arc::make_mut(&mut self.codes).insert(key.clone(), code);
+        }
+    }
 
-        println!("Generating...");
-        let mut rng = thread_rng();
-        for _ in 0..5000usize {
-            let v = random_unit_vector(100, &mut rng).into_iter().map(|v| v + 0.5f32).collect();
-            vectors.push(v);
+    fn encode_batch(&mut self, items: &[(K, Vec<T>)]) {
+        if let Some(quantizer) = self.quantizer.as_ref() {
+            let codes = install(&self.pool, || items.par_iter()
+                .map(|(k, v)| (k.clone(), quantizer.encode(v)))
+                .collect::<Vec<_>>());
+            Arc::make_mut(&mut self.codes).extend(codes);
         }
-        println!("Done");
+    }
 
-        let plane_count = MultiIndex::<usize>::autotune_planes(100, 10f32, &vectors, &mut thread_rng());
-        println!("{}", plane_count);
+    // Results were returned from a ranked query, which counts as a use of each key for LRU eviction
+    fn hit(&self, result: &[DistanceNode<K, T>]) {
+        if let Some(capacity) = self.capacity.as_ref() {
+            capacity.hit(result.iter().map(|n| &n.key));
+        }
+    }
+
+    /// Build an index configured by `builder` containing every item, which is much faster than creating an empty index and
+    /// calling `add` for each item. The vectors are checked and normalized in parallel, then every sub index is filled in a
+    /// single pass. Every key must be unique.
+    #[cfg(feature = "rayon")]
+    pub fn from_vectors<I>(builder: MultiIndexBuilder<K, T, V>, items: I) -> Result<MultiIndex<K, T, V>, HypernonsenseError>
+        where I : IntoParallelIterator<Item=(K, Vec<T>)>
+    {
+        let mut index = builder.build()?;
+        let items = items.into_par_iter();
+        let items = install(&index.pool, || items
+            .map(|(k, v)| index.checked_owned(v).map(|v| (k, v)))
+            .collect::<Result<Vec<_>, _>>())?;
+        index.fill(items);
+        return Ok(index);
+    }
+
+    /// Build an index configured by `builder` containing every item, which is much faster than creating an empty index and
+    /// calling `add` for each item. Every key must be unique.
+    #[cfg(not(feature = "rayon"))]
+    pub fn from_vectors<I>(builder: MultiIndexBuilder<K, T, V>, items: I) -> Result<MultiIndex<K, T, V>, HypernonsenseError>
+        where I : IntoIterator<Item=(K, Vec<T>)>
+    {
+        let mut index = builder.build()?;
+        let items = items.into_iter()
+            .map(|(k, v)| index.checked_owned(v).map(|v| (k, v)))
+            .collect::<Result<Vec<_>, _>>()?;
+        index.fill(items);
+        return Ok(index);
+    }
+
+    // Check a vector and apply the normalization policy, without copying it if it doesn't need to change
+    pub(crate) fn checked_owned(&self, vector: Vec<T>) -> Result<Vec<T>, HypernonsenseError> {
+        check_vector(&vector, self.dimensions())?;
+        return match self.normalized(&vector)? {
+            Cow::Borrowed(_) => Ok(vector),
+            Cow::Owned(v) => Ok(v),
+        };
+    }
+
+    // Add items which have already been checked and normalized to an empty index
+    fn fill(&mut self, items: Vec<(K, Vec<T>)>) {
+        let indices = &mut self.indices;
+        install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+            .for_each(|idx| idx.add_batch(&items)));
+        self.record_insert(items.len());
+        self.emit_added(&items);
+
+        self.encode_batch(&items);
+        let keys = self.inserted_keys(&items);
+        if let Some(vectors) = self.vectors.as_mut().map(Arc::make_mut) {
+            vectors.reserve(items.len());
+            vectors.extend(items);
+        }
+        self.inserted(keys.iter());
+    }
+
+    pub fn nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_with(point, count, &QueryOptions::default(), get_dist);
+    }
+
+    /// Find the nearest `count` points, returns an error instead of panicking if the query vector is not valid or the index is empty
+    pub fn try_nearest<F>(&self, point: &[T], count: usize, get_dist: F) -> Result<Vec<DistanceNode<K, T>>, HypernonsenseError>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        check_vector(point, self.dimensions())?;
+        let point = self.normalized(point)?;
+        if self.is_empty() {
+            return Err(HypernonsenseError::EmptyIndex);
+        }
+
+        return Ok(self.nearest(&point, count, get_dist));
+    }
+
+    /// Find the nearest `count` points, using the given query options
+    pub fn nearest_with<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        // Get a key from each hyperindex
+        // Vary that to all keys within the probe radius
+        // Query indices
+        // Dedupe by collecting into an intermediate hashset
+        // Get distance from each item to original query point
+        // Sort (small->large)
+        // Truncate to the first `count` items
+        if let Some(budget) = options.time_budget {
+            return self.nearest_within(point, None, count, options, budget, get_dist);
+        }
+
+        let point = &*self.normalized_or_panic(point);
+        return self.nearest_keyed(point, |_, i| i.key(point), count, options, |_| true, get_dist);
+    }
+
+    /// Find the nearest `count` points like `nearest_with`, ranking only the candidates which match the predicate.
+    /// Candidates are filtered before any distances are measured and before `max_candidates` or `max_probe_radius` are
+    /// applied, so the filtered out items don't use up any of the query's candidates. `time_budget` is not used.
+    pub fn nearest_filtered<F, P>(&self, point: &[T], count: usize, options: &QueryOptions, keep: P, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync, P : Fn(&K) -> bool + Send + Sync
+    {
+        let point = &*self.normalized_or_panic(point);
+        return self.nearest_keyed(point, |_, i| i.key(point), count, options, keep, get_dist);
+    }
+
+    /// Find the nearest `count` points like `nearest_filtered`, never returning any of the excluded keys (e.g. items which
+    /// have already been shown to a user)
+    pub fn nearest_excluding<F>(&self, point: &[T], count: usize, options: &QueryOptions, exclude: &HashSet<K>, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_filtered(point, count, options, |k| !exclude.contains(k), get_dist);
+    }
+
+    /// Find the nearest `count` points like `nearest_with`, using the keys of the query point which were already calculated
+    /// with `query_keys` (one for each sub index, in order). This skips projecting the point onto the planes, which is a
+    /// large part of the cost of a query, when the same point is queried repeatedly. The point is still needed to measure
+    /// distances.
+    ///
+    /// Panics if there isn't a key with the right number of bits for every sub index.
+    pub fn nearest_with_keys<F>(&self, point: &[T], keys: &[BitVec], count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        assert_eq!(self.indices.len(), keys.len(), "there must be one key for every sub index");
+        assert!(keys.iter().zip(self.indices.iter()).all(|(k, i)| k.len() == i.key_len()), "key does not have the length of the sub index keys");
+
+        if let Some(budget) = options.time_budget {
+            return self.nearest_within(point, Some(keys.to_vec()), count, options, budget, get_dist);
+        }
+
+        let point = &*self.normalized_or_panic(point);
+        return self.nearest_keyed(point, |n, _| keys[n].clone(), count, options, |_| true, get_dist);
+    }
+
+    /// The key of a query point in every sub index, which can be kept and passed to `nearest_with_keys`
+    pub fn query_keys(&self, point: &[T]) -> Vec<BitVec> {
+        let point = self.normalized_or_panic(point);
+        return self.indices.iter().map(|i| i.key(&point)).collect();
+    }
+
+    // Query with a normalized point, `key` gives the key of the point in the sub index at each position. Only candidates
+    // which `keep` accepts are ranked.
+    fn nearest_keyed<F, G, P>(&self, point: &[T], key: G, count: usize, options: &QueryOptions, keep: P, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync, G : Fn(usize, &HyperIndex<K, T>) -> BitVec + Send + Sync, P : Fn(&K) -> bool + Send + Sync
+    {
+        let mut timer = self.start_query();
+        let (result, candidates, radius) = install(&self.pool, || {
+            let mut candidates = self.candidates(&key, options.probe_radius, &mut timer);
+            candidates.retain(|k| keep(k));
+            let mut radius = options.probe_radius;
+            let mut keys = None;
+            if options.max_probe_radius.is_some() && candidates.len() < count {
+                let keys = keys.get_or_insert_with(|| self.indices.iter().enumerate().map(|(n, i)| key(n, i)).collect::<Vec<_>>());
+                radius = self.widen(keys, &mut candidates, count, options, &keep);
+                timer.lap(|p| &mut p.probing);
+            }
+            if options.prunes(candidates.len()) {
+                let keys = keys.get_or_insert_with(|| self.indices.iter().enumerate().map(|(n, i)| key(n, i)).collect::<Vec<_>>());
+                self.cap(keys, radius, &mut candidates, options);
+                timer.lap(|p| &mut p.collection);
+            }
+
+            let candidate_count = candidates.len();
+            let result = rank_borrowed(candidates, options.shortlist_len(count), |k| get_dist(point, k));
+            let result = self.rerank(point, result, count, options);
+            timer.lap(|p| &mut p.ranking);
+            (result, candidate_count, radius)
+        });
+
+        self.record_query(timer, radius, candidates, result.len());
+        self.hit(&result);
+        return result;
+    }
+
+    // Refine the results like `nearest_anytime` until the time budget has been used
+    fn nearest_within<F>(&self, point: &[T], keys: Option<Vec<BitVec>>, count: usize, options: &QueryOptions, budget: Duration, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let start = Instant::now();
+        let mut timer = self.start_query();
+        let point = self.normalized_or_panic(point).into_owned();
+        let keys = keys.unwrap_or_else(|| self.indices.iter().map(|i| i.key(&point)).collect());
+        let mut query = self.anytime(point, keys, options.shortlist_len(count), options, get_dist);
+        query.deadline = Some(start + budget);
+        let mut result = Vec::new();
+        for better in query.by_ref() {
+            result = better;
+        }
+        timer.lap(|p| &mut p.probing);
+
+        let result = self.rerank(&query.point, result, count, options);
+        timer.lap(|p| &mut p.ranking);
+
+        self.record_query(timer, options.probe_radius, query.seen.len(), result.len());
+        self.hit(&result);
+        return result;
+    }
+
+    // Drop the candidates found in fewer than `min_votes` sub indices, then keep only the `max_candidates` which were found
+    // in the most sub indices if there are more than that
+    fn cap<'a>(&'a self, keys: &[BitVec], radius: u8, candidates: &mut HashSet<&'a K>, options: &QueryOptions) {
+        if !options.prunes(candidates.len()) {
+            return;
+        }
+
+        let mut votes = HashMap::<&K, u32>::with_capacity(candidates.len());
+        for (idx, key) in self.indices.iter().zip(keys.iter()) {
+            for group in idx.groups_within(key, radius) {
+                for k in group.iter().filter(|k| candidates.contains(k)) {
+                    *votes.entry(k).or_default() += 1;
+                }
+            }
+        }
+
+        // Break ties the same way as the ranking, so a capped query is still deterministic
+        let mut ranked = votes.into_iter().filter(|(_, v)| *v >= options.min_votes as u32).collect::<Vec<_>>();
+        if let Some(max) = options.max_candidates.filter(|max| ranked.len() > *max) {
+            ranked.sort_unstable_by_key(|(k, v)| (std::cmp::Reverse(*v), tie_break(k)));
+            ranked.truncate(max);
+        }
+
+        candidates.clear();
+        candidates.extend(ranked.into_iter().map(|(k, _)| k));
+    }
+
+    // Probe one more ring of groups around the keys at a time until there are `count` candidates which `keep` accepts or the
+    // maximum radius of the options has been probed, returns the final radius
+    fn widen<'a, P>(&'a self, keys: &[BitVec], candidates: &mut HashSet<&'a K>, count: usize, options: &QueryOptions, keep: &P) -> u8
+        where P : Fn(&K) -> bool
+    {
+        let max_radius = options.max_probe_radius.unwrap_or(0).min(self.indices[0].key_len().min(u8::MAX as usize) as u8);
+        let mut radius = options.probe_radius;
+        while candidates.len() < count && radius < max_radius {
+            radius += 1;
+            for (idx, key) in self.indices.iter().zip(keys.iter()) {
+                for group in idx.groups_at(key, radius) {
+                    candidates.extend(group.iter().filter(|k| keep(k)));
+                }
+            }
+        }
+        return radius;
+    }
+
+    /// Find every pair of items within `threshold` of each other by the index metric, using the stored vectors. Only items
+    /// which share a group in at least one sub index are compared, near duplicates almost always do. Each pair is reported
+    /// once, in no particular order.
+    ///
+    /// Every pair within each group is compared, so this is slow if the groups are large.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn duplicate_pairs(&self, threshold: T) -> Vec<(K, K, T)>
+    {
+        let vectors = self.vectors.as_ref().expect("duplicate_pairs requires vector storage to be enabled");
+        let metric = self.metric;
+
+        let found = install(&self.pool, || self.indices.par_iter()
+            .map(|idx| {
+                let mut pairs = Vec::new();
+                for (_, group) in idx.groups_iter() {
+                    for (i, a) in group.iter().enumerate() {
+                        for b in group[i + 1..].iter() {
+                            let distance = metric.distance(&vectors[a], &vectors[b]);
+                            if distance <= threshold && a != b {
+                                pairs.push((a.clone(), b.clone(), distance));
+                            }
+                        }
+                    }
+                }
+                pairs
+            })
+            .collect::<Vec<_>>());
+
+        // The same pair is usually found by several sub indices, possibly the other way around
+        let mut seen = HashSet::new();
+        let mut result = Vec::new();
+        for (a, b, distance) in found.into_iter().flatten() {
+            if !seen.contains(&(b.clone(), a.clone())) && seen.insert((a.clone(), b.clone())) {
+                result.push((a, b, distance));
+            }
+        }
+
+        return result;
+    }
+
+    /// Group items into clusters of near duplicates, where every item is within `threshold` of at least one other item in
+    /// its cluster (see `duplicate_pairs`). Items without a near duplicate are not included.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn find_duplicates(&self, threshold: T) -> Vec<Vec<K>>
+    {
+        return connected(self.duplicate_pairs(threshold).into_iter().map(|(a, b, _)| (a, b)));
+    }
+
+    /// Group items which share a group with each other in at least `min_votes` sub indices, a cheap rough clustering (no
+    /// vectors are needed). Items are clustered together if they are linked by a chain of such pairs. Items which don't
+    /// meet the threshold with any other item are not included.
+    ///
+    /// `min_votes` should be between 1 and `indices_len()`, higher values give smaller and tighter clusters.
+    pub fn clusters(&self, min_votes: usize) -> Vec<Vec<K>>
+    {
+        let found = install(&self.pool, || self.indices.par_iter()
+            .map(|idx| {
+                let mut pairs = Vec::new();
+                for (_, group) in idx.groups_iter() {
+                    for (i, a) in group.iter().enumerate() {
+                        pairs.extend(group[i + 1..].iter().filter(|b| a != *b).map(|b| (a.clone(), b.clone())));
+                    }
+                }
+                pairs
+            })
+            .collect::<Vec<_>>());
+
+        // Count the sub indices each pair shares a group in, whichever way around they were found
+        let mut votes = HashMap::<(K, K), usize>::new();
+        for (a, b) in found.into_iter().flatten() {
+            let reversed = (b, a);
+            if let Some(count) = votes.get_mut(&reversed) {
+                *count += 1;
+            } else {
+                *votes.entry((reversed.1, reversed.0)).or_default() += 1;
+            }
+        }
+
+        return connected(votes.into_iter().filter(|(_, v)| *v >= min_votes.max(1)).map(|(pair, _)| pair));
+    }
+
+    /// Rank every candidate of a query and keep them, so results can be fetched a page at a time ("show more results")
+    /// without querying again. Only `probe_radius` is used from the options.
+    pub fn nearest_paged<F>(&self, point: &[T], options: &QueryOptions, get_dist: F) -> RankedResults<K, T>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let point = &*self.normalized_or_panic(point);
+        let mut timer = self.start_query();
+        let (result, candidates) = install(&self.pool, || {
+            let candidates = self.candidates(|_, i| i.key(point), options.probe_radius, &mut timer);
+            let candidate_count = candidates.len();
+            let result = rank_borrowed(candidates, usize::MAX, |k| get_dist(point, k));
+            timer.lap(|p| &mut p.ranking);
+            (result, candidate_count)
+        });
+
+        self.record_query(timer, options.probe_radius, candidates, result.len());
+        return RankedResults::new(result);
+    }
+
+    /// Find the nearest `count` points to a set of query points, combining them as described by the aggregation. `Mean`
+    /// queries with the mean of the points and uses every option like `nearest_with`. The other aggregations collect the
+    /// candidates of every point within `probe_radius` and measure their distance to every point, no other options are used.
+    ///
+    /// Panics if there are no query points.
+    pub fn nearest_multi<F>(&self, points: &[Vec<T>], aggregation: Aggregation, count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        assert!(!points.is_empty(), "a multi point query needs at least one point");
+
+        if aggregation == Aggregation::Mean {
+            for point in points {
+                check_vector(point, self.dimensions()).unwrap_or_else(|e| panic!("{}", e));
+            }
+            return self.nearest_with(&mean(points).unwrap(), count, options, get_dist);
+        }
+
+        let points = points.iter().map(|p| self.normalized_or_panic(p)).collect::<Vec<_>>();
+        let mut timer = self.start_query();
+        let (result, candidates) = install(&self.pool, || {
+            let mut candidates = HashSet::new();
+            for point in points.iter() {
+                candidates.extend(self.candidates(|_, i| i.key(point), options.probe_radius, &mut timer));
+            }
+
+            let candidate_count = candidates.len();
+            let distances = points.iter().map(|p| p.as_ref());
+            let result = match aggregation {
+                Aggregation::MinDistance => rank_borrowed(candidates, count, |k| distances.clone().map(|p| get_dist(p, k)).fold(T::infinity(), T::min)),
+                _ => rank_borrowed(candidates, count, |k| distances.clone().map(|p| get_dist(p, k)).sum::<T>() / T::from(points.len()).unwrap()),
+            };
+            timer.lap(|p| &mut p.ranking);
+            (result, candidate_count)
+        });
+
+        self.record_query(timer, options.probe_radius, candidates, result.len());
+        self.hit(&result);
+        return result;
+    }
+
+    /// Find the nearest `count` points to a set of query points like `nearest_multi`, ranked by the index metric using the
+    /// stored vectors.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_k_multi(&self, points: &[Vec<T>], aggregation: Aggregation, count: usize, options: &QueryOptions) -> Vec<DistanceNode<K, T>>
+    {
+        let vectors = self.vectors.as_ref().expect("nearest_k_multi requires vector storage to be enabled");
+        let metric = self.metric;
+        return self.nearest_multi(points, aggregation, count, options, |p, k| metric.distance(p, &vectors[k]));
+    }
+
+    // Recompute the distances of a shortlist with the index metric and keep the nearest `count`, if the options ask for it
+    fn rerank(&self, point: &[T], mut shortlist: Vec<DistanceNode<K, T>>, count: usize, options: &QueryOptions) -> Vec<DistanceNode<K, T>> {
+        if options.rerank_top == 0 {
+            return shortlist;
+        }
+
+        let vectors = self.vectors.as_ref().expect("rerank_top requires vector storage to be enabled");
+        for node in shortlist.iter_mut() {
+            node.distance = self.metric.distance(point, &vectors[&node.key]);
+        }
+        shortlist.sort_unstable();
+        shortlist.truncate(count);
+        return shortlist;
+    }
+
+    /// Find the nearest `count` points, yielding a better answer every time more groups have been probed. Groups are probed
+    /// in order of their distance from the query key, closest first, across every sub index. The last item is the same as
+    /// the result of `nearest_with`, a caller with a deadline can stop early and use the latest item instead.
+    ///
+    /// Only `probe_radius` is used from the options.
+    pub fn nearest_anytime<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> AnytimeQuery<'_, K, T, V, F>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let point = self.normalized_or_panic(point).into_owned();
+        let keys = self.indices.iter().map(|i| i.key(&point)).collect();
+        return self.anytime(point, keys, count, options, get_dist);
+    }
+
+    fn anytime<F>(&self, point: Vec<T>, keys: Vec<BitVec>, count: usize, options: &QueryOptions, get_dist: F) -> AnytimeQuery<'_, K, T, V, F>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return AnytimeQuery {
+            index: self,
+            point,
+            count,
+            get_dist,
+            keys,
+            radius: 0,
+            max_radius: options.probe_radius.min(self.indices[0].key_len().min(u8::MAX as usize) as u8) as usize,
+            next: 0,
+            deadline: None,
+            seen: HashSet::new(),
+            best: Vec::new(),
+        };
+    }
+
+    /// Find the nearest `count` points like `nearest_with`, and report which groups of which sub indices every result was
+    /// found in. This is slower than `nearest_with` and isn't reported to the recorder, it's intended for diagnosing poor
+    /// results. Only `probe_radius` is used from the options.
+    pub fn nearest_explained<F>(&self, point: &[T], count: usize, options: &QueryOptions, get_dist: F) -> Vec<ExplainedNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let point = &*self.normalized_or_panic(point);
+        let indices = &self.indices;
+        return install(&self.pool, || {
+            let found = indices.par_iter()
+                .enumerate()
+                .map(|(i, idx)| {
+                    let key = idx.key(point);
+                    idx.groups_within_keyed(&key, options.probe_radius)
+                        .into_iter()
+                        .map(|(group, members)| {
+                            let source = CandidateSource { index: i, group: group.clone(), hamming_distance: hamming_distance(group, &key) };
+                            (source, members)
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            let mut sources = HashMap::<K, Vec<CandidateSource>>::new();
+            for (source, members) in found.into_iter().flatten() {
+                for k in members {
+                    sources.entry(k.clone()).or_default().push(source.clone());
+                }
+            }
+
+            let candidates = sources.keys().cloned().collect::<HashSet<_>>();
+            return rank_candidates(candidates, count, |k| get_dist(point, k))
+                .into_iter()
+                .map(|node| {
+                    let sources = sources.remove(&node.key).unwrap_or_default();
+                    ExplainedNode { node, sources }
+                })
+                .collect();
+        });
+    }
+
+    /// Find the nearest `count` points by first ranking the candidates with their quantized codes, then measuring the exact
+    /// distance (with `get_dist`) to only the best `shortlist` of them. A larger shortlist improves accuracy, at the cost of
+    /// more exact distance calculations.
+    ///
+    /// Panics if there is no quantizer, see `train_quantizer`.
+    pub fn nearest_quantized<F>(&self, point: &[T], count: usize, shortlist: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_quantized_with(point, count, shortlist, &QueryOptions::default(), get_dist);
+    }
+
+    pub fn nearest_quantized_with<F>(&self, point: &[T], count: usize, shortlist: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let quantizer = self.quantizer.as_ref().expect("nearest_quantized requires a quantizer, see train_quantizer");
+        let point = &*self.normalized_or_panic(point);
+        let mut timer = self.start_query();
+        let (result, candidates) = install(&self.pool, || {
+            let candidates = self.candidates(|_, i| i.key(point), options.probe_radius, &mut timer);
+            let candidate_count = candidates.len();
+
+            let table = quantizer.distance_table(point, self.metric);
+            let mut approximate = candidates.into_par_iter()
+                .filter_map(|k| self.codes.get(k).map(|c| DistanceNode { distance: table.distance(c), key: k }))
+                .collect::<Vec<_>>();
+            approximate.sort_unstable();
+            approximate.truncate(shortlist.max(count));
+
+            let shortlist = approximate.into_iter().map(|n| n.key).collect::<HashSet<_>>();
+            let result = rank_borrowed(shortlist, count, |k| get_dist(point, k));
+            timer.lap(|p| &mut p.ranking);
+            (result, candidate_count)
+        });
+
+        self.record_query(timer, options.probe_radius, candidates, result.len());
+        self.hit(&result);
+        return result;
+    }
+
+    /// Find the nearest `count` points to every one of the query points. Queries are run in parallel, each individual query runs on a single thread.
+    pub fn nearest_batch<F>(&self, points: &[Vec<T>], count: usize, get_dist: F) -> Vec<Vec<DistanceNode<K, T>>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest_batch_with(points, count, &QueryOptions::default(), get_dist);
+    }
+
+    pub fn nearest_batch_with<F>(&self, points: &[Vec<T>], count: usize, options: &QueryOptions, get_dist: F) -> Vec<Vec<DistanceNode<K, T>>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        // Each thread keeps one candidate set and reuses it for every query it runs
+        return install(&self.pool, || points.par_iter()
+            .map_init(HashSet::new, |candidates, point| {
+                let point = &*self.normalized_or_panic(point);
+                let mut timer = self.start_query();
+                candidates.clear();
+                let mut keys = Vec::with_capacity(self.indices.len());
+                for idx in self.indices.iter() {
+                    let key = idx.key(point);
+                    timer.lap(|p| &mut p.keys);
+                    let groups = idx.groups_within(&key, options.probe_radius);
+                    timer.lap(|p| &mut p.probing);
+                    for group in groups {
+                        candidates.extend(group.iter());
+                    }
+                    timer.lap(|p| &mut p.collection);
+                    keys.push(key);
+                }
+                let radius = self.widen(&keys, candidates, count, options, &|_: &K| true);
+                timer.lap(|p| &mut p.probing);
+                self.cap(&keys, radius, candidates, options);
+                timer.lap(|p| &mut p.collection);
+
+                let candidate_count = candidates.len();
+                let mut ranked = candidates.drain()
+                    .map(|a| DistanceNode { distance: get_dist(point, a), key: a })
+                    .collect::<Vec<_>>();
+                ranked.sort_unstable();
+                ranked.truncate(options.shortlist_len(count));
+                let result = self.rerank(point, ranked.iter().map(DistanceNode::cloned).collect(), count, options);
+                timer.lap(|p| &mut p.ranking);
+
+                self.record_query(timer, radius, candidate_count, result.len());
+                self.hit(&result);
+                result
+            })
+            .collect());
+    }
+
+    /// Batch version of `nearest_k`, ranks candidates by the index metric using the stored vectors.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_k_batch(&self, points: &[Vec<T>], count: usize) -> Vec<Vec<DistanceNode<K, T>>>
+    {
+        let vectors = self.vectors.as_ref().expect("nearest_k_batch requires vector storage to be enabled");
+        let metric = self.metric;
+
+        return self.nearest_batch(points, count, |p, k| metric.distance(p, &vectors[k]));
+    }
+
+    /// Find the nearest `count` points, ranked by the index metric using the stored vectors.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_k(&self, point: &[T], count: usize) -> Vec<DistanceNode<K, T>>
+    {
+        return self.nearest_k_with(point, count, &QueryOptions::default());
+    }
+
+    pub fn nearest_k_with(&self, point: &[T], count: usize, options: &QueryOptions) -> Vec<DistanceNode<K, T>>
+    {
+        let vectors = self.vectors.as_ref().expect("nearest_k requires vector storage to be enabled");
+        let metric = self.metric;
+
+        // Every candidate came out of the index, so it must have a stored vector
+        return self.nearest_with(point, count, options, |p, k| metric.distance(p, &vectors[k]));
+    }
+
+    /// Find the nearest `count` points along with the payload attached to each one (if any)
+    pub fn nearest_with_payload<F>(&self, point: &[T], count: usize, get_dist: F) -> Vec<(DistanceNode<K, T>, Option<&V>)>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        return self.nearest(point, count, get_dist)
+            .into_iter()
+            .map(|n| {
+                let payload = self.payloads.get(&n.key);
+                (n, payload)
+            })
+            .collect();
+    }
+
+    /// Find the nearest `count` neighbours of a key which is already in the index, not including the key itself.
+    /// Returns `None` if the key has no stored vector.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_to_key(&self, key: &K, count: usize) -> Option<Vec<DistanceNode<K, T>>>
+    {
+        let vectors = self.vectors.as_ref().expect("nearest_to_key requires vector storage to be enabled");
+        let point = vectors.get(key)?;
+
+        let mut result = self.nearest_k(point, count + 1);
+        result.retain(|n| &n.key != key);
+        result.truncate(count);
+
+        return Some(result);
+    }
+
+    /// Find every point within `max_distance` of the query point, sorted by distance (small->large).
+    ///
+    /// The probe radius starts at zero and is expanded one bit at a time until a step finds no new points within range.
+    pub fn within_radius<F>(&self, point: &[T], max_distance: T, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&[T], &K) -> T + Send + Sync
+    {
+        let point = &*self.normalized_or_panic(point);
+        let mut timer = self.start_query();
+        let mut checked = HashSet::new();
+        let mut result = Vec::new();
+
+        // Expand until the whole key space has been covered, keys are longer than the plane count unless the hash family
+        // has one bit per plane
+        let max_radius = self.indices[0].key_len().min(u8::MAX as usize) as u8;
+        let mut probed = 0;
+        for radius in 0..=max_radius {
+            probed = radius;
+            let options = QueryOptions::default().with_probe_radius(radius);
+
+            // Only measure candidates which were not already checked at a smaller radius
+            let candidates = self.candidates(|_, i| i.key(point), options.probe_radius, &mut timer)
+                .into_iter()
+                .filter(|k| !checked.contains(k))
+                .collect::<Vec<_>>();
+            let found = install(&self.pool, || candidates.par_iter()
+                .map(|k| DistanceNode { distance: get_dist(point, k), key: *k })
+                .filter(|n| n.distance <= max_distance)
+                .map(|n| n.cloned())
+                .collect::<Vec<_>>());
+            timer.lap(|p| &mut p.ranking);
+
+            checked.extend(candidates);
+            if found.is_empty() && !result.is_empty() {
+                break;
+            }
+            result.extend(found);
+        }
+
+        result.sort_unstable();
+        timer.lap(|p| &mut p.ranking);
+        self.record_query(timer, probed, checked.len(), result.len());
+        self.hit(&result);
+        return result;
+    }
+
+    pub fn nearest_points(&self, point: &[T]) -> Vec<K>
+    {
+        return self.nearest_points_with(point, &QueryOptions::default());
+    }
+
+    pub fn nearest_points_with(&self, point: &[T], options: &QueryOptions) -> Vec<K>
+    {
+        // Get a key from each hyperindex
+        // Vary that to all keys within the probe radius
+        // Query indices
+        // Dedupe by collecting borrowed keys into an intermediate hashset
+        let result = self.nearest_point_refs_with(point, options)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        return result;
+    }
+
+    pub fn nearest_points_set(&self, point: &[T]) -> HashSet<K>
+    {
+        return self.nearest_points_set_with(point, &QueryOptions::default());
+    }
+
+    pub fn nearest_points_set_with(&self, point: &[T], options: &QueryOptions) -> HashSet<K>
+    {
+        return self.nearest_point_refs_with(point, options).into_iter().cloned().collect();
+    }
+
+    /// Get every candidate like `nearest_points_set`, borrowing the keys from the index instead of cloning them
+    pub fn nearest_point_refs(&self, point: &[T]) -> HashSet<&K>
+    {
+        return self.nearest_point_refs_with(point, &QueryOptions::default());
+    }
+
+    pub fn nearest_point_refs_with(&self, point: &[T], options: &QueryOptions) -> HashSet<&K>
+    {
+        let mut timer = self.start_query();
+        let candidates = self.candidates(|_, i| i.key(point), options.probe_radius, &mut timer);
+        self.record_query(timer, options.probe_radius, candidates.len(), candidates.len());
+        return candidates;
+    }
+
+    /// Get every candidate like `nearest_points`, along with its distance from the query by the index metric using the
+    /// stored vectors. The pairs are in no particular order, use `nearest_k` for the nearest few in order.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_points_with_distances(&self, point: &[T]) -> Vec<(K, T)>
+    {
+        return self.nearest_points_with_distances_with(point, &QueryOptions::default());
+    }
+
+    pub fn nearest_points_with_distances_with(&self, point: &[T], options: &QueryOptions) -> Vec<(K, T)>
+    {
+        let vectors = self.vectors.as_ref().expect("nearest_points_with_distances requires vector storage to be enabled");
+        let metric = self.metric;
+        let point = &*self.normalized_or_panic(point);
+
+        let mut timer = self.start_query();
+        let candidates = self.candidates(|_, i| i.key(point), options.probe_radius, &mut timer);
+        let candidate_count = candidates.len();
+        let result = install(&self.pool, || candidates.into_par_iter()
+            .map(|k| {
+                let distance = metric.distance(point, &vectors[k]);
+                (k.clone(), distance)
+            })
+            .collect::<Vec<_>>());
+        timer.lap(|p| &mut p.ranking);
+
+        self.record_query(timer, options.probe_radius, candidate_count, result.len());
+        return result;
+    }
+
+    // Find every key in the groups within the probe radius of the query key in each sub index. The keys are borrowed from the
+    // groups, so the same key found by several sub indices is only hashed and never cloned.
+    fn candidates<F>(&self, key: F, probe_radius: u8, timer: &mut QueryTimer) -> HashSet<&K>
+        where F : Fn(usize, &HyperIndex<K, T>) -> BitVec + Send + Sync
+    {
+        // Get a key from each hyperindex
+        // Vary that to all keys within the probe radius
+        // Query indices
+        let timed = timer.enabled();
+        let found = install(&self.pool, || self.indices.par_iter()
+            .enumerate()
+            .map(|(n, i)| {
+                let start = stopwatch(timed);
+                let key = key(n, i);
+                let keys = since(start);
+
+                let start = stopwatch(timed);
+                let groups = i.groups_within(&key, probe_radius);
+                (groups, keys, since(start))
+            })
+            .collect::<Vec<_>>());
+
+        // The sub indices were searched in parallel, so report the time spent on each one instead of the wall clock time
+        let (keys, probing) = found.iter().fold((Duration::ZERO, Duration::ZERO), |(k, p), f| (k + f.1, p + f.2));
+        timer.add(keys, probing);
+
+        // Dedupe by collecting into a hashset, sized for the most candidates any one sub index found so it rarely grows
+        let largest = found.iter().map(|(groups, _, _)| groups.iter().map(|g| g.len()).sum::<usize>()).max().unwrap_or(0);
+        let mut candidates = HashSet::with_capacity(largest);
+        candidates.extend(found.into_iter().flat_map(|(groups, _, _)| groups).flatten());
+        timer.lap(|p| &mut p.collection);
+
+        return candidates;
+    }
+
+    /// Find every point in the same group (or a nearby group) as a sparse query point
+    pub fn nearest_points_set_sparse(&self, point: &SparseVector<T>, options: &QueryOptions) -> HashSet<K>
+    {
+        let mut timer = self.start_query();
+        let candidates = self.candidates(|_, i| i.key_sparse(point), options.probe_radius, &mut timer);
+        self.record_query(timer, options.probe_radius, candidates.len(), candidates.len());
+        return candidates.into_iter().cloned().collect();
+    }
+
+    /// Find the nearest `count` points to a sparse query point
+    pub fn nearest_sparse<F>(&self, point: &SparseVector<T>, count: usize, options: &QueryOptions, get_dist: F) -> Vec<DistanceNode<K, T>>
+        where F : Fn(&SparseVector<T>, &K) -> T + Send + Sync
+    {
+        let mut timer = self.start_query();
+        let (result, candidates) = install(&self.pool, || {
+            let candidates = self.candidates(|_, i| i.key_sparse(point), options.probe_radius, &mut timer);
+            let candidate_count = candidates.len();
+            let result = rank_borrowed(candidates, count, |k| get_dist(point, k));
+            timer.lap(|p| &mut p.ranking);
+            (result, candidate_count)
+        });
+
+        self.record_query(timer, options.probe_radius, candidates, result.len());
+        self.hit(&result);
+        return result;
+    }
+
+    /// Add an item with a sparse vector. If vector storage is enabled a dense copy of the vector is stored.
+    pub fn add_sparse(&mut self, key: K, vector: &SparseVector<T>)
+    {
+        let indices = &mut self.indices;
+        install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+            .for_each(|idx| idx.add_sparse(key.clone(), vector)));
+        self.record_insert(1);
+
+        if self.vectors.is_some() || self.quantizer.is_some() || self.listener.is_some() {
+            let dense = vector.to_dense(self.dimensions());
+            self.emit(|| IndexEvent::KeyAdded { key: key.clone(), vector: dense.clone() });
+            self.encode(&key, &dense);
+            if let Some(vectors) = self.vectors.as_mut().map(Arc::make_mut) {
+                vectors.insert(key.clone(), dense);
+            }
+        }
+        self.inserted([&key]);
+    }
+
+    pub fn add(&mut self, key: K, vector: &[T])
+    {
+        let vector = self.normalized_or_panic(vector).into_owned();
+        self.add_normalized(key, &vector);
+    }
+
+    fn add_normalized(&mut self, key: K, vector: &[T])
+    {
+        let indices = &mut self.indices;
+        install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+            .for_each(|idx| idx.add(key.clone(), vector)));
+        self.record_insert(1);
+        self.emit(|| IndexEvent::KeyAdded { key: key.clone(), vector: vector.to_vec() });
+
+        self.encode(&key, vector);
+        if let Some(vectors) = self.vectors.as_mut().map(Arc::make_mut) {
+            vectors.insert(key.clone(), vector.to_vec());
+        }
+        self.inserted([&key]);
+    }
+
+    /// Add an item, returns an error instead of panicking if the vector is not valid for this index
+    pub fn try_add(&mut self, key: K, vector: &[T]) -> Result<(), HypernonsenseError>
+    {
+        check_vector(vector, self.dimensions())?;
+        let vector = self.normalized(vector)?;
+        self.add_normalized(key, &vector);
+        return Ok(());
+    }
+
+    /// Add every item read from JSON Lines, one `{"key": ..., "vector": [...]}` object per line (see `jsonl::read_jsonl`).
+    /// The items are streamed in batches, so files larger than memory can be added. Returns the number of items added, or the
+    /// first error. Every item before the batch with the error has been added.
+    pub fn add_jsonl<R: BufRead>(&mut self, reader: R) -> Result<usize, HypernonsenseError>
+        where K : FromStr
+    {
+        const BATCH: usize = 4096;
+
+        let mut items = jsonl::read_jsonl::<K, T, R>(reader);
+        let mut added = 0;
+        loop {
+            let batch = items.by_ref()
+                .take(BATCH)
+                .map(|item| item.and_then(|(k, v)| self.checked_owned(v).map(|v| (k, v))))
+                .collect::<Result<Vec<_>, _>>()?;
+            if batch.is_empty() {
+                return Ok(added);
+            }
+
+            added += batch.len();
+            self.add_batch(batch);
+        }
+    }
+
+    /// Add many items at once, this is much faster than calling `add` for each item
+    pub fn add_batch<I>(&mut self, items: I)
+        where I : IntoIterator<Item=(K, Vec<T>)>
+    {
+        self.add_batch_with_progress(items, |_| {});
+    }
+
+    /// Same as `add_batch`, reporting the number of items added so far to `progress` every few thousand items
+    pub fn add_batch_with_progress<I, P>(&mut self, items: I, mut progress: P)
+        where I : IntoIterator<Item=(K, Vec<T>)>, P : FnMut(Progress)
+    {
+        let items = items.into_iter()
+            .map(|(k, v)| {
+                let v = self.normalized_or_panic(&v).into_owned();
+                (k, v)
+            })
+            .collect::<Vec<_>>();
+
+        let indices = &mut self.indices;
+        let mut processed = 0;
+        for chunk in items.chunks(PROGRESS_CHUNK) {
+            install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+                .for_each(|idx| idx.add_batch(chunk)));
+            processed += chunk.len();
+            progress(Progress::Items { processed, total: items.len() });
+        }
+        self.record_insert(items.len());
+        self.emit_added(&items);
+
+        // Evict after the vectors are stored, so evicted items don't leave their vectors behind
+        self.encode_batch(&items);
+        let keys = self.inserted_keys(&items);
+        if let Some(vectors) = self.vectors.as_mut().map(Arc::make_mut) {
+            vectors.extend(items);
+        }
+        self.inserted(keys.iter());
+    }
+
+    /// Replace every sub index with a fresh set of indices with a new index count and plane count, and re-hash every stored
+    /// vector into them. This requires vector storage. The hash family is kept, and if the planes had fitted offsets (see
+    /// `fit_offsets`) they are fitted again to the stored vectors.
+    ///
+    /// If the index has a seed the new planes are generated from the same seed, so the result is the same as building an index
+    /// with the new parameters from that seed.
+    pub fn rebuild(&mut self, index_count: u8, plane_count: u8) -> Result<(), HypernonsenseError>
+    {
+        return self.rebuild_with_progress(index_count, plane_count, |_| {});
+    }
+
+    /// Same as `rebuild`, reporting the number of vectors re-hashed so far to `progress` every few thousand vectors
+    pub fn rebuild_with_progress<P>(&mut self, index_count: u8, plane_count: u8, progress: P) -> Result<(), HypernonsenseError>
+        where P : FnMut(Progress)
+    {
+        if index_count == 0 {
+            return Err(HypernonsenseError::InvalidConfig("index count must be at least one".to_string()));
+        }
+        let vectors = match self.vectors.as_ref() {
+            Some(vectors) => vectors,
+            None => return Err(HypernonsenseError::InvalidConfig("rebuilding an index requires vector storage".to_string())),
+        };
+
+        let family = self.hash_family();
+        let dimension = self.dimensions();
+        let fitted = family == HashFamily::Hyperplane && self.indices.iter().any(|i| i.offsets().iter().any(|o| !o.is_zero()));
+
+        let mut rng = ChaCha20Rng::seed_from_u64(self.seed.unwrap_or_else(|| rand::thread_rng().gen()));
+        let mut indices = (0..index_count)
+            .map(|_| HyperIndex::new_with_family(dimension, plane_count, family, &mut rng))
+            .collect::<Vec<_>>();
+
+        if fitted && !vectors.is_empty() {
+            let sample = vectors.values().cloned().collect::<Vec<_>>();
+            for index in indices.iter_mut() {
+                index.fit_offsets(&sample)?;
+            }
+        }
+
+        return self.rehash(indices, progress);
+    }
+
+    // Hash every stored vector into new sub indices, and replace the old sub indices with them
+    fn rehash<P>(&mut self, mut indices: Vec<HyperIndex<K, T>>, mut progress: P) -> Result<(), HypernonsenseError>
+        where P : FnMut(Progress)
+    {
+        let vectors = match self.vectors.as_ref() {
+            Some(vectors) => vectors,
+            None => return Err(HypernonsenseError::InvalidConfig("rebuilding an index requires vector storage".to_string())),
+        };
+
+        let items = vectors.iter().collect::<Vec<_>>();
+        let mut processed = 0;
+        for chunk in items.chunks(PROGRESS_CHUNK) {
+            install(&self.pool, || indices.par_iter_mut()
+                .for_each(|idx| {
+                    for (key, vector) in chunk.iter() {
+                        idx.add((*key).clone(), vector);
+                    }
+                }));
+            processed += chunk.len();
+            progress(Progress::Items { processed, total: items.len() });
+        }
+
+        self.indices = indices.into_iter().map(Arc::new).collect();
+        self.emit(|| IndexEvent::Rebuilt { planes: self.export_planes() });
+        return Ok(());
+    }
+
+    /// Insert a key, or move it to the correct groups for a new vector if it is already in the index. Any payload attached to the key is kept.
+    ///
+    /// If vectors are stored only the groups the old vector fell into are searched (and nothing is searched for a new key),
+    /// otherwise every group is searched for the key. Returns true if the key was already in the index.
+    pub fn upsert(&mut self, key: K, vector: &[T]) -> bool
+    {
+        let stores_vectors = self.vectors.is_some();
+        let old = self.vectors.as_mut().map(Arc::make_mut).and_then(|v| v.remove(&key));
+
+        let indices = &mut self.indices;
+        let existed = install(&self.pool, || match old {
+            Some(old) => {
+                indices.par_iter_mut().map(Arc::make_mut)
+                    .for_each(|idx| { idx.remove_vector(&key, &old); });
+                true
+            },
+
+            // Every key in an index which stores vectors has a vector, so this is a new key
+            None if stores_vectors => false,
+            None => {
+                indices.par_iter_mut().map(Arc::make_mut)
+                    .map(|idx| idx.remove(&key))
+                    .reduce_with(|a, b| a || b)
+                    .unwrap_or(false)
+            }
+        });
+
+        if existed {
+            self.emit(|| IndexEvent::KeyRemoved { key: key.clone() });
+        }
+        self.add(key, vector);
+        return existed;
+    }
+
+    /// Make the change described by an event from the listener of another index, so this index mirrors it (e.g. a warm
+    /// standby replica kept in step with `subscribe`). A `Rebuilt` event hashes every stored vector again with the new
+    /// planes, so it requires vector storage.
+    pub fn apply(&mut self, event: IndexEvent<K, T>) -> Result<(), HypernonsenseError>
+    {
+        match event {
+            IndexEvent::KeyAdded { key, vector } => self.try_add(key, &vector)?,
+            IndexEvent::KeyRemoved { key } => {
+                self.remove(&key);
+            },
+            IndexEvent::Rebuilt { planes } => {
+                if let Some(p) = planes.iter().find(|p| p.dimensions() != self.dimensions()) {
+                    return Err(HypernonsenseError::DimensionMismatch { expected: self.dimensions(), actual: p.dimensions() });
+                }
+                let indices = MultiIndex::<K, T>::from_indices(planes.into_iter().map(HyperIndex::with_planes).collect())?;
+                self.rehash(indices.into_indices(), |_| {})?;
+            },
+        }
+
+        return Ok(());
+    }
+
+    /// Add an item with a payload attached, the payload is removed along with the key
+    pub fn add_with_payload(&mut self, key: K, vector: &[T], payload: V)
+    {
+        self.payloads.insert(key.clone(), payload);
+        self.add(key, vector);
+    }
+
+    /// Get the payload attached to a key
+    pub fn payload(&self, key: &K) -> Option<&V>
+    {
+        return self.payloads.get(key);
+    }
+
+    /// Add an item along with the time it was inserted, so it can be removed again by `expire_older_than`. Timestamps are
+    /// not saved with the index.
+    pub fn add_with_timestamp(&mut self, key: K, vector: &[T], timestamp: Instant)
+    {
+        self.timestamps.insert(key.clone(), timestamp);
+        self.add(key, vector);
+    }
+
+    /// Get the timestamp attached to a key with `add_with_timestamp`
+    pub fn timestamp(&self, key: &K) -> Option<Instant>
+    {
+        return self.timestamps.get(key);
+    }
+
+    /// Remove every item which was added with a timestamp before `cutoff`, returns the number of items removed. Items added
+    /// without a timestamp never expire. To keep a sliding window of recent items call this with `Instant::now() - window`.
+    ///
+    /// If vectors are stored only the groups the expired vectors fell into are searched, otherwise every group is searched.
+    pub fn expire_older_than(&mut self, cutoff: Instant) -> usize
+    {
+        let expired = self.timestamps.take_older_than(cutoff);
+        if expired.is_empty() {
+            return 0;
+        }
+
+        let vectors = match self.vectors.as_mut().map(Arc::make_mut) {
+            Some(vectors) => vectors,
+            None => {
+                let expired = expired.into_iter().collect::<HashSet<_>>();
+                return self.remove_where(|k| expired.contains(k));
+            }
+        };
+
+        let old = expired.iter()
+            .filter_map(|k| vectors.remove(k).map(|v| (k, v)))
+            .collect::<Vec<_>>();
+        for key in expired.iter() {
+            self.payloads.remove(key);
+            Arc::make_mut(&mut self.codes).remove(key);
+            if let Some(capacity) = self.capacity.as_ref() {
+                capacity.forget(key);
+            }
+        }
+
+        let indices = &mut self.indices;
+        let removed = install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+            .map(|idx| old.iter().filter(|(k, v)| idx.remove_vector(k, v)).count())
+            .max()
+            .unwrap_or(0));
+
+        self.record_remove(removed);
+        for (key, _) in old.iter() {
+            self.emit(|| IndexEvent::KeyRemoved { key: (*key).clone() });
+        }
+        return removed;
+    }
+
+    /// Get the vector stored for a key. Always returns `None` if vector storage is not enabled.
+    pub fn get(&self, key: &K) -> Option<&[T]>
+    {
+        return self.vectors.as_ref()
+            .and_then(|v| v.get(key))
+            .map(|v| v.as_slice());
+    }
+
+    /// Train a product quantizer on a sample of vectors (e.g. the first few thousand to be inserted) and use it to compress
+    /// every vector added from now on, see `set_quantizer`.
+    pub fn train_quantizer<R : Rng + Sized>(&mut self, sample_vectors: &[Vec<T>], subspaces: usize, rng: &mut R) -> Result<(), HypernonsenseError>
+    {
+        for v in sample_vectors.iter() {
+            check_vector(v, self.dimensions())?;
+        }
+        let quantizer = ProductQuantizer::train(sample_vectors, subspaces, ProductQuantizer::<T>::MAX_CENTROIDS, 20, rng)?;
+        return self.set_quantizer(quantizer);
+    }
+
+    /// Keep a quantized code of every item, so candidates can be ranked approximately with `nearest_quantized` without storing
+    /// full vectors. Stored vectors are encoded immediately, items added earlier without vector storage have no code and are
+    /// never returned by `nearest_quantized`. Replaces any previous quantizer. The quantizer and codes are not saved with the index.
+    pub fn set_quantizer(&mut self, quantizer: ProductQuantizer<T>) -> Result<(), HypernonsenseError>
+    {
+        if quantizer.dimensions() != self.dimensions() {
+            return Err(HypernonsenseError::DimensionMismatch { expected: self.dimensions(), actual: quantizer.dimensions() });
+        }
+
+        let codes = match self.vectors.as_ref() {
+            Some(vectors) => install(&self.pool, || vectors.par_iter()
+                .map(|(k, v)| (k.clone(), quantizer.encode(v)))
+                .collect()),
+            None => HashMap::new(),
+        };
+
+        self.quantizer = Some(Arc::new(quantizer));
+        self.codes = Arc::new(codes);
+        return Ok(());
+    }
+
+    pub fn quantizer(&self) -> Option<&ProductQuantizer<T>>
+    {
+        return self.quantizer.as_deref();
+    }
+
+    /// Get the quantized code of a key, `None` if there is no quantizer or the key was added before it was set
+    pub fn code(&self, key: &K) -> Option<&[u8]>
+    {
+        return self.codes.get(key).map(|c| c.as_slice());
+    }
+
+    /// Remove a key from every sub index, returns true if anything was removed
+    pub fn remove(&mut self, key: &K) -> bool
+    {
+        return self.remove_where(|k| k == key) > 0;
+    }
+
+    /// Remove every key which matches the predicate from every sub index, returns the number of items removed
+    pub fn remove_where<F>(&mut self, predicate: F) -> usize
+        where F : Fn(&K) -> bool + Send + Sync
+    {
+        // Find the keys which are about to be removed, only if there is a listener to tell about them
+        let removed_keys = match self.listener {
+            Some(_) => self.indices[0].groups_iter().flat_map(|(_, g)| g.iter()).filter(|k| predicate(k)).cloned().collect::<HashSet<_>>(),
+            None => HashSet::new(),
+        };
+
+        if let Some(vectors) = self.vectors.as_mut().map(Arc::make_mut) {
+            vectors.retain(|k, _| !predicate(k));
+        }
+        self.payloads.retain(|k, _| !predicate(k));
+        self.timestamps.retain(|k| !predicate(k));
+        if !self.codes.is_empty() {
+            Arc::make_mut(&mut self.codes).retain(|k, _| !predicate(k));
+        }
+        if let Some(capacity) = self.capacity.as_ref() {
+            capacity.forget_where(&predicate);
+        }
+
+        // Every sub index contains the same set of keys, so they should all remove the same number of items
+        let indices = &mut self.indices;
+        let removed = install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+            .map(|idx| idx.remove_where(&predicate))
+            .max()
+            .unwrap_or(0));
+
+        self.record_remove(removed);
+        for key in removed_keys {
+            self.emit(|| IndexEvent::KeyRemoved { key });
+        }
+        return removed;
+    }
+
+    /// Keep only the keys which match the predicate, removing every other key (and its stored vector and payload) from every
+    /// sub index. Returns the number of items removed.
+    pub fn retain<F>(&mut self, keep: F) -> usize
+        where F : Fn(&K) -> bool + Send + Sync
+    {
+        return self.remove_where(|k| !keep(k));
+    }
+
+    /// Remove the empty groups left behind by removals and shrink every group, map and the vector storage to fit what is
+    /// left, reclaiming memory after many items have been removed. Sub indices shared with a snapshot are copied first.
+    pub fn compact(&mut self)
+    {
+        let indices = &mut self.indices;
+        install(&self.pool, || indices.par_iter_mut().map(Arc::make_mut)
+            .for_each(|idx| idx.compact()));
+
+        if let Some(vectors) = self.vectors.as_mut().map(Arc::make_mut) {
+            vectors.shrink_to_fit();
+        }
+        if !self.codes.is_empty() {
+            Arc::make_mut(&mut self.codes).shrink_to_fit();
+        }
+        self.payloads.shrink_to_fit();
+    }
+
+    /// Remove every item from the index, returning the keys and their stored vectors in no particular order. The planes are
+    /// kept, so the index can be filled again. Vectors are moved out rather than copied, unless a snapshot still shares them.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn drain(&mut self) -> impl Iterator<Item=(K, Vec<T>)>
+    {
+        let vectors = self.vectors.as_mut().expect("drain requires vector storage to be enabled");
+        let vectors = match Arc::get_mut(vectors) {
+            Some(vectors) => std::mem::take(vectors),
+            None => std::mem::replace(vectors, Arc::new(HashMap::new())).as_ref().clone(),
+        };
+
+        for idx in self.indices.iter_mut() {
+            match Arc::get_mut(idx) {
+                Some(idx) => idx.clear(),
+                None => *idx = Arc::new(idx.empty_copy()),
+            }
+        }
+        self.payloads.clear();
+        self.timestamps.clear();
+        self.codes = Arc::new(HashMap::new());
+        if let Some(capacity) = self.capacity.as_ref() {
+            capacity.clear();
+        }
+
+        self.record_remove(vectors.len());
+        for key in vectors.keys() {
+            self.emit(|| IndexEvent::KeyRemoved { key: key.clone() });
+        }
+        return vectors.into_iter();
+    }
+
+    /// Take a frozen copy of the index, which can be queried while this index keeps changing. This is cheap, the snapshot
+    /// shares its groups and stored vectors with this index. Whenever this index is modified after a snapshot was taken the
+    /// modified parts are copied first (copy on write), so the first write to each sub index after a snapshot is slower.
+    ///
+    /// Payloads are not included in the snapshot.
+    pub fn snapshot(&self) -> MultiIndex<K, T>
+    {
+        return MultiIndex {
+            indices: self.indices.clone(),
+            vectors: self.vectors.clone(),
+            payloads: HashMap::new(),
+            timestamps: Timestamps::new(),
+            capacity: None,
+            quantizer: self.quantizer.clone(),
+            codes: self.codes.clone(),
+            metric: self.metric,
+            seed: self.seed,
+            normalization: self.normalization,
+            pool: self.pool.clone(),
+            recorder: self.recorder.clone(),
+            listener: None
+        };
+    }
+
+    /// Iterate over every key in the index, in no particular order. A key which has been added more than once is returned once per add.
+    pub fn keys(&self) -> impl Iterator<Item=&K> {
+        // Every sub index contains the same keys, so only one needs to be checked
+        self.indices[0].groups_iter().flat_map(|(_, g)| g.iter())
+    }
+
+    /// Every non empty group of every sub index, with the position of the sub index it is in. Each item appears once in
+    /// every sub index.
+    pub fn groups(&self) -> impl Iterator<Item=(usize, &BitVec, &[K])> {
+        self.indices.iter().enumerate().flat_map(|(i, idx)| idx.groups().map(move |(k, g)| (i, k, g)))
+    }
+
+    /// The bucket of every key in each sub index, with the group key read as an integer (the first bit of the key is the
+    /// lowest bit of the label). Labels are only comparable within one sub index, so they can be used as cheap cluster
+    /// labels for each sub index separately. A key which has been added more than once gets the labels of one of its copies.
+    ///
+    /// Returns an error if the group keys are longer than 64 bits.
+    pub fn bucket_labels(&self) -> Result<HashMap<K, Vec<u64>>, HypernonsenseError> {
+        let key_len = self.indices[0].key_len();
+        if key_len > 64 {
+            return Err(HypernonsenseError::InvalidConfig(format!("cannot label buckets with {} bit keys, at most 64 bits are supported", key_len)));
+        }
+
+        let mut labels: HashMap<&K, Vec<u64>> = HashMap::with_capacity(self.len());
+        for (i, key, group) in self.groups() {
+            let label = key.iter().enumerate().fold(0u64, |acc, (bit, set)| if set { acc | (1 << bit) } else { acc });
+            for k in group {
+                labels.entry(k).or_insert_with(|| vec![0; self.indices.len()])[i] = label;
+            }
+        }
+
+        return Ok(labels.into_iter().map(|(k, l)| (k.clone(), l)).collect());
+    }
+
+    /// Iterate over every key and the stored vector for that key, in no particular order. Returns `None` if vector storage is not enabled.
+    pub fn iter(&self) -> Option<impl Iterator<Item=(&K, &[T])>> {
+        self.vectors.as_ref().map(|v| v.iter().map(|(k, v)| (k, v.as_slice())))
+    }
+
+    /// Number of items in the index. A key which has been added more than once is counted once per add.
+    pub fn len(&self) -> usize {
+        self.indices[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices[0].is_empty()
+    }
+
+    /// Check if a key is in the index. This is a fast lookup if vectors are stored, otherwise every group of one sub index is searched.
+    pub fn contains_key(&self, key: &K) -> bool {
+        match self.vectors.as_ref() {
+            Some(vectors) => vectors.contains_key(key),
+            None => self.indices[0].contains_key(key),
+        }
+    }
+
+    /// Group size statistics of every sub index combined. The item count is the total number of items in all sub indices.
+    pub fn stats(&self) -> IndexStats {
+        return IndexStats::from_histogram(self.group_size_histogram());
+    }
+
+    /// Group size statistics of each sub index and of every sub index combined, and the number of distinct keys. Without
+    /// vector storage counting the distinct keys has to hash every key in one sub index.
+    pub fn index_stats(&self) -> MultiIndexStats {
+        let per_index = self.indices.iter().map(|i| i.stats()).collect::<Vec<_>>();
+        let unique_items = match self.vectors.as_ref() {
+            Some(vectors) => vectors.len(),
+            None => self.keys().collect::<HashSet<_>>().len(),
+        };
+
+        return MultiIndexStats {
+            combined: self.stats(),
+            per_index,
+            unique_items,
+        };
+    }
+
+    /// Total number of groups in every sub index (including groups which have had all of their items removed)
+    pub fn groups_len(&self) -> usize {
+        return self.indices.iter().map(|i| i.groups_len()).sum();
+    }
+
+    /// Number of groups of each size (group size -> group count), combined across every sub index
+    pub fn group_size_histogram(&self) -> BTreeMap<usize, usize> {
+        let mut histogram = BTreeMap::new();
+        for h in self.group_size_histograms() {
+            for (size, count) in h {
+                *histogram.entry(size).or_insert(0) += count;
+            }
+        }
+
+        return histogram;
+    }
+
+    /// The group size histogram of each sub index
+    pub fn group_size_histograms(&self) -> Vec<BTreeMap<usize, usize>> {
+        return self.indices.iter().map(|i| i.group_size_histogram()).collect();
+    }
+
+    /// The key and centroid of every group in a sub index, from the stored vectors. See `HyperIndex::group_centroids`.
+    ///
+    /// Panics if vector storage is not enabled or the sub index doesn't exist.
+    pub fn group_centroids(&self, index: usize) -> impl Iterator<Item=(&BitVec, Vec<T>)> {
+        let vectors = self.vectors.as_ref().expect("group_centroids requires vector storage to be enabled");
+        return self.indices[index].group_centroids(move |k| vectors.get(k).map(|v| v.as_slice()));
+    }
+
+    /// Write every stored item as JSON Lines, one `{"key": ..., "vector": [...]}` object per line in no particular order.
+    /// See `jsonl::write_jsonl`.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn write_jsonl<W: Write>(&self, writer: &mut W) -> io::Result<()>
+        where K : Display
+    {
+        let items = self.iter().expect("write_jsonl requires vector storage to be enabled");
+        return jsonl::write_jsonl(writer, items);
+    }
+
+    /// Write the structure of the index as JSON, for debugging how vectors are being grouped. For every sub index this
+    /// includes the planes, the plane offsets and every group key (as a string of bits) with the number of items in the
+    /// group. If `include_members` is set the keys in each group are included too, as strings.
+    pub fn dump_json<W: Write>(&self, writer: &mut W, include_members: bool) -> io::Result<()>
+        where K : Display
+    {
+        let family = match self.hash_family() {
+            HashFamily::Hyperplane => "{\"type\":\"hyperplane\"}".to_string(),
+            HashFamily::PStable { width } => format!("{{\"type\":\"pstable\",\"width\":{}}}", json::number(width)),
+            HashFamily::CrossPolytope { rotation_dims } => format!("{{\"type\":\"cross_polytope\",\"rotation_dims\":{}}}", rotation_dims),
+        };
+        write!(writer, "{{\"dimensions\":{},\"len\":{},\"family\":{},\"indices\":[", self.dimensions(), self.len(), family)?;
+
+        for (i, index) in self.indices.iter().enumerate() {
+            if i > 0 {
+                write!(writer, ",")?;
+            }
+
+            let planes = index.planes().iter()
+                .map(|p| format!("[{}]", p.iter().map(|x| json::number(*x)).collect::<Vec<_>>().join(",")))
+                .collect::<Vec<_>>();
+            let offsets = index.offsets().iter().map(|x| json::number(*x)).collect::<Vec<_>>();
+            write!(writer, "{{\"planes\":[{}],\"offsets\":[{}],\"groups\":[", planes.join(","), offsets.join(","))?;
+
+            // Sort by key so the output is the same every time
+            let mut groups = index.groups_iter()
+                .map(|(key, members)| (key.iter().map(|b| if b { '1' } else { '0' }).collect::<String>(), members))
+                .collect::<Vec<_>>();
+            groups.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+            for (g, (key, members)) in groups.iter().enumerate() {
+                if g > 0 {
+                    write!(writer, ",")?;
+                }
+                write!(writer, "{{\"key\":\"{}\",\"count\":{}", key, members.len())?;
+                if include_members {
+                    let members = members.iter().map(|k| json::string(&k.to_string())).collect::<Vec<_>>();
+                    write!(writer, ",\"members\":[{}]", members.join(","))?;
+                }
+                write!(writer, "}}")?;
+            }
+
+            write!(writer, "]}}")?;
+        }
+
+        return write!(writer, "]}}");
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.indices[0].dimensions()
+    }
+
+    pub fn planes_len(&self) -> usize {
+        self.indices[0].planes_len()
+    }
+
+    /// The family of hash functions used to assign vectors to groups
+    pub fn hash_family(&self) -> HashFamily {
+        self.indices[0].family()
+    }
+
+    pub fn indices_len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// The planes of every sub index, in order, for creating other indices which hash vectors the same way
+    pub fn export_planes(&self) -> Vec<Planes<T>> {
+        self.indices.iter().map(|i| i.export_planes()).collect()
+    }
+}
+
+impl<K:Send+Sync+Eq+Hash+Persist, T:Scalar+Persist, V:Send+Sync+Persist> MultiIndex<K, T, V> {
+    /// Write this index to the given file in the binary index format
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HypernonsenseError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load an index from a file previously written with `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MultiIndex<K, T, V>, HypernonsenseError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::read_from(&mut reader)
+    }
+
+    /// Write this index to the given writer in the binary index format
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), HypernonsenseError> {
+        write_multiindex(self, writer)?;
+        Ok(())
+    }
+
+    /// Read an index from a reader, the data must have been written by `write_to` or `save`
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<MultiIndex<K, T, V>, HypernonsenseError> {
+        read_multiindex(reader)
+    }
+}
+
+impl<K:Clone+Eq+Hash+Debug+Send+Sync+Persist, T:Scalar+Persist> MultiIndex<K, T> {
+    /// Rebuild an index after a crash from the planes it was built with (see `export_planes`) and its write ahead log, by
+    /// replaying every complete record in the log. An incomplete record at the end of the log is ignored.
+    pub fn recover<P: AsRef<Path>>(planes: Vec<Planes<T>>, wal: P) -> Result<MultiIndex<K, T>, HypernonsenseError> {
+        crate::wal::recover(planes, wal)
+    }
+}
+
+#[cfg(all(unix, target_endian = "little"))]
+impl<K:crate::mmap::MappedKey+Debug, T:Scalar, V:Send+Sync> MultiIndex<K, T, V> {
+    /// Write this index to a file which can be opened with `MappedMultiIndex::open` for zero-copy read only queries. Stored vectors and payloads are not included.
+    pub fn save_mapped<P: AsRef<Path>>(&self, path: P) -> Result<(), HypernonsenseError> {
+        crate::mmap::write_mapped(self, path)
+    }
+
+    /// Write the stored vectors to a file which can be opened with `MappedVectorStore::open`, so they can be used to re-rank
+    /// queries with `nearest_k_mapped` without keeping them in memory. Returns an error if vector storage is not enabled.
+    pub fn save_vectors<P: AsRef<Path>>(&self, path: P) -> Result<(), HypernonsenseError> {
+        let vectors = self.vectors.as_ref()
+            .ok_or_else(|| HypernonsenseError::InvalidConfig("saving vectors requires vector storage to be enabled".to_string()))?;
+
+        let mut writer = crate::vectorstore::VectorStoreWriter::create(path, self.dimensions())?;
+        for (key, vector) in vectors.iter() {
+            writer.push(*key, vector)?;
+        }
+        return writer.finish();
+    }
+
+    /// Find the nearest `count` points like `nearest_k`, measuring distances with the vectors in a memory mapped store
+    /// instead of stored vectors. Candidates which are not in the store are skipped.
+    pub fn nearest_k_mapped(&self, point: &[T], count: usize, store: &crate::vectorstore::MappedVectorStore<K, T>) -> Vec<DistanceNode<K, T>> {
+        return self.nearest_k_mapped_with(point, count, &QueryOptions::default(), store);
+    }
+
+    pub fn nearest_k_mapped_with(&self, point: &[T], count: usize, options: &QueryOptions, store: &crate::vectorstore::MappedVectorStore<K, T>) -> Vec<DistanceNode<K, T>> {
+        let metric = self.metric;
+        return self.nearest_filtered(point, count, options, |k| store.id(k).is_some(), |p, k| metric.distance(p, store.get(k).unwrap()));
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K:Clone+Eq+Hash+Debug+Send+Sync+'static, T:Scalar+'static, V:Send+Sync> MultiIndex<K, T, V> {
+    /// Async version of `nearest`. The query runs on a snapshot of the index, on the thread pool of this index (or the global
+    /// rayon pool), so awaiting it never blocks the executor. Items added after this is called are not seen by the query.
+    pub fn nearest_async<F>(&self, point: Vec<T>, count: usize, get_dist: F) -> QueryFuture<Vec<DistanceNode<K, T>>>
+        where F : Fn(&[T], &K) -> T + Send + Sync + 'static
+    {
+        return self.nearest_with_async(point, count, QueryOptions::default(), get_dist);
+    }
+
+    pub fn nearest_with_async<F>(&self, point: Vec<T>, count: usize, options: QueryOptions, get_dist: F) -> QueryFuture<Vec<DistanceNode<K, T>>>
+        where F : Fn(&[T], &K) -> T + Send + Sync + 'static
+    {
+        let snapshot = self.snapshot();
+        return QueryFuture::spawn(self.thread_pool(), move || snapshot.nearest_with(&point, count, &options, get_dist));
+    }
+
+    /// Async version of `nearest_k`.
+    ///
+    /// Panics if vector storage is not enabled.
+    pub fn nearest_k_async(&self, point: Vec<T>, count: usize) -> QueryFuture<Vec<DistanceNode<K, T>>>
+    {
+        assert!(self.stores_vectors(), "nearest_k_async requires vector storage to be enabled");
+
+        let snapshot = self.snapshot();
+        return QueryFuture::spawn(self.thread_pool(), move || snapshot.nearest_k(&point, count));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use std::time::{Duration, Instant};
+
+    use crate::error::HypernonsenseError;
+    use crate::events::IndexEvent;
+    use crate::hyperindex::{ HashFamily, HyperIndex };
+    use crate::metric::Metric;
+    use crate::eviction::Eviction;
+    use crate::multiindex::{ IndexConfig, MultiIndex, Progress };
+    use crate::pq::ProductQuantizer;
+    use crate::query::{ Aggregation, QueryOptions, hamming_distance };
+    use crate::recorder::CountingRecorder;
+    use crate::vector::{ Normalization, SparseVector, random_unit_vector, cosine_distance, euclidean_distance, normalize, sparse_cosine_distance };
+
+    #[test]
+    fn new_creates_index() {
+        let a = MultiIndex::<usize>::new(300, 15, 10, &mut thread_rng());
+
+        assert_eq!(300, a.dimensions());
+        assert_eq!(10, a.planes_len());
+        assert_eq!(15, a.indices_len());
+    }
+
+    #[test]
+    fn remove_removes_from_all_indices() {
+        let mut a = MultiIndex::new(300, 15, 10, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let v = random_unit_vector(300, &mut rng);
+        a.add(0usize, &v);
+        a.add(1usize, &v);
+
+        assert!(a.remove(&0));
+        assert!(!a.remove(&0));
+        assert_eq!(vec![1], a.nearest_points(&v));
+    }
+
+    #[test]
+    fn nearest_point_refs_match_owned_candidates() {
+        let mut a = MultiIndex::new(30, 10, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..200usize {
+            a.add(key.to_string(), &random_unit_vector(30, &mut rng));
+        }
+
+        let q = random_unit_vector(30, &mut rng);
+        let refs = a.nearest_point_refs(&q);
+        let owned = a.nearest_points_set(&q);
+        assert_eq!(owned.len(), refs.len());
+        assert!(refs.iter().all(|k| owned.contains(*k)));
+        assert_eq!(owned.len(), a.nearest_points(&q).len());
+    }
+
+    #[test]
+    fn from_indices_composes_sub_indices() {
+        let mut rng = thread_rng();
+        let vectors = (0..100).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        let shards = (0..4).map(|_| {
+            let mut index = HyperIndex::new(30, 6, &mut rng);
+            for (k, v) in vectors.iter().enumerate() {
+                index.add(k, v);
+            }
+            index
+        }).collect::<Vec<_>>();
+
+        let a = MultiIndex::from_indices(shards).unwrap();
+        assert_eq!(4, a.indices_len());
+        assert_eq!(100, a.len());
+        assert!(a.nearest_points_set(&vectors[3]).contains(&3));
+
+        assert!(matches!(MultiIndex::<usize>::from_indices(Vec::new()), Err(HypernonsenseError::InvalidConfig(_))));
+        let mismatched = vec![HyperIndex::<usize>::new(30, 6, &mut rng), HyperIndex::new(20, 6, &mut rng)];
+        assert!(matches!(MultiIndex::from_indices(mismatched), Err(HypernonsenseError::DimensionMismatch { expected: 30, actual: 20 })));
+        let mut uneven = vec![HyperIndex::<usize>::new(30, 6, &mut rng), HyperIndex::new(30, 6, &mut rng)];
+        uneven[0].add(0, &vectors[0]);
+        assert!(matches!(MultiIndex::from_indices(uneven), Err(HypernonsenseError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn nearest_with_keys_matches_nearest_with() {
+        let mut rng = thread_rng();
+        let mut a = MultiIndex::new_with_storage(30, 6, 6, &mut rng);
+        for key in 0..300usize {
+            a.add(key, &random_unit_vector(30, &mut rng));
+        }
+
+        let q = random_unit_vector(30, &mut rng);
+        let keys = a.query_keys(&q);
+        let options = QueryOptions::default().with_adaptive_probing(4).with_max_candidates(50);
+        let get_dist = |p: &[f32], k: &usize| cosine_distance(p, a.get(k).unwrap());
+        let expected = a.nearest_with(&q, 10, &options, get_dist).into_iter().map(|n| n.key).collect::<Vec<_>>();
+        let actual = a.nearest_with_keys(&q, &keys, 10, &options, get_dist).into_iter().map(|n| n.key).collect::<Vec<_>>();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    #[should_panic]
+    fn nearest_with_keys_rejects_missing_keys() {
+        let a = MultiIndex::<usize>::new(30, 6, 6, &mut thread_rng());
+        let q = random_unit_vector(30, &mut thread_rng());
+        let keys = a.query_keys(&q);
+        a.nearest_with_keys(&q, &keys[1..], 10, &QueryOptions::default(), |_, _| 0f32);
+    }
+
+    #[test]
+    fn remove_where_removes_matching_keys() {
+        let mut a = MultiIndex::new(300, 15, 10, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            a.add(key, &random_unit_vector(300, &mut rng));
+        }
+
+        assert_eq!(50, a.remove_where(|k| k % 2 == 0));
+        assert_eq!(0, a.remove_where(|k| k % 2 == 0));
+    }
+
+    #[test]
+    fn retain_keeps_matching_keys() {
+        let mut a = MultiIndex::new_with_storage(30, 5, 4, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            a.add(key, &random_unit_vector(30, &mut rng));
+        }
+
+        assert_eq!(90, a.retain(|k| *k < 10));
+        assert_eq!(10, a.len());
+        assert!(a.keys().all(|k| *k < 10));
+        assert_eq!(None, a.get(&50));
+        assert!(a.get(&5).is_some());
+    }
+
+    #[test]
+    fn drain_empties_index() {
+        let mut a = MultiIndex::new_with_storage(30, 5, 4, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let vectors = (0..100usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+        let snapshot = a.snapshot();
+
+        let mut drained = a.drain().collect::<Vec<_>>();
+        drained.sort_by_key(|(k, _)| *k);
+        assert_eq!(vectors.into_iter().enumerate().collect::<Vec<_>>(), drained);
+        assert_eq!(0, a.len());
+        assert!(a.nearest_points(&drained[0].1).is_empty());
+
+        // The snapshot still has everything, and the drained index can be filled again
+        assert_eq!(100, snapshot.len());
+        a.add(0, &drained[0].1);
+        assert_eq!(vec![0], a.nearest_points(&drained[0].1));
+    }
+
+    #[test]
+    fn storage_keeps_vectors() {
+        let mut a = MultiIndex::new_with_storage(30, 5, 4, &mut thread_rng());
+        assert!(a.stores_vectors());
+
+        let v = random_unit_vector(30, &mut thread_rng());
+        a.add(1usize, &v);
+        assert_eq!(Some(v.as_slice()), a.get(&1));
+        assert_eq!(None, a.get(&2));
+
+        a.remove(&1);
+        assert_eq!(None, a.get(&1));
+    }
+
+    #[test]
+    fn nearest_k_uses_stored_vectors() {
+        let mut a = MultiIndex::new_with_storage(100, 10, 6, &mut thread_rng());
+        a.set_metric(Metric::Euclidean);
+
+        let mut vectors = Vec::new();
+        let mut rng = thread_rng();
+        for key in 0..2000usize {
+            let v = random_unit_vector(100, &mut rng);
+            a.add(key, &v);
+            vectors.push(v);
+        }
+
+        let q = &vectors[0];
+        let expected = a.nearest(q, 10, |p, k| euclidean_distance(p, &vectors[*k]));
+        let actual = a.nearest_k(q, 10);
+
+        assert_eq!(0, actual[0].key);
+        assert_eq!(expected.iter().map(|n| n.key).collect::<Vec<_>>(), actual.iter().map(|n| n.key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn add_batch_matches_add() {
+        let mut a = MultiIndex::<usize>::builder(50).seed(3).store_vectors(true).build().unwrap();
+        let mut b = MultiIndex::<usize>::builder(50).seed(3).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let items = (0..1000usize).map(|k| (k, random_unit_vector(50, &mut rng))).collect::<Vec<_>>();
+        for (key, vector) in items.iter() {
+            a.add(*key, vector);
+        }
+        b.add_batch(items.clone());
+
+        for (key, vector) in items.iter() {
+            assert_eq!(a.nearest_points_set(vector), b.nearest_points_set(vector));
+            assert_eq!(Some(vector.as_slice()), b.get(key));
+        }
+    }
+
+    #[test]
+    fn from_vectors_matches_add() {
+        let mut a = MultiIndex::<usize>::builder(50).seed(3).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let items = (0..1000usize).map(|k| (k, random_unit_vector(50, &mut rng))).collect::<Vec<_>>();
+        for (key, vector) in items.iter() {
+            a.add(*key, vector);
+        }
+        let b = MultiIndex::from_vectors(MultiIndex::<usize>::builder(50).seed(3).store_vectors(true), items.clone()).unwrap();
+        assert_eq!(1000, b.len());
+
+        for (key, vector) in items.iter() {
+            assert_eq!(a.nearest_points_set(vector), b.nearest_points_set(vector));
+            assert_eq!(Some(vector.as_slice()), b.get(key));
+        }
+
+        let bad = vec![(0usize, vec![0f32; 50]), (1usize, vec![0f32; 10])];
+        assert!(matches!(MultiIndex::from_vectors(MultiIndex::<usize>::builder(50), bad), Err(HypernonsenseError::DimensionMismatch { .. })));
+    }
+
+    #[test]
+    fn nearest_batch_matches_nearest() {
+        let mut a = MultiIndex::new_with_storage(50, 10, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..2000usize {
+            a.add(key, &random_unit_vector(50, &mut rng));
+        }
+
+        let queries = (0..100).map(|_| random_unit_vector(50, &mut rng)).collect::<Vec<_>>();
+        let batch = a.nearest_k_batch(&queries, 5);
+
+        assert_eq!(queries.len(), batch.len());
+        for (q, result) in queries.iter().zip(batch.iter()) {
+            let single = a.nearest_k(q, 5);
+            assert_eq!(single.iter().map(|n| n.key).collect::<Vec<_>>(), result.iter().map(|n| n.key).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn within_radius_finds_close_points() {
+        let mut a = MultiIndex::new(50, 10, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let mut vectors = Vec::new();
+        for key in 0..2000usize {
+            let v = random_unit_vector(50, &mut rng);
+            a.add(key, &v);
+            vectors.push(v);
+        }
+
+        let q = &vectors[7];
+        let result = a.within_radius(q, 0.8, |p, k| euclidean_distance(p, &vectors[*k]));
+
+        assert_eq!(7, result[0].key);
+        assert!(result.iter().all(|n| n.distance <= 0.8));
+        assert!(result.windows(2).all(|w| w[0].distance <= w[1].distance));
+    }
+
+    #[test]
+    fn within_radius_covers_every_key_bit() {
+        let mut a = MultiIndex::<usize>::builder(1).index_count(1).plane_count(1).hash_family(HashFamily::PStable { width: 1f32 }).seed(3).build().unwrap();
+
+        // A p-stable key has several bits per plane, so the only point in the index can be further from the query (in key
+        // bits) than the plane count
+        let q = [0f32];
+        let key = a.indices()[0].key(&q);
+        let far = (1..10000).map(|i| [i as f32 * 0.1]).find(|v| hamming_distance(&key, &a.indices()[0].key(v)) > 1).unwrap();
+        a.add(0, &far);
+
+        let result = a.within_radius(&q, 1e9, |p, _| euclidean_distance(p, &far));
+        assert_eq!(vec![0], result.iter().map(|n| n.key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn payloads_are_returned_and_removed() {
+        let mut a = MultiIndex::<usize, f32, String>::builder(30).build().unwrap();
+
+        let v = random_unit_vector(30, &mut thread_rng());
+        a.add_with_payload(1, &v, "one".to_string());
+        a.add(2, &v);
+
+        let result = a.nearest_with_payload(&v, 10, |_, k| *k as f32);
+        assert_eq!(1, result[0].0.key);
+        assert_eq!(Some(&"one".to_string()), result[0].1);
+        assert_eq!(None, result[1].1);
+
+        a.remove(&1);
+        assert_eq!(None, a.payload(&1));
+    }
+
+    #[test]
+    fn expire_older_than_removes_old_items() {
+        for storage in [false, true] {
+            let mut a = MultiIndex::<usize>::builder(30).store_vectors(storage).build().unwrap();
+
+            let start = Instant::now();
+            let v = random_unit_vector(30, &mut thread_rng());
+            for key in 0..10usize {
+                a.add_with_timestamp(key, &v, start + Duration::from_secs(key as u64));
+            }
+            a.add(10, &v);
+
+            assert_eq!(5, a.expire_older_than(start + Duration::from_secs(5)));
+            assert_eq!(6, a.len());
+            assert_eq!(None, a.timestamp(&0));
+            assert_eq!(Some(start + Duration::from_secs(5)), a.timestamp(&5));
+
+            // Removing a key also forgets its timestamp, so adding it again without one means it never expires
+            a.remove(&6);
+            a.add(6, &v);
+            assert_eq!(4, a.expire_older_than(start + Duration::from_secs(100)));
+
+            let mut left = a.nearest_points(&v);
+            left.sort_unstable();
+            assert_eq!(vec![6, 10], left);
+        }
+    }
+
+    #[test]
+    fn nearest_quantized_finds_neighbours() {
+        let mut a = MultiIndex::<usize>::builder(32).index_count(5).plane_count(5).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..2000).map(|_| random_unit_vector(32, &mut rng)).collect::<Vec<_>>();
+        a.train_quantizer(&vectors[..1000], 8, &mut rng).unwrap();
+        a.add_batch(vectors.iter().cloned().enumerate());
+        assert_eq!(Some(8), a.code(&3).map(|c| c.len()));
+
+        // The shortlist is re-ranked exactly, so the query point itself must come first
+        for key in [0, 500, 1500] {
+            let result = a.nearest_quantized(&vectors[key], 5, 20, |p, k| cosine_distance(p, &vectors[*k]));
+            assert_eq!(key, result[0].key);
+            assert!(result[0].distance < 1e-5);
+        }
+
+        a.remove(&0);
+        assert_eq!(None, a.code(&0));
+        assert!(a.set_quantizer(ProductQuantizer::train(&vec![vec![0f32; 4]; 10], 2, 2, 1, &mut rng).unwrap()).is_err());
+    }
+
+    #[test]
+    fn rerank_top_recomputes_exact_distances() {
+        let mut a = MultiIndex::<usize>::builder(32).index_count(5).plane_count(5).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..1000).map(|_| random_unit_vector(32, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        // Shortlist with a rough distance which only looks at the first few dimensions
+        let options = QueryOptions::default().with_rerank_top(50);
+        let result = a.nearest_with(&vectors[3], 5, &options, |p, k| cosine_distance(&p[..8], &vectors[*k][..8]));
+        assert_eq!(5, result.len());
+        assert_eq!(3, result[0].key);
+        for node in result.iter() {
+            assert_eq!(cosine_distance(&vectors[3], &vectors[node.key]), node.distance);
+        }
+        assert!(result.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+        let batch = a.nearest_batch_with(&vectors[3..4], 5, &options, |p, k| cosine_distance(&p[..8], &vectors[*k][..8]));
+        assert_eq!(result.iter().map(|n| n.key).collect::<Vec<_>>(), batch[0].iter().map(|n| n.key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn anytime_results_improve_until_they_match_nearest() {
+        let mut a = MultiIndex::<usize>::builder(32).index_count(4).plane_count(6).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..1000).map(|_| random_unit_vector(32, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let query = random_unit_vector(32, &mut rng);
+        let options = QueryOptions::default().with_probe_radius(2);
+        let get_dist = |p: &[f32], k: &usize| cosine_distance(p, &vectors[*k]);
+        let steps = a.nearest_anytime(&query, 5, &options, get_dist).collect::<Vec<_>>();
+        assert!(steps.len() > 1);
+
+        // Every step is at least as good as the one before in every position
+        for w in steps.windows(2) {
+            assert!(w[0].len() <= w[1].len());
+            assert!(w[0].iter().zip(w[1].iter()).all(|(a, b)| b.distance <= a.distance));
+        }
+
+        let expected = a.nearest_with(&query, 5, &options, get_dist);
+        let last = steps.last().unwrap();
+        assert_eq!(expected.iter().map(|n| n.distance).collect::<Vec<_>>(), last.iter().map(|n| n.distance).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn anytime_radius_is_limited_to_the_key_length() {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(2).plane_count(30).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..100).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        // A radius larger than the key would probe every possible key, without ever finding anything new
+        let start = Instant::now();
+        let options = QueryOptions::default().with_probe_radius(31);
+        let steps = a.nearest_anytime(&vectors[0], 100, &options, |p, k| cosine_distance(p, &vectors[*k])).collect::<Vec<_>>();
+        assert_eq!(100, steps.last().unwrap().len());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn nearest_explained_reports_sources() {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(6).plane_count(6).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..500).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let options = QueryOptions::default().with_probe_radius(1);
+        let get_dist = |p: &[f32], k: &usize| cosine_distance(p, &vectors[*k]);
+        let result = a.nearest_explained(&vectors[9], 5, &options, get_dist);
+        let expected = a.nearest_with(&vectors[9], 5, &options, get_dist);
+        assert_eq!(expected.iter().map(|n| n.distance).collect::<Vec<_>>(), result.iter().map(|n| n.node.distance).collect::<Vec<_>>());
+
+        // The query point itself is in the group the query falls into, in every sub index
+        assert_eq!(9, result[0].node.key);
+        assert_eq!(6, result[0].votes());
+        for (i, source) in result[0].sources.iter().enumerate() {
+            assert_eq!(i, source.index);
+            assert_eq!(0, source.hamming_distance);
+        }
+
+        for r in result.iter() {
+            assert!(r.votes() >= 1);
+            assert!(r.sources.iter().all(|s| s.hamming_distance <= 1));
+        }
+    }
+
+    #[test]
+    fn nearest_paged_returns_pages_in_order() {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(5).plane_count(5).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..500).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let get_dist = |p: &[f32], k: &usize| cosine_distance(p, &vectors[*k]);
+        let mut pages = a.nearest_paged(&vectors[2], &QueryOptions::default(), get_dist);
+        assert_eq!(a.nearest_points(&vectors[2]).len(), pages.len());
+        assert!(pages.len() > 20);
+
+        let first = pages.next_page(10).iter().map(|n| n.distance).collect::<Vec<_>>();
+        let second = pages.next_page(10).iter().map(|n| n.distance).collect::<Vec<_>>();
+        let expected = a.nearest(&vectors[2], 20, get_dist).iter().map(|n| n.distance).collect::<Vec<_>>();
+        assert_eq!(expected[..10], first[..]);
+        assert_eq!(expected[10..], second[..]);
+        assert_eq!(pages.len() - 20, pages.remaining());
+        assert_eq!(second, pages.page(10, 10).iter().map(|n| n.distance).collect::<Vec<_>>());
+
+        assert_eq!(pages.len() - 20, pages.next_page(usize::MAX).len());
+        assert!(pages.next_page(10).is_empty());
+        assert!(pages.page(pages.len() + 5, 10).is_empty());
+    }
+
+    #[test]
+    fn ties_are_broken_deterministically() {
+        // Every item has the same vector, so every candidate is at the same distance
+        let v = random_unit_vector(20, &mut thread_rng());
+        let ranked = |order: &[usize]| {
+            let mut a = MultiIndex::<usize>::with_seed(20, 4, 4, 7);
+            for key in order {
+                a.add(*key, &v);
+            }
+            a.nearest(&v, 10, |_, _| 0.5f32).iter().map(|n| n.key).collect::<Vec<_>>()
+        };
+
+        let forward = (0..100).collect::<Vec<_>>();
+        let backward = forward.iter().rev().cloned().collect::<Vec<_>>();
+        let expected = ranked(&forward);
+        assert_eq!(10, expected.len());
+        for _ in 0..3 {
+            assert_eq!(expected, ranked(&forward));
+            assert_eq!(expected, ranked(&backward));
+        }
+    }
+
+    #[test]
+    fn find_duplicates_clusters_near_copies() {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(5).plane_count(8).store_vectors(true).build().unwrap();
+
+        // Three copies of vector 0 with tiny changes, and two of vector 1
+        let mut rng = thread_rng();
+        let mut vectors = (0..300).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        for (source, copy) in [(0, 300), (0, 301), (0, 302), (1, 303)] {
+            let mut v = vectors[source].clone();
+            v[3] += 1e-4;
+            normalize(&mut v);
+            vectors.push(v);
+            assert_eq!(copy + 1, vectors.len());
+        }
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let pairs = a.duplicate_pairs(1e-3);
+        assert_eq!(6 + 1, pairs.len());
+        assert!(pairs.iter().all(|(a, b, d)| a != b && *d <= 1e-3));
+
+        let mut clusters = a.find_duplicates(1e-3);
+        for c in clusters.iter_mut() {
+            c.sort_unstable();
+        }
+        clusters.sort_unstable();
+        assert_eq!(vec![vec![0, 300, 301, 302], vec![1, 303]], clusters);
+    }
+
+    #[test]
+    fn clusters_need_enough_votes() {
+        // Seeded, a plane which happens to pass between the points of a group would split it
+        let mut a = MultiIndex::<usize>::with_seed(30, 6, 10, 3);
+
+        // Two tight groups of points around two random directions
+        let mut rng = StdRng::seed_from_u64(3);
+        let centers = [random_unit_vector(30, &mut rng), random_unit_vector(30, &mut rng)];
+        for key in 0..20usize {
+            let mut v = centers[key % 2].iter().map(|x| x + rng.gen_range(-1e-5f32..1e-5f32)).collect::<Vec<_>>();
+            normalize(&mut v);
+            a.add(key, &v);
+        }
+
+        let mut clusters = a.clusters(6);
+        for c in clusters.iter_mut() {
+            c.sort_unstable();
+        }
+        clusters.sort_unstable();
+        assert_eq!(vec![(0..20).step_by(2).collect::<Vec<_>>(), (1..20).step_by(2).collect::<Vec<_>>()], clusters);
+
+        // More votes than there are sub indices can never be met
+        assert!(a.clusters(7).is_empty());
+    }
+
+    #[test]
+    fn group_centroids_are_member_means() {
+        let mut a = MultiIndex::<usize>::builder(10).index_count(2).plane_count(3).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..200).map(|_| random_unit_vector(10, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let centroids = a.group_centroids(1).collect::<Vec<_>>();
+        assert_eq!(a.indices[1].groups_len(), centroids.len());
+        for (key, centroid) in centroids {
+            let members = a.indices[1].group(key).unwrap();
+            for d in 0..10 {
+                let mean = members.iter().map(|k| vectors[*k][d]).sum::<f32>() / members.len() as f32;
+                assert!((mean - centroid[d]).abs() < 1e-5);
+            }
+            assert_eq!(Some(centroid), a.indices[1].group_centroid(key, |k| a.get(k)));
+        }
+
+        // Without any vectors there is nothing to average
+        let key = a.indices[0].key(&vectors[0]);
+        assert_eq!(None, a.indices[0].group_centroid(&key, |_| None));
+        assert_eq!(0, a.indices[0].group_centroids(|_| None).count());
+    }
+
+    #[test]
+    fn capacity_evicts_items() {
+        for storage in [false, true] {
+            let mut a = MultiIndex::<usize>::builder(30).store_vectors(storage).capacity(3, Eviction::Fifo).build().unwrap();
+            let mut b = MultiIndex::<usize>::builder(30).store_vectors(storage).capacity(3, Eviction::Lru).build().unwrap();
+
+            let v = random_unit_vector(30, &mut thread_rng());
+            for key in 0..3usize {
+                a.add(key, &v);
+                b.add(key, &v);
+            }
+
+            // Key 0 is the oldest in both, but the query makes it the most recently used in the LRU index
+            a.nearest(&v, 1, |_, k| *k as f32);
+            b.nearest(&v, 1, |_, k| *k as f32);
+            a.add(3, &v);
+            b.add(3, &v);
+
+            let mut a_keys = a.keys().cloned().collect::<Vec<_>>();
+            let mut b_keys = b.keys().cloned().collect::<Vec<_>>();
+            a_keys.sort_unstable();
+            b_keys.sort_unstable();
+            assert_eq!(vec![1, 2, 3], a_keys);
+            assert_eq!(vec![0, 2, 3], b_keys);
+            assert_eq!(None, a.get(&0));
+
+            a.add_batch((10..15usize).map(|k| (k, v.clone())));
+            assert_eq!(3, a.len());
+            assert_eq!(Some((3, Eviction::Fifo)), a.capacity());
+        }
+
+        assert!(matches!(MultiIndex::<usize>::builder(30).capacity(0, Eviction::Lru).build(), Err(HypernonsenseError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn upsert_moves_key() {
+        for storage in [false, true] {
+            let mut a = MultiIndex::<usize>::builder(30).store_vectors(storage).build().unwrap();
+
+            let v1 = random_unit_vector(30, &mut thread_rng());
+            let v2 = v1.iter().map(|x| -x).collect::<Vec<_>>();
+            assert!(!a.upsert(1, &v1));
+            assert!(a.upsert(1, &v2));
+
+            // The new vector is on the opposite side of every plane, so the old groups must no longer contain the key
+            assert!(a.nearest_points_set_with(&v1, &QueryOptions::default().with_probe_radius(0)).is_empty());
+            assert!(a.nearest_points_set(&v2).contains(&1));
+        }
+    }
+
+    #[test]
+    fn replica_mirrors_change_events() {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(4).plane_count(5).store_vectors(true).build().unwrap();
+        let mut b = MultiIndex::<usize>::builder(30).index_count(4).plane_count(5).store_vectors(true).build().unwrap();
+        b.apply(IndexEvent::Rebuilt { planes: a.export_planes() }).unwrap();
+        let events = a.subscribe();
+
+        let mut rng = thread_rng();
+        let vectors = (0..300).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            a.add(key, v);
+        }
+        a.remove(&3);
+        a.upsert(4, &vectors[5]);
+        a.rebuild(3, 6).unwrap();
+        a.remove_where(|k| *k >= 290);
+
+        for event in events.try_iter() {
+            b.apply(event).unwrap();
+        }
+
+        assert_eq!(a.len(), b.len());
+        assert_eq!(a.export_planes().len(), b.indices_len());
+        assert_eq!(None, b.get(&3));
+        assert_eq!(Some(vectors[5].as_slice()), b.get(&4));
+        for v in vectors.iter().take(20) {
+            assert_eq!(a.nearest_points_set(v), b.nearest_points_set(v));
+        }
+
+        // Without a listener nothing is sent, and the receiver is disconnected
+        a.set_listener(None);
+        a.add(1000, &vectors[0]);
+        assert!(events.recv().is_err());
+    }
+
+    #[test]
+    fn nearest_to_key_excludes_key() {
+        let mut a = MultiIndex::new_with_storage(50, 10, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..1000usize {
+            a.add(key, &random_unit_vector(50, &mut rng));
+        }
+
+        let result = a.nearest_to_key(&3, 5).unwrap();
+        assert_eq!(5, result.len());
+        assert!(result.iter().all(|n| n.key != 3));
+        assert!(a.nearest_to_key(&5000, 5).is_none());
+    }
+
+    #[test]
+    fn len_tracks_items() {
+        for storage in [false, true] {
+            let mut a = MultiIndex::<usize>::builder(30).store_vectors(storage).build().unwrap();
+            assert!(a.is_empty());
+
+            let mut rng = thread_rng();
+            for key in 0..10usize {
+                a.add(key, &random_unit_vector(30, &mut rng));
+            }
+            a.add_batch((10..20usize).map(|k| (k, random_unit_vector(30, &mut rng))));
+            assert_eq!(20, a.len());
+            assert!(a.contains_key(&15));
+
+            a.remove(&15);
+            a.upsert(3, &random_unit_vector(30, &mut rng));
+            assert_eq!(19, a.len());
+            assert!(!a.contains_key(&15));
+        }
+    }
+
+    #[test]
+    fn keys_and_iter_return_everything() {
+        let mut a = MultiIndex::new_with_storage(30, 5, 4, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let mut vectors = Vec::new();
+        for key in 0..100usize {
+            let v = random_unit_vector(30, &mut rng);
+            a.add(key, &v);
+            vectors.push(v);
+        }
+
+        let mut keys = a.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!((0..100).collect::<Vec<_>>(), keys);
+
+        let items = a.iter().unwrap().collect::<Vec<_>>();
+        assert_eq!(100, items.len());
+        assert!(items.iter().all(|(k, v)| vectors[**k].as_slice() == *v));
+
+        assert!(MultiIndex::<usize>::new(30, 5, 4, &mut thread_rng()).iter().is_none());
+    }
+
+    #[test]
+    fn try_methods_return_errors() {
+        let mut a = MultiIndex::<usize>::new(3, 5, 4, &mut thread_rng());
+
+        assert!(matches!(a.try_nearest(&[1f32, 2f32, 3f32], 1, |_, _| 0f32), Err(HypernonsenseError::EmptyIndex)));
+        assert!(matches!(a.try_add(0, &[1f32]), Err(HypernonsenseError::DimensionMismatch { expected: 3, actual: 1 })));
+        assert!(matches!(a.try_add(0, &[1f32, f32::INFINITY, 0f32]), Err(HypernonsenseError::NonFiniteInput)));
+        assert!(a.try_add(0, &[1f32, 2f32, 3f32]).is_ok());
+        assert_eq!(1, a.try_nearest(&[1f32, 2f32, 3f32], 1, |_, _| 0f32).unwrap().len());
+    }
+
+    #[test]
+    fn stats_combine_indices() {
+        let mut a = MultiIndex::new(30, 5, 4, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            a.add(key, &random_unit_vector(30, &mut rng));
+        }
+
+        let stats = a.stats();
+        assert_eq!(500, stats.item_count);
+        assert_eq!(a.indices().iter().map(|i| i.groups_len()).sum::<usize>(), stats.group_count);
+    }
+
+    #[test]
+    fn index_stats_report_each_sub_index() {
+        let mut rng = thread_rng();
+        let mut a = MultiIndex::new(30, 5, 4, &mut rng);
+        let mut b = MultiIndex::new_with_storage(30, 5, 4, &mut rng);
+        for key in 0..100usize {
+            let v = random_unit_vector(30, &mut rng);
+            a.add(key, &v);
+            b.add(key, &v);
+        }
+        a.add(0, &random_unit_vector(30, &mut rng));
+
+        for index in [a, b].iter() {
+            let stats = index.index_stats();
+            assert_eq!(5, stats.per_index.len());
+            assert_eq!(100, stats.unique_items);
+            assert_eq!(index.stats(), stats.combined);
+            assert_eq!(index.groups_len(), stats.combined.group_count);
+            assert!(stats.per_index.iter().all(|s| s.item_count == index.len()));
+        }
+    }
+
+    #[test]
+    fn recorder_counts_inserts_and_queries() {
+        let recorder = Arc::new(CountingRecorder::new());
+        let mut a = MultiIndex::<usize>::builder(30).index_count(3).plane_count(4).store_vectors(true).recorder(recorder.clone()).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..50).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+        a.add(50, &vectors[0]);
+        assert_eq!(51, recorder.inserts());
+
+        let result = a.nearest_k_with(&vectors[1], 5, &QueryOptions::default().with_probe_radius(1));
+        assert_eq!(1, recorder.queries());
+        assert_eq!(15, recorder.probes());
+        assert!(recorder.candidates() >= result.len() as u64);
+
+        // Ranking happens on one thread, so it can't take longer than the whole query
+        let phases = recorder.phases();
+        assert!(phases.ranking > Duration::ZERO);
+        assert!(phases.collection + phases.ranking <= recorder.query_time());
+
+        a.nearest_batch(&vectors[..4], 5, |p, k| cosine_distance(p, &vectors[*k % 50]));
+        assert_eq!(5, recorder.queries());
+
+        a.remove(&50);
+        assert_eq!(1, recorder.removes());
+    }
+
+    #[test]
+    fn dump_json_lists_groups() {
+        let mut a = MultiIndex::with_seed(3, 2, 2, 1);
+        a.add(7usize, &[1f32, 0f32, 0f32]);
+        a.add(8usize, &[1f32, 0f32, 0f32]);
+
+        let mut out = Vec::new();
+        a.dump_json(&mut out, false).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.starts_with("{\"dimensions\":3,\"len\":2,\"family\":{\"type\":\"hyperplane\"},\"indices\":[{\"planes\":[["));
+        assert_eq!(2, json.matches("\"count\":2").count());
+        assert!(!json.contains("members"));
+
+        let mut out = Vec::new();
+        a.dump_json(&mut out, true).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert_eq!(2, json.matches("\"members\":[\"7\",\"8\"]").count());
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+
+    #[test]
+    fn histograms_combine_indices() {
+        let mut a = MultiIndex::new(30, 5, 4, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            a.add(key, &random_unit_vector(30, &mut rng));
+        }
+
+        let per_index = a.group_size_histograms();
+        assert_eq!(5, per_index.len());
+        assert!(per_index.iter().all(|h| h.iter().map(|(s, c)| s * c).sum::<usize>() == 100));
+
+        let combined = a.group_size_histogram();
+        assert_eq!(500, combined.iter().map(|(s, c)| s * c).sum::<usize>());
+    }
+
+    #[test]
+    fn groups_cover_every_sub_index() {
+        let mut a = MultiIndex::new(30, 5, 4, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        for key in 0..100usize {
+            a.add(key, &random_unit_vector(30, &mut rng));
+        }
+
+        for i in 0..5 {
+            let members = a.groups().filter(|(idx, _, _)| *idx == i).flat_map(|(_, _, g)| g.iter()).collect::<HashSet<_>>();
+            assert_eq!(100, members.len());
+        }
+        assert_eq!(500, a.groups().map(|(_, _, g)| g.len()).sum::<usize>());
+    }
+
+    #[test]
+    fn bucket_labels_match_group_keys() {
+        let mut a = MultiIndex::new(30, 3, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let vectors = (0..50).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            a.add(key, v);
+        }
+
+        let labels = a.bucket_labels().unwrap();
+        assert_eq!(50, labels.len());
+        for (key, v) in vectors.iter().enumerate() {
+            for (i, index) in a.indices.iter().enumerate() {
+                let bits = index.key(v);
+                let expected = (0..bits.len()).filter(|b| bits[*b]).map(|b| 1u64 << b).sum::<u64>();
+                assert_eq!(expected, labels[&key][i]);
+            }
+        }
+
+        let wide = MultiIndex::<usize>::new(30, 2, 65, &mut thread_rng());
+        assert!(wide.bucket_labels().is_err());
+    }
+
+    #[test]
+    fn sparse_queries_find_points() {
+        let mut a = MultiIndex::new(1000, 10, 8, &mut thread_rng());
+
+        let mut vectors = Vec::new();
+        for key in 0..500usize {
+            let v = SparseVector::from_pairs((0..10).map(|i| ((key * 7 + i * 31) % 1000, 1f32)).collect());
+            a.add_sparse(key, &v);
+            vectors.push(v);
+        }
+
+        let result = a.nearest_sparse(&vectors[3], 5, &QueryOptions::default(), |p, k| sparse_cosine_distance(p, &vectors[*k]));
+        assert_eq!(3, result[0].key);
+    }
+
+    #[test]
+    fn normalization_policy_is_applied() {
+        let mut a = MultiIndex::<usize>::builder(2).store_vectors(true).normalization(Normalization::NormalizeOnInsert).build().unwrap();
+        a.add(1, &[3f32, 4f32]);
+        assert_eq!(Some([0.6f32, 0.8f32].as_ref()), a.get(&1));
+
+        let mut b = MultiIndex::<usize>::builder(2).normalization(Normalization::RejectNonUnit).build().unwrap();
+        assert!(matches!(b.try_add(1, &[3f32, 4f32]), Err(HypernonsenseError::NonUnitVector)));
+        assert!(b.try_add(1, &[0.6f32, 0.8f32]).is_ok());
+        assert!(matches!(b.try_nearest(&[3f32, 4f32], 1, |_, _| 0f32), Err(HypernonsenseError::NonUnitVector)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn reject_non_unit_panics_in_add() {
+        let mut a = MultiIndex::<usize>::builder(2).normalization(Normalization::RejectNonUnit).build().unwrap();
+        a.add(1, &[3f32, 4f32]);
+    }
+
+    #[test]
+    fn no_storage_by_default() {
+        let mut a = MultiIndex::new(30, 5, 4, &mut thread_rng());
+        assert!(!a.stores_vectors());
+
+        a.add(1usize, &random_unit_vector(30, &mut thread_rng()));
+        assert_eq!(None, a.get(&1));
+    }
+
+    #[test]
+    fn autotune()
+    {
+        let mut vectors = Vec::new();
+
+        println!("Generating...");
+        let mut rng = thread_rng();
+        for _ in 0..5000usize {
+            let v = random_unit_vector(100, &mut rng).into_iter().map(|v| v + 0.5f32).collect();
+            vectors.push(v);
+        }
+        println!("Done");
+
+        let plane_count = MultiIndex::<usize>::autotune_planes(100, 10f32, &vectors, &mut thread_rng());
+        println!("{}", plane_count);
+    }
+
+    #[test]
+    fn autotune_finds_index_count()
+    {
+        let mut rng = thread_rng();
+        let vectors = (0..5000usize).map(|_| random_unit_vector(50, &mut rng)).collect::<Vec<_>>();
+
+        let config = MultiIndex::<usize>::autotune(50, 10f32, 200, 50, &vectors, &mut thread_rng());
+        println!("{:?}", config);
+
+        // Check an index built with the recommended config actually returns enough candidates
+        let mut a = MultiIndex::<usize>::builder(50).config(config).build().unwrap();
+        a.add_batch(vectors.iter().cloned().enumerate());
+        let average = vectors[..100].iter().map(|v| a.nearest_points(v).len()).sum::<usize>() / 100;
+        assert!(config.index_count == 50 || average >= 150);
+    }
+
+    #[test]
+    fn progress_is_reported()
+    {
+        let mut rng = thread_rng();
+        let vectors = (0..25000usize).map(|_| random_unit_vector(10, &mut rng)).collect::<Vec<_>>();
+
+        let mut reports = Vec::new();
+        let config = MultiIndex::<usize>::autotune_with_progress(10, 10f32, 50, 10, &vectors[..2000], &mut rng, |p| reports.push(p));
+        assert_eq!(Some(&Progress::Candidate(config)), reports.last());
+        assert!(reports.contains(&Progress::Candidate(IndexConfig { index_count: 1, plane_count: config.plane_count })));
+
+        let mut a = MultiIndex::<usize>::builder(10).index_count(2).store_vectors(true).build().unwrap();
+        let mut reports = Vec::new();
+        a.add_batch_with_progress(vectors.into_iter().enumerate(), |p| reports.push(p));
+        assert_eq!(vec![
+            Progress::Items { processed: 10000, total: 25000 },
+            Progress::Items { processed: 20000, total: 25000 },
+            Progress::Items { processed: 25000, total: 25000 },
+        ], reports);
+
+        let mut last = None;
+        a.rebuild_with_progress(3, 4, |p| last = Some(p)).unwrap();
+        assert_eq!(Some(Progress::Items { processed: 25000, total: 25000 }), last);
+    }
+
+    #[test]
+    fn autotune_bucket_width_reaches_group_size()
+    {
+        let mut rng = thread_rng();
+        let vectors = (0..2000usize).map(|_| (0..20).map(|_| rng.gen_range(-5f32..5f32)).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+        let width = MultiIndex::<usize>::autotune_bucket_width(20, 4, 10f32, &vectors, &mut thread_rng());
+        assert!(width > 0f32);
+
+        let mut a = MultiIndex::<usize>::builder(20).index_count(1).plane_count(4).hash_family(HashFamily::PStable { width }).build().unwrap();
+        a.add_batch(vectors.iter().cloned().enumerate());
+        let stats = a.stats();
+        println!("{} => {:?}", width, stats.mean_group_size);
+        assert!(stats.mean_group_size > 3f32);
+    }
+
+    #[test]
+    fn pstable_index_finds_euclidean_neighbours()
+    {
+        let mut a = MultiIndex::<usize>::builder(20)
+            .hash_family(HashFamily::PStable { width: 8f32 })
+            .metric(Metric::Euclidean)
+            .store_vectors(true)
+            .build()
+            .unwrap();
+        assert_eq!(HashFamily::PStable { width: 8f32 }, a.hash_family());
+
+        let mut rng = thread_rng();
+        let vectors = (0..1000usize).map(|_| (0..20).map(|_| rng.gen_range(-10f32..10f32)).collect::<Vec<_>>()).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        for (key, v) in vectors.iter().enumerate().take(50) {
+            assert_eq!(key, a.nearest_k(v, 1)[0].key);
+        }
+    }
+
+    #[test]
+    fn cross_polytope_index_finds_neighbours()
+    {
+        let mut a = MultiIndex::<usize>::builder(30)
+            .hash_family(HashFamily::CrossPolytope { rotation_dims: 4 })
+            .plane_count(3)
+            .store_vectors(true)
+            .build()
+            .unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..1000usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        for (key, v) in vectors.iter().enumerate().take(50) {
+            assert_eq!(key, a.nearest_k(v, 1)[0].key);
+        }
+
+        let b = MultiIndex::<usize>::builder(30).hash_family(HashFamily::CrossPolytope { rotation_dims: 31 }).build();
+        assert!(matches!(b, Err(HypernonsenseError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn fit_offsets_balances_groups()
+    {
+        let mut rng = thread_rng();
+        let vectors = (0..2000usize).map(|_| random_unit_vector(30, &mut rng).iter().map(|v| v + 0.5).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+        let mut a = MultiIndex::<usize>::new(30, 5, 6, &mut thread_rng());
+        let mut b = MultiIndex::<usize>::new(30, 5, 6, &mut thread_rng());
+        b.fit_offsets(&vectors).unwrap();
+        a.add_batch(vectors.iter().cloned().enumerate());
+        b.add_batch(vectors.iter().cloned().enumerate());
+
+        println!("{:?} {:?}", a.stats().max_group_size, b.stats().max_group_size);
+        assert!(b.stats().max_group_size < a.stats().max_group_size);
+        assert!(matches!(b.fit_offsets(&vectors), Err(HypernonsenseError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn rebuild_rehashes_stored_vectors()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(3).plane_count(8).store_vectors(true).seed(5).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..500usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        a.rebuild(6, 4).unwrap();
+        assert_eq!(6, a.indices_len());
+        assert_eq!(4, a.planes_len());
+        assert_eq!(500, a.len());
+        for (key, v) in vectors.iter().enumerate().take(50) {
+            assert_eq!(key, a.nearest_k(v, 1)[0].key);
+        }
+
+        // Rebuilding from the seed gives the same planes as building with the new parameters
+        let b = MultiIndex::<usize>::builder(30).index_count(6).plane_count(4).seed(5).build().unwrap();
+        assert_eq!(a.indices()[0].planes(), b.indices()[0].planes());
+
+        let mut c = MultiIndex::<usize>::new(30, 3, 8, &mut thread_rng());
+        assert!(matches!(c.rebuild(6, 4), Err(HypernonsenseError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn snapshot_is_not_affected_by_writes()
+    {
+        let mut a = MultiIndex::<usize>::new_with_storage(30, 5, 6, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let vectors = (0..1000usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors[..500].iter().cloned().enumerate());
+
+        let snapshot = std::sync::Arc::new(a.snapshot());
+        let reader = {
+            let snapshot = snapshot.clone();
+            let vectors = vectors.clone();
+            std::thread::spawn(move || {
+                for (key, v) in vectors.iter().enumerate().take(500) {
+                    assert_eq!(key, snapshot.nearest_k(v, 1)[0].key);
+                }
+            })
+        };
+
+        // Keep writing to the live index while the snapshot is being read
+        a.add_batch(vectors[500..].iter().cloned().enumerate().map(|(k, v)| (k + 500, v)));
+        a.remove(&0);
+        reader.join().unwrap();
+
+        assert_eq!(500, snapshot.len());
+        assert!(snapshot.get(&0).is_some());
+        assert!(snapshot.get(&700).is_none());
+        assert_eq!(999, a.len());
+        assert!(a.get(&0).is_none());
+        assert_eq!(700, a.nearest_k(&vectors[700], 1)[0].key);
+    }
+
+    #[test]
+    fn autotune_recall_reaches_target()
+    {
+        let mut rng = thread_rng();
+        let vectors = (0..2000usize).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        let queries = vectors[..50].to_vec();
+
+        // Brute force the true neighbours of every query
+        let truth = queries.iter().map(|q| {
+            let mut all = (0..vectors.len()).collect::<Vec<_>>();
+            all.sort_by(|a, b| Metric::Cosine.distance(q, &vectors[*a]).partial_cmp(&Metric::Cosine.distance(q, &vectors[*b])).unwrap());
+            all.truncate(10);
+            all
+        }).collect::<Vec<_>>();
+
+        let config = MultiIndex::<usize>::autotune_recall(30, &vectors, &queries, &truth, 10, 0.8, 40, Metric::Cosine, &mut thread_rng()).unwrap();
+        println!("{:?}", config);
+        assert!(config.index_count <= 40);
     }
 
     #[test]
@@ -232,6 +3535,239 @@ mod tests
         assert!(near.len() > 50);
     }
 
+    #[test]
+    fn larger_probe_radius_finds_more_points()
+    {
+        let mut a = MultiIndex::new(300, 10, 12, &mut thread_rng());
+
+        let mut vectors = Vec::new();
+        let mut rng = thread_rng();
+        for key in 0..5000usize {
+            let v = random_unit_vector(300, &mut rng);
+            a.add(key, &v);
+            vectors.push(v);
+        }
+
+        let q = &vectors[0];
+        let r0 = a.nearest_points_with(q, &QueryOptions::default().with_probe_radius(0)).len();
+        let r1 = a.nearest_points(q).len();
+        let r2 = a.nearest_points_with(q, &QueryOptions::default().with_probe_radius(2)).len();
+
+        assert!(r0 >= 1);
+        assert!(r0 <= r1);
+        assert!(r1 < r2);
+    }
+
+    #[test]
+    fn adaptive_probing_finds_enough_results()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(2).plane_count(12).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..300).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        // With 4096 groups per sub index the exact groups of a query hold very few items
+        let q = random_unit_vector(30, &mut rng);
+        let exact = QueryOptions::default().with_probe_radius(0);
+        assert!(a.nearest_k_with(&q, 50, &exact).len() < 50);
+
+        let adaptive = exact.clone().with_adaptive_probing(12);
+        assert_eq!(50, a.nearest_k_with(&q, 50, &adaptive).len());
+        assert_eq!(50, a.nearest_batch_with(std::slice::from_ref(&q), 50, &adaptive, |p, k| cosine_distance(p, &vectors[*k]))[0].len());
+
+        // The budget stops widening even if there are too few candidates
+        let limited = exact.with_adaptive_probing(1);
+        let within_one = a.nearest_points_set_with(&q, &QueryOptions::default().with_probe_radius(1)).len();
+        assert_eq!(within_one.min(300), a.nearest_k_with(&q, 300, &limited).len());
+    }
+
+    #[test]
+    fn max_candidates_keeps_the_most_voted()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(6).plane_count(3).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..2000).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        // With only 8 groups per sub index a query finds almost everything, the cap bounds the work
+        let recorder = Arc::new(CountingRecorder::new());
+        a.set_recorder(Some(recorder.clone()));
+        let options = QueryOptions::default().with_max_candidates(100);
+        let result = a.nearest_k_with(&vectors[5], 10, &options);
+        assert_eq!(100, recorder.candidates());
+        assert_eq!(10, result.len());
+
+        // The query point is in the query group of every sub index, so it always has the most votes
+        assert_eq!(5, result[0].key);
+
+        let batch = a.nearest_batch_with(std::slice::from_ref(&vectors[5]), 10, &options, |p, k| cosine_distance(p, &vectors[*k]));
+        assert_eq!(result.iter().map(|n| n.key).collect::<Vec<_>>(), batch[0].iter().map(|n| n.key).collect::<Vec<_>>());
+        assert_eq!(200, recorder.candidates());
+    }
+
+    #[test]
+    fn excluded_keys_are_never_returned()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(6).plane_count(4).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..1000).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let metric = |p: &[f32], k: &usize| cosine_distance(p, &vectors[*k]);
+        let options = QueryOptions::default().with_max_candidates(50);
+        let exclude = a.nearest_with(&vectors[5], 10, &options, metric).into_iter().map(|n| n.key).collect::<HashSet<_>>();
+        assert!(exclude.contains(&5));
+
+        // Excluded keys are dropped before the cap, so there are still enough candidates left to fill the results
+        let result = a.nearest_excluding(&vectors[5], 10, &options, &exclude, metric);
+        assert_eq!(10, result.len());
+        assert!(result.iter().all(|n| !exclude.contains(&n.key)));
+
+        let even = a.nearest_filtered(&vectors[5], 10, &QueryOptions::default(), |k| k % 2 == 0, metric);
+        assert!(!even.is_empty());
+        assert!(even.iter().all(|n| n.key % 2 == 0));
+    }
+
+    #[test]
+    fn multi_point_queries_aggregate_distances()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(8).plane_count(4).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..500).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        // Each example is at distance zero from itself, so both are the nearest by min distance
+        let examples = vec![vectors[3].clone(), vectors[7].clone()];
+        let options = QueryOptions::default();
+        let nearest = a.nearest_k_multi(&examples, Aggregation::MinDistance, 2, &options).into_iter().map(|n| n.key).collect::<HashSet<_>>();
+        assert_eq!([3, 7].iter().cloned().collect::<HashSet<_>>(), nearest);
+
+        let average = a.nearest_k_multi(&examples, Aggregation::MeanDistance, 10, &options);
+        for node in average.iter() {
+            let expected = (cosine_distance(&vectors[3], &vectors[node.key]) + cosine_distance(&vectors[7], &vectors[node.key])) / 2.0;
+            assert!((expected - node.distance).abs() < 1e-5);
+        }
+
+        // The mean of one point is the point itself
+        let single = a.nearest_k_multi(&examples[..1], Aggregation::Mean, 5, &options);
+        assert_eq!(a.nearest_k(&vectors[3], 5).iter().map(|n| n.key).collect::<Vec<_>>(), single.iter().map(|n| n.key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn jsonl_round_trip_preserves_items()
+    {
+        let mut a = MultiIndex::<u64>::builder(30).index_count(4).plane_count(6).store_vectors(true).build().unwrap();
+        let mut rng = thread_rng();
+        for key in 0..100 {
+            a.add(key, &random_unit_vector(30, &mut rng));
+        }
+
+        let mut bytes = Vec::new();
+        a.write_jsonl(&mut bytes).unwrap();
+        assert_eq!(100, bytes.iter().filter(|b| **b == b'\n').count());
+
+        let mut b = MultiIndex::<u64>::builder(30).index_count(4).plane_count(6).store_vectors(true).build().unwrap();
+        assert_eq!(100, b.add_jsonl(bytes.as_slice()).unwrap());
+        for key in 0..100 {
+            assert_eq!(a.get(&key), b.get(&key));
+        }
+
+        // Vectors with the wrong dimensions are rejected
+        assert!(b.add_jsonl("{\"key\": 200, \"vector\": [1, 2]}".as_bytes()).is_err());
+        assert_eq!(100, b.len());
+    }
+
+    #[test]
+    fn min_votes_drops_rare_candidates()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(6).plane_count(3).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..2000).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let options = QueryOptions::default().with_probe_radius(0).with_min_votes(4);
+        let keys = a.query_keys(&vectors[5]);
+        let voted = (0..2000usize)
+            .filter(|k| a.indices.iter().zip(keys.iter()).filter(|(idx, key)| idx.key(&vectors[*k]) == **key).count() >= 4)
+            .collect::<HashSet<_>>();
+
+        let recorder = Arc::new(CountingRecorder::new());
+        a.set_recorder(Some(recorder.clone()));
+        let result = a.nearest_k_with(&vectors[5], 2000, &options);
+        assert_eq!(voted, result.iter().map(|n| n.key).collect::<HashSet<_>>());
+        assert_eq!(voted.len() as u64, recorder.candidates());
+        assert_eq!(5, result[0].key);
+
+        let batch = a.nearest_batch_with(std::slice::from_ref(&vectors[5]), 2000, &options, |p, k| cosine_distance(p, &vectors[*k]));
+        assert_eq!(voted.len(), batch[0].len());
+    }
+
+    #[test]
+    fn time_budget_returns_results_found_so_far()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(4).plane_count(6).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..1000).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        // A generous budget finishes every probe, so gives the same results as an unlimited query
+        let options = QueryOptions::default().with_probe_radius(2);
+        let expected = a.nearest_k_with(&vectors[8], 10, &options);
+        let result = a.nearest_k_with(&vectors[8], 10, &options.clone().with_time_budget(Duration::from_secs(60)));
+        assert_eq!(expected.iter().map(|n| n.distance).collect::<Vec<_>>(), result.iter().map(|n| n.distance).collect::<Vec<_>>());
+
+        // With no time at all the first step is still finished, which includes the group the query point is in
+        let result = a.nearest_k_with(&vectors[8], 10, &options.with_time_budget(Duration::ZERO));
+        assert!(!result.is_empty());
+        assert_eq!(8, result[0].key);
+    }
+
+    #[test]
+    fn time_budget_is_checked_between_empty_probes()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).index_count(8).plane_count(28).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..3000).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        // Nearly every ring only contains groups which were found at a smaller radius, so probing them yields nothing
+        let query = vectors[8].clone();
+        let options = QueryOptions::default().with_probe_radius(29);
+        let start = Instant::now();
+        a.nearest_k_with(&query, 10, &options);
+        let unlimited = start.elapsed();
+
+        let start = Instant::now();
+        let result = a.nearest_k_with(&query, 10, &options.with_time_budget(Duration::from_millis(2)));
+        let limited = start.elapsed();
+        assert_eq!(8, result[0].key);
+        assert!(limited < unlimited / 4);
+    }
+
+    #[test]
+    fn nearest_points_with_distances_matches_metric()
+    {
+        let mut a = MultiIndex::<usize>::builder(30).store_vectors(true).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..500).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        a.add_batch(vectors.iter().cloned().enumerate());
+
+        let result = a.nearest_points_with_distances(&vectors[4]);
+        assert_eq!(a.nearest_points(&vectors[4]).len(), result.len());
+        assert!(result.contains(&(4, cosine_distance(&vectors[4], &vectors[4]))));
+        for (k, d) in result.iter() {
+            assert_eq!(cosine_distance(&vectors[4], &vectors[*k]), *d);
+        }
+    }
+
     #[test]
     fn multiindex_compare() {
         let mut a = MultiIndex::new(300, 15, 5, &mut thread_rng());
@@ -257,8 +3793,8 @@ mod tests
         let end_linear = Instant::now();
         println!("{:?} seconds for linear", end_linear - start_linear);
 
-        for i in 0..20 {
-            println!("idx:{:?}\t\tdist:{:?}", (nearest_linear[i].1).0, nearest_linear[i].0);
+        for (dist, item) in nearest_linear.iter().take(20) {
+            println!("idx:{:?}\t\tdist:{:?}", item.0, dist);
         }
 
         let start_indexed = Instant::now();
@@ -273,8 +3809,8 @@ mod tests
         let end_indexed = Instant::now();
         println!("{:?} seconds for index", end_indexed - start_indexed);
         
-        for i in 0.. near.len().min(20) {
-            println!("idx:{:?}\t\tdist:{:?}", near[i].key, near[i].distance);
+        for node in near.iter().take(20) {
+            println!("idx:{:?}\t\tdist:{:?}", node.key, node.distance);
         }
 
         let linear_set: HashSet<_> = nearest_linear.iter().map(|a| (a.1).0).take(20).collect();
@@ -0,0 +1,363 @@
+// Loading matrices saved by NumPy, with one vector per row. `.npy` files are a short text header followed by the raw
+// elements (https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html). `.npz` files are zip archives of
+// `.npy` files, only archives written without compression (`numpy.savez`, not `numpy.savez_compressed`) can be read since
+// the crate has no deflate implementation.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::error::HypernonsenseError;
+use crate::vector::Scalar;
+
+const MAGIC: &[u8; 6] = b"\x93NUMPY";
+
+/// A matrix from a `.npz` archive: the name of the array and its rows
+pub type NamedMatrix<T> = (String, Vec<Vec<T>>);
+
+fn invalid(msg: &str) -> HypernonsenseError {
+    HypernonsenseError::InvalidFormat(msg.to_string())
+}
+
+// The text after `'key':` in the header dictionary
+fn header_value<'a>(header: &'a str, key: &str) -> Result<&'a str, HypernonsenseError> {
+    let pattern = format!("'{}':", key);
+    let start = header.find(&pattern).ok_or_else(|| HypernonsenseError::InvalidFormat(format!("npy header has no {}", key)))?;
+    return Ok(header[start + pattern.len()..].trim_start());
+}
+
+// Element type of a matrix: size in bytes and if it is big endian
+fn parse_descr(header: &str) -> Result<(usize, bool), HypernonsenseError> {
+    let value = header_value(header, "descr")?;
+    let descr = value.strip_prefix('\'')
+        .and_then(|v| v.split('\'').next())
+        .ok_or_else(|| invalid("npy descr is not a string"))?;
+
+    return match descr {
+        "<f4" | "=f4" => Ok((4, false)),
+        ">f4" => Ok((4, true)),
+        "<f8" | "=f8" => Ok((8, false)),
+        ">f8" => Ok((8, true)),
+        other => Err(HypernonsenseError::InvalidFormat(format!("unsupported npy element type {}, only f4 and f8 matrices can be loaded", other))),
+    };
+}
+
+fn parse_shape(header: &str) -> Result<(usize, usize), HypernonsenseError> {
+    let value = header_value(header, "shape")?;
+    let inner = value.strip_prefix('(')
+        .and_then(|v| v.split(')').next())
+        .ok_or_else(|| invalid("npy shape is not a tuple"))?;
+    let dims = inner.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim_end_matches('L').parse::<usize>().map_err(|_| invalid("npy shape is not a tuple of integers")))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    return match dims.as_slice() {
+        [rows, cols] => Ok((*rows, *cols)),
+        _ => Err(HypernonsenseError::InvalidFormat(format!("expected a two dimensional matrix, npy shape has {} dimensions", dims.len()))),
+    };
+}
+
+/// Read a `.npy` matrix of `f4` or `f8` elements, returning each row as a vector converted to `T`. The result can be passed
+/// to `MultiIndex::from_vectors` after giving each vector a key, e.g. with `enumerate`.
+pub fn read_npy<T:Scalar, R: Read>(reader: &mut R) -> Result<Vec<Vec<T>>, HypernonsenseError> {
+    let mut preamble = [0u8; 8];
+    read_exact(reader, &mut preamble)?;
+    if &preamble[..6] != MAGIC {
+        return Err(invalid("not a npy file"));
+    }
+
+    let header_len = match preamble[6] {
+        1 => {
+            let mut len = [0u8; 2];
+            read_exact(reader, &mut len)?;
+            u16::from_le_bytes(len) as usize
+        },
+        2 | 3 => {
+            let mut len = [0u8; 4];
+            read_exact(reader, &mut len)?;
+            u32::from_le_bytes(len) as usize
+        },
+        v => return Err(HypernonsenseError::UnsupportedVersion(v as u32)),
+    };
+
+    let mut header = Vec::new();
+    reader.take(header_len as u64).read_to_end(&mut header)?;
+    if header.len() != header_len {
+        return Err(invalid("unexpected end of data"));
+    }
+    let header = String::from_utf8(header).map_err(|_| invalid("npy header is not valid utf8"))?;
+
+    let (size, big_endian) = parse_descr(&header)?;
+    let (rows, cols) = parse_shape(&header)?;
+    let fortran_order = header_value(&header, "fortran_order")?.starts_with("True");
+
+    let mut data = Vec::new();
+    let len = rows.checked_mul(cols).and_then(|n| n.checked_mul(size)).ok_or_else(|| invalid("npy matrix is too large"))?;
+    reader.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(invalid("unexpected end of data"));
+    }
+
+    let elements = data.chunks_exact(size)
+        .map(|b| {
+            let mut b = b.to_vec();
+            if big_endian {
+                b.reverse();
+            }
+            match size {
+                4 => T::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])).unwrap(),
+                _ => T::from(f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])).unwrap(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Fortran order matrices are stored column by column
+    return Ok(match fortran_order {
+        false => elements.chunks(cols.max(1)).take(rows).map(|r| r.to_vec()).collect(),
+        true => (0..rows).map(|r| (0..cols).map(|c| elements[c * rows + r]).collect()).collect(),
+    });
+}
+
+/// Load a `.npy` matrix from a file, see `read_npy`
+pub fn load_npy<T:Scalar, P: AsRef<Path>>(path: P) -> Result<Vec<Vec<T>>, HypernonsenseError> {
+    let bytes = fs::read(path)?;
+    return read_npy(&mut bytes.as_slice());
+}
+
+/// Write vectors as a `.npy` matrix with one row per vector. The elements are written as `f8` if `T` is larger than 32 bits,
+/// otherwise as `f4`. Every vector must have the same length.
+pub fn write_npy<T:Scalar, W: Write>(writer: &mut W, vectors: &[Vec<T>]) -> Result<(), HypernonsenseError> {
+    let cols = vectors.first().map(Vec::len).unwrap_or(0);
+    if vectors.iter().any(|v| v.len() != cols) {
+        return Err(HypernonsenseError::InvalidConfig("every vector in a matrix must have the same length".to_string()));
+    }
+
+    let wide = std::mem::size_of::<T>() > 4;
+    let mut header = format!("{{'descr': '<f{}', 'fortran_order': False, 'shape': ({}, {}), }}", if wide { 8 } else { 4 }, vectors.len(), cols);
+
+    // The header is padded with spaces and ends with a newline, so the data starts on a 64 byte boundary
+    let unpadded = MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    let header_len = u16::try_from(header.len()).map_err(|_| invalid("npy header is too long"))?;
+    writer.write_all(&header_len.to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+
+    for v in vectors.iter().flatten() {
+        match wide {
+            true => writer.write_all(&v.to_f64().unwrap_or(f64::NAN).to_le_bytes())?,
+            false => writer.write_all(&v.to_f32().unwrap_or(f32::NAN).to_le_bytes())?,
+        }
+    }
+    return Ok(());
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), HypernonsenseError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => invalid("unexpected end of data"),
+        _ => HypernonsenseError::Io(e),
+    })
+}
+
+// Little endian integers at an offset into the archive, any read past the end is an error
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, HypernonsenseError> {
+    let b = data.get(offset..offset + 2).ok_or_else(|| invalid("unexpected end of data"))?;
+    return Ok(u16::from_le_bytes([b[0], b[1]]));
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, HypernonsenseError> {
+    let b = data.get(offset..offset + 4).ok_or_else(|| invalid("unexpected end of data"))?;
+    return Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, HypernonsenseError> {
+    let b = data.get(offset..offset + 8).ok_or_else(|| invalid("unexpected end of data"))?;
+    return Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]));
+}
+
+fn usize_at(value: u64) -> Result<usize, HypernonsenseError> {
+    return usize::try_from(value).map_err(|_| invalid("npz offset does not fit in usize"));
+}
+
+/// Read every matrix in a `.npz` archive, returning the name of each array (without `.npy`) and its rows. Only archives
+/// written without compression (`numpy.savez`) are supported.
+pub fn read_npz<T:Scalar>(data: &[u8]) -> Result<Vec<NamedMatrix<T>>, HypernonsenseError> {
+    // The end of central directory record is the last thing in the file, followed by a comment of up to 64KiB
+    let search_start = data.len().saturating_sub(22 + u16::MAX as usize);
+    let eocd = (search_start..data.len().saturating_sub(21)).rev()
+        .find(|i| data[*i..].starts_with(&[0x50, 0x4b, 0x05, 0x06]))
+        .ok_or_else(|| invalid("not a npz file"))?;
+
+    let mut entries = u16_at(data, eocd + 10)? as u64;
+    let mut directory = u32_at(data, eocd + 16)? as u64;
+    if entries == u16::MAX as u64 || directory == u32::MAX as u64 {
+        // Zip64, the real values are in another record found through the locator just before this one
+        let locator = eocd.checked_sub(20).filter(|l| u32_at(data, *l).ok() == Some(0x07064b50)).ok_or_else(|| invalid("npz zip64 locator is missing"))?;
+        let record = usize_at(u64_at(data, locator + 8)?)?;
+        if u32_at(data, record)? != 0x06064b50 {
+            return Err(invalid("npz zip64 record is missing"));
+        }
+        entries = u64_at(data, record + 32)?;
+        directory = u64_at(data, record + 48)?;
+    }
+
+    let mut result = Vec::new();
+    let mut offset = usize_at(directory)?;
+    for _ in 0..entries {
+        if u32_at(data, offset)? != 0x02014b50 {
+            return Err(invalid("npz central directory is corrupt"));
+        }
+        let method = u16_at(data, offset + 10)?;
+        let mut size = u32_at(data, offset + 20)? as u64;
+        let name_len = u16_at(data, offset + 28)? as usize;
+        let extra_len = u16_at(data, offset + 30)? as usize;
+        let comment_len = u16_at(data, offset + 32)? as usize;
+        let mut local = u32_at(data, offset + 42)? as u64;
+        let name = data.get(offset + 46..offset + 46 + name_len).ok_or_else(|| invalid("unexpected end of data"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        // Sizes and offsets which don't fit in 32 bits are in the zip64 extra field, in this order
+        let extra_start = offset + 46 + name_len;
+        let mut extra = extra_start;
+        while extra + 4 <= extra_start + extra_len {
+            let id = u16_at(data, extra)?;
+            let len = u16_at(data, extra + 2)? as usize;
+            if id == 1 {
+                let mut field = extra + 4;
+                if u32_at(data, offset + 24)? == u32::MAX {
+                    // Uncompressed size, which is the same as the compressed size of a stored entry
+                    field += 8;
+                }
+                if u32_at(data, offset + 20)? == u32::MAX {
+                    size = u64_at(data, field)?;
+                    field += 8;
+                }
+                if u32_at(data, offset + 42)? == u32::MAX {
+                    local = u64_at(data, field)?;
+                }
+            }
+            extra += 4 + len;
+        }
+        offset = extra_start + extra_len + comment_len;
+
+        if method != 0 {
+            return Err(HypernonsenseError::InvalidFormat(format!("{} is compressed, only npz files saved without compression can be loaded", name)));
+        }
+
+        let local = usize_at(local)?;
+        if u32_at(data, local)? != 0x04034b50 {
+            return Err(invalid("npz local header is corrupt"));
+        }
+        let start = local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        let mut contents = data.get(start..start.saturating_add(usize_at(size)?)).ok_or_else(|| invalid("unexpected end of data"))?;
+
+        let name = name.strip_suffix(".npy").map(str::to_string).unwrap_or(name);
+        result.push((name, read_npy(&mut contents)?));
+    }
+
+    return Ok(result);
+}
+
+/// Load every matrix in a `.npz` file, see `read_npz`
+pub fn load_npz<T:Scalar, P: AsRef<Path>>(path: P) -> Result<Vec<NamedMatrix<T>>, HypernonsenseError> {
+    return read_npz(&fs::read(path)?);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::npy::{ read_npy, read_npz, write_npy };
+
+    // A npz archive holding one uncompressed npy file, laid out like `numpy.savez` writes it
+    fn npz(name: &str, npy: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        data.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&(npy.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(npy.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(npy);
+
+        let directory = data.len();
+        data.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        data.extend_from_slice(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&(npy.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(npy.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(&[0; 12]);
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        let directory_len = data.len() - directory;
+
+        data.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+        data.extend_from_slice(&(directory_len as u32).to_le_bytes());
+        data.extend_from_slice(&(directory as u32).to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        return data;
+    }
+
+    #[test]
+    fn round_trip_preserves_matrix() {
+        let vectors = vec![vec![1f32, 2.0, 3.0], vec![4.0, 5.0, 6.0]];
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &vectors).unwrap();
+        assert_eq!(0, (bytes.len() - 6 * 4) % 64);
+        assert_eq!(vectors, read_npy::<f32, _>(&mut bytes.as_slice()).unwrap());
+
+        let wide = vec![vec![0.1f64, 0.2]];
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &wide).unwrap();
+        assert_eq!(wide, read_npy::<f64, _>(&mut bytes.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn fortran_order_is_transposed() {
+        let header = "{'descr': '>f4', 'fortran_order': True, 'shape': (2, 3), }\n";
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        for x in [1f32, 4.0, 2.0, 5.0, 3.0, 6.0].iter() {
+            bytes.extend_from_slice(&x.to_be_bytes());
+        }
+
+        assert_eq!(vec![vec![1f32, 2.0, 3.0], vec![4.0, 5.0, 6.0]], read_npy::<f32, _>(&mut bytes.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn read_rejects_unsupported_matrices() {
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &[vec![1f32]]).unwrap();
+        let text = String::from_utf8_lossy(&bytes).replace("<f4", "<i8").into_bytes();
+        assert!(read_npy::<f32, _>(&mut text.as_slice()).is_err());
+        assert!(read_npy::<f32, _>(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(read_npy::<f32, _>(&mut &b"not numpy"[..]).is_err());
+    }
+
+    #[test]
+    fn npz_arrays_are_named() {
+        let vectors = vec![vec![1f32, 2.0], vec![3.0, 4.0]];
+        let mut npy = Vec::new();
+        write_npy(&mut npy, &vectors).unwrap();
+
+        let arrays = read_npz::<f32>(&npz("embeddings.npy", &npy)).unwrap();
+        assert_eq!(1, arrays.len());
+        assert_eq!("embeddings", arrays[0].0);
+        assert_eq!(vectors, arrays[0].1);
+
+        // Compressed entries can't be read
+        let mut compressed = npz("embeddings.npy", &npy);
+        compressed[8] = 8;
+        let directory = compressed.len() - 22 - (46 + "embeddings.npy".len());
+        compressed[directory + 10] = 8;
+        assert!(read_npz::<f32>(&compressed).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+// Parallel iteration. With the `rayon` feature (the default) this is rayon, without it the same methods are provided for
+// ordinary iterators so everything runs sequentially on the calling thread.
+//
+// Only use methods which exist in both: rayon's `fold` and `reduce` have different signatures to the `Iterator` methods of
+// the same name, use `fold_with` and `reduce_with` instead. `zip` and `enumerate` (from rayon's `IndexedParallelIterator`) are
+// already on `Iterator`.
+
+// The thread pool a `MultiIndex` runs its parallel work on, `None` uses the global rayon pool
+#[cfg(feature = "rayon")]
+pub(crate) type Pool = Option<std::sync::Arc<rayon::ThreadPool>>;
+
+// Without rayon there is never a pool
+#[cfg(not(feature = "rayon"))]
+#[derive(Clone)]
+pub(crate) enum NoPool {}
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) type Pool = Option<NoPool>;
+
+/// Run some work on the given pool, any parallel iterators used inside will run on that pool's threads
+#[cfg(feature = "rayon")]
+pub(crate) fn install<R:Send, F:FnOnce() -> R + Send>(pool: &Pool, f: F) -> R {
+    return match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    };
+}
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) fn install<R:Send, F:FnOnce() -> R + Send>(_pool: &Pool, f: F) -> R {
+    return f();
+}
+
+#[cfg(feature = "rayon")]
+pub(crate) mod prelude {
+    pub(crate) use rayon::prelude::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator};
+}
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) mod prelude {
+    pub(crate) trait IntoParallelIterator : IntoIterator + Sized {
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<I:IntoIterator> IntoParallelIterator for I {
+    }
+
+    pub(crate) trait IntoParallelRefIterator<'a> {
+        type Iter : Iterator;
+
+        fn par_iter(&'a self) -> Self::Iter;
+    }
+
+    impl<'a, I:'a + ?Sized> IntoParallelRefIterator<'a> for I
+        where &'a I : IntoIterator
+    {
+        type Iter = <&'a I as IntoIterator>::IntoIter;
+
+        fn par_iter(&'a self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+
+    pub(crate) trait IntoParallelRefMutIterator<'a> {
+        type Iter : Iterator;
+
+        fn par_iter_mut(&'a mut self) -> Self::Iter;
+    }
+
+    impl<'a, I:'a + ?Sized> IntoParallelRefMutIterator<'a> for I
+        where &'a mut I : IntoIterator
+    {
+        type Iter = <&'a mut I as IntoIterator>::IntoIter;
+
+        fn par_iter_mut(&'a mut self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+
+    pub(crate) trait ParallelIterator : Iterator + Sized {
+        fn flat_map_iter<U, F>(self, f: F) -> std::iter::FlatMap<Self, U, F>
+            where U : IntoIterator, F : FnMut(Self::Item) -> U
+        {
+            self.flat_map(f)
+        }
+
+        fn map_init<S, R, INIT, F>(self, init: INIT, f: F) -> MapInit<Self, S, F>
+            where INIT : Fn() -> S, F : FnMut(&mut S, Self::Item) -> R
+        {
+            MapInit { iter: self, state: init(), f }
+        }
+
+        fn fold_with<A, F>(self, init: A, f: F) -> std::iter::Once<A>
+            where F : FnMut(A, Self::Item) -> A
+        {
+            std::iter::once(self.fold(init, f))
+        }
+
+        fn reduce_with<F>(self, f: F) -> Option<Self::Item>
+            where F : FnMut(Self::Item, Self::Item) -> Self::Item
+        {
+            self.reduce(f)
+        }
+    }
+
+    impl<I:Iterator> ParallelIterator for I {
+    }
+
+    // A map with a single piece of state, the sequential version of rayon's `map_init`
+    pub(crate) struct MapInit<I, S, F> {
+        iter: I,
+        state: S,
+        f: F,
+    }
+
+    impl<I:Iterator, S, R, F:FnMut(&mut S, I::Item) -> R> Iterator for MapInit<I, S, F> {
+        type Item = R;
+
+        fn next(&mut self) -> Option<R> {
+            let item = self.iter.next()?;
+            Some((self.f)(&mut self.state, item))
+        }
+    }
+}
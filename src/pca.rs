@@ -0,0 +1,151 @@
+use rand::Rng;
+use crate::par::prelude::*;
+
+use crate::error::HypernonsenseError;
+use crate::hyperindex::principal_component;
+use crate::vector::{Scalar, check_vector, dot, mean, sub};
+
+/// Principal component analysis, fitted to a sample of vectors. Transforming a vector centers it on the mean of the sample
+/// and projects it onto the directions in which the sample varies the most, which decorrelates the dimensions and keeps as
+/// much of the variance as possible in `output_dimensions` dimensions. Real embeddings are rarely centered on the origin or
+/// spread evenly in every direction, so transforming them before indexing gives more balanced groups.
+#[derive(Clone, Debug)]
+pub struct Pca<T:Scalar=f32> {
+    mean: Vec<T>,
+
+    // One row of `input_dims` values for each output dimension, most variance first
+    components: Vec<T>,
+    variance: Vec<T>,
+    input_dims: usize,
+}
+
+impl<T:Scalar> Pca<T> {
+    /// Find the `target_dims` principal components of a sample of vectors, with power iteration. The sample should be
+    /// representative of the vectors which will be transformed.
+    pub fn fit<R : Rng + Sized>(sample_vectors: &[Vec<T>], target_dims: usize, rng: &mut R) -> Result<Pca<T>, HypernonsenseError>
+    {
+        if sample_vectors.is_empty() {
+            return Err(HypernonsenseError::EmptyIndex);
+        }
+        let dims = sample_vectors[0].len();
+        for v in sample_vectors.iter() {
+            check_vector(v, dims)?;
+        }
+        if target_dims == 0 || target_dims > dims {
+            return Err(HypernonsenseError::InvalidConfig("target dimensions must be between one and the vector dimension".to_string()));
+        }
+
+        let mean = mean(sample_vectors).unwrap();
+        let centered = sample_vectors.par_iter().map(|v| sub(v, &mean)).collect::<Vec<_>>();
+
+        let mut components = Vec::<Vec<T>>::with_capacity(target_dims);
+        for _ in 0..target_dims {
+            let component = principal_component(&centered, &components, rng);
+            components.push(component);
+        }
+
+        let count = T::from(centered.len()).unwrap();
+        let variance = components.iter()
+            .map(|c| centered.iter().map(|x| dot(x, c) * dot(x, c)).sum::<T>() / count)
+            .collect();
+
+        return Ok(Pca { mean, components: components.concat(), variance, input_dims: dims });
+    }
+
+    pub fn input_dimensions(&self) -> usize {
+        return self.input_dims;
+    }
+
+    pub fn output_dimensions(&self) -> usize {
+        return self.variance.len();
+    }
+
+    /// Mean of the sample the components were fitted to
+    pub fn mean(&self) -> &[T] {
+        return &self.mean;
+    }
+
+    /// The principal components, row major with one row for each output dimension (most variance first)
+    pub fn components(&self) -> &[T] {
+        return &self.components;
+    }
+
+    /// Variance of the sample along each principal component
+    pub fn explained_variance(&self) -> &[T] {
+        return &self.variance;
+    }
+
+    /// Center a vector and project it onto the principal components.
+    ///
+    /// Panics if the vector does not have `input_dimensions` elements.
+    pub fn transform(&self, vector: &[T]) -> Vec<T> {
+        assert_eq!(self.input_dims, vector.len(), "vector does not have the input dimensions of the PCA");
+        return T::dot_rows(&self.components, &sub(vector, &self.mean));
+    }
+
+    /// Transform a vector, returns an error instead of panicking if the vector is not valid
+    pub fn try_transform(&self, vector: &[T]) -> Result<Vec<T>, HypernonsenseError> {
+        check_vector(vector, self.input_dims)?;
+        return Ok(self.transform(vector));
+    }
+
+    /// Transform many vectors in parallel
+    pub fn transform_batch(&self, vectors: &[Vec<T>]) -> Vec<Vec<T>> {
+        return vectors.par_iter().map(|v| self.transform(v)).collect();
+    }
+
+    /// Map a transformed vector back into the input space. Only the variance kept by the components is recovered.
+    pub fn inverse_transform(&self, vector: &[T]) -> Vec<T> {
+        assert_eq!(self.output_dimensions(), vector.len(), "vector does not have the output dimensions of the PCA");
+        let mut result = self.mean.clone();
+        for (row, x) in self.components.chunks_exact(self.input_dims).zip(vector.iter()) {
+            for (r, c) in result.iter_mut().zip(row.iter()) {
+                *r = *r + *c * *x;
+            }
+        }
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::pca::Pca;
+
+    #[test]
+    fn components_follow_the_variance() {
+        let mut rng = thread_rng();
+
+        // Points spread along (1, 1, 0) around (5, 5, 5), with a little noise along z
+        let sample = (0..500)
+            .map(|_| {
+                let t = rng.gen_range(-10f64..10f64);
+                vec![5.0 + t, 5.0 + t, 5.0 + rng.gen_range(-0.1..0.1)]
+            })
+            .collect::<Vec<_>>();
+        let pca = Pca::fit(&sample, 1, &mut rng).unwrap();
+
+        let c = pca.components();
+        assert!((c[0].abs() - 0.5f64.sqrt()).abs() < 1e-3);
+        assert!((c[1].abs() - 0.5f64.sqrt()).abs() < 1e-3);
+        assert!(c[2].abs() < 1e-2);
+        assert!((pca.mean()[0] - 5.0).abs() < 1.0);
+
+        // A point on the line is recovered exactly by the single component
+        let point = vec![8.0, 8.0, pca.mean()[2]];
+        let back = pca.inverse_transform(&pca.transform(&point));
+        assert!(back.iter().zip(point.iter()).all(|(a, b)| (a - b).abs() < 1e-2));
+    }
+
+    #[test]
+    fn fit_rejects_bad_dimensions() {
+        let mut rng = thread_rng();
+        let sample = vec![vec![1f32, 2.0], vec![3.0, 4.0]];
+        assert!(Pca::fit(&sample, 3, &mut rng).is_err());
+        assert!(Pca::fit(&sample, 0, &mut rng).is_err());
+        assert!(Pca::<f32>::fit(&[], 1, &mut rng).is_err());
+        assert!(Pca::fit(&sample, 2, &mut rng).unwrap().try_transform(&[1.0]).is_err());
+    }
+}
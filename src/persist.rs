@@ -0,0 +1,803 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+
+use bit_vec::BitVec;
+
+use crate::error::{HypernonsenseError, Section};
+use crate::group::Group;
+use crate::hyperindex::{HashFamily, HyperIndex};
+use crate::metric::Metric;
+use crate::multiindex::MultiIndex;
+use crate::query::Groups;
+use crate::vector::{Normalization, Scalar, check_scalar_kind};
+
+// File layout (all integers little endian):
+//
+//  magic         4 bytes "HYPN"
+//  version       u32
+//  scalar        u32 (the kind of the plane element type, see `ScalarKind::code`)
+//  header section:
+//      dims          u64
+//      index count   u32
+//      plane count   u32
+//  for each index:
+//      planes section:
+//          hash family   u8 (0 = hyperplane, 1 = p-stable followed by the bucket width as f32, 2 = cross-polytope followed
+//                        by the rotation dimensions as u8)
+//          planes        plane count * rows per plane * dims * T (rows per plane is 1 except for cross-polytope)
+//          offsets       plane count * T
+//      groups section:
+//          group count   u64
+//          for each group:
+//              key bytes     ceil(key bits / 8) bytes (key bits is plane count * bits per plane of the hash family)
+//              member count  u64
+//              members       member count * K (see `Persist`)
+//  vectors section:
+//      has vectors   u8
+//      if has vectors:
+//          vector count  u64
+//          for each vector:
+//              key           K
+//              vector        dims * T
+//  metadata section:
+//      metric        u8 (0 = cosine, 1 = euclidean)
+//      payload count u64
+//      for each payload:
+//          key           K
+//          payload       V
+//      has seed      u8
+//      seed          u64 (only if has seed)
+//      normalization u8 (0 = none, 1 = normalize on insert, 2 = reject non unit)
+//
+// Every section is written as
+//
+//  length        u64 (of the contents)
+//  checksum      u32 (CRC-32 of the contents)
+//  contents
+//
+// A section is only parsed once its checksum matches, so a corrupt file is reported as `HypernonsenseError::CorruptSection`
+// naming the section instead of being parsed into garbage.
+//
+// A single `HyperIndex` is saved as a file with one sub index and nothing else, so it can also be loaded as a `MultiIndex`.
+//
+// When the layout changes bump `FORMAT_VERSION` and keep a reader for the old version around, so old files can still be loaded.
+const MAGIC: &[u8; 4] = b"HYPN";
+const FORMAT_VERSION: u32 = 1;
+
+// Never trust a length read from the file when pre-allocating, a corrupt length should produce an error not an enormous allocation
+const MAX_PREALLOCATE: usize = 1 << 16;
+
+/// A type which can be written to and read from the binary index format
+pub trait Persist : Sized {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError>;
+}
+
+macro_rules! persist_primitive {
+    ($($t:ty),*) => {
+        $(
+            impl Persist for $t {
+                fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+                    let mut buf = [0u8; std::mem::size_of::<$t>()];
+                    read_exact(reader, &mut buf)?;
+                    Ok(<$t>::from_le_bytes(buf))
+                }
+            }
+        )*
+    }
+}
+
+persist_primitive!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+// Pointer sized integers are always stored as 64 bits, so files are portable between platforms
+impl Persist for usize {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u64).persist(writer)
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        let v = u64::restore(reader)?;
+        usize::try_from(v).map_err(|_| HypernonsenseError::InvalidFormat(format!("value {} does not fit in usize", v)))
+    }
+}
+
+impl Persist for isize {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as i64).persist(writer)
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        let v = i64::restore(reader)?;
+        isize::try_from(v).map_err(|_| HypernonsenseError::InvalidFormat(format!("value {} does not fit in isize", v)))
+    }
+}
+
+impl Persist for bool {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u8).persist(writer)
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        match u8::restore(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(HypernonsenseError::InvalidFormat(format!("invalid bool value {}", v))),
+        }
+    }
+}
+
+impl Persist for () {
+    fn persist<W: Write>(&self, _: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore<R: Read>(_: &mut R) -> Result<Self, HypernonsenseError> {
+        Ok(())
+    }
+}
+
+impl Persist for String {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().persist(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        let bytes = read_bytes(reader)?;
+        String::from_utf8(bytes).map_err(|_| HypernonsenseError::InvalidFormat("string is not valid utf8".to_string()))
+    }
+}
+
+impl Persist for Metric {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let tag: u8 = match self {
+            Metric::Cosine => 0,
+            Metric::Euclidean => 1,
+        };
+        tag.persist(writer)
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        match u8::restore(reader)? {
+            0 => Ok(Metric::Cosine),
+            1 => Ok(Metric::Euclidean),
+            v => Err(HypernonsenseError::InvalidFormat(format!("unknown metric {}", v))),
+        }
+    }
+}
+
+impl Persist for Normalization {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let tag: u8 = match self {
+            Normalization::None => 0,
+            Normalization::NormalizeOnInsert => 1,
+            Normalization::RejectNonUnit => 2,
+        };
+        tag.persist(writer)
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        match u8::restore(reader)? {
+            0 => Ok(Normalization::None),
+            1 => Ok(Normalization::NormalizeOnInsert),
+            2 => Ok(Normalization::RejectNonUnit),
+            v => Err(HypernonsenseError::InvalidFormat(format!("unknown normalization {}", v))),
+        }
+    }
+}
+
+impl Persist for HashFamily {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            HashFamily::Hyperplane => 0u8.persist(writer),
+            HashFamily::PStable { width } => {
+                1u8.persist(writer)?;
+                width.persist(writer)
+            }
+            HashFamily::CrossPolytope { rotation_dims } => {
+                2u8.persist(writer)?;
+                rotation_dims.persist(writer)
+            }
+        }
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        match u8::restore(reader)? {
+            0 => Ok(HashFamily::Hyperplane),
+            1 => Ok(HashFamily::PStable { width: f32::restore(reader)? }),
+            2 => Ok(HashFamily::CrossPolytope { rotation_dims: u8::restore(reader)? }),
+            v => Err(HypernonsenseError::InvalidFormat(format!("unknown hash family {}", v))),
+        }
+    }
+}
+
+impl<T:Persist> Persist for Vec<T> {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().persist(writer)?;
+        for item in self.iter() {
+            item.persist(writer)?;
+        }
+        Ok(())
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        let len = usize::restore(reader)?;
+        let mut result = Vec::with_capacity(len.min(MAX_PREALLOCATE));
+        for _ in 0..len {
+            result.push(T::restore(reader)?);
+        }
+        Ok(result)
+    }
+}
+
+// Written the same way as a `Vec`
+impl<T:Persist> Persist for Group<T> {
+    fn persist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().persist(writer)?;
+        for item in self.iter() {
+            item.persist(writer)?;
+        }
+        Ok(())
+    }
+
+    fn restore<R: Read>(reader: &mut R) -> Result<Self, HypernonsenseError> {
+        return Ok(Vec::<T>::restore(reader)?.into());
+    }
+}
+
+// CRC-32 (IEEE, as used by zip and png) lookup table
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    return table;
+}
+
+/// CRC-32 checksum of some bytes, for detecting corrupt or partially written data
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc = CRC_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    return !crc;
+}
+
+pub(crate) fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), HypernonsenseError> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            HypernonsenseError::InvalidFormat("unexpected end of data".to_string())
+        } else {
+            HypernonsenseError::Io(e)
+        }
+    })
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>, HypernonsenseError> {
+    let len = usize::restore(reader)?;
+
+    // Read through `take` so a corrupt length can't cause a huge allocation up front
+    let mut bytes = Vec::with_capacity(len.min(MAX_PREALLOCATE));
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(HypernonsenseError::InvalidFormat("unexpected end of data".to_string()));
+    }
+
+    return Ok(bytes);
+}
+
+fn write_planes<K:Send+Persist, T:Scalar+Persist, W: Write>(index: &HyperIndex<K, T>, writer: &mut W) -> io::Result<()> {
+    index.family().persist(writer)?;
+    for plane in index.planes() {
+        for v in plane {
+            v.persist(writer)?;
+        }
+    }
+
+    for v in index.offsets() {
+        v.persist(writer)?;
+    }
+
+    return Ok(());
+}
+
+fn write_groups<K:Send+Persist, T:Scalar+Persist, W: Write>(index: &HyperIndex<K, T>, writer: &mut W) -> io::Result<()> {
+    index.groups_len().persist(writer)?;
+    for (key, members) in index.groups_iter() {
+        writer.write_all(&key.to_bytes())?;
+        members.persist(writer)?;
+    }
+
+    return Ok(());
+}
+
+fn write_vectors<K:Persist, T:Scalar+Persist, W: Write>(vectors: Option<&HashMap<K, Vec<T>>>, writer: &mut W) -> io::Result<()> {
+    match vectors {
+        None => false.persist(writer)?,
+        Some(vectors) => {
+            true.persist(writer)?;
+            vectors.len().persist(writer)?;
+            for (key, vector) in vectors.iter() {
+                key.persist(writer)?;
+                for v in vector {
+                    v.persist(writer)?;
+                }
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+fn write_metadata<K:Persist, V:Persist, W: Write>(writer: &mut W, metric: Metric, payloads: &HashMap<K, V>, seed: Option<u64>, normalization: Normalization) -> io::Result<()> {
+    metric.persist(writer)?;
+
+    payloads.len().persist(writer)?;
+    for (key, payload) in payloads.iter() {
+        key.persist(writer)?;
+        payload.persist(writer)?;
+    }
+
+    match seed {
+        None => false.persist(writer)?,
+        Some(seed) => {
+            true.persist(writer)?;
+            seed.persist(writer)?;
+        }
+    }
+
+    return normalization.persist(writer);
+}
+
+// Write one section: its length, the checksum of its contents and then the contents. The contents are built in `buffer`
+// first, since the length has to be written before them.
+fn write_section<W: Write, F>(writer: &mut W, buffer: &mut Vec<u8>, write: F) -> io::Result<()>
+    where F : FnOnce(&mut Vec<u8>) -> io::Result<()>
+{
+    buffer.clear();
+    write(buffer)?;
+
+    buffer.len().persist(writer)?;
+    crc32(buffer).persist(writer)?;
+    return writer.write_all(buffer);
+}
+
+// Read one section, it's only parsed once its checksum has been checked
+fn read_section<R: Read, X, F>(reader: &mut R, section: Section, read: F) -> Result<X, HypernonsenseError>
+    where F : FnOnce(&mut dyn Read) -> Result<X, HypernonsenseError>
+{
+    let len = usize::restore(reader)?;
+    let checksum = u32::restore(reader)?;
+    let mut contents = Vec::with_capacity(len.min(MAX_PREALLOCATE));
+    reader.take(len as u64).read_to_end(&mut contents)?;
+    if contents.len() != len {
+        return Err(HypernonsenseError::InvalidFormat(format!("the {} section is truncated", section)));
+    }
+    if crc32(&contents) != checksum {
+        return Err(HypernonsenseError::CorruptSection(section));
+    }
+
+    let mut remaining = contents.as_slice();
+    let value = read(&mut remaining)?;
+    if !remaining.is_empty() {
+        return Err(HypernonsenseError::InvalidFormat(format!("unexpected data at the end of the {} section", section)));
+    }
+
+    return Ok(value);
+}
+
+type PlaneParts<T> = (HashFamily, Vec<Vec<T>>, Vec<T>);
+
+fn read_planes<T:Scalar+Persist, R: Read>(reader: &mut R, dims: usize, plane_count: usize) -> Result<PlaneParts<T>, HypernonsenseError> {
+    // The family comes first, the plane size depends on it
+    let family = check_family(HashFamily::restore(reader)?, dims)?;
+
+    let plane_len = dims.checked_mul(family.rows_per_plane()).ok_or_else(|| HypernonsenseError::InvalidFormat("plane is too large".to_string()))?;
+    let mut planes = Vec::with_capacity(plane_count);
+    for _ in 0..plane_count {
+        let mut plane = Vec::with_capacity(plane_len.min(MAX_PREALLOCATE));
+        for _ in 0..plane_len {
+            plane.push(T::restore(reader)?);
+        }
+        planes.push(plane);
+    }
+
+    let mut offsets = Vec::with_capacity(plane_count);
+    for _ in 0..plane_count {
+        offsets.push(T::restore(reader)?);
+    }
+
+    return Ok((family, planes, offsets));
+}
+
+// A file must not produce a hash family which `MultiIndex::builder` would reject, e.g. zero length cross-polytope planes
+fn check_family(family: HashFamily, dims: usize) -> Result<HashFamily, HypernonsenseError> {
+    family.validate(dims).map_err(HypernonsenseError::InvalidFormat)?;
+    return Ok(family);
+}
+
+fn read_groups<K:Persist, R: Read>(reader: &mut R, key_len: usize) -> Result<Groups<K>, HypernonsenseError> {
+    let group_count = usize::restore(reader)?;
+    let mut groups = Groups::with_capacity_and_hasher(group_count.min(MAX_PREALLOCATE), Default::default());
+    let mut key_bytes = vec![0u8; key_len.div_ceil(8)];
+    for _ in 0..group_count {
+        read_exact(reader, &mut key_bytes)?;
+        let mut key = BitVec::from_bytes(&key_bytes);
+        key.truncate(key_len);
+
+        let members = Group::<K>::restore(reader)?;
+        if groups.insert(key, members).is_some() {
+            return Err(HypernonsenseError::InvalidFormat("duplicate group key".to_string()));
+        }
+    }
+
+    return Ok(groups);
+}
+
+fn read_vectors<K:Eq+Hash+Persist, T:Scalar+Persist, R: Read>(reader: &mut R, dims: usize) -> Result<Option<HashMap<K, Vec<T>>>, HypernonsenseError> {
+    if !bool::restore(reader)? {
+        return Ok(None);
+    }
+
+    let count = usize::restore(reader)?;
+    let mut vectors = HashMap::with_capacity(count.min(MAX_PREALLOCATE));
+    for _ in 0..count {
+        let key = K::restore(reader)?;
+        let mut vector = Vec::with_capacity(dims.min(MAX_PREALLOCATE));
+        for _ in 0..dims {
+            vector.push(T::restore(reader)?);
+        }
+        vectors.insert(key, vector);
+    }
+
+    return Ok(Some(vectors));
+}
+
+type Metadata<K, V> = (Metric, HashMap<K, V>, Option<u64>, Normalization);
+
+fn read_metadata<K:Eq+Hash+Persist, V:Persist, R: Read>(reader: &mut R) -> Result<Metadata<K, V>, HypernonsenseError> {
+    let metric = Metric::restore(reader)?;
+
+    let count = usize::restore(reader)?;
+    let mut payloads = HashMap::with_capacity(count.min(MAX_PREALLOCATE));
+    for _ in 0..count {
+        let key = K::restore(reader)?;
+        payloads.insert(key, V::restore(reader)?);
+    }
+
+    let seed = if bool::restore(reader)? {
+        Some(u64::restore(reader)?)
+    } else {
+        None
+    };
+
+    let normalization = Normalization::restore(reader)?;
+    return Ok((metric, payloads, seed, normalization));
+}
+
+fn write_header<T:Scalar, W: Write>(writer: &mut W, buffer: &mut Vec<u8>, dims: usize, index_count: usize, plane_count: usize) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    FORMAT_VERSION.persist(writer)?;
+    T::KIND.code().persist(writer)?;
+
+    return write_section(writer, buffer, |w| {
+        dims.persist(w)?;
+        (index_count as u32).persist(w)?;
+        (plane_count as u32).persist(w)
+    });
+}
+
+pub(crate) fn write_single_hyperindex<K:Send+Persist, T:Scalar+Persist, W: Write>(index: &HyperIndex<K, T>, writer: &mut W) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    write_header::<T, W>(writer, &mut buffer, index.dimensions(), 1, index.planes_len())?;
+    write_section(writer, &mut buffer, |w| write_planes(index, w))?;
+    write_section(writer, &mut buffer, |w| write_groups(index, w))?;
+
+    // No vectors, the default metric, no payloads, no seed and the default normalization
+    write_section(writer, &mut buffer, |w| write_vectors::<K, T, _>(None, w))?;
+    write_section(writer, &mut buffer, |w| write_metadata(w, Metric::default(), &HashMap::<K, ()>::new(), None, Normalization::default()))?;
+
+    return Ok(());
+}
+
+pub(crate) fn read_single_hyperindex<K:Clone+Send+Sync+Eq+Hash+Persist, T:Scalar+Persist, R: Read>(reader: &mut R) -> Result<HyperIndex<K, T>, HypernonsenseError> {
+    let index = read_multiindex::<K, T, (), R>(reader)?;
+    if index.indices().len() != 1 {
+        return Err(HypernonsenseError::InvalidFormat(format!("expected a single index, file contains {}", index.indices().len())));
+    }
+
+    return Ok(index.into_indices().remove(0));
+}
+
+pub(crate) fn write_multiindex<K:Send+Sync+Eq+Hash+Persist, T:Scalar+Persist, V:Send+Sync+Persist, W: Write>(index: &MultiIndex<K, T, V>, writer: &mut W) -> io::Result<()> {
+    let indices = index.indices();
+    let mut buffer = Vec::new();
+    write_header::<T, W>(writer, &mut buffer, indices[0].dimensions(), indices.len(), indices[0].planes_len())?;
+
+    for idx in indices {
+        write_section(writer, &mut buffer, |w| write_planes(idx, w))?;
+        write_section(writer, &mut buffer, |w| write_groups(idx, w))?;
+    }
+
+    write_section(writer, &mut buffer, |w| write_vectors(index.vectors(), w))?;
+    write_section(writer, &mut buffer, |w| write_metadata(w, index.metric(), index.payloads(), index.seed(), index.normalization()))?;
+
+    return Ok(());
+}
+
+pub(crate) fn read_multiindex<K:Send+Sync+Eq+Hash+Persist, T:Scalar+Persist, V:Send+Sync+Persist, R: Read>(reader: &mut R) -> Result<MultiIndex<K, T, V>, HypernonsenseError> {
+    let mut magic = [0u8; 4];
+    read_exact(reader, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(HypernonsenseError::InvalidFormat("not a hypernonsense index".to_string()));
+    }
+
+    let version = u32::restore(reader)?;
+    if version != FORMAT_VERSION {
+        return Err(HypernonsenseError::UnsupportedVersion(version));
+    }
+    check_scalar_kind::<T>(u32::restore(reader)? as u64, "index")?;
+
+    let (dims, index_count, plane_count) = read_section(reader, Section::Header, |mut r| {
+        Ok((usize::restore(&mut r)?, u32::restore(&mut r)? as usize, u32::restore(&mut r)? as usize))
+    })?;
+    if index_count == 0 {
+        return Err(HypernonsenseError::InvalidFormat("index count must be at least one".to_string()));
+    }
+    if plane_count > u8::MAX as usize {
+        return Err(HypernonsenseError::InvalidFormat(format!("too many planes ({})", plane_count)));
+    }
+
+    let mut indices = Vec::with_capacity(index_count.min(MAX_PREALLOCATE));
+    for index in 0..index_count {
+        let (family, planes, offsets) = read_section(reader, Section::Planes { index }, |mut r| read_planes::<T, _>(&mut r, dims, plane_count))?;
+        let key_len = plane_count * family.bits_per_plane();
+        let groups = read_section(reader, Section::Groups { index }, |mut r| read_groups::<K, _>(&mut r, key_len))?;
+        indices.push(HyperIndex::from_parts(dims, planes, offsets, family, groups));
+    }
+
+    let vectors = read_section(reader, Section::Vectors, |mut r| read_vectors(&mut r, dims))?;
+    let (metric, payloads, seed, normalization) = read_section(reader, Section::Metadata, |mut r| read_metadata(&mut r))?;
+
+    return Ok(MultiIndex::from_parts(indices, vectors, payloads, metric, seed, normalization));
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::convert::TryInto;
+
+    use rand::prelude::*;
+
+    use crate::error::{ HypernonsenseError, Section };
+    use crate::hyperindex::HashFamily;
+    use crate::metric::Metric;
+    use crate::multiindex::MultiIndex;
+    use crate::persist::{ FORMAT_VERSION, Persist, crc32, read_planes };
+    use crate::vector::{ Normalization, random_unit_vector, random_unit_vector_of };
+
+    #[test]
+    fn crc32_matches_reference_values() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn read_rejects_invalid_hash_families() {
+        for family in [HashFamily::CrossPolytope { rotation_dims: 0 }, HashFamily::CrossPolytope { rotation_dims: 11 }, HashFamily::PStable { width: 0f32 }, HashFamily::PStable { width: f32::NAN }] {
+            let mut buf = Vec::new();
+            family.persist(&mut buf).unwrap();
+            buf.extend(std::iter::repeat_n(0u8, 1024));
+
+            let result = read_planes::<f32, _>(&mut buf.as_slice(), 10, 1);
+            assert!(matches!(result, Err(HypernonsenseError::InvalidFormat(_))), "{:?} was accepted", family);
+        }
+    }
+
+    #[test]
+    fn round_trip_preserves_index() {
+        let mut a = MultiIndex::new(50, 5, 8, &mut thread_rng());
+
+        let mut rng = thread_rng();
+        let mut vectors = Vec::new();
+        for key in 0..500usize {
+            let v = random_unit_vector(50, &mut rng);
+            a.add(format!("key{}", key), &v);
+            vectors.push(v);
+        }
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        let b = MultiIndex::<String>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(a.dimensions(), b.dimensions());
+        assert_eq!(a.planes_len(), b.planes_len());
+        assert_eq!(a.indices_len(), b.indices_len());
+        for v in vectors.iter() {
+            let mut expected = a.nearest_points(v);
+            let mut actual = b.nearest_points(v);
+            expected.sort();
+            actual.sort();
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn round_trip_preserves_vectors() {
+        let mut a = MultiIndex::new_with_storage(20, 3, 4, &mut thread_rng());
+        let v = random_unit_vector(20, &mut thread_rng());
+        a.add(3u32, &v);
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        let b = MultiIndex::<u32>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert!(b.stores_vectors());
+        assert_eq!(Some(v.as_slice()), b.get(&3));
+    }
+
+    #[test]
+    fn round_trip_preserves_metric() {
+        let mut a = MultiIndex::<u32>::new(20, 3, 4, &mut thread_rng());
+        a.set_metric(Metric::Euclidean);
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        let b = MultiIndex::<u32>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(Metric::Euclidean, b.metric());
+    }
+
+    #[test]
+    fn round_trip_preserves_seed() {
+        let a = MultiIndex::<u32>::with_seed(20, 3, 4, 1234);
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        let b = MultiIndex::<u32>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(Some(1234), b.seed());
+    }
+
+    #[test]
+    fn round_trip_preserves_normalization() {
+        let mut a = MultiIndex::<u32>::new(20, 3, 4, &mut thread_rng());
+        a.set_normalization(Normalization::RejectNonUnit);
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        let b = MultiIndex::<u32>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(Normalization::RejectNonUnit, b.normalization());
+    }
+
+    #[test]
+    fn round_trip_preserves_hash_family() {
+        let mut a = MultiIndex::<u32>::builder(20).index_count(3).plane_count(4).hash_family(HashFamily::PStable { width: 2f32 }).build().unwrap();
+        let mut c = MultiIndex::<u32>::builder(20).index_count(3).plane_count(4).hash_family(HashFamily::CrossPolytope { rotation_dims: 6 }).build().unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..100).map(|_| random_unit_vector(20, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            a.add(key as u32, v);
+            c.add(key as u32, v);
+        }
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        let b = MultiIndex::<u32>::read_from(&mut buf.as_slice()).unwrap();
+
+        let mut buf = Vec::new();
+        c.write_to(&mut buf).unwrap();
+        let d = MultiIndex::<u32>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(HashFamily::PStable { width: 2f32 }, b.hash_family());
+        assert_eq!(HashFamily::CrossPolytope { rotation_dims: 6 }, d.hash_family());
+        for v in vectors.iter() {
+            for (original, loaded) in [(&a, &b), (&c, &d)] {
+                let mut expected = original.nearest_points(v);
+                let mut actual = loaded.nearest_points(v);
+                expected.sort();
+                actual.sort();
+                assert_eq!(expected, actual);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_preserves_payloads() {
+        let mut a = MultiIndex::<u32, f32, String>::builder(20).index_count(3).plane_count(4).build().unwrap();
+        a.add_with_payload(3, &random_unit_vector(20, &mut thread_rng()), "three".to_string());
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        let b = MultiIndex::<u32, f32, String>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(Some(&"three".to_string()), b.payload(&3));
+    }
+
+    #[test]
+    fn read_rejects_wrong_scalar_type() {
+        let mut a = MultiIndex::<usize, f64>::new(10, 2, 4, &mut thread_rng());
+        a.add(1, &random_unit_vector_of(10, &mut thread_rng()));
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+
+        let result = MultiIndex::<usize, f32>::read_from(&mut buf.as_slice());
+        assert!(matches!(result, Err(HypernonsenseError::InvalidFormat(_))));
+        assert!(MultiIndex::<usize, f64>::read_from(&mut buf.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn read_rejects_bad_magic() {
+        let result = MultiIndex::<usize>::read_from(&mut b"NOPE\x01\x00\x00\x00".as_ref());
+        assert!(matches!(result, Err(HypernonsenseError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn read_rejects_other_versions() {
+        let a = MultiIndex::<usize>::new(10, 2, 4, &mut thread_rng());
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        assert_eq!(FORMAT_VERSION.to_le_bytes(), buf[4..8]);
+
+        for version in [ 0u32, FORMAT_VERSION + 1, 255 ] {
+            buf[4..8].copy_from_slice(&version.to_le_bytes());
+            let result = MultiIndex::<usize>::read_from(&mut buf.as_slice());
+            assert!(matches!(result, Err(HypernonsenseError::UnsupportedVersion(v)) if v == version));
+        }
+    }
+
+    #[test]
+    fn read_reports_corrupt_sections() {
+        let mut a = MultiIndex::<u32>::builder(10).index_count(2).plane_count(4).store_vectors(true).build().unwrap();
+        a.add(1, &random_unit_vector(10, &mut thread_rng()));
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+
+        // Flip a byte in the contents of each section in turn, sections start after the magic, version and scalar size
+        let sections = [Section::Header, Section::Planes { index: 0 }, Section::Groups { index: 0 }, Section::Planes { index: 1 }, Section::Groups { index: 1 }, Section::Vectors, Section::Metadata];
+        let mut start = 12;
+        for section in sections.iter() {
+            let len = u64::from_le_bytes(buf[start..start + 8].try_into().unwrap()) as usize;
+            let mut corrupt = buf.clone();
+            corrupt[start + 12] ^= 0x10;
+
+            let result = MultiIndex::<u32>::read_from(&mut corrupt.as_slice());
+            assert!(matches!(result, Err(HypernonsenseError::CorruptSection(s)) if s == *section), "{:?}", section);
+            start += 12 + len;
+        }
+        assert_eq!(buf.len(), start);
+    }
+
+    #[test]
+    fn read_rejects_truncated_data() {
+        let mut a = MultiIndex::new(50, 5, 8, &mut thread_rng());
+        a.add(1usize, &random_unit_vector(50, &mut thread_rng()));
+
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        buf.truncate(buf.len() - 3);
+
+        let result = MultiIndex::<usize>::read_from(&mut buf.as_slice());
+        assert!(matches!(result, Err(HypernonsenseError::InvalidFormat(_))));
+    }
+}
@@ -0,0 +1,246 @@
+use rand::Rng;
+use rand::seq::index::sample;
+use crate::par::prelude::*;
+
+use crate::error::HypernonsenseError;
+use crate::metric::Metric;
+use crate::vector::{Scalar, check_vector};
+
+/// Product quantizer, compresses vectors into one byte per subspace.
+///
+/// The dimensions are split into `subspaces` contiguous ranges and each range has its own codebook of up to 256 centroids,
+/// found by k-means over a sample of vectors. A vector is encoded as the index of the nearest centroid in each subspace, so
+/// a 300 dimension `f32` vector (1200 bytes) quantized with 50 subspaces takes 50 bytes.
+#[derive(Clone, Debug)]
+pub struct ProductQuantizer<T:Scalar=f32> {
+    dims: usize,
+
+    // Start of each subspace, plus the end of the last one
+    bounds: Vec<usize>,
+
+    // Centroids of each subspace, and their squared lengths
+    centroids: Vec<Vec<Vec<T>>>,
+    norms: Vec<Vec<T>>,
+}
+
+impl<T:Scalar> ProductQuantizer<T> {
+    /// Maximum number of centroids in each subspace, so that a code fits in a byte
+    pub const MAX_CENTROIDS: usize = 256;
+
+    /// Train a quantizer on a sample of vectors, running k-means with `centroids` centroids for `iterations` iterations in
+    /// each subspace. The sample should be representative of the vectors which will be encoded and should contain at least
+    /// `centroids` vectors.
+    pub fn train<R : Rng + Sized>(sample_vectors: &[Vec<T>], subspaces: usize, centroids: usize, iterations: usize, rng: &mut R) -> Result<ProductQuantizer<T>, HypernonsenseError>
+    {
+        if sample_vectors.is_empty() {
+            return Err(HypernonsenseError::EmptyIndex);
+        }
+        let dims = sample_vectors[0].len();
+        for v in sample_vectors.iter() {
+            check_vector(v, dims)?;
+        }
+        if subspaces == 0 || subspaces > dims {
+            return Err(HypernonsenseError::InvalidConfig("subspaces must be between one and the vector dimension".to_string()));
+        }
+        if centroids == 0 || centroids > Self::MAX_CENTROIDS {
+            return Err(HypernonsenseError::InvalidConfig(format!("centroids must be between one and {}", Self::MAX_CENTROIDS)));
+        }
+
+        // Split the dimensions as evenly as possible, subspaces differ in size by at most one
+        let bounds = (0..=subspaces).map(|s| s * dims / subspaces).collect::<Vec<_>>();
+
+        let centroids = bounds.windows(2)
+            .map(|b| {
+                let parts = sample_vectors.iter().map(|v| &v[b[0]..b[1]]).collect::<Vec<_>>();
+                kmeans(&parts, centroids, iterations, rng)
+            })
+            .collect::<Vec<_>>();
+        let norms = centroids.iter()
+            .map(|c| c.iter().map(|c| T::dot(c, c)).collect())
+            .collect();
+
+        return Ok(ProductQuantizer { dims, bounds, centroids, norms });
+    }
+
+    pub fn dimensions(&self) -> usize {
+        return self.dims;
+    }
+
+    pub fn subspaces(&self) -> usize {
+        return self.centroids.len();
+    }
+
+    /// Number of centroids in each subspace
+    pub fn centroids(&self) -> usize {
+        return self.centroids[0].len();
+    }
+
+    fn parts<'a>(&'a self, vector: &'a [T]) -> impl Iterator<Item=&'a [T]> {
+        return self.bounds.windows(2).map(move |b| &vector[b[0]..b[1]]);
+    }
+
+    /// Encode a vector as the nearest centroid in each subspace
+    pub fn encode(&self, vector: &[T]) -> Vec<u8> {
+        assert_eq!(self.dims, vector.len(), "vector does not have the dimension of the quantizer");
+
+        return self.parts(vector)
+            .zip(self.centroids.iter())
+            .map(|(part, centroids)| nearest_centroid(centroids, part) as u8)
+            .collect();
+    }
+
+    /// Reconstruct the approximate vector represented by a code
+    pub fn decode(&self, code: &[u8]) -> Vec<T> {
+        return code.iter()
+            .zip(self.centroids.iter())
+            .flat_map(|(c, centroids)| centroids[*c as usize].iter().cloned())
+            .collect();
+    }
+
+    /// Precompute the distance from a query to every centroid, so the distance to an encoded vector can be found with one
+    /// lookup per subspace
+    pub fn distance_table(&self, query: &[T], metric: Metric) -> DistanceTable<'_, T> {
+        assert_eq!(self.dims, query.len(), "query does not have the dimension of the quantizer");
+
+        let table = self.parts(query)
+            .zip(self.centroids.iter())
+            .map(|(part, centroids)| centroids.iter()
+                .map(|c| match metric {
+                    Metric::Cosine => T::dot(part, c),
+                    Metric::Euclidean => T::squared_euclidean(part, c),
+                })
+                .collect())
+            .collect();
+
+        return DistanceTable { quantizer: self, metric, table, query_norm: T::dot(query, query) };
+    }
+}
+
+/// Distances from one query to every centroid of a `ProductQuantizer`, see `ProductQuantizer::distance_table`
+pub struct DistanceTable<'a, T:Scalar> {
+    quantizer: &'a ProductQuantizer<T>,
+    metric: Metric,
+
+    // Dot product (cosine) or squared distance (euclidean) from the query to every centroid of every subspace
+    table: Vec<Vec<T>>,
+    query_norm: T,
+}
+
+impl<'a, T:Scalar> DistanceTable<'a, T> {
+    /// Distance from the query to the decoded vector of a code
+    pub fn distance(&self, code: &[u8]) -> T {
+        let sum = code.iter().zip(self.table.iter()).map(|(c, t)| t[*c as usize]).sum::<T>();
+
+        return match self.metric {
+            Metric::Cosine => {
+                let norm = code.iter().zip(self.quantizer.norms.iter()).map(|(c, n)| n[*c as usize]).sum::<T>();
+                let len = (norm * self.query_norm).sqrt();
+                if len == T::zero() {
+                    T::one()
+                } else {
+                    (T::one() - sum / len).max(T::zero())
+                }
+            },
+            Metric::Euclidean => sum.sqrt(),
+        };
+    }
+}
+
+pub(crate) fn nearest_centroid<T:Scalar>(centroids: &[Vec<T>], part: &[T]) -> usize {
+    let mut best = 0;
+    let mut best_distance = T::infinity();
+    for (i, c) in centroids.iter().enumerate() {
+        let d = T::squared_euclidean(part, c);
+        if d < best_distance {
+            best = i;
+            best_distance = d;
+        }
+    }
+    return best;
+}
+
+// Lloyd's algorithm, starting from randomly chosen sample points. A centroid which ends up with no points keeps its position.
+pub(crate) fn kmeans<T:Scalar, R:Rng>(parts: &[&[T]], count: usize, iterations: usize, rng: &mut R) -> Vec<Vec<T>> {
+    let count = count.min(parts.len());
+    let mut centroids = sample(rng, parts.len(), count).iter().map(|i| parts[i].to_vec()).collect::<Vec<_>>();
+
+    for _ in 0..iterations {
+        let assignments = parts.par_iter().map(|p| nearest_centroid(&centroids, p)).collect::<Vec<_>>();
+
+        let mut sums = vec![vec![T::zero(); parts[0].len()]; count];
+        let mut counts = vec![0usize; count];
+        for (part, c) in parts.iter().zip(assignments.iter()) {
+            counts[*c] += 1;
+            for (s, x) in sums[*c].iter_mut().zip(part.iter()) {
+                *s = *s + *x;
+            }
+        }
+
+        let mut moved = false;
+        for ((centroid, sum), n) in centroids.iter_mut().zip(sums).zip(counts) {
+            if n > 0 {
+                let n = T::from(n).unwrap();
+                let mean = sum.into_iter().map(|s| s / n).collect::<Vec<_>>();
+                moved |= mean != *centroid;
+                *centroid = mean;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    return centroids;
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::error::HypernonsenseError;
+    use crate::metric::Metric;
+    use crate::pq::ProductQuantizer;
+    use crate::vector::{ euclidean_distance, random_unit_vector };
+
+    #[test]
+    fn codes_decode_near_the_original() {
+        let mut rng = thread_rng();
+        let vectors = (0..2000).map(|_| random_unit_vector(32, &mut rng)).collect::<Vec<_>>();
+        let pq = ProductQuantizer::train(&vectors, 8, 64, 10, &mut rng).unwrap();
+        assert_eq!(8, pq.subspaces());
+        assert_eq!(64, pq.centroids());
+
+        let code = pq.encode(&vectors[0]);
+        assert_eq!(8, code.len());
+
+        // Quantizing must do better than pointing in a random direction
+        let error = euclidean_distance(&vectors[0], &pq.decode(&code));
+        assert!(error < 1f32, "{}", error);
+    }
+
+    #[test]
+    fn table_distance_matches_decoded_distance() {
+        let mut rng = thread_rng();
+        let vectors = (0..500).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        let pq = ProductQuantizer::train(&vectors, 7, 16, 5, &mut rng).unwrap();
+
+        let query = random_unit_vector(30, &mut rng);
+        for metric in [Metric::Cosine, Metric::Euclidean] {
+            let table = pq.distance_table(&query, metric);
+            for v in vectors.iter().take(20) {
+                let code = pq.encode(v);
+                let expected = metric.distance(&query, &pq.decode(&code));
+                assert!((expected - table.distance(&code)).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn train_rejects_bad_config() {
+        let vectors = vec![vec![0f32; 4]; 10];
+        assert!(matches!(ProductQuantizer::train(&vectors, 5, 4, 1, &mut thread_rng()), Err(HypernonsenseError::InvalidConfig(_))));
+        assert!(matches!(ProductQuantizer::train(&vectors, 2, 257, 1, &mut thread_rng()), Err(HypernonsenseError::InvalidConfig(_))));
+        assert!(matches!(ProductQuantizer::<f32>::train(&[], 2, 4, 1, &mut thread_rng()), Err(HypernonsenseError::EmptyIndex)));
+    }
+}
@@ -0,0 +1,216 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use crate::par::prelude::*;
+
+use crate::error::HypernonsenseError;
+use crate::hyperindex::random_rotation;
+use crate::persist::Persist;
+use crate::vector::{Scalar, check_vector};
+
+// File layout (all integers little endian):
+//
+//  magic         4 bytes "HYPP"
+//  version       u32
+//  scalar size   u32 (size of the matrix element type in bytes)
+//  input dims    u64
+//  output dims   u64
+//  matrix        output dims * input dims * T (row major, one row per output dimension)
+//
+// The layout is kept separate from the index format, so a projection can be written to the same stream just before the index
+// it was used to build.
+const MAGIC: &[u8; 4] = b"HYPP";
+const FORMAT_VERSION: u32 = 1;
+
+/// A linear projection applied to vectors before they are indexed, e.g. to reduce 1536 dimension embeddings to 256
+/// dimensions. Random projections approximately preserve distances between vectors (the Johnson-Lindenstrauss lemma), so
+/// the index can be built and queried in the smaller space. Every query must be projected the same way as the indexed
+/// vectors, save the projection alongside the index and load it back with it.
+#[derive(Clone, Debug)]
+pub struct Projection<T:Scalar=f32> {
+    // One row of `input_dims` values for each output dimension
+    matrix: Vec<T>,
+    input_dims: usize,
+    output_dims: usize,
+}
+
+impl<T:Scalar> Projection<T> {
+    /// Create a gaussian random projection from a seed, scaled by `1 / sqrt(output_dims)` so lengths are preserved on
+    /// average. The same seed always generates the same projection, on every platform.
+    pub fn random(input_dims: usize, output_dims: usize, seed: u64) -> Result<Projection<T>, HypernonsenseError> {
+        Self::check_dims(input_dims, output_dims)?;
+
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let scale = T::one() / T::from(output_dims).unwrap().sqrt();
+        let matrix = (0..input_dims * output_dims).map(|_| T::standard_normal(&mut rng) * scale).collect();
+        return Ok(Projection { matrix, input_dims, output_dims });
+    }
+
+    /// Create a projection onto `output_dims` random orthonormal directions from a seed. With `output_dims == input_dims`
+    /// this is a random rotation, which preserves every distance exactly. `output_dims` can't be larger than `input_dims`.
+    pub fn rotation(input_dims: usize, output_dims: usize, seed: u64) -> Result<Projection<T>, HypernonsenseError> {
+        Self::check_dims(input_dims, output_dims)?;
+        if output_dims > input_dims {
+            return Err(HypernonsenseError::InvalidConfig("a rotation cannot have more output dimensions than input dimensions".to_string()));
+        }
+
+        let matrix = random_rotation(input_dims, output_dims, &mut ChaCha20Rng::seed_from_u64(seed));
+        return Ok(Projection { matrix, input_dims, output_dims });
+    }
+
+    /// Create a projection from a row major matrix, with one row of `input_dims` values for each output dimension
+    pub fn from_matrix(input_dims: usize, output_dims: usize, matrix: Vec<T>) -> Result<Projection<T>, HypernonsenseError> {
+        Self::check_dims(input_dims, output_dims)?;
+        check_vector(&matrix, input_dims * output_dims)?;
+        return Ok(Projection { matrix, input_dims, output_dims });
+    }
+
+    fn check_dims(input_dims: usize, output_dims: usize) -> Result<(), HypernonsenseError> {
+        if input_dims == 0 || output_dims == 0 {
+            return Err(HypernonsenseError::InvalidConfig("a projection must have at least one input and output dimension".to_string()));
+        }
+        return Ok(());
+    }
+
+    pub fn input_dimensions(&self) -> usize {
+        return self.input_dims;
+    }
+
+    pub fn output_dimensions(&self) -> usize {
+        return self.output_dims;
+    }
+
+    /// The projection matrix, row major with one row for each output dimension
+    pub fn matrix(&self) -> &[T] {
+        return &self.matrix;
+    }
+
+    /// Project a vector into the output space.
+    ///
+    /// Panics if the vector does not have `input_dimensions` elements.
+    pub fn project(&self, vector: &[T]) -> Vec<T> {
+        assert_eq!(self.input_dims, vector.len(), "vector does not have the input dimensions of the projection");
+        return T::dot_rows(&self.matrix, vector);
+    }
+
+    /// Project a vector, returns an error instead of panicking if the vector is not valid
+    pub fn try_project(&self, vector: &[T]) -> Result<Vec<T>, HypernonsenseError> {
+        check_vector(vector, self.input_dims)?;
+        return Ok(self.project(vector));
+    }
+
+    /// Project many vectors in parallel
+    pub fn project_batch(&self, vectors: &[Vec<T>]) -> Vec<Vec<T>> {
+        return vectors.par_iter().map(|v| self.project(v)).collect();
+    }
+}
+
+impl<T:Scalar+Persist> Projection<T> {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HypernonsenseError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        return Ok(());
+    }
+
+    /// Load a projection from a file previously written with `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Projection<T>, HypernonsenseError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        return Self::read_from(&mut reader);
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), HypernonsenseError> {
+        writer.write_all(MAGIC)?;
+        FORMAT_VERSION.persist(writer)?;
+        (std::mem::size_of::<T>() as u32).persist(writer)?;
+        self.input_dims.persist(writer)?;
+        self.output_dims.persist(writer)?;
+        for v in self.matrix.iter() {
+            v.persist(writer)?;
+        }
+        return Ok(());
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Projection<T>, HypernonsenseError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(HypernonsenseError::InvalidFormat("not a hypernonsense projection".to_string()));
+        }
+
+        let version = u32::restore(reader)?;
+        if version != FORMAT_VERSION {
+            return Err(HypernonsenseError::UnsupportedVersion(version));
+        }
+        let scalar_size = u32::restore(reader)? as usize;
+        if scalar_size != std::mem::size_of::<T>() {
+            return Err(HypernonsenseError::InvalidFormat(format!("projection was written with {} byte scalars", scalar_size)));
+        }
+
+        let input_dims = usize::restore(reader)?;
+        let output_dims = usize::restore(reader)?;
+        let len = input_dims.checked_mul(output_dims)
+            .ok_or_else(|| HypernonsenseError::InvalidFormat("projection is too large".to_string()))?;
+
+        // Don't trust the dimensions when pre-allocating, a corrupt header should produce an error not an enormous allocation
+        let mut matrix = Vec::with_capacity(len.min(1 << 16));
+        for _ in 0..len {
+            matrix.push(T::restore(reader)?);
+        }
+
+        return Self::from_matrix(input_dims, output_dims, matrix)
+            .map_err(|e| HypernonsenseError::InvalidFormat(e.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::projection::Projection;
+    use crate::vector::{ dot, euclidean_distance, random_unit_vector };
+
+    #[test]
+    fn random_projection_roughly_preserves_distances() {
+        let mut rng = thread_rng();
+        let a = random_unit_vector(512, &mut rng);
+        let b = random_unit_vector(512, &mut rng);
+
+        let projection = Projection::random(512, 256, 7).unwrap();
+        let before = euclidean_distance(&a, &b);
+        let after = euclidean_distance(&projection.project(&a), &projection.project(&b));
+        assert_eq!(256, projection.project(&a).len());
+        assert!((after / before - 1.0).abs() < 0.3, "distance changed from {} to {}", before, after);
+
+        // The same seed always gives the same projection
+        assert_eq!(projection.matrix(), Projection::<f32>::random(512, 256, 7).unwrap().matrix());
+    }
+
+    #[test]
+    fn rotation_preserves_dot_products() {
+        let mut rng = thread_rng();
+        let a = random_unit_vector(30, &mut rng);
+        let b = random_unit_vector(30, &mut rng);
+
+        let rotation = Projection::rotation(30, 30, 3).unwrap();
+        assert!((dot(&a, &b) - dot(&rotation.project(&a), &rotation.project(&b))).abs() < 1e-4);
+        assert!(Projection::<f32>::rotation(30, 31, 3).is_err());
+    }
+
+    #[test]
+    fn round_trip_preserves_projection() {
+        let projection = Projection::<f64>::random(20, 5, 1).unwrap();
+        let mut bytes = Vec::new();
+        projection.write_to(&mut bytes).unwrap();
+
+        let loaded = Projection::<f64>::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(projection.matrix(), loaded.matrix());
+        assert_eq!(5, loaded.output_dimensions());
+        assert!(Projection::<f32>::read_from(&mut bytes.as_slice()).is_err());
+        assert!(Projection::<f64>::read_from(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+}
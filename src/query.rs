@@ -0,0 +1,504 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::time::Duration;
+
+use bit_vec::BitVec;
+use crate::par::prelude::*;
+
+use crate::error::HypernonsenseError;
+use crate::group::Group;
+use crate::multiindex::DistanceNode;
+use crate::vector::{Scalar, check_vector};
+
+/// Options which control how a `MultiIndex` query is executed
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    /// Every group within this hamming distance of the query key is probed in each sub index. Radius 0 only probes the
+    /// group the query point falls into, radius 1 (the default) also probes every group one plane away, and so on.
+    /// Larger radii increase recall at the cost of query time.
+    pub probe_radius: u8,
+
+    /// When this isn't zero, `nearest_with` ranks the candidates with the distance function it was given and keeps the best
+    /// `rerank_top` of them, then recomputes their distances exactly with the index metric and the stored vectors. This lets
+    /// a cheap approximate distance (e.g. from quantized codes) do most of the work without losing accuracy in the final
+    /// results. Requires vector storage. Defaults to zero.
+    pub rerank_top: usize,
+
+    /// When this is set, `nearest_with` keeps widening the probe radius past `probe_radius` until at least `count` distinct
+    /// candidates have been found, or this radius has been probed. This avoids returning too few results from sparse
+    /// areas of the index without making every query probe more groups. Defaults to None.
+    pub max_probe_radius: Option<u8>,
+
+    /// When this is set, no more than this many candidates are ranked, which bounds the number of distance calculations a
+    /// query can make when it lands in very large groups. The candidates found in the most sub indices are kept. Defaults
+    /// to None.
+    pub max_candidates: Option<usize>,
+
+    /// Candidates found in fewer than this many sub indices are dropped before any distances are measured. A point found
+    /// in many sub indices is much more likely to be a true neighbour, so this cuts the long tail of chance collisions
+    /// from large candidate sets. Zero and one keep every candidate. Defaults to zero.
+    pub min_votes: u8,
+
+    /// When this is set, `nearest_with` probes groups in the same order as `MultiIndex::nearest_anytime` and stops once
+    /// this much time has passed, returning the best results found so far. The time is checked before probing each ring
+    /// (once the group the query falls into has been probed in every sub index), so a query can overrun by the time to
+    /// probe and rank one ring. `max_probe_radius`, `max_candidates` and `min_votes`
+    /// are not used by these queries. Defaults to None.
+    pub time_budget: Option<Duration>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            probe_radius: 1,
+            rerank_top: 0,
+            max_probe_radius: None,
+            max_candidates: None,
+            min_votes: 0,
+            time_budget: None,
+        }
+    }
+}
+
+impl QueryOptions {
+    pub fn with_probe_radius(mut self, probe_radius: u8) -> Self {
+        self.probe_radius = probe_radius;
+        self
+    }
+
+    pub fn with_rerank_top(mut self, rerank_top: usize) -> Self {
+        self.rerank_top = rerank_top;
+        self
+    }
+
+    /// Widen the probe radius up to `max_probe_radius` until enough candidates are found, see `max_probe_radius`
+    pub fn with_adaptive_probing(mut self, max_probe_radius: u8) -> Self {
+        self.max_probe_radius = Some(max_probe_radius);
+        self
+    }
+
+    pub fn with_max_candidates(mut self, max_candidates: usize) -> Self {
+        self.max_candidates = Some(max_candidates);
+        self
+    }
+
+    pub fn with_min_votes(mut self, min_votes: u8) -> Self {
+        self.min_votes = min_votes;
+        self
+    }
+
+    pub fn with_time_budget(mut self, time_budget: Duration) -> Self {
+        self.time_budget = Some(time_budget);
+        self
+    }
+
+    // Whether a candidate set of this size needs to be pruned by votes before ranking
+    pub(crate) fn prunes(&self, candidates: usize) -> bool {
+        self.min_votes > 1 || self.max_candidates.is_some_and(|max| candidates > max)
+    }
+
+    // Number of results to keep from the first ranking
+    pub(crate) fn shortlist_len(&self, count: usize) -> usize {
+        if self.rerank_top == 0 { count } else { self.rerank_top.max(count) }
+    }
+}
+
+/// How the results of a query with several query points (e.g. a set of examples) are combined by `MultiIndex::nearest_multi`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Aggregation {
+    /// Query with the mean of the query points, this finds items similar to the set as a whole
+    #[default]
+    Mean,
+
+    /// Union the candidates of every query point and rank each one by its distance to the nearest query point, this finds
+    /// items similar to any one of the examples
+    MinDistance,
+
+    /// Union the candidates of every query point and rank each one by its average distance to all of the query points
+    MeanDistance,
+}
+
+/// Builds a query point from positive and negative examples, as `positive_weight * mean(positive) - negative_weight *
+/// mean(negative)`. This searches for items which are more like the positive examples and less like the negative ones.
+#[derive(Clone, Debug)]
+pub struct ExampleQuery<T:Scalar=f32> {
+    positive: Vec<Vec<T>>,
+    negative: Vec<Vec<T>>,
+    positive_weight: T,
+    negative_weight: T,
+}
+
+impl<T:Scalar> Default for ExampleQuery<T> {
+    fn default() -> Self {
+        ExampleQuery {
+            positive: Vec::new(),
+            negative: Vec::new(),
+            positive_weight: T::one(),
+            negative_weight: T::one(),
+        }
+    }
+}
+
+impl<T:Scalar> ExampleQuery<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an example the results should be similar to
+    pub fn like(mut self, vector: &[T]) -> Self {
+        self.positive.push(vector.to_vec());
+        self
+    }
+
+    /// Add an example the results should not be similar to
+    pub fn unlike(mut self, vector: &[T]) -> Self {
+        self.negative.push(vector.to_vec());
+        self
+    }
+
+    /// Weight of the mean of the positive examples, defaults to one
+    pub fn positive_weight(mut self, weight: T) -> Self {
+        self.positive_weight = weight;
+        self
+    }
+
+    /// Weight of the mean of the negative examples, defaults to one
+    pub fn negative_weight(mut self, weight: T) -> Self {
+        self.negative_weight = weight;
+        self
+    }
+
+    /// Combine the examples into one query point with the given number of dimensions. Returns an error if there are no
+    /// positive examples or an example has the wrong number of dimensions.
+    pub fn build(&self, dimensions: usize) -> Result<Vec<T>, HypernonsenseError> {
+        if self.positive.is_empty() {
+            return Err(HypernonsenseError::InvalidConfig("an example query needs at least one positive example".to_string()));
+        }
+
+        let mut query = vec![T::zero(); dimensions];
+        for (examples, weight) in [(&self.positive, self.positive_weight), (&self.negative, -self.negative_weight)] {
+            if examples.is_empty() {
+                continue;
+            }
+
+            let scale = weight / T::from(examples.len()).unwrap();
+            for example in examples.iter() {
+                check_vector(example, dimensions)?;
+                for (q, x) in query.iter_mut().zip(example.iter()) {
+                    *q = *q + *x * scale;
+                }
+            }
+        }
+        return Ok(query);
+    }
+}
+
+/// Every candidate of a query ranked nearest first, returned by `MultiIndex::nearest_paged`. The results don't borrow the
+/// index, so they can be kept (e.g. per user session) and pages fetched later without running the query again.
+pub struct RankedResults<K:Eq+Hash, T:Scalar=f32> {
+    ranked: Vec<DistanceNode<K, T>>,
+    next: usize,
+}
+
+impl<K:Eq+Hash, T:Scalar> RankedResults<K, T> {
+    pub(crate) fn new(ranked: Vec<DistanceNode<K, T>>) -> RankedResults<K, T> {
+        return RankedResults { ranked, next: 0 };
+    }
+
+    /// Get the next `count` results after the last page, the page is empty once every result has been returned
+    pub fn next_page(&mut self, count: usize) -> &[DistanceNode<K, T>] {
+        let start = self.next;
+        self.next = start.saturating_add(count).min(self.ranked.len());
+        return &self.ranked[start..self.next];
+    }
+
+    /// Get `count` results starting from the `start`th nearest, without moving the position of `next_page`
+    pub fn page(&self, start: usize, count: usize) -> &[DistanceNode<K, T>] {
+        let start = start.min(self.ranked.len());
+        let end = start.saturating_add(count).min(self.ranked.len());
+        return &self.ranked[start..end];
+    }
+
+    /// Number of results which have not been returned by `next_page` yet
+    pub fn remaining(&self) -> usize {
+        return self.ranked.len() - self.next;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.ranked.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ranked.is_empty();
+    }
+}
+
+/// Measure the distance to every candidate in parallel, then sort (small->large) and keep the nearest `count`
+pub(crate) fn rank_candidates<K, T, F>(candidates: HashSet<K>, count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+    where K : Eq + Hash + Send, T : Scalar, F : Fn(&K) -> T + Send + Sync
+{
+    let mut result = candidates
+        .into_par_iter()
+        .map(|a| DistanceNode { distance: get_dist(&a), key: a })
+        .collect::<Vec<_>>();
+
+    result.sort_unstable();
+    result.truncate(count);
+    result.shrink_to_fit();
+
+    return result;
+}
+
+/// Rank candidates which are borrowed from the groups of an index like `rank_candidates`, only the nearest `count` keys are cloned
+pub(crate) fn rank_borrowed<K, T, F>(candidates: HashSet<&K>, count: usize, get_dist: F) -> Vec<DistanceNode<K, T>>
+    where K : Clone + Eq + Hash + Sync, T : Scalar, F : Fn(&K) -> T + Send + Sync
+{
+    return rank_candidates(candidates, count, |k| get_dist(k))
+        .iter()
+        .map(DistanceNode::cloned)
+        .collect();
+}
+
+/// Number of keys within the given hamming distance of a key with `bits` bits (saturating)
+pub(crate) fn probe_count(bits: usize, radius: u8) -> usize {
+    let mut total = 1usize;
+    let mut combinations = 1usize;
+    for i in 0..(radius as usize).min(bits) {
+        // C(n, i+1) = C(n, i) * (n - i) / (i + 1)
+        combinations = combinations.saturating_mul(bits - i) / (i + 1);
+        total = total.saturating_add(combinations);
+    }
+    return total;
+}
+
+/// Call `f` with every key within `radius` bits of `key`. The key is modified in place while probing and restored before returning.
+pub(crate) fn for_each_probe<F: FnMut(&BitVec)>(key: &mut BitVec, radius: u8, f: &mut F) {
+    probe_from(key, 0, radius, f);
+}
+
+fn probe_from<F: FnMut(&BitVec)>(key: &mut BitVec, start: usize, remaining: u8, f: &mut F) {
+    f(key);
+    if remaining == 0 {
+        return;
+    }
+
+    for i in start..key.len() {
+        key.set(i, !key[i]);
+        probe_from(key, i + 1, remaining - 1, f);
+        key.set(i, !key[i]);
+    }
+}
+
+/// Groups of an index, by group key
+pub(crate) type Groups<K> = HashMap<BitVec, Group<K>, BuildHasherDefault<GroupHasher>>;
+
+// FxHash (the hasher used inside rustc) for group keys. Every query looks up many groups and SipHash is a large part of the
+// cost of each lookup. Group keys come from random planes which aren't visible outside of the index, so the protection
+// SipHash gives against chosen collisions isn't needed.
+#[derive(Default)]
+pub(crate) struct GroupHasher {
+    hash: u64,
+}
+
+impl GroupHasher {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+}
+
+impl Hasher for GroupHasher {
+    fn finish(&self) -> u64 {
+        return self.hash;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+}
+
+/// Get every non empty group whose key is within `radius` bits of the given key
+pub(crate) fn groups_within<'a, K>(groups: &'a Groups<K>, key: &BitVec, radius: u8) -> Vec<&'a [K]> {
+    return groups_within_keyed(groups, key, radius).into_iter().map(|(_, g)| g.as_slice()).collect();
+}
+
+/// Get every non empty group whose key is within `radius` bits of the given key, along with the key of the group
+pub(crate) fn groups_within_keyed<'a, K>(groups: &'a Groups<K>, key: &BitVec, radius: u8) -> Vec<(&'a BitVec, &'a Group<K>)> {
+    let mut result = Vec::new();
+
+    // When there are more keys to probe than there are groups it's cheaper to check the distance to every group
+    if probe_count(key.len(), radius) > groups.len() {
+        for (k, group) in groups.iter() {
+            if hamming_distance(k, key) <= radius as u32 {
+                result.push((k, group));
+            }
+        }
+    } else {
+        let mut probe = key.clone();
+        for_each_probe(&mut probe, radius, &mut |k| {
+            if let Some(entry) = groups.get_key_value(k) {
+                result.push(entry);
+            }
+        });
+    }
+
+    return result;
+}
+
+/// Get every non empty group whose key is exactly `radius` bits away from the given key
+pub(crate) fn groups_at<'a, K>(groups: &'a Groups<K>, key: &BitVec, radius: u8) -> Vec<&'a [K]> {
+    let mut result = Vec::new();
+    if radius as usize > key.len() {
+        return result;
+    }
+
+    // Long keys saturate the probe count, then the ring is larger than any number of groups (not the difference of two
+    // saturated counts, which is zero)
+    let total = probe_count(key.len(), radius);
+    let ring = match total {
+        usize::MAX => usize::MAX,
+        _ => total - if radius == 0 { 0 } else { probe_count(key.len(), radius - 1) },
+    };
+    if ring > groups.len() {
+        for (k, group) in groups.iter() {
+            if hamming_distance(k, key) == radius as u32 {
+                result.push(group);
+            }
+        }
+    } else {
+        let mut probe = key.clone();
+        ring_from(&mut probe, 0, radius, &mut |k| {
+            if let Some(group) = groups.get(k) {
+                result.push(group);
+            }
+        });
+    }
+
+    return result;
+}
+
+fn ring_from<F: FnMut(&BitVec)>(key: &mut BitVec, start: usize, remaining: u8, f: &mut F) {
+    if remaining == 0 {
+        f(key);
+        return;
+    }
+
+    // Leave enough bits after this one to flip the rest, otherwise the recursion walks every subset of the remaining bits
+    // without reaching the ring
+    for i in start..(key.len() + 1).saturating_sub(remaining as usize) {
+        key.set(i, !key[i]);
+        ring_from(key, i + 1, remaining - 1, f);
+        key.set(i, !key[i]);
+    }
+}
+
+pub(crate) fn hamming_distance(a: &BitVec, b: &BitVec) -> u32 {
+    return a.blocks()
+        .zip(b.blocks())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashSet;
+    use std::hash::{BuildHasher, BuildHasherDefault};
+
+    use bit_vec::BitVec;
+
+    use crate::query::{ ExampleQuery, GroupHasher, Groups, for_each_probe, groups_at, probe_count, hamming_distance };
+
+    #[test]
+    fn example_queries_combine_weighted_means() {
+        let query = ExampleQuery::new()
+            .like(&[1f32, 0.0])
+            .like(&[0.0, 1.0])
+            .unlike(&[1.0, 1.0])
+            .negative_weight(0.5)
+            .build(2)
+            .unwrap();
+        assert_eq!(vec![0.0, 0.0], query);
+
+        assert!(ExampleQuery::<f32>::new().unlike(&[1.0]).build(1).is_err());
+        assert!(ExampleQuery::new().like(&[1f32, 0.0]).build(3).is_err());
+    }
+
+    #[test]
+    fn group_hasher_separates_short_keys() {
+        // Every 10 bit key fits in one block, and must land in a different bucket of a table with 1024 buckets
+        let hasher = BuildHasherDefault::<GroupHasher>::default();
+        let buckets = (0..1024u32)
+            .map(|i| BitVec::from_fn(10, |b| (i >> b) & 1 == 1))
+            .map(|k| hasher.hash_one(&k) & 1023)
+            .collect::<HashSet<_>>();
+        assert_eq!(1024, buckets.len());
+    }
+
+    #[test]
+    fn probe_count_matches_binomials() {
+        assert_eq!(1, probe_count(10, 0));
+        assert_eq!(11, probe_count(10, 1));
+        assert_eq!(56, probe_count(10, 2));
+        assert_eq!(1024, probe_count(10, 10));
+        assert_eq!(1024, probe_count(10, 200));
+    }
+
+    #[test]
+    fn probes_are_unique_and_within_radius() {
+        let original = BitVec::from_bytes(&[0b1010_0110, 0b1100_0000]);
+        let mut key = original.clone();
+
+        let mut seen = HashSet::new();
+        for_each_probe(&mut key, 2, &mut |k| {
+            assert!(hamming_distance(k, &original) <= 2);
+            assert!(seen.insert(k.clone()));
+        });
+
+        assert_eq!(original, key);
+        assert_eq!(probe_count(16, 2), seen.len());
+    }
+
+    #[test]
+    fn groups_at_scans_groups_when_probe_count_saturates() {
+        let key = BitVec::from_elem(128, false);
+        let mut far = key.clone();
+        for i in 0..100 {
+            far.set(i, true);
+        }
+        let mut near = key.clone();
+        near.set(0, true);
+
+        let mut groups = Groups::default();
+        groups.insert(far, vec![1].into());
+        groups.insert(near, vec![2].into());
+
+        // Enumerating every key 100 bits away would never finish
+        assert_eq!(usize::MAX, probe_count(128, 100));
+        assert_eq!(vec![&vec![1]], groups_at(&groups, &key, 100));
+        assert_eq!(vec![&vec![2]], groups_at(&groups, &key, 1));
+
+        // No key is further away than its length
+        let short = BitVec::from_elem(20, false);
+        assert!(groups_at(&groups, &short, 21).is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Details of a single query, passed to a `Recorder` after the query has finished
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryEvent {
+    /// Number of distinct candidates found in the groups which were probed
+    pub candidates: usize,
+
+    /// Number of groups looked up, across all sub indices
+    pub probes: usize,
+
+    /// Number of results returned
+    pub results: usize,
+
+    /// Time taken by the whole query
+    pub duration: Duration,
+
+    /// Time taken by each phase of the query
+    pub phases: QueryPhases,
+}
+
+/// Time spent in each phase of a query. Sub indices are searched in parallel, so the key and probing times are the total
+/// across every sub index and can add up to more than the duration of the query.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueryPhases {
+    /// Projecting the query onto the planes to get a key for each sub index
+    pub keys: Duration,
+
+    /// Looking up the groups within the probe radius of each key
+    pub probing: Duration,
+
+    /// Merging and deduplicating the keys found in every group
+    pub collection: Duration,
+
+    /// Measuring the distance to every candidate and sorting them
+    pub ranking: Duration,
+}
+
+impl QueryPhases {
+    fn iter(&self) -> [(&'static str, Duration); 4] {
+        [("keys", self.keys), ("probing", self.probing), ("collection", self.collection), ("ranking", self.ranking)]
+    }
+}
+
+// Times a query and its phases. This does nothing unless there is a recorder to report to, so queries without one don't
+// read the clock (which isn't available on every target).
+pub(crate) struct QueryTimer {
+    start: Option<Instant>,
+    last: Option<Instant>,
+    pub(crate) phases: QueryPhases,
+}
+
+impl QueryTimer {
+    pub(crate) fn new(enabled: bool) -> QueryTimer {
+        let start = if enabled { Some(Instant::now()) } else { None };
+        QueryTimer { start, last: start, phases: QueryPhases::default() }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.start.is_some()
+    }
+
+    /// Add the time since the last lap to a phase
+    pub(crate) fn lap<F>(&mut self, phase: F)
+        where F : FnOnce(&mut QueryPhases) -> &mut Duration
+    {
+        if let Some(last) = self.last {
+            let now = Instant::now();
+            *phase(&mut self.phases) += now - last;
+            self.last = Some(now);
+        }
+    }
+
+    /// Add time measured elsewhere to the phases, and start the next lap from now
+    pub(crate) fn add(&mut self, keys: Duration, probing: Duration) {
+        if self.last.is_some() {
+            self.phases.keys += keys;
+            self.phases.probing += probing;
+            self.last = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.map(|s| s.elapsed()).unwrap_or_default()
+    }
+}
+
+// Start timing something inside a phase, if the timer is enabled
+pub(crate) fn stopwatch(enabled: bool) -> Option<Instant> {
+    if enabled { Some(Instant::now()) } else { None }
+}
+
+pub(crate) fn since(start: Option<Instant>) -> Duration {
+    start.map(|s| s.elapsed()).unwrap_or_default()
+}
+
+/// Receives events from a `MultiIndex`, so a service can monitor how the index is being used. Every method does nothing by
+/// default, implement the ones you need.
+///
+/// Events are reported from whichever thread did the work, so implementations should be cheap and must not block.
+pub trait Recorder : Send + Sync {
+    /// Some items were inserted
+    fn insert(&self, _count: usize) {
+    }
+
+    /// Some items were removed
+    fn remove(&self, _count: usize) {
+    }
+
+    /// A query finished
+    fn query(&self, _event: &QueryEvent) {
+    }
+}
+
+/// Upper bounds (in seconds) of the query latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1, 1.0];
+
+/// A `Recorder` which keeps counters and a latency histogram, and can write them in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct CountingRecorder {
+    inserts: AtomicU64,
+    removes: AtomicU64,
+    queries: AtomicU64,
+    candidates: AtomicU64,
+    probes: AtomicU64,
+    results: AtomicU64,
+    query_nanos: AtomicU64,
+    phase_nanos: [AtomicU64; 4],
+
+    // Number of queries in each latency bucket, the last one is for queries slower than every bucket
+    latency: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl CountingRecorder {
+    pub fn new() -> CountingRecorder {
+        Default::default()
+    }
+
+    pub fn inserts(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed)
+    }
+
+    pub fn removes(&self) -> u64 {
+        self.removes.load(Ordering::Relaxed)
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    /// Total number of candidates found by every query
+    pub fn candidates(&self) -> u64 {
+        self.candidates.load(Ordering::Relaxed)
+    }
+
+    /// Total number of groups probed by every query
+    pub fn probes(&self) -> u64 {
+        self.probes.load(Ordering::Relaxed)
+    }
+
+    /// Total time taken by every query
+    pub fn query_time(&self) -> Duration {
+        Duration::from_nanos(self.query_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Total time spent in each phase by every query
+    pub fn phases(&self) -> QueryPhases {
+        let phase = |i: usize| Duration::from_nanos(self.phase_nanos[i].load(Ordering::Relaxed));
+        QueryPhases { keys: phase(0), probing: phase(1), collection: phase(2), ranking: phase(3) }
+    }
+
+    /// Write every counter in the Prometheus text exposition format. Every metric name starts with `prefix`.
+    pub fn write_prometheus<W: Write>(&self, prefix: &str, writer: &mut W) -> io::Result<()> {
+        let counters = [
+            ("inserts_total", "Number of items inserted", self.inserts()),
+            ("removes_total", "Number of items removed", self.removes()),
+            ("queries_total", "Number of queries", self.queries()),
+            ("candidates_total", "Number of candidates found by queries", self.candidates()),
+            ("probes_total", "Number of groups probed by queries", self.probes()),
+            ("results_total", "Number of results returned by queries", self.results.load(Ordering::Relaxed)),
+        ];
+        for (name, help, value) in counters.iter() {
+            writeln!(writer, "# HELP {}_{} {}", prefix, name, help)?;
+            writeln!(writer, "# TYPE {}_{} counter", prefix, name)?;
+            writeln!(writer, "{}_{} {}", prefix, name, value)?;
+        }
+
+        writeln!(writer, "# HELP {}_query_phase_seconds_total Time spent in each phase of a query", prefix)?;
+        writeln!(writer, "# TYPE {}_query_phase_seconds_total counter", prefix)?;
+        for (phase, duration) in self.phases().iter().iter() {
+            writeln!(writer, "{}_query_phase_seconds_total{{phase=\"{}\"}} {}", prefix, phase, duration.as_secs_f64())?;
+        }
+
+        writeln!(writer, "# HELP {}_query_duration_seconds Query latency", prefix)?;
+        writeln!(writer, "# TYPE {}_query_duration_seconds histogram", prefix)?;
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.latency.iter()) {
+            cumulative += count.load(Ordering::Relaxed);
+            writeln!(writer, "{}_query_duration_seconds_bucket{{le=\"{}\"}} {}", prefix, bound, cumulative)?;
+        }
+        cumulative += self.latency[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        writeln!(writer, "{}_query_duration_seconds_bucket{{le=\"+Inf\"}} {}", prefix, cumulative)?;
+        writeln!(writer, "{}_query_duration_seconds_sum {}", prefix, self.query_time().as_secs_f64())?;
+        writeln!(writer, "{}_query_duration_seconds_count {}", prefix, cumulative)?;
+
+        return Ok(());
+    }
+}
+
+impl Recorder for CountingRecorder {
+    fn insert(&self, count: usize) {
+        self.inserts.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn remove(&self, count: usize) {
+        self.removes.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn query(&self, event: &QueryEvent) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.candidates.fetch_add(event.candidates as u64, Ordering::Relaxed);
+        self.probes.fetch_add(event.probes as u64, Ordering::Relaxed);
+        self.results.fetch_add(event.results as u64, Ordering::Relaxed);
+        self.query_nanos.fetch_add(event.duration.as_nanos() as u64, Ordering::Relaxed);
+        for (total, (_, duration)) in self.phase_nanos.iter().zip(event.phases.iter().iter()) {
+            total.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        }
+
+        let seconds = event.duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|b| seconds <= *b).unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+
+    use crate::recorder::{ CountingRecorder, QueryEvent, QueryPhases, Recorder };
+
+    #[test]
+    fn counting_recorder_writes_prometheus() {
+        let a = CountingRecorder::new();
+        a.insert(3);
+        let phases = QueryPhases { ranking: Duration::from_millis(250), ..Default::default() };
+        a.query(&QueryEvent { candidates: 10, probes: 4, results: 2, duration: Duration::from_micros(300), phases: QueryPhases::default() });
+        a.query(&QueryEvent { candidates: 5, probes: 4, results: 2, duration: Duration::from_secs(2), phases });
+
+        let mut out = Vec::new();
+        a.write_prometheus("hn", &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        assert!(text.contains("hn_inserts_total 3\n"));
+        assert!(text.contains("hn_candidates_total 15\n"));
+        assert!(text.contains("hn_query_duration_seconds_bucket{le=\"0.00025\"} 0\n"));
+        assert!(text.contains("hn_query_duration_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("hn_query_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("hn_query_duration_seconds_count 2\n"));
+        assert!(text.contains("hn_query_phase_seconds_total{phase=\"ranking\"} 0.25\n"));
+    }
+}
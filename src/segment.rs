@@ -0,0 +1,348 @@
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+use crate::error::HypernonsenseError;
+use crate::multiindex::MultiIndex;
+use crate::persist::Persist;
+use crate::vector::Scalar;
+use crate::wal::{self, WriteAheadLog};
+
+// Directory layout:
+//
+//  base-<n>.hyp     the whole index in the binary index format, including the changes in every segment up to `n`
+//  segment-<n>.wal  the changes flushed after segment `n - 1`, in the write ahead log format
+//
+// Every file is written under a temporary name, synced and then renamed, so a crash never leaves a partially written file
+// behind. If a crash happens after a compaction renamed its base but before it deleted the segments it replaced, the base
+// with the highest number still says which segments are already included in it.
+const BASE: (&str, &str) = ("base-", ".hyp");
+const SEGMENT: (&str, &str) = ("segment-", ".wal");
+const TEMPORARY: &str = "tmp";
+
+enum Change<K, T> {
+    Add(K, Vec<T>),
+    Remove(K),
+}
+
+/// A `MultiIndex` persisted to a directory incrementally. Changes are applied to the index in memory immediately, and
+/// `flush` appends every change since the last flush to the directory as a new segment file, so the cost of persisting
+/// is proportional to the number of changes rather than the size of the index. `compact` merges the segments into a new
+/// base file, a copy of the whole index which loads without replaying anything.
+///
+/// Changes which have not been flushed are lost if the process crashes, use `DurableMultiIndex` if every change must be
+/// persisted as it happens.
+pub struct SegmentedMultiIndex<K:Send+Sync, T:Scalar=f32> {
+    index: MultiIndex<K, T>,
+    dir: PathBuf,
+    pending: Vec<Change<K, T>>,
+    next_segment: u64,
+}
+
+impl<K:Clone+Eq+Hash+Debug+Send+Sync+Persist, T:Scalar+Persist> SegmentedMultiIndex<K, T> {
+    /// Persist an index to a new directory, writing the whole index as the first base file. Returns an error if the
+    /// directory already contains an index.
+    pub fn create<P: AsRef<Path>>(index: MultiIndex<K, T>, dir: P) -> Result<SegmentedMultiIndex<K, T>, HypernonsenseError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        if list(&dir)?.iter().any(|(_, kind, _)| *kind == BASE) {
+            return Err(HypernonsenseError::InvalidConfig(format!("{} already contains an index", dir.display())));
+        }
+
+        write_base(&dir, 0, &index)?;
+        return Ok(SegmentedMultiIndex { index, dir, pending: Vec::new(), next_segment: 1 });
+    }
+
+    /// Load an index from a directory, replaying every segment flushed since the last compaction on top of the latest base
+    /// file. Files left behind by an interrupted flush or compaction are deleted.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<SegmentedMultiIndex<K, T>, HypernonsenseError> {
+        let dir = dir.as_ref().to_path_buf();
+        remove_temporary(&dir)?;
+        let files = list(&dir)?;
+        let base = files.iter().filter(|(_, kind, _)| *kind == BASE).map(|(_, _, id)| *id).max()
+            .ok_or_else(|| HypernonsenseError::InvalidFormat(format!("{} does not contain an index", dir.display())))?;
+
+        let mut index = MultiIndex::load(dir.join(file_name(BASE, base)))?;
+        let mut segments = files.iter().filter(|(_, kind, id)| *kind == SEGMENT && *id > base).map(|(_, _, id)| *id).collect::<Vec<_>>();
+        segments.sort_unstable();
+        for id in segments.iter() {
+            wal::replay(&mut index, dir.join(file_name(SEGMENT, *id)))?;
+        }
+
+        remove_replaced(&dir, base)?;
+
+        let next_segment = segments.last().copied().unwrap_or(base) + 1;
+        return Ok(SegmentedMultiIndex { index, dir, pending: Vec::new(), next_segment });
+    }
+
+    pub fn index(&self) -> &MultiIndex<K, T> {
+        return &self.index;
+    }
+
+    /// Return the index, any changes which have not been flushed are not persisted
+    pub fn into_inner(self) -> MultiIndex<K, T> {
+        return self.index;
+    }
+
+    /// The number of changes which have not been flushed yet
+    pub fn pending_len(&self) -> usize {
+        return self.pending.len();
+    }
+
+    /// Add an item to the index, it is persisted by the next `flush`. Returns an error without changing anything if the
+    /// vector is not valid.
+    pub fn add(&mut self, key: K, vector: &[T]) -> Result<(), HypernonsenseError> {
+        let vector = self.index.checked_owned(vector.to_vec())?;
+        self.index.add(key.clone(), &vector);
+        self.pending.push(Change::Add(key, vector));
+        return Ok(());
+    }
+
+    /// Remove a key from the index, it is persisted by the next `flush`. Returns true if anything was removed.
+    pub fn remove(&mut self, key: &K) -> bool {
+        if !self.index.remove(key) {
+            return false;
+        }
+        self.pending.push(Change::Remove(key.clone()));
+        return true;
+    }
+
+    /// Append every change since the last flush to the directory as a new segment. Does nothing if there are no changes.
+    pub fn flush(&mut self) -> Result<(), HypernonsenseError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.dir.join(file_name(SEGMENT, self.next_segment));
+        let temporary = temporary_path(&path);
+        let mut log = WriteAheadLog::create(&temporary, &self.index)?;
+        for change in self.pending.iter() {
+            match change {
+                Change::Add(key, vector) => log.log_add(key, vector)?,
+                Change::Remove(key) => log.log_remove(key)?,
+            }
+        }
+        log.sync()?;
+        drop(log);
+        rename(&temporary, &path)?;
+
+        self.pending.clear();
+        self.next_segment += 1;
+        return Ok(());
+    }
+
+    /// Flush, then merge every segment into a new base file and delete the segments it replaced
+    pub fn compact(&mut self) -> Result<(), HypernonsenseError> {
+        self.flush()?;
+        let base = self.next_segment - 1;
+        write_base(&self.dir, base, &self.index)?;
+        return remove_replaced(&self.dir, base);
+    }
+}
+
+impl<K:Clone+Eq+Hash+Debug+Send+Sync+Persist+'static, T:Scalar+Persist+'static> SegmentedMultiIndex<K, T> {
+    /// Flush, then compact on a background thread while this index keeps being used. The background thread writes a
+    /// snapshot of the index as it was when compaction started, so changes made while it runs are kept in new segments.
+    pub fn compact_in_background(&mut self) -> Result<JoinHandle<Result<(), HypernonsenseError>>, HypernonsenseError> {
+        self.flush()?;
+        let base = self.next_segment - 1;
+        let snapshot = self.index.snapshot();
+        let dir = self.dir.clone();
+
+        return Ok(std::thread::spawn(move || {
+            write_base(&dir, base, &snapshot)?;
+            remove_replaced(&dir, base)
+        }));
+    }
+}
+
+fn file_name(kind: (&str, &str), id: u64) -> String {
+    return format!("{}{}{}", kind.0, id, kind.1);
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(TEMPORARY);
+    return PathBuf::from(name);
+}
+
+fn is_temporary(path: &Path) -> bool {
+    return path.extension() == Some(TEMPORARY.as_ref());
+}
+
+type Entry = (PathBuf, (&'static str, &'static str), u64);
+
+// Every base and segment in the directory, with its kind and number. Temporary files are not included.
+fn list(dir: &Path) -> Result<Vec<Entry>, HypernonsenseError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        for kind in [BASE, SEGMENT].iter() {
+            let id = name.strip_prefix(kind.0)
+                .and_then(|n| n.strip_suffix(kind.1))
+                .and_then(|n| n.parse::<u64>().ok());
+            if let Some(id) = id {
+                files.push((path.clone(), *kind, id));
+            }
+        }
+    }
+    return Ok(files);
+}
+
+// Temporary files are only left behind by a crash part way through writing them, no other process can be writing to the
+// directory. They were never renamed into place, so nothing they contain is needed.
+fn remove_temporary(dir: &Path) -> Result<(), HypernonsenseError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_temporary(&path) {
+            fs::remove_file(path)?;
+        }
+    }
+    return Ok(());
+}
+
+// Write the whole index as the base file which includes every segment up to `id`
+fn write_base<K, T>(dir: &Path, id: u64, index: &MultiIndex<K, T>) -> Result<(), HypernonsenseError>
+    where K : Send + Sync + Eq + Hash + Persist, T : Scalar + Persist
+{
+    let path = dir.join(file_name(BASE, id));
+    let temporary = temporary_path(&path);
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    index.write_to(&mut writer)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    return rename(&temporary, &path);
+}
+
+// Delete the bases older than `base`, and the segments which it includes
+fn remove_replaced(dir: &Path, base: u64) -> Result<(), HypernonsenseError> {
+    for (path, kind, id) in list(dir)? {
+        let replaced = (kind == BASE && id < base) || (kind == SEGMENT && id <= base);
+        if replaced {
+            fs::remove_file(path)?;
+        }
+    }
+    return Ok(());
+}
+
+// Rename a fully written file into place, and make sure the rename itself survives a crash
+fn rename(from: &Path, to: &Path) -> Result<(), HypernonsenseError> {
+    fs::rename(from, to)?;
+    #[cfg(unix)]
+    {
+        if let Some(dir) = to.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::path::Path;
+
+    use rand::prelude::*;
+
+    use crate::multiindex::MultiIndex;
+    use crate::segment::SegmentedMultiIndex;
+    use crate::vector::random_unit_vector;
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect::<Vec<_>>();
+        names.sort();
+        return names;
+    }
+
+    #[test]
+    fn segments_are_replayed_and_compacted() {
+        let dir = std::env::temp_dir().join(format!("hypernonsense_segments_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut rng = thread_rng();
+        let vectors = (0..60).map(|_| random_unit_vector(20, &mut rng)).collect::<Vec<_>>();
+
+        let index = MultiIndex::<u64>::builder(20).index_count(3).plane_count(5).store_vectors(true).build().unwrap();
+        let mut segmented = SegmentedMultiIndex::create(index, &dir).unwrap();
+        assert!(SegmentedMultiIndex::create(MultiIndex::<u64>::builder(20).build().unwrap(), &dir).is_err());
+
+        for (key, v) in vectors.iter().enumerate().take(30) {
+            segmented.add(key as u64, v).unwrap();
+        }
+        segmented.flush().unwrap();
+        for (key, v) in vectors.iter().enumerate().skip(30).take(20) {
+            segmented.add(key as u64, v).unwrap();
+        }
+        assert!(segmented.remove(&4));
+        assert!(!segmented.remove(&1000));
+        segmented.flush().unwrap();
+
+        // Not flushed, so lost
+        segmented.add(100, &vectors[50]).unwrap();
+        assert_eq!(1, segmented.pending_len());
+        drop(segmented);
+        assert_eq!(vec!["base-0.hyp", "segment-1.wal", "segment-2.wal"], file_names(&dir));
+
+        let mut segmented = SegmentedMultiIndex::<u64>::open(&dir).unwrap();
+        assert_eq!(49, segmented.index().len());
+        assert_eq!(None, segmented.index().get(&4));
+        assert_eq!(Some(vectors[40].as_slice()), segmented.index().get(&40));
+
+        segmented.add(50, &vectors[50]).unwrap();
+        segmented.compact().unwrap();
+        assert_eq!(vec!["base-3.hyp"], file_names(&dir));
+
+        // Changes made while compacting in the background are kept in new segments
+        segmented.add(51, &vectors[51]).unwrap();
+        let compaction = segmented.compact_in_background().unwrap();
+        segmented.add(52, &vectors[52]).unwrap();
+        segmented.flush().unwrap();
+        compaction.join().unwrap().unwrap();
+        assert_eq!(vec!["base-4.hyp", "segment-5.wal"], file_names(&dir));
+
+        let segmented = SegmentedMultiIndex::<u64>::open(&dir).unwrap();
+        assert_eq!(52, segmented.index().len());
+        assert_eq!(52, segmented.index().nearest_k(&vectors[52], 1)[0].key);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_ignores_files_left_by_a_crash() {
+        let dir = std::env::temp_dir().join(format!("hypernonsense_segments_crash_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut rng = thread_rng();
+        let vectors = (0..20).map(|_| random_unit_vector(20, &mut rng)).collect::<Vec<_>>();
+
+        // A temporary base from an interrupted create doesn't count as an index
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("base-0.hyp.tmp"), b"partial").unwrap();
+        let index = MultiIndex::<u64>::builder(20).index_count(3).plane_count(5).store_vectors(true).build().unwrap();
+        let mut segmented = SegmentedMultiIndex::create(index, &dir).unwrap();
+        for (key, v) in vectors.iter().enumerate() {
+            segmented.add(key as u64, v).unwrap();
+        }
+        segmented.flush().unwrap();
+        drop(segmented);
+
+        // Crashes part way through a compaction and a flush, neither file was renamed into place
+        std::fs::write(dir.join("base-1.hyp.tmp"), b"partial").unwrap();
+        std::fs::write(dir.join("segment-2.wal.tmp"), b"partial").unwrap();
+
+        let segmented = SegmentedMultiIndex::<u64>::open(&dir).unwrap();
+        assert_eq!(20, segmented.index().len());
+        assert_eq!(Some(vectors[7].as_slice()), segmented.index().get(&7));
+        assert_eq!(vec!["base-0.hyp", "segment-1.wal"], file_names(&dir));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1097 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use bit_vec::BitVec;
+use serde_core::{Serialize, Serializer, Deserialize, Deserializer};
+use serde_core::ser::{SerializeStruct, SerializeStructVariant, SerializeTuple};
+use serde_core::de::{self, Visitor, SeqAccess, MapAccess, EnumAccess, VariantAccess};
+
+use crate::hyperindex::{HashFamily, HyperIndex};
+use crate::metric::Metric;
+use crate::multiindex::MultiIndex;
+use crate::vector::{Normalization, Scalar};
+
+// BitVec does not implement serde (without pulling in the full serde crate), so it's serialized as a tuple of (bit count, packed bytes)
+pub(crate) struct SerBitVec<'a>(pub &'a BitVec);
+
+impl<'a> Serialize for SerBitVec<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(2)?;
+        tup.serialize_element(&self.0.len())?;
+        tup.serialize_element(&self.0.to_bytes())?;
+        return tup.end();
+    }
+}
+
+pub(crate) struct DeBitVec(pub BitVec);
+
+impl<'de> Deserialize<'de> for DeBitVec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (len, bytes) = <(usize, Vec<u8>)>::deserialize(deserializer)?;
+        if len > bytes.len() * 8 {
+            return Err(de::Error::custom("bit count is larger than the packed bytes"));
+        }
+
+        let mut bits = BitVec::from_bytes(&bytes);
+        bits.truncate(len);
+        return Ok(DeBitVec(bits));
+    }
+}
+
+impl<K:Send+Serialize, T:Scalar+Serialize> Serialize for HyperIndex<K, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let groups = self.groups_iter()
+            .map(|(k, v)| (SerBitVec(k), v.as_slice()))
+            .collect::<Vec<_>>();
+
+        let mut s = serializer.serialize_struct("HyperIndex", 5)?;
+        s.serialize_field("dims", &self.dimensions())?;
+        s.serialize_field("planes", &self.planes())?;
+        s.serialize_field("groups", &groups)?;
+        s.serialize_field("family", &self.family())?;
+        s.serialize_field("offsets", self.offsets())?;
+        return s.end();
+    }
+}
+
+const HYPERINDEX_FIELDS: &[&str] = &[ "dims", "planes", "groups", "family", "offsets" ];
+
+enum HyperIndexField { Dims, Planes, Groups, Family, Offsets }
+
+impl<'de> Deserialize<'de> for HyperIndexField {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldVisitor;
+
+        impl<'de> Visitor<'de> for FieldVisitor {
+            type Value = HyperIndexField;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("`dims`, `planes`, `groups`, `family` or `offsets`")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<HyperIndexField, E> {
+                match value {
+                    "dims" => Ok(HyperIndexField::Dims),
+                    "planes" => Ok(HyperIndexField::Planes),
+                    "groups" => Ok(HyperIndexField::Groups),
+                    "family" => Ok(HyperIndexField::Family),
+                    "offsets" => Ok(HyperIndexField::Offsets),
+                    _ => Err(de::Error::unknown_field(value, HYPERINDEX_FIELDS)),
+                }
+            }
+        }
+
+        deserializer.deserialize_identifier(FieldVisitor)
+    }
+}
+
+impl<'de, K:Send+Deserialize<'de>, T:Scalar+Deserialize<'de>> Deserialize<'de> for HyperIndex<K, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HyperIndexVisitor<K, T>(PhantomData<(K, T)>);
+
+        impl<'de, K:Send+Deserialize<'de>, T:Scalar+Deserialize<'de>> Visitor<'de> for HyperIndexVisitor<K, T> {
+            type Value = HyperIndex<K, T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct HyperIndex")
+            }
+
+            fn visit_seq<V: SeqAccess<'de>>(self, mut seq: V) -> Result<HyperIndex<K, T>, V::Error> {
+                let dims = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let planes = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let groups = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+
+                // Hash families were added later, data serialized before then is always hyperplanes
+                let family = seq.next_element()?.unwrap_or_default();
+                let offsets = seq.next_element()?;
+                return build_hyperindex(dims, planes, groups, family, offsets);
+            }
+
+            fn visit_map<V: MapAccess<'de>>(self, mut map: V) -> Result<HyperIndex<K, T>, V::Error> {
+                let mut dims = None;
+                let mut planes = None;
+                let mut groups = None;
+                let mut family = None;
+                let mut offsets = None;
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        HyperIndexField::Dims => {
+                            if dims.is_some() {
+                                return Err(de::Error::duplicate_field("dims"));
+                            }
+                            dims = Some(map.next_value()?);
+                        }
+                        HyperIndexField::Planes => {
+                            if planes.is_some() {
+                                return Err(de::Error::duplicate_field("planes"));
+                            }
+                            planes = Some(map.next_value()?);
+                        }
+                        HyperIndexField::Groups => {
+                            if groups.is_some() {
+                                return Err(de::Error::duplicate_field("groups"));
+                            }
+                            groups = Some(map.next_value()?);
+                        }
+                        HyperIndexField::Family => {
+                            if family.is_some() {
+                                return Err(de::Error::duplicate_field("family"));
+                            }
+                            family = Some(map.next_value()?);
+                        }
+                        HyperIndexField::Offsets => {
+                            if offsets.is_some() {
+                                return Err(de::Error::duplicate_field("offsets"));
+                            }
+                            offsets = Some(map.next_value()?);
+                        }
+                    }
+                }
+
+                let dims = dims.ok_or_else(|| de::Error::missing_field("dims"))?;
+                let planes = planes.ok_or_else(|| de::Error::missing_field("planes"))?;
+                let groups = groups.ok_or_else(|| de::Error::missing_field("groups"))?;
+                return build_hyperindex(dims, planes, groups, family.unwrap_or_default(), offsets);
+            }
+        }
+
+        deserializer.deserialize_struct("HyperIndex", HYPERINDEX_FIELDS, HyperIndexVisitor(PhantomData))
+    }
+}
+
+fn build_hyperindex<K:Send, T:Scalar, E:de::Error>(dims: usize, planes: Vec<Vec<T>>, groups: Vec<(DeBitVec, Vec<K>)>, family: HashFamily, offsets: Option<Vec<T>>) -> Result<HyperIndex<K, T>, E> {
+    family.validate(dims).map_err(de::Error::custom)?;
+    if planes.iter().any(|p| p.len() != dims * family.rows_per_plane()) {
+        return Err(de::Error::custom("plane length does not match index dimension"));
+    }
+    if groups.iter().any(|(k, _)| k.0.len() != planes.len() * family.bits_per_plane()) {
+        return Err(de::Error::custom("group key length does not match plane count"));
+    }
+
+    let offsets = offsets.unwrap_or_else(|| vec![T::zero(); planes.len()]);
+    if offsets.len() != planes.len() {
+        return Err(de::Error::custom("offset count does not match plane count"));
+    }
+
+    return Ok(HyperIndex::from_parts(dims, planes, offsets, family, groups.into_iter().map(|(k, v)| (k.0, v.into())).collect()));
+}
+
+const HASH_FAMILY_VARIANTS: &[&str] = &[ "Hyperplane", "PStable", "CrossPolytope" ];
+
+impl Serialize for HashFamily {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            HashFamily::Hyperplane => serializer.serialize_unit_variant("HashFamily", 0, "Hyperplane"),
+            HashFamily::PStable { width } => {
+                let mut v = serializer.serialize_struct_variant("HashFamily", 1, "PStable", 1)?;
+                v.serialize_field("width", width)?;
+                v.end()
+            }
+            HashFamily::CrossPolytope { rotation_dims } => {
+                let mut v = serializer.serialize_struct_variant("HashFamily", 2, "CrossPolytope", 1)?;
+                v.serialize_field("rotation_dims", rotation_dims)?;
+                v.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HashFamily {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Clone, Copy)]
+        enum Variant { Hyperplane, PStable, CrossPolytope }
+
+        struct VariantVisitor;
+
+        impl<'de> Visitor<'de> for VariantVisitor {
+            type Value = Variant;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a hash family name or index")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Variant, E> {
+                match value {
+                    0 => Ok(Variant::Hyperplane),
+                    1 => Ok(Variant::PStable),
+                    2 => Ok(Variant::CrossPolytope),
+                    _ => Err(de::Error::invalid_value(de::Unexpected::Unsigned(value), &self)),
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Variant, E> {
+                match value {
+                    "Hyperplane" => Ok(Variant::Hyperplane),
+                    "PStable" => Ok(Variant::PStable),
+                    "CrossPolytope" => Ok(Variant::CrossPolytope),
+                    _ => Err(de::Error::unknown_variant(value, HASH_FAMILY_VARIANTS)),
+                }
+            }
+        }
+
+        // The variant identifier, which may be given either by name or by index
+        struct VariantTag(Variant);
+
+        impl<'de> Deserialize<'de> for VariantTag {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_identifier(VariantVisitor).map(VariantTag)
+            }
+        }
+
+        // The fields of the `PStable` variant
+        struct PStableVisitor;
+
+        impl<'de> Visitor<'de> for PStableVisitor {
+            type Value = HashFamily;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct variant HashFamily::PStable")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<HashFamily, A::Error> {
+                let width = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                return Ok(HashFamily::PStable { width });
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<HashFamily, A::Error> {
+                let mut width = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "width" => {
+                            if width.is_some() {
+                                return Err(de::Error::duplicate_field("width"));
+                            }
+                            width = Some(map.next_value()?);
+                        }
+                        _ => return Err(de::Error::unknown_field(&key, &[ "width" ])),
+                    }
+                }
+
+                let width = width.ok_or_else(|| de::Error::missing_field("width"))?;
+                return Ok(HashFamily::PStable { width });
+            }
+        }
+
+        // The fields of the `CrossPolytope` variant
+        struct CrossPolytopeVisitor;
+
+        impl<'de> Visitor<'de> for CrossPolytopeVisitor {
+            type Value = HashFamily;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct variant HashFamily::CrossPolytope")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<HashFamily, A::Error> {
+                let rotation_dims = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                return Ok(HashFamily::CrossPolytope { rotation_dims });
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<HashFamily, A::Error> {
+                let mut rotation_dims = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "rotation_dims" => {
+                            if rotation_dims.is_some() {
+                                return Err(de::Error::duplicate_field("rotation_dims"));
+                            }
+                            rotation_dims = Some(map.next_value()?);
+                        }
+                        _ => return Err(de::Error::unknown_field(&key, &[ "rotation_dims" ])),
+                    }
+                }
+
+                let rotation_dims = rotation_dims.ok_or_else(|| de::Error::missing_field("rotation_dims"))?;
+                return Ok(HashFamily::CrossPolytope { rotation_dims });
+            }
+        }
+
+        struct HashFamilyVisitor;
+
+        impl<'de> Visitor<'de> for HashFamilyVisitor {
+            type Value = HashFamily;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("enum HashFamily")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<HashFamily, A::Error> {
+                let (tag, variant) = data.variant::<VariantTag>()?;
+                match tag.0 {
+                    Variant::Hyperplane => {
+                        variant.unit_variant()?;
+                        Ok(HashFamily::Hyperplane)
+                    }
+                    Variant::PStable => variant.struct_variant(&[ "width" ], PStableVisitor),
+                    Variant::CrossPolytope => variant.struct_variant(&[ "rotation_dims" ], CrossPolytopeVisitor),
+                }
+            }
+        }
+
+        deserializer.deserialize_enum("HashFamily", HASH_FAMILY_VARIANTS, HashFamilyVisitor)
+    }
+}
+
+const METRIC_VARIANTS: &[&str] = &[ "Cosine", "Euclidean" ];
+
+impl Serialize for Metric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Metric::Cosine => serializer.serialize_unit_variant("Metric", 0, "Cosine"),
+            Metric::Euclidean => serializer.serialize_unit_variant("Metric", 1, "Euclidean"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Metric {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VariantVisitor;
+
+        impl<'de> Visitor<'de> for VariantVisitor {
+            type Value = Metric;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a metric name or index")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Metric, E> {
+                match value {
+                    0 => Ok(Metric::Cosine),
+                    1 => Ok(Metric::Euclidean),
+                    _ => Err(de::Error::invalid_value(de::Unexpected::Unsigned(value), &self)),
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Metric, E> {
+                match value {
+                    "Cosine" => Ok(Metric::Cosine),
+                    "Euclidean" => Ok(Metric::Euclidean),
+                    _ => Err(de::Error::unknown_variant(value, METRIC_VARIANTS)),
+                }
+            }
+        }
+
+        // The variant identifier, which may be given either by name or by index
+        struct VariantTag(Metric);
+
+        impl<'de> Deserialize<'de> for VariantTag {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_identifier(VariantVisitor).map(VariantTag)
+            }
+        }
+
+        struct MetricVisitor;
+
+        impl<'de> Visitor<'de> for MetricVisitor {
+            type Value = Metric;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("enum Metric")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Metric, A::Error> {
+                let (tag, variant) = data.variant::<VariantTag>()?;
+                variant.unit_variant()?;
+                return Ok(tag.0);
+            }
+        }
+
+        deserializer.deserialize_enum("Metric", METRIC_VARIANTS, MetricVisitor)
+    }
+}
+
+const NORMALIZATION_VARIANTS: &[&str] = &[ "None", "NormalizeOnInsert", "RejectNonUnit" ];
+
+impl Serialize for Normalization {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Normalization::None => serializer.serialize_unit_variant("Normalization", 0, "None"),
+            Normalization::NormalizeOnInsert => serializer.serialize_unit_variant("Normalization", 1, "NormalizeOnInsert"),
+            Normalization::RejectNonUnit => serializer.serialize_unit_variant("Normalization", 2, "RejectNonUnit"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Normalization {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VariantVisitor;
+
+        impl<'de> Visitor<'de> for VariantVisitor {
+            type Value = Normalization;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a normalization name or index")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Normalization, E> {
+                match value {
+                    0 => Ok(Normalization::None),
+                    1 => Ok(Normalization::NormalizeOnInsert),
+                    2 => Ok(Normalization::RejectNonUnit),
+                    _ => Err(de::Error::invalid_value(de::Unexpected::Unsigned(value), &self)),
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Normalization, E> {
+                match value {
+                    "None" => Ok(Normalization::None),
+                    "NormalizeOnInsert" => Ok(Normalization::NormalizeOnInsert),
+                    "RejectNonUnit" => Ok(Normalization::RejectNonUnit),
+                    _ => Err(de::Error::unknown_variant(value, NORMALIZATION_VARIANTS)),
+                }
+            }
+        }
+
+        // The variant identifier, which may be given either by name or by index
+        struct VariantTag(Normalization);
+
+        impl<'de> Deserialize<'de> for VariantTag {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_identifier(VariantVisitor).map(VariantTag)
+            }
+        }
+
+        struct NormalizationVisitor;
+
+        impl<'de> Visitor<'de> for NormalizationVisitor {
+            type Value = Normalization;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("enum Normalization")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Normalization, A::Error> {
+                let (tag, variant) = data.variant::<VariantTag>()?;
+                variant.unit_variant()?;
+                return Ok(tag.0);
+            }
+        }
+
+        deserializer.deserialize_enum("Normalization", NORMALIZATION_VARIANTS, NormalizationVisitor)
+    }
+}
+
+impl<K:Send+Sync+Serialize, T:Scalar+Serialize, V:Send+Sync+Serialize> Serialize for MultiIndex<K, T, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let vectors = self.vectors()
+            .map(|v| v.iter().collect::<Vec<_>>());
+        let payloads = self.payloads().iter().collect::<Vec<_>>();
+        let indices = self.indices().iter().map(|i| &**i).collect::<Vec<_>>();
+
+        let mut s = serializer.serialize_struct("MultiIndex", 6)?;
+        s.serialize_field("indices", &indices)?;
+        s.serialize_field("vectors", &vectors)?;
+        s.serialize_field("metric", &self.metric())?;
+        s.serialize_field("payloads", &payloads)?;
+        s.serialize_field("seed", &self.seed())?;
+        s.serialize_field("normalization", &self.normalization())?;
+        return s.end();
+    }
+}
+
+const MULTIINDEX_FIELDS: &[&str] = &[ "indices", "vectors", "metric", "payloads", "seed", "normalization" ];
+
+impl<'de, K:Send+Sync+Eq+Hash+Deserialize<'de>, T:Scalar+Deserialize<'de>, V:Send+Sync+Deserialize<'de>> Deserialize<'de> for MultiIndex<K, T, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MultiIndexVisitor<K, T, V>(PhantomData<(K, T, V)>);
+
+        impl<'de, K:Send+Sync+Eq+Hash+Deserialize<'de>, T:Scalar+Deserialize<'de>, V:Send+Sync+Deserialize<'de>> Visitor<'de> for MultiIndexVisitor<K, T, V> {
+            type Value = MultiIndex<K, T, V>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct MultiIndex")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MultiIndex<K, T, V>, A::Error> {
+                let indices = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+
+                // Vectors were added later, data serialized before then won't have them
+                let vectors = seq.next_element()?.unwrap_or(None);
+                let metric = seq.next_element()?.unwrap_or_default();
+                let payloads = seq.next_element()?.unwrap_or_default();
+                let seed = seq.next_element()?.unwrap_or(None);
+                let normalization = seq.next_element()?.unwrap_or_default();
+                return build_multiindex(indices, vectors, metric, payloads, seed, normalization);
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MultiIndex<K, T, V>, A::Error> {
+                let mut indices = None;
+                let mut vectors = None;
+                let mut metric = None;
+                let mut payloads = None;
+                let mut seed = None;
+                let mut normalization = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "indices" => {
+                            if indices.is_some() {
+                                return Err(de::Error::duplicate_field("indices"));
+                            }
+                            indices = Some(map.next_value()?);
+                        }
+                        "vectors" => {
+                            if vectors.is_some() {
+                                return Err(de::Error::duplicate_field("vectors"));
+                            }
+                            vectors = Some(map.next_value()?);
+                        }
+                        "metric" => {
+                            if metric.is_some() {
+                                return Err(de::Error::duplicate_field("metric"));
+                            }
+                            metric = Some(map.next_value()?);
+                        }
+                        "payloads" => {
+                            if payloads.is_some() {
+                                return Err(de::Error::duplicate_field("payloads"));
+                            }
+                            payloads = Some(map.next_value()?);
+                        }
+                        "seed" => {
+                            if seed.is_some() {
+                                return Err(de::Error::duplicate_field("seed"));
+                            }
+                            seed = Some(map.next_value()?);
+                        }
+                        "normalization" => {
+                            if normalization.is_some() {
+                                return Err(de::Error::duplicate_field("normalization"));
+                            }
+                            normalization = Some(map.next_value()?);
+                        }
+                        _ => return Err(de::Error::unknown_field(&key, MULTIINDEX_FIELDS)),
+                    }
+                }
+
+                let indices = indices.ok_or_else(|| de::Error::missing_field("indices"))?;
+                return build_multiindex(indices, vectors.unwrap_or(None), metric.unwrap_or_default(), payloads.unwrap_or_default(), seed.unwrap_or(None), normalization.unwrap_or_default());
+            }
+        }
+
+        deserializer.deserialize_struct("MultiIndex", MULTIINDEX_FIELDS, MultiIndexVisitor(PhantomData))
+    }
+}
+
+fn build_multiindex<K:Send+Sync+Eq+Hash, T:Scalar, V:Send+Sync, E:de::Error>(indices: Vec<HyperIndex<K, T>>, vectors: Option<Vec<(K, Vec<T>)>>, metric: Metric, payloads: Vec<(K, V)>, seed: Option<u64>, normalization: Normalization) -> Result<MultiIndex<K, T, V>, E> {
+    if indices.is_empty() {
+        return Err(de::Error::custom("multi index must contain at least one index"));
+    }
+
+    let dims = indices[0].dimensions();
+    let planes = indices[0].planes_len();
+    if indices.iter().any(|i| i.dimensions() != dims || i.planes_len() != planes) {
+        return Err(de::Error::custom("all indices must have the same dimension and plane count"));
+    }
+
+    if let Some(vectors) = vectors.as_ref() {
+        if vectors.iter().any(|(_, v)| v.len() != dims) {
+            return Err(de::Error::custom("stored vector length does not match index dimension"));
+        }
+    }
+
+    let vectors = vectors.map(|v| v.into_iter().collect::<HashMap<_, _>>());
+    return Ok(MultiIndex::from_parts(indices, vectors, payloads.into_iter().collect(), metric, seed, normalization));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fmt;
+
+    use rand::prelude::*;
+    use serde_core::{ Deserialize, Deserializer, Serialize, Serializer, forward_to_deserialize_any };
+    use serde_core::de::{ self, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor };
+    use serde_core::ser::{ self, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple, SerializeTupleStruct, SerializeTupleVariant };
+
+    use crate::hyperindex::HashFamily;
+    use crate::metric::Metric;
+    use crate::multiindex::MultiIndex;
+    use crate::vector::{ Normalization, random_unit_vector };
+
+    // A minimal self describing data model, so the serde impls can be tested without a format crate. The compact form
+    // writes structs as sequences and variants by index (like a binary format), the other writes structs as maps and
+    // variants by name (like JSON).
+    #[derive(Clone, Debug, PartialEq)]
+    enum Value {
+        Unit,
+        Bool(bool),
+        U64(u64),
+        I64(i64),
+        F64(f64),
+        Str(String),
+        Option(Option<Box<Value>>),
+        Seq(Vec<Value>),
+        Map(Vec<(Value, Value)>),
+        Variant(Box<Value>, Box<Value>),
+    }
+
+    #[derive(Debug)]
+    struct Error(String);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl std::error::Error for Error { }
+
+    impl ser::Error for Error {
+        fn custom<M: fmt::Display>(msg: M) -> Self {
+            return Error(msg.to_string());
+        }
+    }
+
+    impl de::Error for Error {
+        fn custom<M: fmt::Display>(msg: M) -> Self {
+            return Error(msg.to_string());
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct ValueSerializer {
+        compact: bool,
+    }
+
+    impl ValueSerializer {
+        fn tag(self, index: u32, name: &'static str) -> Value {
+            if self.compact {
+                return Value::U64(index as u64);
+            }
+            return Value::Str(name.to_string());
+        }
+
+        fn compound(self, tag: Option<Value>) -> Compound {
+            return Compound { compact: self.compact, tag, items: Vec::new(), entries: Vec::new(), key: None };
+        }
+    }
+
+    struct Compound {
+        compact: bool,
+        tag: Option<Value>,
+        items: Vec<Value>,
+        entries: Vec<(Value, Value)>,
+        key: Option<Value>,
+    }
+
+    impl Compound {
+        fn element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+            self.items.push(value.serialize(ValueSerializer { compact: self.compact })?);
+            return Ok(());
+        }
+
+        fn field<T: ?Sized + Serialize>(&mut self, name: &'static str, value: &T) -> Result<(), Error> {
+            let value = value.serialize(ValueSerializer { compact: self.compact })?;
+            if self.compact {
+                self.items.push(value);
+            } else {
+                self.entries.push((Value::Str(name.to_string()), value));
+            }
+            return Ok(());
+        }
+
+        fn finish(self, value: Value) -> Value {
+            return match self.tag {
+                Some(tag) => Value::Variant(Box::new(tag), Box::new(value)),
+                None => value,
+            };
+        }
+
+        fn finish_seq(mut self) -> Result<Value, Error> {
+            let items = std::mem::take(&mut self.items);
+            return Ok(self.finish(Value::Seq(items)));
+        }
+
+        fn finish_struct(mut self) -> Result<Value, Error> {
+            let value = if self.compact { Value::Seq(std::mem::take(&mut self.items)) } else { Value::Map(std::mem::take(&mut self.entries)) };
+            return Ok(self.finish(value));
+        }
+    }
+
+    impl SerializeSeq for Compound {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> { self.element(value) }
+        fn end(self) -> Result<Value, Error> { self.finish_seq() }
+    }
+
+    impl SerializeTuple for Compound {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> { self.element(value) }
+        fn end(self) -> Result<Value, Error> { self.finish_seq() }
+    }
+
+    impl SerializeTupleStruct for Compound {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> { self.element(value) }
+        fn end(self) -> Result<Value, Error> { self.finish_seq() }
+    }
+
+    impl SerializeTupleVariant for Compound {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> { self.element(value) }
+        fn end(self) -> Result<Value, Error> { self.finish_seq() }
+    }
+
+    impl SerializeStruct for Compound {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, name: &'static str, value: &T) -> Result<(), Error> { self.field(name, value) }
+        fn end(self) -> Result<Value, Error> { self.finish_struct() }
+    }
+
+    impl SerializeStructVariant for Compound {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, name: &'static str, value: &T) -> Result<(), Error> { self.field(name, value) }
+        fn end(self) -> Result<Value, Error> { self.finish_struct() }
+    }
+
+    impl SerializeMap for Compound {
+        type Ok = Value;
+        type Error = Error;
+
+        fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+            self.key = Some(key.serialize(ValueSerializer { compact: self.compact })?);
+            return Ok(());
+        }
+
+        fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+            let key = self.key.take().ok_or_else(|| Error("map value without a key".to_string()))?;
+            self.entries.push((key, value.serialize(ValueSerializer { compact: self.compact })?));
+            return Ok(());
+        }
+
+        fn end(mut self) -> Result<Value, Error> {
+            let entries = std::mem::take(&mut self.entries);
+            return Ok(self.finish(Value::Map(entries)));
+        }
+    }
+
+    impl Serializer for ValueSerializer {
+        type Ok = Value;
+        type Error = Error;
+        type SerializeSeq = Compound;
+        type SerializeTuple = Compound;
+        type SerializeTupleStruct = Compound;
+        type SerializeTupleVariant = Compound;
+        type SerializeMap = Compound;
+        type SerializeStruct = Compound;
+        type SerializeStructVariant = Compound;
+
+        fn serialize_bool(self, v: bool) -> Result<Value, Error> { Ok(Value::Bool(v)) }
+        fn serialize_i8(self, v: i8) -> Result<Value, Error> { Ok(Value::I64(v as i64)) }
+        fn serialize_i16(self, v: i16) -> Result<Value, Error> { Ok(Value::I64(v as i64)) }
+        fn serialize_i32(self, v: i32) -> Result<Value, Error> { Ok(Value::I64(v as i64)) }
+        fn serialize_i64(self, v: i64) -> Result<Value, Error> { Ok(Value::I64(v)) }
+        fn serialize_u8(self, v: u8) -> Result<Value, Error> { Ok(Value::U64(v as u64)) }
+        fn serialize_u16(self, v: u16) -> Result<Value, Error> { Ok(Value::U64(v as u64)) }
+        fn serialize_u32(self, v: u32) -> Result<Value, Error> { Ok(Value::U64(v as u64)) }
+        fn serialize_u64(self, v: u64) -> Result<Value, Error> { Ok(Value::U64(v)) }
+        fn serialize_f32(self, v: f32) -> Result<Value, Error> { Ok(Value::F64(v as f64)) }
+        fn serialize_f64(self, v: f64) -> Result<Value, Error> { Ok(Value::F64(v)) }
+        fn serialize_char(self, v: char) -> Result<Value, Error> { Ok(Value::Str(v.to_string())) }
+        fn serialize_str(self, v: &str) -> Result<Value, Error> { Ok(Value::Str(v.to_string())) }
+        fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> { Ok(Value::Seq(v.iter().map(|b| Value::U64(*b as u64)).collect())) }
+        fn serialize_none(self) -> Result<Value, Error> { Ok(Value::Option(None)) }
+        fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value, Error> { Ok(Value::Option(Some(Box::new(value.serialize(self)?)))) }
+        fn serialize_unit(self) -> Result<Value, Error> { Ok(Value::Unit) }
+        fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> { Ok(Value::Unit) }
+
+        fn serialize_unit_variant(self, _name: &'static str, index: u32, variant: &'static str) -> Result<Value, Error> {
+            return Ok(Value::Variant(Box::new(self.tag(index, variant)), Box::new(Value::Unit)));
+        }
+
+        fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<Value, Error> {
+            return value.serialize(self);
+        }
+
+        fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, index: u32, variant: &'static str, value: &T) -> Result<Value, Error> {
+            return Ok(Value::Variant(Box::new(self.tag(index, variant)), Box::new(value.serialize(self)?)));
+        }
+
+        fn serialize_seq(self, _len: Option<usize>) -> Result<Compound, Error> { Ok(self.compound(None)) }
+        fn serialize_tuple(self, _len: usize) -> Result<Compound, Error> { Ok(self.compound(None)) }
+        fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Compound, Error> { Ok(self.compound(None)) }
+        fn serialize_map(self, _len: Option<usize>) -> Result<Compound, Error> { Ok(self.compound(None)) }
+        fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Compound, Error> { Ok(self.compound(None)) }
+
+        fn serialize_tuple_variant(self, _name: &'static str, index: u32, variant: &'static str, _len: usize) -> Result<Compound, Error> {
+            return Ok(self.compound(Some(self.tag(index, variant))));
+        }
+
+        fn serialize_struct_variant(self, _name: &'static str, index: u32, variant: &'static str, _len: usize) -> Result<Compound, Error> {
+            return Ok(self.compound(Some(self.tag(index, variant))));
+        }
+    }
+
+    struct ValueDeserializer(Value);
+
+    impl<'de> Deserializer<'de> for ValueDeserializer {
+        type Error = Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            return match self.0 {
+                Value::Unit => visitor.visit_unit(),
+                Value::Bool(v) => visitor.visit_bool(v),
+                Value::U64(v) => visitor.visit_u64(v),
+                Value::I64(v) => visitor.visit_i64(v),
+                Value::F64(v) => visitor.visit_f64(v),
+                Value::Str(v) => visitor.visit_string(v),
+                Value::Option(None) => visitor.visit_none(),
+                Value::Option(Some(v)) => visitor.visit_some(ValueDeserializer(*v)),
+                Value::Seq(items) => visitor.visit_seq(Items(items.into_iter())),
+                Value::Map(entries) => visitor.visit_map(Entries(entries.into_iter(), None)),
+                Value::Variant(tag, value) => visitor.visit_enum(Variant(*tag, *value)),
+            };
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit unit_struct
+            newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+        }
+    }
+
+    struct Items(std::vec::IntoIter<Value>);
+
+    impl<'de> SeqAccess<'de> for Items {
+        type Error = Error;
+
+        fn next_element_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, Error> {
+            return self.0.next().map(|v| seed.deserialize(ValueDeserializer(v))).transpose();
+        }
+    }
+
+    struct Entries(std::vec::IntoIter<(Value, Value)>, Option<Value>);
+
+    impl<'de> MapAccess<'de> for Entries {
+        type Error = Error;
+
+        fn next_key_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, Error> {
+            let Some((key, value)) = self.0.next() else {
+                return Ok(None);
+            };
+            self.1 = Some(value);
+            return seed.deserialize(ValueDeserializer(key)).map(Some);
+        }
+
+        fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, Error> {
+            let value = self.1.take().ok_or_else(|| Error("map value without a key".to_string()))?;
+            return seed.deserialize(ValueDeserializer(value));
+        }
+    }
+
+    struct Variant(Value, Value);
+
+    impl<'de> EnumAccess<'de> for Variant {
+        type Error = Error;
+        type Variant = ValueDeserializer;
+
+        fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, ValueDeserializer), Error> {
+            let tag = seed.deserialize(ValueDeserializer(self.0))?;
+            return Ok((tag, ValueDeserializer(self.1)));
+        }
+    }
+
+    impl<'de> VariantAccess<'de> for ValueDeserializer {
+        type Error = Error;
+
+        fn unit_variant(self) -> Result<(), Error> {
+            return match self.0 {
+                Value::Unit => Ok(()),
+                _ => Err(Error("expected a unit variant".to_string())),
+            };
+        }
+
+        fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, Error> {
+            return seed.deserialize(self);
+        }
+
+        fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+            return self.deserialize_any(visitor);
+        }
+
+        fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+            return self.deserialize_any(visitor);
+        }
+    }
+
+    fn to_value<S: Serialize>(value: &S, compact: bool) -> Value {
+        return value.serialize(ValueSerializer { compact }).unwrap();
+    }
+
+    fn from_value<'de, D: Deserialize<'de>>(value: Value) -> Result<D, Error> {
+        return D::deserialize(ValueDeserializer(value));
+    }
+
+    // Get a field of a struct serialized in the map form
+    fn field<'a>(value: &'a mut Value, name: &str) -> &'a mut Value {
+        let Value::Map(entries) = value else {
+            panic!("expected a map, got {:?}", value);
+        };
+        return entries.iter_mut()
+            .find(|(k, _)| *k == Value::Str(name.to_string()))
+            .map(|(_, v)| v)
+            .unwrap();
+    }
+
+    fn items(value: &mut Value) -> &mut Vec<Value> {
+        let Value::Seq(items) = value else {
+            panic!("expected a sequence, got {:?}", value);
+        };
+        return items;
+    }
+
+    fn build(family: HashFamily, store_vectors: bool) -> (MultiIndex<u64, f32, String>, Vec<Vec<f32>>) {
+        let mut a = MultiIndex::builder(20)
+            .index_count(3)
+            .plane_count(6)
+            .hash_family(family)
+            .metric(Metric::Euclidean)
+            .normalization(Normalization::NormalizeOnInsert)
+            .seed(7)
+            .store_vectors(store_vectors)
+            .build()
+            .unwrap();
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let vectors = (0..100).map(|_| random_unit_vector(20, &mut rng)).collect::<Vec<_>>();
+        for (i, v) in vectors.iter().enumerate() {
+            if i % 2 == 0 {
+                a.add_with_payload(i as u64, v, format!("item {}", i));
+            } else {
+                a.add(i as u64, v);
+            }
+        }
+
+        return (a, vectors);
+    }
+
+    fn assert_same(a: &MultiIndex<u64, f32, String>, b: &MultiIndex<u64, f32, String>) {
+        assert_eq!(a.indices_len(), b.indices_len());
+        for (x, y) in a.indices().iter().zip(b.indices().iter()) {
+            assert_eq!(x.dimensions(), y.dimensions());
+            assert_eq!(x.planes(), y.planes());
+            assert_eq!(x.offsets(), y.offsets());
+            assert_eq!(x.family(), y.family());
+            assert_eq!(x.groups_iter().collect::<HashMap<_, _>>(), y.groups_iter().collect::<HashMap<_, _>>());
+        }
+
+        assert_eq!(a.vectors(), b.vectors());
+        assert_eq!(a.payloads(), b.payloads());
+        assert_eq!(a.metric(), b.metric());
+        assert_eq!(a.normalization(), b.normalization());
+        assert_eq!(a.seed(), b.seed());
+    }
+
+    #[test]
+    fn multiindex_round_trips_in_both_forms() {
+        let families = [ HashFamily::Hyperplane, HashFamily::PStable { width: 2.5 }, HashFamily::CrossPolytope { rotation_dims: 8 } ];
+        for family in families {
+            for store_vectors in [ false, true ] {
+                let (a, vectors) = build(family, store_vectors);
+                for compact in [ false, true ] {
+                    let b: MultiIndex<u64, f32, String> = from_value(to_value(&a, compact)).unwrap();
+                    assert_same(&a, &b);
+
+                    let mut expected = a.nearest_points(&vectors[3]);
+                    let mut actual = b.nearest_points(&vectors[3]);
+                    expected.sort_unstable();
+                    actual.sort_unstable();
+                    assert_eq!(expected, actual);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_sequences_load_with_defaults() {
+        let (a, _) = build(HashFamily::Hyperplane, true);
+
+        // Keep only the fields which existed in the first format: the indices, and for each index the dims, planes and groups
+        let mut value = to_value(&a, true);
+        let fields = items(&mut value);
+        fields.truncate(1);
+        for index in items(&mut fields[0]) {
+            items(index).truncate(3);
+        }
+
+        let b: MultiIndex<u64, f32, String> = from_value(value).unwrap();
+        assert!(b.vectors().is_none());
+        assert!(b.payloads().is_empty());
+        assert_eq!(Metric::default(), b.metric());
+        assert_eq!(Normalization::default(), b.normalization());
+        assert_eq!(None, b.seed());
+        for (x, y) in a.indices().iter().zip(b.indices().iter()) {
+            assert_eq!(HashFamily::Hyperplane, y.family());
+            assert!(y.offsets().iter().all(|o| *o == 0.0));
+            assert_eq!(x.groups_iter().collect::<HashMap<_, _>>(), y.groups_iter().collect::<HashMap<_, _>>());
+        }
+    }
+
+    #[test]
+    fn mismatched_lengths_are_rejected() {
+        let (a, _) = build(HashFamily::Hyperplane, true);
+        let value = to_value(&a, false);
+
+        // A plane which is shorter than the dimension
+        let mut planes = value.clone();
+        let index = &mut items(field(&mut planes, "indices"))[0];
+        items(&mut items(field(index, "planes"))[0]).pop();
+        assert!(from_value::<MultiIndex<u64, f32, String>>(planes).is_err());
+
+        // A group key with the wrong number of bits
+        let mut keys = value.clone();
+        let index = &mut items(field(&mut keys, "indices"))[1];
+        let group = &mut items(field(index, "groups"))[0];
+        items(&mut items(group)[0])[0] = Value::U64(3);
+        assert!(from_value::<MultiIndex<u64, f32, String>>(keys).is_err());
+
+        // A stored vector which is longer than the dimension
+        let mut vectors = value.clone();
+        let Value::Option(Some(stored)) = field(&mut vectors, "vectors") else {
+            panic!("expected stored vectors");
+        };
+        items(&mut items(stored)[0])[1] = Value::Seq(vec![ Value::F64(0.0); 21 ]);
+        assert!(from_value::<MultiIndex<u64, f32, String>>(vectors).is_err());
+
+        // Indices with different dimensions
+        let mut dims = value;
+        let index = &mut items(field(&mut dims, "indices"))[2];
+        *field(index, "dims") = Value::U64(19);
+        assert!(from_value::<MultiIndex<u64, f32, String>>(dims).is_err());
+    }
+
+    #[test]
+    fn invalid_hash_families_are_rejected() {
+        let pstable = |width: f64| Value::Variant(Box::new(Value::Str("PStable".to_string())), Box::new(Value::Map(vec![ (Value::Str("width".to_string()), Value::F64(width)) ])));
+        let cross = |dims: u64| Value::Variant(Box::new(Value::Str("CrossPolytope".to_string())), Box::new(Value::Map(vec![ (Value::Str("rotation_dims".to_string()), Value::U64(dims)) ])));
+        let with_family = |value: &Value, family: Value| {
+            let mut value = value.clone();
+            let index = &mut items(field(&mut value, "indices"))[0];
+            *field(index, "family") = family;
+            return from_value::<MultiIndex<u64, f32, String>>(value);
+        };
+
+        let (a, _) = build(HashFamily::PStable { width: 2.5 }, false);
+        let value = to_value(&a, false);
+        assert!(with_family(&value, pstable(4.0)).is_ok());
+        for width in [ 0.0, -1.0, f64::NAN, f64::INFINITY ] {
+            let err = with_family(&value, pstable(width)).err().unwrap();
+            assert!(err.0.contains("bucket width"), "{}", err);
+        }
+
+        let (a, _) = build(HashFamily::CrossPolytope { rotation_dims: 8 }, false);
+        let value = to_value(&a, false);
+        assert!(with_family(&value, cross(8)).is_ok());
+        for dims in [ 0, 21 ] {
+            let err = with_family(&value, cross(dims)).err().unwrap();
+            assert!(err.0.contains("rotation dimensions"), "{}", err);
+        }
+    }
+}
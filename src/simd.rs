@@ -0,0 +1,420 @@
+// SIMD implementations of the vector kernels. Each public function picks the best implementation available on the current
+// CPU at runtime and falls back to the plain scalar loop, so results should only ever differ from the scalar versions by
+// floating point rounding (the order of additions is different).
+
+use crate::vector::{ dot_scalar, squared_euclidean_scalar };
+
+pub(crate) fn dot_f32(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return unsafe { x86::dot_f32(a, b) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        return unsafe { neon::dot_f32(a, b) };
+    }
+
+    #[allow(unreachable_code)]
+    return dot_scalar(a, b);
+}
+
+// Dot product of a vector with every row of a row major matrix
+pub(crate) fn dot_rows_f32(matrix: &[f32], vector: &[f32]) -> Vec<f32> {
+    assert!(!vector.is_empty() && matrix.len().is_multiple_of(vector.len()), "matrix rows do not have the length of the vector");
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return unsafe { x86::dot_rows_f32(matrix, vector) };
+        }
+    }
+
+    return matrix.chunks_exact(vector.len()).map(|row| dot_f32(row, vector)).collect();
+}
+
+pub(crate) fn dot_f64(a: &[f64], b: &[f64]) -> f64 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return unsafe { x86::dot_f64(a, b) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        return unsafe { neon::dot_f64(a, b) };
+    }
+
+    #[allow(unreachable_code)]
+    return dot_scalar(a, b);
+}
+
+pub(crate) fn squared_euclidean_f32(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return unsafe { x86::squared_euclidean_f32(a, b) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        return unsafe { neon::squared_euclidean_f32(a, b) };
+    }
+
+    #[allow(unreachable_code)]
+    return squared_euclidean_scalar(a, b);
+}
+
+pub(crate) fn squared_euclidean_f64(a: &[f64], b: &[f64]) -> f64 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return unsafe { x86::squared_euclidean_f64(a, b) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        return unsafe { neon::squared_euclidean_f64(a, b) };
+    }
+
+    #[allow(unreachable_code)]
+    return squared_euclidean_scalar(a, b);
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn sum_f32(v: __m256) -> f32 {
+        let hi = _mm256_extractf128_ps(v, 1);
+        let lo = _mm256_castps256_ps128(v);
+        let s = _mm_add_ps(hi, lo);
+        let s = _mm_add_ps(s, _mm_movehl_ps(s, s));
+        let s = _mm_add_ss(s, _mm_shuffle_ps(s, s, 1));
+        _mm_cvtss_f32(s)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn sum_f64(v: __m256d) -> f64 {
+        let hi = _mm256_extractf128_pd(v, 1);
+        let lo = _mm256_castpd256_pd128(v);
+        let s = _mm_add_pd(hi, lo);
+        let s = _mm_add_sd(s, _mm_unpackhi_pd(s, s));
+        _mm_cvtsd_f64(s)
+    }
+
+    // Four independent accumulators hide the latency of the fused multiply add
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot_f32(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc = [_mm256_setzero_ps(); 4];
+        let mut i = 0;
+        while i + 32 <= n {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                let o = i + j * 8;
+                *acc = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(o)), _mm256_loadu_ps(pb.add(o)), *acc);
+            }
+            i += 32;
+        }
+        while i + 8 <= n {
+            acc[0] = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)), acc[0]);
+            i += 8;
+        }
+
+        let total = _mm256_add_ps(_mm256_add_ps(acc[0], acc[1]), _mm256_add_ps(acc[2], acc[3]));
+        let mut result = sum_f32(total);
+        while i < n {
+            result += a[i] * b[i];
+            i += 1;
+        }
+
+        result
+    }
+
+    // Four rows at a time, so each chunk of the vector is loaded once and used for all four rows
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot_rows_f32(matrix: &[f32], vector: &[f32]) -> Vec<f32> {
+        let n = vector.len();
+        let pv = vector.as_ptr();
+        let mut result = Vec::with_capacity(matrix.len() / n);
+
+        let mut blocks = matrix.chunks_exact(n * 4);
+        for block in &mut blocks {
+            let pm = block.as_ptr();
+            let mut acc = [_mm256_setzero_ps(); 4];
+            let mut i = 0;
+            while i + 8 <= n {
+                let v = _mm256_loadu_ps(pv.add(i));
+                for (r, acc) in acc.iter_mut().enumerate() {
+                    *acc = _mm256_fmadd_ps(_mm256_loadu_ps(pm.add(r * n + i)), v, *acc);
+                }
+                i += 8;
+            }
+
+            for (row, acc) in block.chunks_exact(n).zip(acc.iter()) {
+                let tail = row[i..].iter().zip(vector[i..].iter()).map(|(a, b)| a * b).sum::<f32>();
+                result.push(sum_f32(*acc) + tail);
+            }
+        }
+        for row in blocks.remainder().chunks_exact(n) {
+            result.push(dot_f32(row, vector));
+        }
+
+        result
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot_f64(a: &[f64], b: &[f64]) -> f64 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc = [_mm256_setzero_pd(); 4];
+        let mut i = 0;
+        while i + 16 <= n {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                let o = i + j * 4;
+                *acc = _mm256_fmadd_pd(_mm256_loadu_pd(pa.add(o)), _mm256_loadu_pd(pb.add(o)), *acc);
+            }
+            i += 16;
+        }
+        while i + 4 <= n {
+            acc[0] = _mm256_fmadd_pd(_mm256_loadu_pd(pa.add(i)), _mm256_loadu_pd(pb.add(i)), acc[0]);
+            i += 4;
+        }
+
+        let total = _mm256_add_pd(_mm256_add_pd(acc[0], acc[1]), _mm256_add_pd(acc[2], acc[3]));
+        let mut result = sum_f64(total);
+        while i < n {
+            result += a[i] * b[i];
+            i += 1;
+        }
+
+        result
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn squared_euclidean_f32(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc = [_mm256_setzero_ps(); 4];
+        let mut i = 0;
+        while i + 32 <= n {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                let o = i + j * 8;
+                let d = _mm256_sub_ps(_mm256_loadu_ps(pa.add(o)), _mm256_loadu_ps(pb.add(o)));
+                *acc = _mm256_fmadd_ps(d, d, *acc);
+            }
+            i += 32;
+        }
+        while i + 8 <= n {
+            let d = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+            acc[0] = _mm256_fmadd_ps(d, d, acc[0]);
+            i += 8;
+        }
+
+        let total = _mm256_add_ps(_mm256_add_ps(acc[0], acc[1]), _mm256_add_ps(acc[2], acc[3]));
+        let mut result = sum_f32(total);
+        while i < n {
+            let d = a[i] - b[i];
+            result += d * d;
+            i += 1;
+        }
+
+        result
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn squared_euclidean_f64(a: &[f64], b: &[f64]) -> f64 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc = [_mm256_setzero_pd(); 4];
+        let mut i = 0;
+        while i + 16 <= n {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                let o = i + j * 4;
+                let d = _mm256_sub_pd(_mm256_loadu_pd(pa.add(o)), _mm256_loadu_pd(pb.add(o)));
+                *acc = _mm256_fmadd_pd(d, d, *acc);
+            }
+            i += 16;
+        }
+        while i + 4 <= n {
+            let d = _mm256_sub_pd(_mm256_loadu_pd(pa.add(i)), _mm256_loadu_pd(pb.add(i)));
+            acc[0] = _mm256_fmadd_pd(d, d, acc[0]);
+            i += 4;
+        }
+
+        let total = _mm256_add_pd(_mm256_add_pd(acc[0], acc[1]), _mm256_add_pd(acc[2], acc[3]));
+        let mut result = sum_f64(total);
+        while i < n {
+            let d = a[i] - b[i];
+            result += d * d;
+            i += 1;
+        }
+
+        result
+    }
+}
+
+// NEON is part of the aarch64 baseline, so there's no runtime detection needed
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn dot_f32(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc0 = vdupq_n_f32(0.0);
+        let mut acc1 = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i + 8 <= n {
+            acc0 = vfmaq_f32(acc0, vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+            acc1 = vfmaq_f32(acc1, vld1q_f32(pa.add(i + 4)), vld1q_f32(pb.add(i + 4)));
+            i += 8;
+        }
+
+        let mut result = vaddvq_f32(vaddq_f32(acc0, acc1));
+        while i < n {
+            result += a[i] * b[i];
+            i += 1;
+        }
+
+        result
+    }
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn dot_f64(a: &[f64], b: &[f64]) -> f64 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc0 = vdupq_n_f64(0.0);
+        let mut acc1 = vdupq_n_f64(0.0);
+        let mut i = 0;
+        while i + 4 <= n {
+            acc0 = vfmaq_f64(acc0, vld1q_f64(pa.add(i)), vld1q_f64(pb.add(i)));
+            acc1 = vfmaq_f64(acc1, vld1q_f64(pa.add(i + 2)), vld1q_f64(pb.add(i + 2)));
+            i += 4;
+        }
+
+        let mut result = vaddvq_f64(vaddq_f64(acc0, acc1));
+        while i < n {
+            result += a[i] * b[i];
+            i += 1;
+        }
+
+        result
+    }
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn squared_euclidean_f32(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i + 4 <= n {
+            let d = vsubq_f32(vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+            acc = vfmaq_f32(acc, d, d);
+            i += 4;
+        }
+
+        let mut result = vaddvq_f32(acc);
+        while i < n {
+            let d = a[i] - b[i];
+            result += d * d;
+            i += 1;
+        }
+
+        result
+    }
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn squared_euclidean_f64(a: &[f64], b: &[f64]) -> f64 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+
+        let mut acc = vdupq_n_f64(0.0);
+        let mut i = 0;
+        while i + 2 <= n {
+            let d = vsubq_f64(vld1q_f64(pa.add(i)), vld1q_f64(pb.add(i)));
+            acc = vfmaq_f64(acc, d, d);
+            i += 2;
+        }
+
+        let mut result = vaddvq_f64(acc);
+        while i < n {
+            let d = a[i] - b[i];
+            result += d * d;
+            i += 1;
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::simd::{ dot_f32, dot_f64, dot_rows_f32, squared_euclidean_f32, squared_euclidean_f64 };
+    use crate::vector::{ dot_scalar, squared_euclidean_scalar, random_unit_vector_of };
+
+    #[test]
+    fn simd_matches_scalar() {
+        let mut rng = thread_rng();
+
+        // Check lengths around every chunk boundary
+        for len in 0..70 {
+            let a = random_unit_vector_of::<f32, _>(len, &mut rng);
+            let b = random_unit_vector_of::<f32, _>(len, &mut rng);
+            assert!((dot_f32(&a, &b) - dot_scalar(&a, &b)).abs() < 1e-5);
+            assert!((squared_euclidean_f32(&a, &b) - squared_euclidean_scalar(&a, &b)).abs() < 1e-5);
+
+            let a = random_unit_vector_of::<f64, _>(len, &mut rng);
+            let b = random_unit_vector_of::<f64, _>(len, &mut rng);
+            assert!((dot_f64(&a, &b) - dot_scalar(&a, &b)).abs() < 1e-12);
+            assert!((squared_euclidean_f64(&a, &b) - squared_euclidean_scalar(&a, &b)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn dot_rows_matches_scalar() {
+        let mut rng = thread_rng();
+
+        // Row counts around the block size and lengths around the chunk size
+        for len in 1..20 {
+            for rows in 0..10 {
+                let matrix = (0..rows).flat_map(|_| random_unit_vector_of::<f32, _>(len, &mut rng)).collect::<Vec<_>>();
+                let v = random_unit_vector_of::<f32, _>(len, &mut rng);
+
+                let result = dot_rows_f32(&matrix, &v);
+                assert_eq!(rows, result.len());
+                for (row, d) in matrix.chunks_exact(len).zip(result) {
+                    assert!((d - dot_scalar(row, &v)).abs() < 1e-5);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Statistics about the group sizes of an index
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexStats {
+    /// Number of groups (including groups which have had all of their items removed)
+    pub group_count: usize,
+
+    /// Total number of items in all groups
+    pub item_count: usize,
+
+    pub min_group_size: usize,
+    pub mean_group_size: f32,
+    pub max_group_size: usize,
+    pub group_size_std_dev: f32,
+
+    /// Number of groups of each size (group size -> group count)
+    pub histogram: BTreeMap<usize, usize>,
+}
+
+impl IndexStats {
+    /// Calculate stats from a histogram of group sizes (group size -> group count)
+    pub fn from_histogram(histogram: BTreeMap<usize, usize>) -> IndexStats {
+        let group_count = histogram.values().sum::<usize>();
+        let item_count = histogram.iter().map(|(size, count)| size * count).sum::<usize>();
+
+        let mean = item_count as f32 / (group_count.max(1) as f32);
+        let variance = histogram.iter()
+            .map(|(size, count)| (*size as f32 - mean).powi(2) * *count as f32)
+            .sum::<f32>() / (group_count.max(1) as f32);
+
+        return IndexStats {
+            group_count,
+            item_count,
+            min_group_size: histogram.keys().next().cloned().unwrap_or(0),
+            mean_group_size: mean,
+            max_group_size: histogram.keys().next_back().cloned().unwrap_or(0),
+            group_size_std_dev: variance.sqrt(),
+            histogram
+        };
+    }
+}
+
+/// Statistics about every sub index of a `MultiIndex`, returned by `MultiIndex::index_stats`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiIndexStats {
+    /// Stats of each sub index, in order
+    pub per_index: Vec<IndexStats>,
+
+    /// Stats of every sub index combined, the item count is the total number of items in all sub indices
+    pub combined: IndexStats,
+
+    /// Number of distinct keys in the index
+    pub unique_items: usize,
+}
+
+/// Write a group size histogram as CSV, with a `group_size,group_count` header
+pub fn write_histogram_csv<W: Write>(histogram: &BTreeMap<usize, usize>, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "group_size,group_count")?;
+    for (size, count) in histogram.iter() {
+        writeln!(writer, "{},{}", size, count)?;
+    }
+    return Ok(());
+}
+
+/// Format a group size histogram as a JSON array of `{"group_size":..,"group_count":..}` objects
+pub fn histogram_json(histogram: &BTreeMap<usize, usize>) -> String {
+    let entries = histogram.iter()
+        .map(|(size, count)| format!("{{\"group_size\":{},\"group_count\":{}}}", size, count))
+        .collect::<Vec<_>>();
+    return format!("[{}]", entries.join(","));
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::BTreeMap;
+
+    use crate::stats::{ IndexStats, histogram_json, write_histogram_csv };
+
+    #[test]
+    fn from_histogram_calculates_stats() {
+        let mut histogram = BTreeMap::new();
+        histogram.insert(2, 2);
+        histogram.insert(4, 2);
+
+        let stats = IndexStats::from_histogram(histogram);
+        assert_eq!(4, stats.group_count);
+        assert_eq!(12, stats.item_count);
+        assert_eq!(2, stats.min_group_size);
+        assert_eq!(3f32, stats.mean_group_size);
+        assert_eq!(4, stats.max_group_size);
+        assert_eq!(1f32, stats.group_size_std_dev);
+    }
+
+    #[test]
+    fn from_empty_histogram() {
+        let stats = IndexStats::from_histogram(BTreeMap::new());
+        assert_eq!(0, stats.group_count);
+        assert_eq!(0, stats.max_group_size);
+        assert_eq!(0f32, stats.mean_group_size);
+    }
+
+    #[test]
+    fn histogram_exports() {
+        let mut histogram = BTreeMap::new();
+        histogram.insert(1, 5);
+        histogram.insert(3, 2);
+
+        let mut csv = Vec::new();
+        write_histogram_csv(&histogram, &mut csv).unwrap();
+        assert_eq!("group_size,group_count\n1,5\n3,2\n", String::from_utf8(csv).unwrap());
+        assert_eq!(r#"[{"group_size":1,"group_count":5},{"group_size":3,"group_count":2}]"#, histogram_json(&histogram));
+    }
+}
@@ -1,49 +1,531 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
+use std::iter::Sum;
+
+use num_traits::Float;
 use rand::{Rng};
 use rand_distr::StandardNormal;
 
-pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+use crate::error::HypernonsenseError;
+use crate::simd;
+
+/// A floating point type which can be used for vector elements
+pub trait Scalar : Float + Sum + Default + Debug + Send + Sync + 'static {
+    /// Identifies the type in saved files, so a file written with one type is never read as another type of the same size
+    const KIND: ScalarKind;
+
+    /// Sample a value from the standard normal distribution
+    fn standard_normal<R:Rng>(rng: &mut R) -> Self;
+
+    /// Dot product of two slices, types may override this with a SIMD implementation
+    fn dot(a: &[Self], b: &[Self]) -> Self {
+        dot_scalar(a, b)
+    }
+
+    /// Squared euclidean distance between two slices, types may override this with a SIMD implementation
+    fn squared_euclidean(a: &[Self], b: &[Self]) -> Self {
+        squared_euclidean_scalar(a, b)
+    }
+
+    /// Dot product of a vector with every row of a row major matrix, types may override this with a blocked implementation
+    fn dot_rows(matrix: &[Self], vector: &[Self]) -> Vec<Self> {
+        assert!(!vector.is_empty() && matrix.len().is_multiple_of(vector.len()), "matrix rows do not have the length of the vector");
+        matrix.chunks_exact(vector.len()).map(|row| Self::dot(row, vector)).collect()
+    }
+}
+
+/// The element type an index was saved with. Files record this rather than just the size of the type, because `f16` and
+/// `bf16` are the same size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarKind {
+    F32,
+    F64,
+    F16,
+    Bf16,
+}
+
+impl ScalarKind {
+    /// The value written to files for this kind
+    pub fn code(self) -> u32 {
+        match self {
+            ScalarKind::F32 => 0,
+            ScalarKind::F64 => 1,
+            ScalarKind::F16 => 2,
+            ScalarKind::Bf16 => 3,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<ScalarKind> {
+        match code {
+            0 => Some(ScalarKind::F32),
+            1 => Some(ScalarKind::F64),
+            2 => Some(ScalarKind::F16),
+            3 => Some(ScalarKind::Bf16),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ScalarKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScalarKind::F32 => write!(f, "f32"),
+            ScalarKind::F64 => write!(f, "f64"),
+            ScalarKind::F16 => write!(f, "f16"),
+            ScalarKind::Bf16 => write!(f, "bf16"),
+        }
+    }
+}
+
+// Check the scalar kind read from a file is the type it is being loaded as, `file` names the kind of file for the error
+pub(crate) fn check_scalar_kind<T:Scalar>(code: u64, file: &str) -> Result<(), HypernonsenseError> {
+    return match u32::try_from(code).ok().and_then(ScalarKind::from_code) {
+        Some(kind) if kind == T::KIND => Ok(()),
+        Some(kind) => Err(HypernonsenseError::InvalidFormat(format!("{} was written with {} scalars", file, kind))),
+        None => Err(HypernonsenseError::InvalidFormat(format!("{} was written with unknown scalar kind {}", file, code))),
+    };
+}
+
+impl Scalar for f32 {
+    const KIND: ScalarKind = ScalarKind::F32;
+
+    fn standard_normal<R:Rng>(rng: &mut R) -> Self {
+        rng.sample(StandardNormal)
+    }
+
+    fn dot(a: &[Self], b: &[Self]) -> Self {
+        simd::dot_f32(a, b)
+    }
+
+    fn squared_euclidean(a: &[Self], b: &[Self]) -> Self {
+        simd::squared_euclidean_f32(a, b)
+    }
+
+    fn dot_rows(matrix: &[Self], vector: &[Self]) -> Vec<Self> {
+        simd::dot_rows_f32(matrix, vector)
+    }
+}
+
+impl Scalar for f64 {
+    const KIND: ScalarKind = ScalarKind::F64;
+
+    fn standard_normal<R:Rng>(rng: &mut R) -> Self {
+        rng.sample(StandardNormal)
+    }
+
+    fn dot(a: &[Self], b: &[Self]) -> Self {
+        simd::dot_f64(a, b)
+    }
+
+    fn squared_euclidean(a: &[Self], b: &[Self]) -> Self {
+        simd::squared_euclidean_f64(a, b)
+    }
+}
+
+pub(crate) fn dot_scalar<T:Scalar>(a: &[T], b: &[T]) -> T {
     assert_eq!(a.len(), b.len());
 
     return a.iter()
         .zip(b.iter())
-        .map(|(a, b)| a * b)
-        .sum::<f32>();
+        .map(|(a, b)| *a * *b)
+        .sum::<T>();
+}
+
+pub(crate) fn squared_euclidean_scalar<T:Scalar>(a: &[T], b: &[T]) -> T {
+    assert_eq!(a.len(), b.len());
+
+    let mut acc = T::zero();
+    for index in 0..a.len() {
+        let d = a[index] - b[index];
+        acc = acc + d * d;
+    }
+
+    return acc;
+}
+
+/// Check a vector has the expected number of dimensions and only contains finite elements
+pub(crate) fn check_vector<T:Scalar>(vector: &[T], dimensions: usize) -> Result<(), HypernonsenseError> {
+    if vector.len() != dimensions {
+        return Err(HypernonsenseError::DimensionMismatch { expected: dimensions, actual: vector.len() });
+    }
+    if vector.iter().any(|v| !v.is_finite()) {
+        return Err(HypernonsenseError::NonFiniteInput);
+    }
+
+    return Ok(());
+}
+
+/// How an index treats vectors which are not unit length
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// Vectors are used as they are
+    #[default]
+    None,
+
+    /// Vectors are scaled to unit length before they are inserted or queried. Zero length vectors are left alone.
+    NormalizeOnInsert,
+
+    /// Vectors which are not unit length are rejected
+    RejectNonUnit,
+}
+
+// How far the squared length of a vector can be from one and still be considered a unit vector
+const UNIT_TOLERANCE: f64 = 1e-3;
+
+/// Scale a vector to unit length. Zero length vectors are left unchanged.
+pub fn normalize<T:Scalar>(vector: &mut [T]) {
+    let length = norm(vector);
+    if length == T::zero() {
+        return;
+    }
+
+    for item in vector.iter_mut() {
+        *item = *item / length;
+    }
+}
+
+/// A unit length copy of a vector, see `normalize`
+pub fn normalized<T:Scalar>(vector: &[T]) -> Vec<T> {
+    let mut result = vector.to_vec();
+    normalize(&mut result);
+    return result;
+}
+
+/// Length (L2 norm) of a vector
+pub fn norm<T:Scalar>(vector: &[T]) -> T {
+    dot(vector, vector).sqrt()
+}
+
+/// Add `b` to `a` element by element
+pub fn add_in_place<T:Scalar>(a: &mut [T], b: &[T]) {
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        *a = *a + *b;
+    }
+}
+
+pub fn add<T:Scalar>(a: &[T], b: &[T]) -> Vec<T> {
+    let mut result = a.to_vec();
+    add_in_place(&mut result, b);
+    return result;
+}
+
+/// Subtract `b` from `a` element by element
+pub fn sub_in_place<T:Scalar>(a: &mut [T], b: &[T]) {
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        *a = *a - *b;
+    }
+}
+
+pub fn sub<T:Scalar>(a: &[T], b: &[T]) -> Vec<T> {
+    let mut result = a.to_vec();
+    sub_in_place(&mut result, b);
+    return result;
+}
+
+/// Multiply every element of a vector by `factor`
+pub fn scale_in_place<T:Scalar>(vector: &mut [T], factor: T) {
+    for item in vector.iter_mut() {
+        *item = *item * factor;
+    }
+}
+
+pub fn scale<T:Scalar>(vector: &[T], factor: T) -> Vec<T> {
+    let mut result = vector.to_vec();
+    scale_in_place(&mut result, factor);
+    return result;
+}
+
+/// Element by element mean of some vectors, `None` if there are no vectors. Panics if the vectors have different lengths.
+pub fn mean<T:Scalar, V:AsRef<[T]>>(vectors: &[V]) -> Option<Vec<T>> {
+    let (first, rest) = vectors.split_first()?;
+    let mut result = first.as_ref().to_vec();
+    for v in rest {
+        add_in_place(&mut result, v.as_ref());
+    }
+    scale_in_place(&mut result, T::one() / T::from(vectors.len()).unwrap());
+    return Some(result);
+}
+
+/// Check if a vector is unit length (within a small tolerance)
+pub fn is_unit<T:Scalar>(vector: &[T]) -> bool {
+    let squared = dot(vector, vector).to_f64().unwrap_or(f64::NAN);
+    return (squared - 1.0).abs() <= UNIT_TOLERANCE;
+}
+
+pub fn dot<T:Scalar>(a: &[T], b: &[T]) -> T {
+    T::dot(a, b)
 }
 
 //distance metric based on cosine distance which is offset from [-1,1] range into the [0,2] range
-pub fn modified_cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+pub fn modified_cosine_distance<T:Scalar>(a: &[T], b: &[T]) -> T {
     let d = dot(a, b);
-    (2f32 - (d + 1f32)).max(0f32)
+    (T::one() - d).max(T::zero())
 }
 
-pub fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+/// Cosine distance (`1 - cos(angle)`), does not require the vectors to be normalized. Returns 1 if either vector has zero length.
+pub fn cosine_distance<T:Scalar>(a: &[T], b: &[T]) -> T {
+    let d = dot(a, b);
+    let len = (dot(a, a) * dot(b, b)).sqrt();
+    if len == T::zero() {
+        return T::one();
+    }
+
+    return (T::one() - d / len).max(T::zero());
+}
+
+pub fn euclidean_distance<T:Scalar>(a: &[T], b: &[T]) -> T {
+    T::squared_euclidean(a, b).sqrt()
+}
+
+/// Squared euclidean distance, cheaper than `euclidean_distance` and gives the same ordering
+pub fn squared_euclidean_distance<T:Scalar>(a: &[T], b: &[T]) -> T {
+    T::squared_euclidean(a, b)
+}
+
+/// Sum of the absolute differences of each element (L1 distance)
+pub fn manhattan_distance<T:Scalar>(a: &[T], b: &[T]) -> T {
     assert_eq!(a.len(), b.len());
 
-    let mut acc = 0f32;
-    for index in 0..a.len() {
-        acc += (a[index] - b[index]).powf(2f32);
+    return a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (*a - *b).abs())
+        .sum::<T>();
+}
+
+/// Minkowski distance of order `p`. `p = 1` is manhattan distance, `p = 2` is euclidean distance and `p = infinity` is
+/// the largest difference of any element (chebyshev distance).
+pub fn minkowski_distance<T:Scalar>(a: &[T], b: &[T], p: T) -> T {
+    assert_eq!(a.len(), b.len());
+    assert!(p >= T::one(), "minkowski distance is only a metric for p >= 1");
+
+    if p.is_infinite() {
+        return a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| (*a - *b).abs())
+            .fold(T::zero(), T::max);
+    }
+
+    return a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (*a - *b).abs().powf(p))
+        .sum::<T>()
+        .powf(T::one() / p);
+}
+
+/// A sparse vector, stored as a list of the non zero elements sorted by index
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseVector<T:Scalar=f32> {
+    indices: Vec<usize>,
+    values: Vec<T>
+}
+
+impl<T:Scalar> SparseVector<T> {
+    /// Create a sparse vector from (index, value) pairs in any order. Values for duplicate indices are summed and zeros are dropped.
+    pub fn from_pairs(mut pairs: Vec<(usize, T)>) -> SparseVector<T> {
+        pairs.sort_unstable_by_key(|p| p.0);
+
+        let mut indices: Vec<usize> = Vec::with_capacity(pairs.len());
+        let mut values: Vec<T> = Vec::with_capacity(pairs.len());
+        for (i, v) in pairs {
+            if indices.last() == Some(&i) {
+                let last = values.len() - 1;
+                values[last] = values[last] + v;
+            } else {
+                indices.push(i);
+                values.push(v);
+            }
+        }
+
+        let mut result = SparseVector { indices, values };
+        result.retain_non_zero();
+        return result;
+    }
+
+    /// Create a sparse vector from the non zero elements of a dense vector
+    pub fn from_dense(dense: &[T]) -> SparseVector<T> {
+        let mut result = SparseVector {
+            indices: (0..dense.len()).collect(),
+            values: dense.to_vec()
+        };
+        result.retain_non_zero();
+        return result;
+    }
+
+    fn retain_non_zero(&mut self) {
+        let mut write = 0;
+        for read in 0..self.values.len() {
+            if self.values[read] != T::zero() {
+                self.indices[write] = self.indices[read];
+                self.values[write] = self.values[read];
+                write += 1;
+            }
+        }
+        self.indices.truncate(write);
+        self.values.truncate(write);
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    /// Number of non zero elements
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// One more than the largest non zero index
+    pub fn min_dimensions(&self) -> usize {
+        self.indices.last().map(|i| i + 1).unwrap_or(0)
+    }
+
+    pub fn to_dense(&self, dimension: usize) -> Vec<T> {
+        let mut dense = vec![T::zero(); dimension];
+        for (i, v) in self.indices.iter().zip(self.values.iter()) {
+            dense[*i] = *v;
+        }
+        return dense;
+    }
+}
+
+/// Dot product of two sparse vectors
+pub fn sparse_dot<T:Scalar>(a: &SparseVector<T>, b: &SparseVector<T>) -> T {
+    // Both index lists are sorted, so walk them together
+    let mut acc = T::zero();
+    let (mut i, mut j) = (0, 0);
+    while i < a.indices.len() && j < b.indices.len() {
+        match a.indices[i].cmp(&b.indices[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                acc = acc + a.values[i] * b.values[j];
+                i += 1;
+                j += 1;
+            }
+        }
     }
-    acc = acc.sqrt();
 
     return acc;
 }
 
+/// Dot product of a sparse vector and a dense vector
+pub fn sparse_dense_dot<T:Scalar>(a: &SparseVector<T>, b: &[T]) -> T {
+    return a.indices.iter()
+        .zip(a.values.iter())
+        .map(|(i, v)| *v * b[*i])
+        .sum::<T>();
+}
+
+/// Cosine distance (`1 - cos(angle)`) between two sparse vectors. Returns 1 if either vector has zero length.
+pub fn sparse_cosine_distance<T:Scalar>(a: &SparseVector<T>, b: &SparseVector<T>) -> T {
+    let len = (sparse_dot(a, a) * sparse_dot(b, b)).sqrt();
+    if len == T::zero() {
+        return T::one();
+    }
+
+    return (T::one() - sparse_dot(a, b) / len).max(T::zero());
+}
+
 pub fn random_unit_vector<R:Rng>(dimension:usize, rng: &mut R) -> Vec<f32>
+{
+    random_unit_vector_of(dimension, rng)
+}
+
+/// Generate a random unit vector with elements of any scalar type
+pub fn random_unit_vector_of<T:Scalar, R:Rng>(dimension:usize, rng: &mut R) -> Vec<T>
 {
     // Generate a random vector
-    let mut v : Vec<f32> = rng.sample_iter(&StandardNormal).take(dimension).collect::<Vec<f32>>();
+    let mut v : Vec<T> = (0..dimension).map(|_| T::standard_normal(rng)).collect::<Vec<T>>();
 
     // Calculate vector length
     let length = v.iter()
-        .map(|a| *a as f64)
+        .map(|a| a.to_f64().unwrap())
         .map(|a| a * a)
         .sum::<f64>()
         .sqrt();
 
     // Normalize
-    for i in 0..dimension {
-        v[i] /= length as f32;
+    let length = T::from(length).unwrap();
+    for item in v.iter_mut() {
+        *item = *item / length;
     }
 
     v
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::vector::{ SparseVector, add, add_in_place, cosine_distance, dot, euclidean_distance, is_unit, manhattan_distance, mean, minkowski_distance, norm, normalize, normalized, scale, sparse_cosine_distance, sparse_dense_dot, sparse_dot, sub, sub_in_place };
+
+    #[test]
+    fn minkowski_generalises_other_distances() {
+        let a = vec![1f32, -2f32, 3f32];
+        let b = vec![4f32, 2f32, 3f32];
+
+        assert_eq!(7f32, manhattan_distance(&a, &b));
+        assert_eq!(manhattan_distance(&a, &b), minkowski_distance(&a, &b, 1f32));
+        assert!((euclidean_distance(&a, &b) - minkowski_distance(&a, &b, 2f32)).abs() < 1e-6);
+        assert_eq!(4f32, minkowski_distance(&a, &b, f32::INFINITY));
+    }
+
+    #[test]
+    fn normalize_makes_unit_vectors() {
+        let mut v = vec![3f32, 4f32];
+        assert!(!is_unit(&v));
+
+        normalize(&mut v);
+        assert!(is_unit(&v));
+        assert_eq!(vec![0.6f32, 0.8f32], v);
+
+        let mut zero = vec![0f32, 0f32];
+        normalize(&mut zero);
+        assert_eq!(vec![0f32, 0f32], zero);
+    }
+
+    #[test]
+    fn vector_arithmetic() {
+        let a = vec![1f32, 2f32];
+        let b = vec![3f32, -1f32];
+
+        assert_eq!(vec![4f32, 1f32], add(&a, &b));
+        assert_eq!(vec![-2f32, 3f32], sub(&a, &b));
+        assert_eq!(vec![2f32, 4f32], scale(&a, 2f32));
+        assert_eq!(Some(vec![2f32, 0.5f32]), mean(&[a.clone(), b.clone()]));
+        assert_eq!(None, mean::<f32, Vec<f32>>(&[]));
+        assert_eq!(5f32, norm(&[3f32, 4f32]));
+        assert_eq!(vec![0.6f32, 0.8f32], normalized(&[3f32, 4f32]));
+
+        let mut c = a.clone();
+        add_in_place(&mut c, &b);
+        sub_in_place(&mut c, &b);
+        assert_eq!(a, c);
+    }
+
+    #[test]
+    fn sparse_from_pairs_sorts_and_merges() {
+        let v = SparseVector::from_pairs(vec![(5, 1f32), (1, 2f32), (5, 2f32), (3, 0f32)]);
+
+        assert_eq!(&[1, 5], v.indices());
+        assert_eq!(&[2f32, 3f32], v.values());
+        assert_eq!(6, v.min_dimensions());
+        assert_eq!(vec![0f32, 2f32, 0f32, 0f32, 0f32, 3f32], v.to_dense(6));
+    }
+
+    #[test]
+    fn sparse_matches_dense() {
+        let a = vec![1f32, 0f32, 2f32, 0f32, -1f32];
+        let b = vec![0f32, 3f32, 1f32, 0f32, 2f32];
+        let sa = SparseVector::from_dense(&a);
+        let sb = SparseVector::from_dense(&b);
+
+        assert_eq!(dot(&a, &b), sparse_dot(&sa, &sb));
+        assert_eq!(dot(&a, &b), sparse_dense_dot(&sa, &b));
+        assert!((cosine_distance(&a, &b) - sparse_cosine_distance(&sa, &sb)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use crate::error::HypernonsenseError;
+use crate::metric::Metric;
+use crate::mmap::{MappedKey, Mmap};
+use crate::vector::{Scalar, check_scalar_kind};
+
+// File layout. Like the mapped index format every section is 8 byte aligned and stored in native (little endian) byte
+// order, so vectors can be read straight out of the mapped memory without copying.
+//
+//  magic         8 bytes "HYPNVECS"
+//  version       u64
+//  dims          u64
+//  count         u64
+//  key size      u64 (size_of::<K>(), checked on open)
+//  scalar        u64 (the kind of T, see `ScalarKind::code`, checked on open)
+//  vectors       count * dims * T, the vector with id `i` is at `i * dims`
+//  padding       to a multiple of 8 bytes
+//  keys          count * K, the key of every id in order
+const MAGIC: &[u8; 8] = b"HYPNVECS";
+const FORMAT_VERSION: u64 = 1;
+const HEADER_LEN: usize = 8 * 6;
+
+/// Writes vectors to a file which can be opened with `MappedVectorStore::open`. Vectors are streamed to the file as they are
+/// pushed, only the keys are kept in memory until `finish`.
+pub struct VectorStoreWriter<K:MappedKey, T:Scalar=f32> {
+    file: BufWriter<File>,
+    dims: usize,
+    keys: Vec<K>,
+    ids: HashMap<K, usize>,
+    phantom: PhantomData<T>,
+}
+
+impl<K:MappedKey, T:Scalar> VectorStoreWriter<K, T> {
+    pub fn create<P: AsRef<Path>>(path: P, dims: usize) -> Result<VectorStoreWriter<K, T>, HypernonsenseError> {
+        if dims == 0 {
+            return Err(HypernonsenseError::InvalidConfig("dimension must be at least one".to_string()));
+        }
+
+        // The header is written again with the real count by `finish`
+        let mut file = BufWriter::new(File::create(path)?);
+        write_header::<K, T, _>(&mut file, dims, 0)?;
+        return Ok(VectorStoreWriter { file, dims, keys: Vec::new(), ids: HashMap::new(), phantom: PhantomData });
+    }
+
+    /// Append a vector, returns the id it will have in the store. Returns an error if the vector does not have the dimension
+    /// of the store or the key has already been pushed.
+    pub fn push(&mut self, key: K, vector: &[T]) -> Result<usize, HypernonsenseError> {
+        if vector.len() != self.dims {
+            return Err(HypernonsenseError::DimensionMismatch { expected: self.dims, actual: vector.len() });
+        }
+        let id = self.keys.len();
+        if self.ids.insert(key, id).is_some() {
+            return Err(HypernonsenseError::InvalidConfig("key has already been written to the store".to_string()));
+        }
+
+        self.file.write_all(bytes_of(vector))?;
+        self.keys.push(key);
+        return Ok(id);
+    }
+
+    /// Write the keys and the final header, after this the file can be opened
+    pub fn finish(mut self) -> Result<(), HypernonsenseError> {
+        let written = self.keys.len() * self.dims * std::mem::size_of::<T>();
+        self.file.write_all(&[0u8; 8][..written.div_ceil(8) * 8 - written])?;
+        self.file.write_all(bytes_of(&self.keys))?;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        write_header::<K, T, _>(&mut self.file, self.dims, self.keys.len())?;
+        self.file.flush()?;
+        return Ok(());
+    }
+}
+
+fn write_header<K, T:Scalar, W: Write>(writer: &mut W, dims: usize, count: usize) -> Result<(), HypernonsenseError> {
+    writer.write_all(MAGIC)?;
+    for v in [FORMAT_VERSION, dims as u64, count as u64, std::mem::size_of::<K>() as u64, T::KIND.code() as u64] {
+        writer.write_all(&v.to_ne_bytes())?;
+    }
+    return Ok(());
+}
+
+// Keys and scalars are plain old data, so they can be written as they are laid out in memory
+fn bytes_of<E: Copy>(items: &[E]) -> &[u8] {
+    return unsafe { std::slice::from_raw_parts(items.as_ptr() as *const u8, std::mem::size_of_val(items)) };
+}
+
+/// A read only store of vectors in a memory mapped file, for re-ranking query candidates by their exact distance without
+/// keeping a copy of every vector in memory (see `MultiIndex::nearest_k_mapped`). Only the map from keys to ids is held in
+/// memory, vectors are paged in by the operating system as they are read.
+pub struct MappedVectorStore<K:MappedKey, T:Scalar=f32> {
+    map: Mmap,
+    ids: HashMap<K, usize>,
+    dims: usize,
+    count: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<K:MappedKey, T:Scalar> MappedVectorStore<K, T> {
+    /// Open a file written by `VectorStoreWriter` or `MultiIndex::save_vectors`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedVectorStore<K, T>, HypernonsenseError> {
+        let map = Mmap::open(path)?;
+        if map.bytes().len() < HEADER_LEN || &map.bytes()[0..8] != MAGIC {
+            return Err(HypernonsenseError::InvalidFormat("not a hypernonsense vector store".to_string()));
+        }
+
+        let header = map.slice::<u64>(8, 5)?;
+        if header[0] != FORMAT_VERSION {
+            return Err(HypernonsenseError::UnsupportedVersion(header[0] as u32));
+        }
+        check_scalar_kind::<T>(header[4], "file")?;
+        if header[3] as usize != std::mem::size_of::<K>() {
+            return Err(HypernonsenseError::InvalidFormat(format!("file was written with {} byte keys", header[3])));
+        }
+
+        let dims = header[1] as usize;
+        let count = header[2] as usize;
+        let len = count.checked_mul(dims)
+            .ok_or_else(|| HypernonsenseError::InvalidFormat("section size overflows".to_string()))?;
+
+        // Check the vectors fit in the file before reading the keys after them
+        map.slice::<T>(HEADER_LEN, len)?;
+        let keys = map.slice::<K>((HEADER_LEN + len * std::mem::size_of::<T>()).div_ceil(8) * 8, count)?;
+
+        let mut ids = HashMap::with_capacity(count);
+        for (id, key) in keys.iter().enumerate() {
+            if ids.insert(*key, id).is_some() {
+                return Err(HypernonsenseError::InvalidFormat("duplicate key".to_string()));
+            }
+        }
+
+        return Ok(MappedVectorStore { map, ids, dims, count, phantom: PhantomData });
+    }
+
+    pub fn dimensions(&self) -> usize {
+        return self.dims;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.count;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.count == 0;
+    }
+
+    /// The id of a key, i.e. its position in the file
+    pub fn id(&self, key: &K) -> Option<usize> {
+        return self.ids.get(key).copied();
+    }
+
+    /// The vector with an id.
+    ///
+    /// Panics if the id is not less than `len`.
+    pub fn vector(&self, id: usize) -> &[T] {
+        assert!(id < self.count, "id is out of range");
+
+        // Bounds and alignment were checked when the file was opened
+        return self.map.slice::<T>(HEADER_LEN + id * self.dims * std::mem::size_of::<T>(), self.dims).unwrap();
+    }
+
+    /// The vector with a key, if it is in the store
+    pub fn get(&self, key: &K) -> Option<&[T]> {
+        return self.id(key).map(|id| self.vector(id));
+    }
+
+    /// The distance from a point to the vector with a key, if it is in the store
+    pub fn distance(&self, metric: Metric, point: &[T], key: &K) -> Option<T> {
+        return self.get(key).map(|v| metric.distance(point, v));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use rand::prelude::*;
+
+    use crate::error::HypernonsenseError;
+    use crate::multiindex::MultiIndex;
+    use crate::vector::random_unit_vector;
+    use crate::vectorstore::{ MappedVectorStore, VectorStoreWriter };
+
+    #[test]
+    fn mapped_vectors_rerank_like_stored_vectors() {
+        let path = std::env::temp_dir().join(format!("hypernonsense_vectors_{}.vec", std::process::id()));
+
+        let mut index = MultiIndex::<u32>::builder(30).index_count(5).plane_count(6).store_vectors(true).build().unwrap();
+        let mut rng = thread_rng();
+        let vectors = (0..200).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            index.add(key as u32, v);
+        }
+
+        index.save_vectors(&path).unwrap();
+        let store = MappedVectorStore::<u32>::open(&path).unwrap();
+        assert_eq!(200, store.len());
+        assert_eq!(Some(vectors[17].as_slice()), store.get(&17));
+        assert_eq!(None, store.get(&1000));
+
+        for v in vectors.iter().take(20) {
+            let expected = index.nearest_k(v, 5).into_iter().map(|n| n.key).collect::<Vec<_>>();
+            let actual = index.nearest_k_mapped(v, 5, &store).into_iter().map(|n| n.key).collect::<Vec<_>>();
+            assert_eq!(expected, actual);
+        }
+
+        assert!(matches!(MappedVectorStore::<u64>::open(&path), Err(HypernonsenseError::InvalidFormat(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writer_assigns_ids_in_order() {
+        let path = std::env::temp_dir().join(format!("hypernonsense_vector_writer_{}.vec", std::process::id()));
+
+        let mut writer = VectorStoreWriter::<u64, f64>::create(&path, 3).unwrap();
+        assert_eq!(0, writer.push(10, &[1.0, 2.0, 3.0]).unwrap());
+        assert_eq!(1, writer.push(20, &[4.0, 5.0, 6.0]).unwrap());
+        assert!(writer.push(10, &[0.0, 0.0, 0.0]).is_err());
+        assert!(writer.push(30, &[0.0]).is_err());
+        writer.finish().unwrap();
+
+        let store = MappedVectorStore::<u64, f64>::open(&path).unwrap();
+        assert_eq!(2, store.len());
+        assert_eq!(Some(1), store.id(&20));
+        assert_eq!(&[4.0, 5.0, 6.0], store.vector(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::fmt::Debug;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use crate::error::HypernonsenseError;
+use crate::hyperindex::{HyperIndex, Planes};
+use crate::metric::Metric;
+use crate::multiindex::MultiIndex;
+use crate::persist::{Persist, crc32, read_exact};
+use crate::vector::{Normalization, Scalar, check_scalar_kind};
+
+// Log layout (all integers little endian):
+//
+//  magic         4 bytes "HYPW"
+//  version       u32
+//  scalar        u32 (the kind of the vector element type, see `ScalarKind::code`)
+//  dims          u64
+//  has vectors   u8 (if the logged index stores vectors)
+//  metric        u8 (see the index format)
+//  normalization u8 (see the index format)
+//  for each record:
+//      length        u32 (of the payload)
+//      checksum      u32 (CRC-32 of the payload)
+//      payload       tag u8, then for an add (0) the key and dims * T, or for a remove (1) the key
+//
+// A crash in the middle of appending a record leaves an incomplete record at the end of the log. Its checksum won't match,
+// so it is ignored when the log is replayed and cut off when the log is opened for appending again.
+const MAGIC: &[u8; 4] = b"HYPW";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: u64 = 23;
+
+const ADD: u8 = 0;
+const REMOVE: u8 = 1;
+
+// Configuration of the logged index, written at the start of the log
+struct Header {
+    dims: usize,
+    stores_vectors: bool,
+    metric: Metric,
+    normalization: Normalization,
+}
+
+fn read_header<T:Scalar, R: Read>(reader: &mut R) -> Result<Header, HypernonsenseError> {
+    let mut magic = [0u8; 4];
+    read_exact(reader, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(HypernonsenseError::InvalidFormat("not a hypernonsense write ahead log".to_string()));
+    }
+
+    let version = u32::restore(reader)?;
+    if version != FORMAT_VERSION {
+        return Err(HypernonsenseError::UnsupportedVersion(version));
+    }
+    check_scalar_kind::<T>(u32::restore(reader)? as u64, "log")?;
+
+    return Ok(Header {
+        dims: usize::restore(reader)?,
+        stores_vectors: bool::restore(reader)?,
+        metric: Metric::restore(reader)?,
+        normalization: Normalization::restore(reader)?,
+    });
+}
+
+// Read the next record, `None` at the end of the log or at an incomplete or corrupt record
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, HypernonsenseError> {
+    let mut frame = [0u8; 8];
+    match read_exact(reader, &mut frame) {
+        Ok(()) => {},
+        Err(HypernonsenseError::InvalidFormat(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) as u64;
+    let checksum = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len || crc32(&payload) != checksum {
+        return Ok(None);
+    }
+    return Ok(Some(payload));
+}
+
+/// An append only log of every item added to and removed from an index. If the process crashes the index can be rebuilt
+/// from its planes and the log with `MultiIndex::recover`, without having to save the whole index after every change.
+///
+/// Every record is flushed to the operating system before it returns, so the log survives the process crashing. Records are
+/// only guaranteed to survive the machine losing power once they have been synced, see `set_sync`.
+pub struct WriteAheadLog<K, T:Scalar=f32> {
+    file: BufWriter<File>,
+    dims: usize,
+    sync: bool,
+    buffer: Vec<u8>,
+    phantom: PhantomData<fn(&K, &[T])>,
+}
+
+impl<K:Persist, T:Scalar+Persist> WriteAheadLog<K, T> {
+    /// Create an empty log for an index, replacing the file if it exists. The log records the configuration of the index
+    /// (dimension, metric, normalization and if vectors are stored) so it can be recreated when recovering.
+    pub fn create<P: AsRef<Path>, V:Send+Sync>(path: P, index: &MultiIndex<K, T, V>) -> Result<WriteAheadLog<K, T>, HypernonsenseError>
+        where K : Clone + Eq + Hash + Debug + Send + Sync
+    {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        FORMAT_VERSION.persist(&mut file)?;
+        T::KIND.code().persist(&mut file)?;
+        index.dimensions().persist(&mut file)?;
+        index.stores_vectors().persist(&mut file)?;
+        index.metric().persist(&mut file)?;
+        index.normalization().persist(&mut file)?;
+        file.flush()?;
+        file.get_ref().sync_data()?;
+
+        return Ok(WriteAheadLog { file, dims: index.dimensions(), sync: false, buffer: Vec::new(), phantom: PhantomData });
+    }
+
+    /// Open an existing log to append more records to it. An incomplete record left at the end of the log by a crash is
+    /// removed first.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<WriteAheadLog<K, T>, HypernonsenseError> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut reader = BufReader::new(&mut file);
+        let header = read_header::<T, _>(&mut reader)?;
+        let mut end = HEADER_LEN;
+        while let Some(record) = read_record(&mut reader)? {
+            end += 8 + record.len() as u64;
+        }
+
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        return Ok(WriteAheadLog { file: BufWriter::new(file), dims: header.dims, sync: false, buffer: Vec::new(), phantom: PhantomData });
+    }
+
+    /// Set if every record is synced to disk before it returns. This makes every record survive power loss, at the cost of
+    /// waiting for the disk on every change. Defaults to false.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    /// Sync every record written so far to disk
+    pub fn sync(&mut self) -> Result<(), HypernonsenseError> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        return Ok(());
+    }
+
+    /// Append an add to the log. The vector should be the one which is actually inserted, i.e. already normalized.
+    ///
+    /// Panics if the vector does not have the dimension of the log.
+    pub fn log_add(&mut self, key: &K, vector: &[T]) -> Result<(), HypernonsenseError> {
+        assert_eq!(self.dims, vector.len(), "vector does not have the dimension of the log");
+
+        self.buffer.clear();
+        self.buffer.push(ADD);
+        key.persist(&mut self.buffer)?;
+        for v in vector {
+            v.persist(&mut self.buffer)?;
+        }
+        return self.append();
+    }
+
+    /// Append a remove to the log
+    pub fn log_remove(&mut self, key: &K) -> Result<(), HypernonsenseError> {
+        self.buffer.clear();
+        self.buffer.push(REMOVE);
+        key.persist(&mut self.buffer)?;
+        return self.append();
+    }
+
+    fn append(&mut self) -> Result<(), HypernonsenseError> {
+        let len = u32::try_from(self.buffer.len()).map_err(|_| HypernonsenseError::InvalidConfig("record is too large for the log".to_string()))?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(&crc32(&self.buffer).to_le_bytes())?;
+        self.file.write_all(&self.buffer)?;
+        self.file.flush()?;
+        if self.sync {
+            self.file.get_ref().sync_data()?;
+        }
+        return Ok(());
+    }
+}
+
+// Rebuild an index from its planes and a log, see `MultiIndex::recover`
+pub(crate) fn recover<K, T, P>(planes: Vec<Planes<T>>, path: P) -> Result<MultiIndex<K, T>, HypernonsenseError>
+    where K : Clone + Eq + Hash + Debug + Send + Sync + Persist, T : Scalar + Persist, P : AsRef<Path>
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = read_header::<T, _>(&mut reader)?;
+    if let Some(p) = planes.iter().find(|p| p.dimensions() != header.dims) {
+        return Err(HypernonsenseError::DimensionMismatch { expected: header.dims, actual: p.dimensions() });
+    }
+
+    let index = MultiIndex::from_indices(planes.into_iter().map(HyperIndex::with_planes).collect())?;
+    let vectors = if header.stores_vectors { Some(Default::default()) } else { None };
+    let mut index = MultiIndex::from_parts(index.into_indices(), vectors, Default::default(), header.metric, None, header.normalization);
+
+    replay_records(&mut index, &mut reader, header.dims)?;
+    return Ok(index);
+}
+
+// Apply every record in a log to an index which was saved when the log was created, see `SegmentedMultiIndex`
+pub(crate) fn replay<K, T, P>(index: &mut MultiIndex<K, T>, path: P) -> Result<(), HypernonsenseError>
+    where K : Clone + Eq + Hash + Debug + Send + Sync + Persist, T : Scalar + Persist, P : AsRef<Path>
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = read_header::<T, _>(&mut reader)?;
+    if header.dims != index.dimensions() {
+        return Err(HypernonsenseError::DimensionMismatch { expected: index.dimensions(), actual: header.dims });
+    }
+    return replay_records(index, &mut reader, header.dims);
+}
+
+fn replay_records<K, T, R>(index: &mut MultiIndex<K, T>, reader: &mut R, dims: usize) -> Result<(), HypernonsenseError>
+    where K : Clone + Eq + Hash + Debug + Send + Sync + Persist, T : Scalar + Persist, R : Read
+{
+    while let Some(record) = read_record(reader)? {
+        let mut payload = record.as_slice();
+        let mut tag = [0u8];
+        read_exact(&mut payload, &mut tag)?;
+        let key = K::restore(&mut payload)?;
+        match tag[0] {
+            ADD => {
+                let vector = (0..dims).map(|_| T::restore(&mut payload)).collect::<Result<Vec<_>, _>>()?;
+                index.add(key, &vector);
+            },
+            REMOVE => {
+                index.remove(&key);
+            },
+            t => return Err(HypernonsenseError::InvalidFormat(format!("unknown log record {}", t))),
+        }
+    }
+
+    return Ok(());
+}
+
+/// A `MultiIndex` which appends every add and remove to a `WriteAheadLog` before applying it, so the index can be recovered
+/// after a crash with `DurableMultiIndex::recover`. Queries go through `index()`.
+pub struct DurableMultiIndex<K:Send+Sync, T:Scalar=f32> {
+    index: MultiIndex<K, T>,
+    wal: WriteAheadLog<K, T>,
+}
+
+impl<K:Clone+Eq+Hash+Debug+Send+Sync+Persist, T:Scalar+Persist> DurableMultiIndex<K, T> {
+    /// Start logging changes to an index in a new log file, replacing the file if it exists. The index should be empty, or
+    /// saved separately, since only changes made from now on are logged.
+    pub fn create<P: AsRef<Path>>(index: MultiIndex<K, T>, path: P) -> Result<DurableMultiIndex<K, T>, HypernonsenseError> {
+        let wal = WriteAheadLog::create(path, &index)?;
+        return Ok(DurableMultiIndex { index, wal });
+    }
+
+    /// Rebuild an index from its planes and log after a crash, then keep appending to the same log
+    pub fn recover<P: AsRef<Path>>(planes: Vec<Planes<T>>, path: P) -> Result<DurableMultiIndex<K, T>, HypernonsenseError> {
+        let index = recover(planes, path.as_ref())?;
+        let wal = WriteAheadLog::open(path)?;
+        return Ok(DurableMultiIndex { index, wal });
+    }
+
+    pub fn index(&self) -> &MultiIndex<K, T> {
+        return &self.index;
+    }
+
+    pub fn wal_mut(&mut self) -> &mut WriteAheadLog<K, T> {
+        return &mut self.wal;
+    }
+
+    /// Stop logging and return the index
+    pub fn into_inner(self) -> MultiIndex<K, T> {
+        return self.index;
+    }
+
+    /// Log an add, then add the item to the index. Returns an error without changing anything if the vector is not valid
+    /// or the log can't be written.
+    pub fn add(&mut self, key: K, vector: &[T]) -> Result<(), HypernonsenseError> {
+        let vector = self.index.checked_owned(vector.to_vec())?;
+        self.wal.log_add(&key, &vector)?;
+        self.index.add(key, &vector);
+        return Ok(());
+    }
+
+    /// Log a remove, then remove the key from the index. Returns true if anything was removed.
+    pub fn remove(&mut self, key: &K) -> Result<bool, HypernonsenseError> {
+        self.wal.log_remove(key)?;
+        return Ok(self.index.remove(key));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use rand::prelude::*;
+
+    use crate::metric::Metric;
+    use crate::multiindex::MultiIndex;
+    use crate::wal::DurableMultiIndex;
+    use crate::vector::random_unit_vector;
+
+    #[test]
+    fn recover_replays_the_log() {
+        let path = std::env::temp_dir().join(format!("hypernonsense_wal_{}.log", std::process::id()));
+
+        let index = MultiIndex::<u64>::builder(30).index_count(4).plane_count(6).store_vectors(true).metric(Metric::Euclidean).build().unwrap();
+        let planes = index.export_planes();
+        let mut durable = DurableMultiIndex::create(index, &path).unwrap();
+
+        let mut rng = thread_rng();
+        let vectors = (0..50).map(|_| random_unit_vector(30, &mut rng)).collect::<Vec<_>>();
+        for (key, v) in vectors.iter().enumerate() {
+            durable.add(key as u64, v).unwrap();
+        }
+        assert!(durable.remove(&3).unwrap());
+        assert!(durable.add(100, &[1.0]).is_err());
+
+        // Simulate a crash part way through writing a record
+        drop(durable);
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[20, 0, 0, 0, 1, 2]).unwrap();
+
+        let mut recovered = DurableMultiIndex::<u64>::recover(planes.clone(), &path).unwrap();
+        assert_eq!(49, recovered.index().len());
+        assert_eq!(Metric::Euclidean, recovered.index().metric());
+        assert_eq!(None, recovered.index().get(&3));
+        assert_eq!(Some(vectors[7].as_slice()), recovered.index().get(&7));
+        assert_eq!(7, recovered.index().nearest_k(&vectors[7], 1)[0].key);
+
+        // The incomplete record was cut off, so records appended after recovering are replayed too
+        recovered.add(200, &vectors[0]).unwrap();
+        drop(recovered);
+        assert_eq!(50, MultiIndex::<u64>::recover(planes, &path).unwrap().len());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}